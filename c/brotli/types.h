@@ -0,0 +1,19 @@
+/* Common types of the C API of the rust-brotli encoder and decoder (see src/ffi).
+   The declarations match those of the C library, so code written against it builds unchanged. */
+
+#ifndef BROTLI_COMMON_TYPES_H_
+#define BROTLI_COMMON_TYPES_H_
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define BROTLI_BOOL int
+#define BROTLI_TRUE 1
+#define BROTLI_FALSE 0
+#define TO_BROTLI_BOOL(X) (!!(X) ? BROTLI_TRUE : BROTLI_FALSE)
+
+/* Custom allocators are not supported: pass NULL for both functions. */
+typedef void* (*brotli_alloc_func)(void* opaque, size_t size);
+typedef void (*brotli_free_func)(void* opaque, void* address);
+
+#endif  /* BROTLI_COMMON_TYPES_H_ */
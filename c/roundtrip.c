@@ -0,0 +1,217 @@
+/* Round trips data through the C API exported by src/ffi, both one-shot and streaming with small
+   buffers, and checks the output matches the input. Build and run it with `make -C c test`. */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "brotli/decode.h"
+#include "brotli/encode.h"
+
+#define CHECK(cond)                                                   \
+  do {                                                                \
+    if (!(cond)) {                                                    \
+      fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+      exit(1);                                                        \
+    }                                                                 \
+  } while (0)
+
+static uint8_t* make_input(size_t size) {
+  static const char* words[] = {"brotli ", "stream ", "window ", "quality ", "\n"};
+  uint8_t* input = (uint8_t*)malloc(size);
+  size_t i = 0;
+  uint32_t seed = 12345;
+  CHECK(input != NULL);
+  while (i < size) {
+    const char* word;
+    size_t len;
+    seed = seed * 1103515245u + 12345u;
+    word = words[(seed >> 16) % 5];
+    len = strlen(word);
+    if (len > size - i) len = size - i;
+    memcpy(input + i, word, len);
+    i += len;
+  }
+  return input;
+}
+
+static void test_one_shot(const uint8_t* input, size_t input_size, int quality) {
+  size_t encoded_size = BrotliEncoderMaxCompressedSize(input_size);
+  uint8_t* encoded = (uint8_t*)malloc(encoded_size);
+  size_t decoded_size = input_size;
+  uint8_t* decoded = (uint8_t*)malloc(input_size + 1);
+  CHECK(encoded != NULL && decoded != NULL);
+  CHECK(BrotliEncoderCompress(quality, BROTLI_DEFAULT_WINDOW, BROTLI_MODE_TEXT, input_size, input,
+                              &encoded_size, encoded));
+  CHECK(encoded_size < input_size);
+  CHECK(BrotliDecoderDecompress(encoded_size, encoded, &decoded_size, decoded) ==
+        BROTLI_DECODER_RESULT_SUCCESS);
+  CHECK(decoded_size == input_size);
+  CHECK(memcmp(decoded, input, input_size) == 0);
+  /* an output buffer one byte short is an error */
+  decoded_size = input_size - 1;
+  CHECK(BrotliDecoderDecompress(encoded_size, encoded, &decoded_size, decoded) ==
+        BROTLI_DECODER_RESULT_ERROR);
+  free(encoded);
+  free(decoded);
+}
+
+/* Compresses with 7-byte input and 5-byte output buffers, then decompresses the same way except
+   that every other call drains the decoder through BrotliDecoderTakeOutput. Qualities 0 and 1
+   compress whatever input each call gets, so their output can outgrow the one-shot bound. */
+static void test_streaming(const uint8_t* input, size_t input_size, int quality) {
+  size_t capacity = BrotliEncoderMaxCompressedSize(input_size);
+  uint8_t* encoded = (uint8_t*)malloc(capacity);
+  uint8_t* decoded = (uint8_t*)malloc(input_size);
+  size_t encoded_size = 0;
+  size_t decoded_size = 0;
+  size_t in_pos = 0;
+  size_t total_out = 0;
+  int take = 0;
+  BrotliEncoderState* enc = BrotliEncoderCreateInstance(NULL, NULL, NULL);
+  BrotliDecoderState* dec = BrotliDecoderCreateInstance(NULL, NULL, NULL);
+  CHECK(encoded != NULL && decoded != NULL && enc != NULL && dec != NULL);
+  CHECK(BrotliEncoderSetParameter(enc, BROTLI_PARAM_QUALITY, (uint32_t)quality));
+  CHECK(BrotliEncoderSetParameter(enc, BROTLI_PARAM_LGWIN, 18));
+  CHECK(BrotliEncoderSetParameter(enc, BROTLI_PARAM_SIZE_HINT, (uint32_t)input_size));
+  /* the distance layout of font mode, which only takes effect from quality 4 */
+  CHECK(BrotliEncoderSetParameter(enc, BROTLI_PARAM_NPOSTFIX, 1));
+  CHECK(BrotliEncoderSetParameter(enc, BROTLI_PARAM_NDIRECT, 12));
+
+  while (!BrotliEncoderIsFinished(enc)) {
+    size_t chunk = input_size - in_pos < 7 ? input_size - in_pos : 7;
+    size_t available_in = chunk;
+    const uint8_t* next_in = input + in_pos;
+    uint8_t buffer[5];
+    size_t available_out = sizeof(buffer);
+    uint8_t* next_out = buffer;
+    BrotliEncoderOperation op =
+        in_pos + chunk == input_size ? BROTLI_OPERATION_FINISH : BROTLI_OPERATION_PROCESS;
+    CHECK(BrotliEncoderCompressStream(enc, op, &available_in, &next_in, &available_out, &next_out,
+                                      &total_out));
+    CHECK(next_in == input + in_pos + (chunk - available_in));
+    in_pos += chunk - available_in;
+    if (encoded_size + (size_t)(next_out - buffer) > capacity) {
+      capacity *= 2;
+      encoded = (uint8_t*)realloc(encoded, capacity);
+      CHECK(encoded != NULL);
+    }
+    memcpy(encoded + encoded_size, buffer, (size_t)(next_out - buffer));
+    encoded_size += (size_t)(next_out - buffer);
+    CHECK(total_out == encoded_size);
+  }
+  CHECK(in_pos == input_size);
+  CHECK(!BrotliEncoderHasMoreOutput(enc));
+  BrotliEncoderDestroyInstance(enc);
+
+  in_pos = 0;
+  for (;;) {
+    BrotliDecoderResult result;
+    size_t available_in = encoded_size - in_pos < 7 ? encoded_size - in_pos : 7;
+    size_t offered = available_in;
+    const uint8_t* next_in = encoded + in_pos;
+    uint8_t buffer[5];
+    size_t available_out = sizeof(buffer);
+    uint8_t* next_out = buffer;
+    result = BrotliDecoderDecompressStream(dec, &available_in, &next_in, &available_out, &next_out,
+                                           &total_out);
+    CHECK(result != BROTLI_DECODER_RESULT_ERROR);
+    in_pos += offered - available_in;
+    CHECK(decoded_size + (size_t)(next_out - buffer) <= input_size);
+    memcpy(decoded + decoded_size, buffer, (size_t)(next_out - buffer));
+    decoded_size += (size_t)(next_out - buffer);
+    if (result == BROTLI_DECODER_RESULT_SUCCESS) break;
+    CHECK(result != BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT || in_pos < encoded_size);
+    if (result == BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT && (take++ & 1)) {
+      size_t size = 3;
+      const uint8_t* taken;
+      CHECK(BrotliDecoderHasMoreOutput(dec));
+      taken = BrotliDecoderTakeOutput(dec, &size);
+      CHECK(size <= 3 && (size == 0 || taken != NULL));
+      CHECK(decoded_size + size <= input_size);
+      memcpy(decoded + decoded_size, taken, size);
+      decoded_size += size;
+      if (BrotliDecoderIsFinished(dec)) break;
+    }
+  }
+  CHECK(BrotliDecoderIsFinished(dec));
+  CHECK(BrotliDecoderGetErrorCode(dec) == BROTLI_DECODER_SUCCESS);
+  {
+    /* a finished decoder keeps reporting success, and the count includes taken output */
+    size_t available_in = 0;
+    const uint8_t* next_in = NULL;
+    size_t available_out = 0;
+    CHECK(BrotliDecoderDecompressStream(dec, &available_in, &next_in, &available_out, NULL,
+                                        &total_out) == BROTLI_DECODER_RESULT_SUCCESS);
+  }
+  CHECK(total_out == input_size);
+  CHECK(decoded_size == input_size);
+  CHECK(memcmp(decoded, input, input_size) == 0);
+  BrotliDecoderDestroyInstance(dec);
+  free(encoded);
+  free(decoded);
+}
+
+static void* unused_alloc(void* opaque, size_t size) {
+  (void)opaque;
+  return malloc(size);
+}
+
+static void test_errors(void) {
+  static const uint8_t garbage[] = {0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff};
+  uint8_t out[16];
+  size_t out_size = sizeof(out);
+  size_t available_in = sizeof(garbage);
+  const uint8_t* next_in = garbage;
+  size_t available_out = sizeof(out);
+  uint8_t* next_out = out;
+  BrotliDecoderState* dec = BrotliDecoderCreateInstance(NULL, NULL, NULL);
+  BrotliDecoderState* dec2 = BrotliDecoderCreateInstance(NULL, NULL, NULL);
+  BrotliEncoderState* enc = BrotliEncoderCreateInstance(NULL, NULL, NULL);
+  CHECK(dec != NULL && dec2 != NULL && enc != NULL);
+  CHECK(BrotliDecoderDecompress(sizeof(garbage), garbage, &out_size, out) ==
+        BROTLI_DECODER_RESULT_ERROR);
+  CHECK(BrotliDecoderGetErrorCode(dec) == BROTLI_DECODER_NO_ERROR);
+  CHECK(BrotliDecoderDecompressStream(dec, &available_in, &next_in, &available_out, &next_out,
+                                      NULL) == BROTLI_DECODER_RESULT_ERROR);
+  CHECK(!BrotliDecoderIsFinished(dec));
+  CHECK(BrotliDecoderGetErrorCode(dec) == BROTLI_DECODER_ERROR_FORMAT);
+  CHECK(strcmp(BrotliDecoderErrorString(BrotliDecoderGetErrorCode(dec)), "_ERROR_FORMAT") == 0);
+  /* a failed decoder stays failed */
+  available_in = sizeof(garbage);
+  next_in = garbage;
+  CHECK(BrotliDecoderDecompressStream(dec, &available_in, &next_in, &available_out, &next_out,
+                                      NULL) == BROTLI_DECODER_RESULT_ERROR);
+  CHECK(BrotliDecoderGetErrorCode(dec) == BROTLI_DECODER_ERROR_FORMAT);
+  /* room for output but nowhere to put it */
+  CHECK(BrotliDecoderDecompressStream(dec2, &available_in, &next_in, &available_out, NULL,
+                                      NULL) == BROTLI_DECODER_RESULT_ERROR);
+  CHECK(BrotliDecoderGetErrorCode(dec2) == BROTLI_DECODER_ERROR_INVALID_ARGUMENTS);
+  CHECK(strcmp(BrotliDecoderErrorString(BROTLI_DECODER_NEEDS_MORE_INPUT), "_NEEDS_MORE_INPUT") == 0);
+  CHECK(strcmp(BrotliDecoderErrorString(BROTLI_DECODER_ERROR_FORMAT_PADDING_1),
+               "_ERROR_FORMAT_PADDING_1") == 0);
+  CHECK(strcmp(BrotliDecoderErrorString((BrotliDecoderErrorCode)-17), "INVALID") == 0);
+  CHECK(!BrotliEncoderSetParameter(enc, (BrotliEncoderParameter)99, 0));
+  CHECK(BrotliEncoderCreateInstance(unused_alloc, NULL, NULL) == NULL);
+  BrotliDecoderDestroyInstance(dec);
+  BrotliDecoderDestroyInstance(dec2);
+  BrotliEncoderDestroyInstance(enc);
+}
+
+int main(void) {
+  static const size_t sizes[] = {1000, 65536 + 17, 300000};
+  static const int qualities[] = {0, 1, 5, 9, 11};
+  size_t i, j;
+  CHECK(BrotliEncoderVersion() == BrotliDecoderVersion());
+  for (i = 0; i < sizeof(sizes) / sizeof(sizes[0]); ++i) {
+    uint8_t* input = make_input(sizes[i]);
+    for (j = 0; j < sizeof(qualities) / sizeof(qualities[0]); ++j) {
+      test_one_shot(input, sizes[i], qualities[j]);
+      test_streaming(input, sizes[i], qualities[j]);
+    }
+    free(input);
+  }
+  test_errors();
+  printf("ok\n");
+  return 0;
+}
@@ -0,0 +1,7 @@
+// Builds the C ABI of the brotli crate (src/ffi, declared in c/brotli/*.h) as libbrotli_ffi.a and
+// libbrotli_ffi.so (.dylib, .dll): cargo build --release -p brotli-ffi
+extern crate brotli;
+
+pub use brotli::ffi::compressor::*;
+pub use brotli::ffi::decompressor::*;
+pub use brotli::ffi::{BROTLI_BOOL, BROTLI_FALSE, BROTLI_TRUE};
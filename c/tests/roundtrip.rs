@@ -0,0 +1,205 @@
+// Checks the prototypes in the C headers in c/brotli against what src/ffi exports, then builds
+// c/roundtrip.c against the static library of this crate and runs it. The second test needs a C
+// compiler (CC, or cc) and fails without one.
+extern crate brotli_ffi;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn read(path: &Path) -> String {
+  match fs::read_to_string(path) {
+    Ok(text) => text,
+    Err(e) => panic!("{}: {}", path.display(), e),
+  }
+}
+
+fn manifest_dir() -> PathBuf {
+  PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn identifier_at(text: &str) -> &str {
+  let end = text.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(text.len());
+  &text[..end]
+}
+
+// The C spelling of a Rust parameter or return type of the FFI.
+fn c_type(rust: &str) -> String {
+  let rust = rust.trim();
+  if rust.starts_with("*mut ") {
+    return c_type(&rust["*mut ".len()..]) + "*";
+  }
+  if rust.starts_with("*const ") {
+    let pointee = c_type(&rust["*const ".len()..]);
+    // const binds to the innermost pointee: *const *mut u8 is not used
+    return if pointee.ends_with('*') { pointee + "*" } else { format!("const {}*", pointee) };
+  }
+  match rust {
+    "c_int" => "int",
+    "c_char" => "char",
+    "c_void" => "void",
+    "u8" => "uint8_t",
+    "u32" => "uint32_t",
+    "usize" => "size_t",
+    "" => "void",
+    other => other,
+  }.to_string()
+}
+
+// The prototypes of the #[no_mangle] functions in the Rust sources, as name -> "ret(args)" in C
+// spelling.
+fn exported_prototypes(sources: &[String]) -> BTreeMap<String, String> {
+  let mut prototypes = BTreeMap::new();
+  for source in sources.iter() {
+    let mut rest = &source[..];
+    while let Some(pos) = rest.find("#[no_mangle]") {
+      rest = &rest[pos..];
+      let fn_pos = rest.find("extern \"C\" fn ").unwrap() + "extern \"C\" fn ".len();
+      rest = &rest[fn_pos..];
+      let name = identifier_at(rest);
+      let open = rest.find('(').unwrap();
+      let close = rest.find(')').unwrap();
+      let args: Vec<String> = rest[open + 1..close].split(',')
+        .filter(|arg| !arg.trim().is_empty())
+        .map(|arg| c_type(&arg[arg.find(':').unwrap() + 1..]))
+        .collect();
+      let after = &rest[close + 1..rest.find('{').unwrap()];
+      let ret = c_type(after.trim().trim_start_matches("->"));
+      let args = if args.is_empty() { "void".to_string() } else { args.join(", ") };
+      prototypes.insert(name.to_string(), format!("{}({})", ret, args));
+    }
+  }
+  prototypes
+}
+
+// A C type with the spacing of c_type; the enums are ints to the ABI, and name[] is a pointer.
+fn normalize_c_type(c: &str, enums: &BTreeSet<String>) -> String {
+  let mut tokens: Vec<String> = c.replace('*', " * ").split_whitespace().map(|t| t.to_string()).collect();
+  if tokens.len() > 1 && tokens[tokens.len() - 1] != "*" {
+    // drop the parameter name, turning name[] into a pointer
+    let name = tokens.pop().unwrap();
+    if name.ends_with("[]") {
+      tokens.push("*".to_string());
+    }
+  }
+  let mut ret = String::new();
+  for token in tokens.iter() {
+    if token != "*" && !ret.is_empty() {
+      ret.push(' ');
+    }
+    ret.push_str(if enums.contains(token) { "int" } else { token });
+  }
+  ret
+}
+
+// The prototypes the headers declare, outside of comments, preprocessor lines and typedefs, in
+// the form of exported_prototypes.
+fn declared_prototypes(headers: &[String]) -> BTreeMap<String, String> {
+  let mut code = String::new();
+  for header in headers.iter() {
+    let mut in_comment = false;
+    for line in header.lines() {
+      let line = line.trim();
+      if in_comment || line.starts_with("/*") {
+        in_comment = !line.contains("*/");
+        continue;
+      }
+      if !line.starts_with('#') {
+        code.push_str(line);
+        code.push(' ');
+      }
+    }
+  }
+  let mut enums = BTreeSet::new();
+  for statement in code.split(';') {
+    // (the first typedef follows extern "C" {)
+    if let Some(pos) = statement.find("typedef enum") {
+      let statement = &statement[pos..];
+      enums.insert(identifier_at(&statement["typedef enum".len()..].trim_start()).to_string());
+      let close = statement.rfind('}').unwrap();
+      enums.insert(statement[close + 1..].trim().to_string());
+    }
+  }
+  let mut prototypes = BTreeMap::new();
+  for statement in code.split(|c| c == ';' || c == '{' || c == '}') {
+    let statement = statement.trim();
+    let open = match statement.find('(') {
+      Some(open) if !statement.starts_with("typedef") => open,
+      _ => continue,
+    };
+    let head = statement[..open].trim_end();
+    let name_start = head.rfind(|c: char| !(c.is_alphanumeric() || c == '_')).map_or(0, |pos| pos + 1);
+    if !head[name_start..].starts_with("Brotli") {
+      continue;
+    }
+    let close = statement.rfind(')').unwrap();
+    let args: Vec<String> = statement[open + 1..close].split(',').map(|arg| normalize_c_type(arg, &enums)).collect();
+    prototypes.insert(head[name_start..].to_string(),
+                      format!("{}({})", normalize_c_type(&head[..name_start], &enums), args.join(", ")));
+  }
+  prototypes
+}
+
+// NAME = value pairs of the BROTLI_DECODER results and error codes, from `pub const NAME: c_int =
+// value;` in Rust and `NAME = value,` in C.
+fn decoder_constants(text: &str) -> BTreeSet<(String, i32)> {
+  let mut constants = BTreeSet::new();
+  for line in text.lines() {
+    let line = line.trim().trim_start_matches("pub const ");
+    if !line.starts_with("BROTLI_DECODER_") || line.starts_with("BROTLI_DECODER_PARAM_") {
+      continue;
+    }
+    let name = identifier_at(line);
+    let value = line[name.len()..].trim_start_matches(": c_int").trim_start();
+    if !value.starts_with('=') || value.starts_with("=>") {
+      continue;
+    }
+    let value = value[1..].trim().trim_end_matches(|c| c == ',' || c == ';');
+    constants.insert((name.to_string(), value.parse().unwrap()));
+  }
+  constants
+}
+
+#[test]
+fn test_headers_match_exports() {
+  let ffi = manifest_dir().join("../src/ffi");
+  let sources = [read(&ffi.join("compressor.rs")), read(&ffi.join("decompressor.rs"))];
+  let headers = [read(&manifest_dir().join("brotli/encode.h")), read(&manifest_dir().join("brotli/decode.h"))];
+  let exported = exported_prototypes(&sources);
+  assert_eq!(exported["BrotliEncoderCompressStream"],
+             "BROTLI_BOOL(BrotliEncoderState*, int, size_t*, const uint8_t**, size_t*, uint8_t**, size_t*)");
+  assert_eq!(exported["BrotliDecoderHasMoreOutput"], "BROTLI_BOOL(const BrotliDecoderState*)");
+  assert_eq!(exported, declared_prototypes(&headers));
+  let rust_constants = decoder_constants(&sources[1]);
+  let c_constants = decoder_constants(&headers[1]);
+  assert!(rust_constants.contains(&("BROTLI_DECODER_ERROR_FORMAT".to_string(), -32)));
+  assert_eq!(rust_constants, c_constants);
+}
+
+#[test]
+fn test_c_roundtrip() {
+  // the test binary sits next to the libraries of this crate in target/<profile>/deps
+  let deps = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+  let library = deps.join("libbrotli_ffi.a");
+  assert!(library.exists(), "{} was not built", library.display());
+  let program = deps.join("brotli_ffi_roundtrip");
+  let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+  let mut compile = Command::new(&compiler);
+  compile.args(["-O2", "-Wall", "-Wextra", "-Werror", "-std=c99", "-I"])
+    .arg(manifest_dir())
+    .arg("-o")
+    .arg(&program)
+    .arg(manifest_dir().join("roundtrip.c"))
+    .arg(&library)
+    .args(["-lpthread", "-ldl", "-lm"]);
+  let status = match compile.status() {
+    Ok(status) => status,
+    Err(e) => panic!("{} does not run ({}); set CC to a C compiler", compiler, e),
+  };
+  assert!(status.success(), "{:?} failed", compile);
+  let output = Command::new(&program).output().unwrap();
+  assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+  assert_eq!(&output.stdout[..], &b"ok\n"[..]);
+}
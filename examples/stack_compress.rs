@@ -0,0 +1,66 @@
+extern crate brotli;
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use brotli::{CustomRead, CustomWrite};
+use brotli::enc::stack_profile::{BROTLI_STACK_PROFILE_BUFFER_SIZE, BROTLI_STACK_PROFILE_FAST_MEMORY_SIZE};
+
+struct Stdin(io::Stdin);
+
+impl CustomRead<io::Error> for Stdin {
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, io::Error> {
+        loop {
+            match self.0.read(data) {
+                Err(e) => {
+                    if let io::ErrorKind::Interrupted = e.kind() {
+                        continue;
+                    }
+                    return Err(e);
+                }
+                Ok(size) => return Ok(size),
+            }
+        }
+    }
+}
+
+struct Stdout(io::Stdout);
+
+impl CustomWrite<io::Error> for Stdout {
+    fn write(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+        loop {
+            match self.0.write(data) {
+                Err(e) => {
+                    if let io::ErrorKind::Interrupted = e.kind() {
+                        continue;
+                    }
+                    return Err(e);
+                }
+                Ok(size) => return Ok(size),
+            }
+        }
+    }
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.0.flush()
+    }
+}
+
+// Compresses stdin to stdout at quality 1 with a 64KB window without touching the heap: all the
+// memory the compressor uses is on the stack of a thread that has little else, the pools of
+// qualities 0 and 1, the input and output buffers and 256KB for the frames of the encoder.
+fn main() {
+    let stack_size = BROTLI_STACK_PROFILE_FAST_MEMORY_SIZE + 2 * BROTLI_STACK_PROFILE_BUFFER_SIZE + 256 * 1024;
+    let child = thread::Builder::new().stack_size(stack_size).spawn(|| {
+        let mut params = brotli::enc::BrotliEncoderInitParams();
+        params.quality = 1;
+        params.lgwin = brotli::enc::stack_profile::BROTLI_STACK_PROFILE_MAX_LGWIN;
+        brotli::BrotliCompressStackProfile(&mut Stdin(io::stdin()),
+                                           &mut Stdout(io::stdout()),
+                                           &params,
+                                           io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected EOF"),
+                                           io::Error::new(io::ErrorKind::InvalidInput, "Invalid Parameters"))
+    }).unwrap();
+    match child.join().unwrap() {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+    }
+}
@@ -203,6 +203,94 @@ pub fn decompress<InputType, OutputType>(r: &mut InputType,
                                    },
                                    Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF"))
 }
+// Compresses through a VerifyingCompressorWriter, which decodes the output as it goes and fails
+// if it does not reproduce the input.
+#[cfg(not(feature="no-stdlib"))]
+fn compress_verified<InputType, OutputType>(r: &mut InputType,
+                                            w: &mut OutputType,
+                                            buffer_size: usize,
+                                            params: &brotli::enc::BrotliEncoderParams)
+                                            -> Result<usize, io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  let mut writer = brotli::VerifyingCompressorWriter::with_params(w, buffer_size, params);
+  let size = io::copy(r, &mut writer)?;
+  writer.finish()?;
+  Ok(size as usize)
+}
+
+#[cfg(feature="no-stdlib")]
+fn compress_verified<InputType, OutputType>(_r: &mut InputType,
+                                            _w: &mut OutputType,
+                                            _buffer_size: usize,
+                                            _params: &brotli::enc::BrotliEncoderParams)
+                                            -> Result<usize, io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  Err(io::Error::new(io::ErrorKind::Other, "--verify needs the stdlib"))
+}
+
+// Compresses with compress, or with compress_verified if --verify was given.
+fn compress_maybe_verified<InputType, OutputType>(r: &mut InputType,
+                                                  w: &mut OutputType,
+                                                  buffer_size: usize,
+                                                  params: &brotli::enc::BrotliEncoderParams,
+                                                  verify: bool)
+                                                  -> Result<usize, io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  if verify {
+    compress_verified(r, w, buffer_size, params)
+  } else {
+    compress(r, w, buffer_size, params)
+  }
+}
+
+// Decompresses through a LimitedDecompressor, which fails once the output passes max_output bytes.
+#[cfg(not(feature="no-stdlib"))]
+fn decompress_with_limit<InputType, OutputType>(r: &mut InputType,
+                                                w: &mut OutputType,
+                                                buffer_size: usize,
+                                                max_output: usize)
+                                                -> Result<(), io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  let mut reader = brotli::LimitedDecompressor::new(r, buffer_size, max_output);
+  io::copy(&mut reader, w)?;
+  Ok(())
+}
+
+#[cfg(feature="no-stdlib")]
+fn decompress_with_limit<InputType, OutputType>(_r: &mut InputType,
+                                                _w: &mut OutputType,
+                                                _buffer_size: usize,
+                                                _max_output: usize)
+                                                -> Result<(), io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  Err(io::Error::new(io::ErrorKind::Other, "--max-output needs the stdlib"))
+}
+
+// Decompresses with decompress, or with decompress_with_limit if --max-output was given.
+fn decompress_maybe_limited<InputType, OutputType>(r: &mut InputType,
+                                                   w: &mut OutputType,
+                                                   buffer_size: usize,
+                                                   max_output: Option<usize>)
+                                                   -> Result<(), io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  match max_output {
+    Some(max_output) => decompress_with_limit(r, w, buffer_size, max_output),
+    None => decompress(r, w, buffer_size),
+  }
+}
+
 #[cfg(feature="seccomp")]
 extern "C" {
   fn calloc(n_elem: usize, el_size: usize) -> *mut u8;
@@ -261,9 +349,6 @@ pub fn compress<InputType, OutputType>(r: &mut InputType,
                                        params:&brotli::enc::BrotliEncoderParams) -> Result<usize, io::Error>
     where InputType: Read,
           OutputType: Write {
-    let mut alloc_u8 = HeapAllocator::<u8> { default_value: 0 };
-    let mut input_buffer = alloc_u8.alloc_cell(buffer_size);
-    let mut output_buffer = alloc_u8.alloc_cell(buffer_size);
     let mut log = |pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
                    data:&mut [brotli::interface::Command<brotli::SliceOffset>],
                    mb:brotli::InputPair,
@@ -283,6 +368,26 @@ pub fn compress<InputType, OutputType>(r: &mut InputType,
     if params.log_meta_block {
         println_stderr!("window {} 0 0 0", params.lgwin);
     }
+    compress_with_callback(r, w, buffer_size, params, &mut log)
+}
+
+// Compresses with a callback that receives the IR of each metablock when params.log_meta_block is set.
+pub fn compress_with_callback<InputType, OutputType, Cb>(r: &mut InputType,
+                                                         w: &mut OutputType,
+                                                         buffer_size: usize,
+                                                         params:&brotli::enc::BrotliEncoderParams,
+                                                         callback: &mut Cb) -> Result<usize, io::Error>
+    where InputType: Read,
+          OutputType: Write,
+          Cb: FnMut(&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                    &mut [StaticCommand],
+                    brotli::InputPair,
+                    &mut HeapAllocator<brotli::enc::Mem256f>,
+                    &mut HeapAllocator<brotli::enc::PDF>,
+                    &mut HeapAllocator<StaticCommand>) {
+    let mut alloc_u8 = HeapAllocator::<u8> { default_value: 0 };
+    let mut input_buffer = alloc_u8.alloc_cell(buffer_size);
+    let mut output_buffer = alloc_u8.alloc_cell(buffer_size);
     brotli::BrotliCompressCustomIo(&mut IoReaderWrapper::<InputType>(r),
                                    &mut IoWriterWrapper::<OutputType>(w),
                                    &mut input_buffer.slice_mut(),
@@ -319,7 +424,7 @@ pub fn compress<InputType, OutputType>(r: &mut InputType,
                                    HeapAllocator::<ZopfliNode>{
                                        default_value:ZopfliNode::default(),
                                    },
-                                   &mut log,
+                                   callback,
                                    Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF"))
 }
 
@@ -375,12 +480,71 @@ fn writeln_time<OutputType: Write>(strm: &mut OutputType,
   writeln!(strm, "{:} {:} {:}.{:09}", v0, data, v1, v2)
 }
 
+fn read_samples(path: &Path, samples: &mut Vec<Vec<u8>>) -> Result<(), io::Error> {
+  if path.is_dir() {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+      entries.push(entry?.path());
+    }
+    entries.sort();
+    for entry in entries.iter() {
+      read_samples(entry, samples)?;
+    }
+  } else {
+    let mut sample = Vec::new();
+    File::open(path)?.read_to_end(&mut sample)?;
+    samples.push(sample);
+  }
+  Ok(())
+}
+
+// Trains a dictionary on every file under path and reports on stderr how much it saves.
+#[cfg(not(feature="no-stdlib"))]
+fn train(path: &Path,
+         max_size: usize,
+         params: &brotli::enc::BrotliEncoderParams) -> Result<Vec<u8>, io::Error> {
+  let mut samples = Vec::new();
+  read_samples(path, &mut samples)?;
+  let refs: Vec<&[u8]> = samples.iter().map(|s| &s[..]).collect();
+  let dictionary = brotli::enc::dictionary_train::train_dictionary_with_params(&refs[..], max_size, params)?;
+  let (baseline, with_dictionary) = brotli::enc::dictionary_train::evaluate_dictionary(&refs[..], &dictionary[..], params)?;
+  println_stderr!("{} samples, {} byte dictionary: {} bytes compressed without it, {} with it ({:.1}% saved)",
+                  refs.len(), dictionary.len(), baseline, with_dictionary,
+                  100.0 * (baseline as f64 - with_dictionary as f64) / core::cmp::max(baseline, 1) as f64);
+  Ok(dictionary)
+}
+
+#[cfg(feature="no-stdlib")]
+fn train(_path: &Path,
+         _max_size: usize,
+         _params: &brotli::enc::BrotliEncoderParams) -> Result<Vec<u8>, io::Error> {
+  Err(io::Error::new(io::ErrorKind::Other, "dictionary training needs the stdlib"))
+}
+
+#[cfg(not(feature="no-stdlib"))]
+fn analyze(data: &[u8]) {
+  match brotli::enc::analyzer::BrotliAnalyzeStream(data) {
+    Ok(report) => print!("{}", report),
+    Err(e) => panic!("Error {:?} while analyzing the stream", e),
+  }
+}
+
+#[cfg(feature="no-stdlib")]
+fn analyze(_data: &[u8]) {
+  panic!("--analyze needs the stdlib");
+}
+
 fn main() {
   let mut do_compress = false;
   let mut params = brotli::enc::BrotliEncoderInitParams();
   params.quality = 11; // default
   let mut filenames = [std::string::String::new(), std::string::String::new()];
   let mut num_benchmarks = 1;
+  let mut do_train = false;
+  let mut do_analyze = false;
+  let mut max_dictionary_size = 65536usize;
+  let mut max_output: Option<usize> = None;
+  let mut verify = false;
   if env::args_os().len() > 1 {
     let mut first = true;
     for argument in env::args() {
@@ -471,6 +635,46 @@ fn main() {
         params.quality = 11;
         continue;
       }
+      if argument == "-12" || argument == "-q12" {
+        params.quality = 12;
+        continue;
+      }
+      if argument == "-exhaustive" {
+        params.exhaustive_match_search = true;
+        continue;
+      }
+      if argument == "--train" {
+        do_train = true;
+        continue;
+      }
+      if argument == "--analyze" {
+        do_analyze = true;
+        continue;
+      }
+      if argument == "--verify" {
+        verify = true;
+        continue;
+      }
+      if argument.starts_with("--max-output=") {
+        max_output = Some(argument.trim_start_matches("--max-output=").parse::<usize>().unwrap());
+        continue;
+      }
+      if argument.starts_with("-maxdict=") {
+        max_dictionary_size = argument.trim_start_matches("-maxdict=").parse::<usize>().unwrap();
+        continue;
+      }
+      if argument == "-nodict" {
+        params.hasher.disable_static_dictionary = true;
+        continue;
+      }
+      if argument.starts_with("-dictminlen=") {
+        params.hasher.static_dictionary_min_word_len = argument.trim_start_matches("-dictminlen=").parse::<i32>().unwrap();
+        continue;
+      }
+      if argument.starts_with("-dicttransforms=") {
+        params.hasher.static_dictionary_transforms = argument.trim_start_matches("-dicttransforms=").parse::<u32>().unwrap() & brotli::enc::static_dict::BROTLI_DICTIONARY_TRANSFORM_ALL;
+        continue;
+      }
       if argument == "-q9.5y" {
           params.quality = 12;
           params.q9_5 = true;
@@ -546,8 +750,25 @@ fn main() {
           }
           continue;
       }
+      if argument.starts_with("-zopfliiterations=") {
+          params.zopfli_iterations = argument.trim_start_matches("-zopfliiterations=").parse::<u32>().unwrap();
+          continue;
+      }
+      if argument.starts_with("-zopflicandidates=") {
+          params.zopfli_max_candidates = argument.trim_start_matches("-zopflicandidates=").parse::<u32>().unwrap();
+          continue;
+      }
+      if argument.starts_with("-zopflimaxlen=") {
+          params.zopfli_max_len = argument.trim_start_matches("-zopflimaxlen=").parse::<u32>().unwrap();
+          continue;
+      }
+      if argument == "-exhaustivedistanceprefixsearch" {
+          params.exhaustive_distance_prefix_search = true;
+          continue;
+      }
       if argument == "-avoiddistanceprefixsearch" {
           params.avoid_distance_prefix_search = true;
+          continue;
       }
       if argument.starts_with("-b") {
           num_benchmarks = argument.trim_matches('-').trim_matches('b').parse::<usize>().unwrap();
@@ -558,7 +779,7 @@ fn main() {
         continue;
       }
       if argument == "-h" || argument == "-help" || argument == "--help" {
-        println_stderr!("Decompression:\nbrotli [input_file] [output_file]\nCompression:brotli -c -q9.5 -w22 [input_file] [output_file]\nQuality may be one of -q9.5 -q9.5x -q9.5y or -q[0-11] for standard brotli settings.\nOptional size hint -s<size> to direct better compression\n\nThe -i parameter produces a cross human readdable IR representation of the file.\nThis can be ingested by other compressors.\nIR-specific options include:\n-findprior\n-speed=<inc,max,inc,max,inc,max,inc,max>");
+        println_stderr!("Decompression:\nbrotli [input_file] [output_file]\nCompression:brotli -c -q9.5 -w22 [input_file] [output_file]\nQuality may be one of -q9.5 -q9.5x -q9.5y or -q[0-11] for standard brotli settings.\n-q12 (or -exhaustive at -q11) searches every match with a suffix array and also runs the -q11 parse,\nkeeping the smaller: 3 to 4 times slower than -q11, but never larger.\n-exhaustivedistanceprefixsearch prices every NPOSTFIX/NDIRECT distance layout at -q10 and -q11 (implied by -q12).\n-zopfliiterations=<n> -zopflicandidates=<n> -zopflimaxlen=<n> trade time for ratio at -q10 and -q11 (see README).\n-nodict skips the built-in static dictionary; -dictminlen=<n> ignores dictionary words shorter than n bytes;\n-dicttransforms=<mask> limits transforms to identity(1) omit-last(2) omit-first(4) uppercase(8) affix(16).\nDictionary training: brotli --train -maxdict=65536 [sample_dir] [dictionary_file]\nStream structure report: brotli --analyze [input_file]\nDecompression fails once the output would pass n bytes with --max-output=<n>.\n--verify decodes the compressed output as it is written and fails unless it reproduces the input.\nOptional size hint -s<size> to direct better compression\n\nThe -i parameter produces a cross human readdable IR representation of the file.\nThis can be ingested by other compressors.\nIR-specific options include:\n-findprior\n-speed=<inc,max,inc,max,inc,max,inc,max>");
         return;
       }
       if filenames[0] == "" {
@@ -571,6 +792,43 @@ fn main() {
       }
       panic!("Unknown Argument {:}", argument);
    }
+   if params.exhaustive_match_search && (params.quality < 11 || params.q9_5) {
+      // the encoder ignores the flag off the plain quality 11 path
+      println_stderr!("-exhaustive only applies at -q11; ignoring it");
+      params.exhaustive_match_search = false;
+   }
+   if do_train {
+      if filenames[0] == "" {
+        panic!("--train needs a directory or file of samples");
+      }
+      let dictionary = match train(Path::new(&filenames[0]), max_dictionary_size, &params) {
+        Ok(dictionary) => dictionary,
+        Err(e) => panic!("Error {:?}", e),
+      };
+      if filenames[1] != "" {
+        let mut output = match File::create(&Path::new(&filenames[1])) {
+          Err(why) => panic!("couldn't open file for writing: {:}\n{:}", filenames[1], why),
+          Ok(file) => file,
+        };
+        output.write_all(&dictionary[..]).unwrap();
+      } else {
+        io::stdout().write_all(&dictionary[..]).unwrap();
+      }
+      return;
+   }
+   if do_analyze {
+      let mut data = Vec::new();
+      if filenames[0] != "" {
+        match File::open(&Path::new(&filenames[0])) {
+          Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
+          Ok(mut file) => file.read_to_end(&mut data).unwrap(),
+        };
+      } else {
+        io::stdin().read_to_end(&mut data).unwrap();
+      }
+      analyze(&data[..]);
+      return;
+   }
    if filenames[0] != "" {
       let mut input = match File::open(&Path::new(&filenames[0])) {
         Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
@@ -583,12 +841,12 @@ fn main() {
         };
         for i in 0..num_benchmarks {
           if do_compress {
-            match compress(&mut input, &mut output, 65536, &params) {
+            match compress_maybe_verified(&mut input, &mut output, 65536, &params, verify) {
                 Ok(_) => {}
                 Err(e) => panic!("Error {:?}", e),
             }
           } else {
-            match decompress(&mut input, &mut output, 65536) {
+            match decompress_maybe_limited(&mut input, &mut output, 65536, max_output) {
               Ok(_) => {}
               Err(e) => panic!("Error: {:} during brotli decompress\nTo compress with Brotli, specify the -c flag.", e),
             }
@@ -602,12 +860,12 @@ fn main() {
       } else {
         assert_eq!(num_benchmarks, 1);
         if do_compress {
-          match compress(&mut input, &mut io::stdout(), 65536, &params) {
+          match compress_maybe_verified(&mut input, &mut io::stdout(), 65536, &params, verify) {
             Ok(_) => {}
             Err(e) => panic!("Error {:?}", e),
           }
         } else {
-          match decompress(&mut input, &mut io::stdout(), 65536) {
+          match decompress_maybe_limited(&mut input, &mut io::stdout(), 65536, max_output) {
             Ok(_) => {}
             Err(e) => panic!("Error: {:} during brotli decompress\nTo compress with Brotli, specify the -c flag.", e),
           }
@@ -617,12 +875,12 @@ fn main() {
    } else {
       assert_eq!(num_benchmarks, 1);
       if do_compress {
-        match compress(&mut io::stdin(), &mut io::stdout(), 65536, &params) {
+        match compress_maybe_verified(&mut io::stdin(), &mut io::stdout(), 65536, &params, verify) {
           Ok(_) => return,
           Err(e) => panic!("Error {:?}", e),
         }
       } else {
-        match decompress(&mut io::stdin(), &mut io::stdout(), 65536) {
+        match decompress_maybe_limited(&mut io::stdin(), &mut io::stdout(), 65536, max_output) {
           Ok(_) => return,
           Err(e) => panic!("Error: {:} during brotli decompress\nTo compress with Brotli, specify the -c flag.", e),
         }
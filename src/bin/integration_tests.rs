@@ -7,6 +7,7 @@ use super::HeapAllocator;
 #[allow(unused_imports)]
 use super::alloc_no_stdlib::{Allocator, SliceWrapper, SliceWrapperMut};
 use super::brotli::BrotliResult;
+use super::brotli::{CustomRead, CustomWrite};
 use super::brotli::BrotliState;
 #[cfg(not(feature="no-stdlib"))]
 use super::brotli::{CompressorReader, CompressorWriter};
@@ -305,11 +306,15 @@ fn roundtrip_helper(in_buf: &[u8], q: i32, lgwin: i32, q9_5: bool) -> usize {
   params.quality = q;
   params.q9_5 = q9_5;
   params.lgwin = lgwin;
+  params_roundtrip_helper(in_buf, &mut params)
+}
+
+fn params_roundtrip_helper(in_buf: &[u8], params: &mut super::brotli::enc::BrotliEncoderParams) -> usize {
   params.size_hint = if in_buf.len() > 100000 { 2048 * 1024} else {in_buf.len()};
   let mut input = UnlimitedBuffer::new(&in_buf);
   let mut compressed = UnlimitedBuffer::new(&[]);
   let mut output = UnlimitedBuffer::new(&[]);
-  match super::compress(&mut input, &mut compressed, 4096, &params) {
+  match super::compress(&mut input, &mut compressed, 4096, params) {
     Ok(_) => {}
     Err(e) => panic!("Error {:?}", e),
   }
@@ -385,34 +390,46 @@ fn test_random_then_unicode_9() {
 #[cfg(not(feature="no-stdlib"))]
 const random_then_unicode_compressed_size_9_5 : usize = 136542;
 #[cfg(not(feature="no-stdlib"))]
-const random_then_unicode_compressed_size_9_5x : usize = 136045;
+const random_then_unicode_compressed_size_9_5x : usize = 136026;
 
 
+#[cfg(not(feature="fixed-point"))]
 #[cfg(feature="no-stdlib")]
 const alice_compressed_size_10 : usize = 47490;
+#[cfg(not(feature="fixed-point"))]
 #[cfg(feature="no-stdlib")]
 const alice_compressed_size_11 : usize = 46496;
 
+#[cfg(not(feature="fixed-point"))]
 #[cfg(not(feature="no-stdlib"))]
 #[cfg(not(feature="float64"))]
 const alice_compressed_size_10 : usize = 47477;
+#[cfg(not(feature="fixed-point"))]
 #[cfg(not(feature="no-stdlib"))]
 #[cfg(not(feature="float64"))]
 const alice_compressed_size_11 : usize = 46487;
 
+#[cfg(not(feature="fixed-point"))]
 #[cfg(not(feature="no-stdlib"))]
 #[cfg(feature="float64")]
 const alice_compressed_size_10 : usize = 47515;
+#[cfg(not(feature="fixed-point"))]
 #[cfg(not(feature="no-stdlib"))]
 #[cfg(feature="float64")]
 const alice_compressed_size_11 : usize = 46510;
 
+// the fixed-point cost model replaces the log approximations, so no-stdlib does not change it
+#[cfg(feature="fixed-point")]
+const alice_compressed_size_10 : usize = 47512;
+#[cfg(feature="fixed-point")]
+const alice_compressed_size_11 : usize = 46512;
+
 
 
 #[cfg(feature="no-stdlib")] // approx log
-const random_then_unicode_compressed_size_9_5 : usize = 136699;
+const random_then_unicode_compressed_size_9_5 : usize = 136613;
 #[cfg(feature="no-stdlib")] // approx log
-const random_then_unicode_compressed_size_9_5x : usize = 136095;
+const random_then_unicode_compressed_size_9_5x : usize = 136088;
 
 #[test]
 fn test_random_then_unicode_9_5() {
@@ -438,6 +455,1283 @@ fn test_alice29_10() {
     assert_eq!(c_size, alice_compressed_size_10);
 }
 
+fn exhaustive_never_larger_helper(data: &[u8]) {
+  let q11_size = roundtrip_helper(data, 11, 22, false);
+  let q12_size = roundtrip_helper(data, 12, 22, false);
+  assert!(q12_size <= q11_size, "{} > {}", q12_size, q11_size);
+}
+
+#[test]
+fn test_exhaustive_alice29() {
+  exhaustive_never_larger_helper(include_bytes!("../../testdata/alice29.txt"));
+}
+
+#[test]
+fn test_exhaustive_as_you_lik() {
+  exhaustive_never_larger_helper(include_bytes!("../../testdata/asyoulik.txt"));
+}
+
+#[test]
+fn test_exhaustive_quickfox_repeated() {
+  exhaustive_never_larger_helper(include_bytes!("../../testdata/quickfox_repeated"));
+}
+
+#[test]
+fn test_exhaustive_compressed_repeated() {
+  exhaustive_never_larger_helper(include_bytes!("../../testdata/compressed_repeated"));
+}
+
+#[test]
+fn test_exhaustive_aaabaaaa() {
+  exhaustive_never_larger_helper(include_bytes!("../../testdata/aaabaaaa"));
+}
+
+// the flag only applies at quality 11 and leaves the lower qualities as they were
+#[test]
+fn test_exhaustive_flag_every_quality() {
+  let input = &include_bytes!("../../testdata/alice29.txt")[..32768];
+  for quality in 0..12 {
+    let mut sizes = [0usize; 2];
+    for (index, exhaustive) in [false, true].iter().enumerate() {
+      let mut params = super::brotli::enc::BrotliEncoderInitParams();
+      params.quality = quality;
+      params.exhaustive_match_search = *exhaustive;
+      let mut compressed = UnlimitedBuffer::new(&[]);
+      super::compress(&mut UnlimitedBuffer::new(input), &mut compressed, 4096, &params).unwrap();
+      let mut output = UnlimitedBuffer::new(&[]);
+      match super::decompress(&mut UnlimitedBuffer::new(&compressed.data[..]), &mut output, 4096) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?} at quality {}", e, quality),
+      }
+      assert!(output.data[..] == input[..], "quality {}", quality);
+      sizes[index] = compressed.data.len();
+    }
+    if quality < 11 {
+      assert_eq!(sizes[0], sizes[1], "quality {}", quality);
+    } else {
+      assert!(sizes[1] <= sizes[0], "{} > {}", sizes[1], sizes[0]);
+    }
+  }
+}
+
+// whole-buffer input keeps the suffix array between metablocks, and a small window makes it reindex
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+#[test]
+fn test_exhaustive_whole_buffer() {
+  use super::brotli::compress_to_vec;
+  let mut data = Vec::new();
+  data.extend_from_slice(&include_bytes!("../../testdata/random_then_unicode")[..]);
+  data.extend_from_slice(&include_bytes!("../../testdata/asyoulik.txt")[..]);
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.lgwin = 16;
+  params.quality = 11;
+  let q11 = compress_to_vec(&data[..], &params).unwrap();
+  params.quality = 12;
+  let q12 = compress_to_vec(&data[..], &params).unwrap();
+  assert!(q12.len() <= q11.len(), "{} > {}", q12.len(), q11.len());
+  let mut compressed_in = UnlimitedBuffer::new(&q12[..]);
+  let mut output = UnlimitedBuffer::new(&[]);
+  super::decompress(&mut compressed_in, &mut output, 4096).unwrap();
+  assert_eq!(&output.data[..], &data[..]);
+}
+
+#[test]
+fn test_zopfli_controls() {
+  let data = include_bytes!("../../testdata/asyoulik.txt");
+  let default_size = roundtrip_helper(data, 11, 22, false);
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = 11;
+  params.lgwin = 22;
+  // spelling out the quality 11 defaults changes nothing
+  params.zopfli_iterations = 2;
+  params.zopfli_max_candidates = 5;
+  params.zopfli_max_len = 325;
+  assert_eq!(params_roundtrip_helper(data, &mut params), default_size);
+  // and each knob on its own changes the output
+  params.zopfli_iterations = 1;
+  let one_iteration_size = params_roundtrip_helper(data, &mut params);
+  assert!(one_iteration_size > default_size, "{} {}", one_iteration_size, default_size);
+  params.zopfli_iterations = 2;
+  params.zopfli_max_candidates = 1;
+  let one_candidate_size = params_roundtrip_helper(data, &mut params);
+  assert!(one_candidate_size > default_size, "{} {}", one_candidate_size, default_size);
+  params.zopfli_max_candidates = 5;
+  params.zopfli_max_len = 8;
+  assert!(params_roundtrip_helper(data, &mut params) != default_size);
+  params.zopfli_max_len = 0;
+  // a pass that comes out more expensive than an earlier one is dropped, so more passes help
+  params.zopfli_iterations = 3;
+  let three_iterations_size = params_roundtrip_helper(data, &mut params);
+  assert!(three_iterations_size < default_size, "{} {}", three_iterations_size, default_size);
+  params.zopfli_iterations = 8;
+  let eight_iterations_size = params_roundtrip_helper(data, &mut params);
+  assert!(eight_iterations_size < three_iterations_size, "{} {}", eight_iterations_size, three_iterations_size);
+  // candidates past 8 take a larger start position queue, up to 64 entries
+  params.zopfli_iterations = 2;
+  params.zopfli_max_candidates = 8;
+  let eight_candidates_size = params_roundtrip_helper(data, &mut params);
+  params.zopfli_max_candidates = 16;
+  assert!(params_roundtrip_helper(data, &mut params) != eight_candidates_size);
+  params.zopfli_max_candidates = 100;
+  let many_candidates_size = params_roundtrip_helper(data, &mut params);
+  params.zopfli_max_candidates = 64;
+  assert_eq!(params_roundtrip_helper(data, &mut params), many_candidates_size);
+  // a second iteration takes quality 10 to the quality 11 search
+  params.quality = 10;
+  params.zopfli_iterations = 2;
+  params.zopfli_max_candidates = 0;
+  params.zopfli_max_len = 0;
+  let q10_size = roundtrip_helper(data, 10, 22, false);
+  let q10_two_iterations_size = params_roundtrip_helper(data, &mut params);
+  assert!(q10_two_iterations_size < q10_size, "{} {}", q10_two_iterations_size, q10_size);
+}
+
+// Size and leading SHA-256 bytes of the fixed-point encoder's output for each file and quality,
+// with the static dictionary on as in the default parameters. These may only change along with the
+// encoder itself, never between the simd, no-stdlib and default builds.
+#[cfg(feature="fixed-point")]
+static FIXED_POINT_GOLDEN: [(&'static str, i32, usize, u64); 15] = [
+  ("alice29.txt", 1, 63741, 0x157abc960c68ed0d),
+  ("alice29.txt", 5, 52808, 0xf8ecbeb074e91b8b),
+  ("alice29.txt", 9, 51055, 0xc73e0019b84bcbd8),
+  ("alice29.txt", 10, 47512, 0x5f84456f1bc92d1e),
+  ("alice29.txt", 11, 46512, 0x3fbddfc2bfae8886),
+  ("random_then_unicode", 1, 176505, 0x9197263f563c1e4c),
+  ("random_then_unicode", 5, 140784, 0xfcc350c1291b44c4),
+  ("random_then_unicode", 9, 138835, 0xceef55ffda0d621b),
+  ("random_then_unicode", 10, 135382, 0xcdb0c290e7e74acc),
+  ("random_then_unicode", 11, 130215, 0x868fa2c53cc86044),
+  ("compressed_repeated", 1, 101449, 0xc4b8ddf30cd4386b),
+  ("compressed_repeated", 5, 50445, 0xb2c47163e5bb60ab),
+  ("compressed_repeated", 9, 50443, 0x902e0d4161cd94b6),
+  ("compressed_repeated", 10, 50167, 0xfe36076655539e1e),
+  ("compressed_repeated", 11, 50156, 0x6617c42c08ef0b20),
+];
+
+#[cfg(feature="fixed-point")]
+#[test]
+fn test_fixed_point_golden_output() {
+  let mut mismatches = Vec::new();
+  for &(name, q, golden_size, golden_hash) in FIXED_POINT_GOLDEN.iter() {
+    let data: &[u8] = match name {
+      "alice29.txt" => include_bytes!("../../testdata/alice29.txt"),
+      "random_then_unicode" => RANDOM_THEN_UNICODE,
+      "compressed_repeated" => include_bytes!("../../testdata/compressed_repeated"),
+      _ => panic!("no golden input {}", name),
+    };
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = q;
+    params.lgwin = 22;
+    let mut compressed = UnlimitedBuffer::new(&[]);
+    super::compress(&mut UnlimitedBuffer::new(data), &mut compressed, 65536, &params).unwrap();
+    let mut output = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut UnlimitedBuffer::new(&compressed.data[..]), &mut output, 65536) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+    assert!(output.data[..] == data[..]);
+    let digest = super::brotli::enc::sha256::sha256(&compressed.data[..]);
+    let mut hash: u64 = 0;
+    for byte in digest[..8].iter() {
+      hash = (hash << 8) | u64::from(*byte);
+    }
+    if compressed.data.len() != golden_size || hash != golden_hash {
+      mismatches.push(format!("(\"{}\", {}, {}, 0x{:016x}),", name, q, compressed.data.len(), hash));
+    }
+  }
+  assert!(mismatches.is_empty(), "output differs from the golden table:\n{}", mismatches.join("\n"));
+}
+
+// Compresses data at every level the simd kernels can run at, and checks that they all agree.
+#[cfg(all(feature="simd", not(feature="no-stdlib")))]
+fn simd_levels_agree_helper(data: &[u8], q: i32) {
+  use super::brotli::enc::simd::{BrotliSetSimdLevelLimit, SimdLevel};
+  struct ResetLimit;
+  impl Drop for ResetLimit {
+    fn drop(&mut self) {
+      BrotliSetSimdLevelLimit(SimdLevel::Avx2);
+    }
+  }
+  let _reset = ResetLimit;
+  let mut outputs = Vec::new();
+  for level in [SimdLevel::Scalar, SimdLevel::Sse2, SimdLevel::Avx2].iter() {
+    BrotliSetSimdLevelLimit(*level);
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = q;
+    params.lgwin = 22;
+    let mut compressed = UnlimitedBuffer::new(&[]);
+    super::compress(&mut UnlimitedBuffer::new(data), &mut compressed, 65536, &params).unwrap();
+    outputs.push(compressed.data);
+  }
+  for (level, output) in outputs.iter().enumerate() {
+    assert!(output[..] == outputs[0][..], "q{}: level {} differs from scalar", q, level);
+  }
+}
+
+#[cfg(all(feature="simd", not(feature="no-stdlib")))]
+#[test]
+fn test_simd_levels_agree_alice29() {
+  for q in [2, 5, 9, 10, 11].iter() {
+    simd_levels_agree_helper(include_bytes!("../../testdata/alice29.txt"), *q);
+  }
+}
+
+#[cfg(all(feature="simd", not(feature="no-stdlib")))]
+#[test]
+fn test_simd_levels_agree_random_then_unicode() {
+  for q in [5, 10, 11].iter() {
+    simd_levels_agree_helper(RANDOM_THEN_UNICODE, *q);
+  }
+}
+
+// Compresses and decompresses data, and returns the compressed size along with the word length
+// and transform of every static dictionary reference that the encoder emitted.
+fn dictionary_references_helper(data: &[u8], params: &mut super::brotli::enc::BrotliEncoderParams)
+                                -> (usize, Vec<(u8, u8)>) {
+  use super::brotli::interface::{Command, PredictionModeContextMap};
+  params.log_meta_block = true;
+  let mut references = Vec::new();
+  let mut compressed = UnlimitedBuffer::new(&[]);
+  {
+    let mut record = |_pm: &mut PredictionModeContextMap<super::brotli::InputReferenceMut>,
+                      cmds: &mut [super::StaticCommand],
+                      _mb: super::brotli::InputPair,
+                      _mfv: &mut HeapAllocator<super::brotli::enc::Mem256f>,
+                      _mpdf: &mut HeapAllocator<super::brotli::enc::PDF>,
+                      _mc: &mut HeapAllocator<super::StaticCommand>| {
+      for cmd in cmds.iter() {
+        if let Command::Dict(ref dict) = *cmd {
+          references.push((dict.word_size, dict.transform));
+        }
+      }
+    };
+    match super::compress_with_callback(&mut UnlimitedBuffer::new(data), &mut compressed, 4096, params, &mut record) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+  }
+  let mut output = UnlimitedBuffer::new(&[]);
+  match super::decompress(&mut UnlimitedBuffer::new(&compressed.data[..]), &mut output, 4096) {
+    Ok(_) => {}
+    Err(e) => panic!("Error {:?}", e),
+  }
+  assert!(output.data[..] == data[..]);
+  (compressed.data.len(), references)
+}
+
+// Compresses data with the full static dictionary, with words of 6 bytes or more used as they
+// are, and with no dictionary, checking that each stream only refers to the words it may.
+// Returns the compressed size and the number of dictionary references of each of the three.
+fn static_dictionary_restricted_helper(data: &[u8], q: i32) -> [(usize, usize); 3] {
+  use super::brotli::enc::static_dict::{BrotliDictionaryTransformClasses, BROTLI_DICTIONARY_TRANSFORM_IDENTITY,
+                                       kBrotliEncDictionary};
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = q;
+  params.lgwin = 22;
+  let (full_size, full_references) = dictionary_references_helper(data, &mut params.clone());
+  params.hasher.static_dictionary_min_word_len = 6;
+  params.hasher.static_dictionary_transforms = BROTLI_DICTIONARY_TRANSFORM_IDENTITY;
+  let (restricted_size, references) = dictionary_references_helper(data, &mut params.clone());
+  for &(word_size, transform) in references.iter() {
+    assert!(word_size >= 6, "q{}: {} byte word", q, word_size);
+    assert_eq!(BrotliDictionaryTransformClasses(&kBrotliEncDictionary, transform as usize),
+               BROTLI_DICTIONARY_TRANSFORM_IDENTITY,
+               "q{}: transform {}", q, transform);
+  }
+  params.hasher.disable_static_dictionary = true;
+  let (nodict_size, nodict_references) = dictionary_references_helper(data, &mut params);
+  assert_eq!(nodict_references.len(), 0, "q{}", q);
+  [(full_size, full_references.len()), (restricted_size, references.len()), (nodict_size, 0)]
+}
+
+#[test]
+fn test_static_dictionary_restricted_alice29_9() {
+  let [(full_size, _), _, (nodict_size, _)] =
+    static_dictionary_restricted_helper(include_bytes!("../../testdata/alice29.txt"), 9);
+  assert!(nodict_size > full_size, "{} <= {}", nodict_size, full_size);
+}
+
+#[test]
+fn test_static_dictionary_restricted_alice29_10() {
+  let [(full_size, _), (restricted_size, restricted_references), (nodict_size, _)] =
+    static_dictionary_restricted_helper(include_bytes!("../../testdata/alice29.txt"), 10);
+  assert!(nodict_size > full_size, "{} <= {}", nodict_size, full_size);
+  assert!(restricted_references > 0);
+  assert!(restricted_size < nodict_size, "{} >= {}", restricted_size, nodict_size);
+}
+
+#[test]
+fn test_static_dictionary_restricted_alice29_11() {
+  let [(full_size, _), (restricted_size, restricted_references), (nodict_size, _)] =
+    static_dictionary_restricted_helper(include_bytes!("../../testdata/alice29.txt"), 11);
+  assert!(nodict_size > full_size, "{} <= {}", nodict_size, full_size);
+  assert!(restricted_references > 0);
+  assert!(restricted_size < nodict_size, "{} >= {}", restricted_size, nodict_size);
+}
+
+#[test]
+fn test_static_dictionary_restricted_quickfox_repeated() {
+  // only the first sentence can use the dictionary, the rest copies it
+  let [(_, full_references), (_, restricted_references), _] =
+    static_dictionary_restricted_helper(include_bytes!("../../testdata/quickfox_repeated"), 11);
+  assert!(full_references > 0);
+  assert!(restricted_references < full_references, "{} >= {}", restricted_references, full_references);
+}
+
+// Records the IR the encoder logs for in_buf, compiles it back into a stream and checks that
+// the stream decodes to in_buf. Returns the sizes of the encoder's and the compiled stream.
+#[cfg(not(feature="no-stdlib"))]
+fn ir_compile_roundtrip_helper(in_buf: &[u8], q: i32) -> (usize, usize) {
+  use super::brotli::interface::{Command, LiteralCommand, PredictionModeContextMap, FeatureFlagSliceType};
+  use super::brotli::{InputReference, SliceOffset};
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = q;
+  params.log_meta_block = true;
+  let mut ir = Vec::<super::StaticCommand>::new();
+  // the predmode/speed/distance map of each metablock, referenced by its PredictionMode command
+  let mut maps = Vec::<u8>::new();
+  let mut compressed = UnlimitedBuffer::new(&[]);
+  {
+    let mut metablock_start = 0usize;
+    let mut record = |pm: &mut PredictionModeContextMap<super::brotli::InputReferenceMut>,
+                      data: &mut [super::StaticCommand],
+                      _mb: super::brotli::InputPair,
+                      _mfv: &mut HeapAllocator<super::brotli::enc::Mem256f>,
+                      _mpdf: &mut HeapAllocator<super::brotli::enc::PDF>,
+                      _mc: &mut HeapAllocator<super::StaticCommand>| {
+      let base = metablock_start;
+      let map = pm.predmode_speed_and_distance_context_map.slice();
+      ir.push(Command::PredictionMode(PredictionModeContextMap::<SliceOffset> {
+        literal_context_map: SliceOffset(0, 0),
+        predmode_speed_and_distance_context_map: SliceOffset(maps.len(), map.len() as u32),
+      }));
+      maps.extend_from_slice(map);
+      for cmd in data.iter() {
+        match *cmd {
+          Command::Literal(ref lit) => {
+            ir.push(Command::Literal(LiteralCommand::<SliceOffset> {
+              data: SliceOffset(base + lit.data.offset(), lit.data.len32()),
+              prob: FeatureFlagSliceType::<SliceOffset>::default(),
+              high_entropy: lit.high_entropy,
+            }));
+            metablock_start += lit.data.len();
+          }
+          Command::Copy(ref copy) => {
+            ir.push(cmd.clone());
+            metablock_start += copy.num_bytes as usize;
+          }
+          Command::Dict(ref dict) => {
+            ir.push(cmd.clone());
+            metablock_start += dict.final_size as usize;
+          }
+          _ => ir.push(cmd.clone()),
+        }
+      }
+    };
+    match super::compress_with_callback(&mut UnlimitedBuffer::new(in_buf), &mut compressed, 4096, &params, &mut record) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+  }
+  let commands: Vec<Command<InputReference>> = ir.iter().map(|cmd| match *cmd {
+    Command::PredictionMode(_) => super::brotli::thaw(cmd, &maps[..]),
+    _ => super::brotli::thaw(cmd, in_buf),
+  }).collect();
+  let compiled = super::brotli::enc::ir_compile::BrotliCompileIRToVec(&params, &commands[..]).unwrap();
+  let mut output = UnlimitedBuffer::new(&[]);
+  match super::decompress(&mut UnlimitedBuffer::new(&compiled[..]), &mut output, 4096) {
+    Ok(_) => {}
+    Err(e) => panic!("Error {:?}", e),
+  }
+  assert_eq!(output.data.len(), in_buf.len());
+  assert!(output.data[..] == in_buf[..]);
+  (compressed.data.len(), compiled.len())
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_ir_compile_alice29() {
+  let (encoded, compiled) = ir_compile_roundtrip_helper(include_bytes!("../../testdata/alice29.txt"), 10);
+  assert!(compiled < encoded + encoded / 50, "{} vs {}", compiled, encoded);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_ir_compile_asyoulik_q5() {
+  let (encoded, compiled) = ir_compile_roundtrip_helper(include_bytes!("../../testdata/asyoulik.txt"), 5);
+  assert!(compiled < encoded + encoded / 20, "{} vs {}", compiled, encoded);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_ir_compile_random_then_unicode() {
+  ir_compile_roundtrip_helper(RANDOM_THEN_UNICODE, 11);
+}
+
+// Prints the IR the way -i does, parses it back and checks that the parsed commands print
+// identically and still compile to the input.
+#[cfg(not(feature="no-stdlib"))]
+fn ir_text_roundtrip_helper(in_buf: &[u8], q: i32) {
+  use super::brotli::interface::{Command, PredictionModeContextMap};
+  use super::brotli::InputReference;
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = q;
+  params.log_meta_block = true;
+  let mut text = format!("window {} 0 0 0\n", params.lgwin).into_bytes();
+  let header_len = text.len();
+  {
+    let mut log = |pm: &mut PredictionModeContextMap<super::brotli::InputReferenceMut>,
+                   data: &mut [super::StaticCommand],
+                   mb: super::brotli::InputPair,
+                   _mfv: &mut HeapAllocator<super::brotli::enc::Mem256f>,
+                   _mpdf: &mut HeapAllocator<super::brotli::enc::PDF>,
+                   _mc: &mut HeapAllocator<super::StaticCommand>| {
+      let prediction = Command::PredictionMode(PredictionModeContextMap::<InputReference> {
+        literal_context_map: InputReference::from(&pm.literal_context_map),
+        predmode_speed_and_distance_context_map: InputReference::from(&pm.predmode_speed_and_distance_context_map),
+      });
+      super::util::write_one_to(&mut text, &prediction).unwrap();
+      for cmd in data.iter() {
+        super::util::write_one_to(&mut text, &super::brotli::thaw_pair(cmd, &mb)).unwrap();
+      }
+    };
+    match super::compress_with_callback(&mut UnlimitedBuffer::new(in_buf), &mut io::sink(), 4096, &params, &mut log) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+  }
+  let commands = super::brotli::enc::ir_parse::BrotliParseIR(&text[..]).unwrap();
+  let mut reprinted = Vec::<u8>::new();
+  for cmd in commands.iter() {
+    super::util::write_one_to(&mut reprinted, cmd).unwrap();
+  }
+  assert!(reprinted[..] == text[header_len..]);
+  let compiled = super::brotli::enc::ir_compile::BrotliCompileIRToVec(&params, &commands[..]).unwrap();
+  let mut output = UnlimitedBuffer::new(&[]);
+  match super::decompress(&mut UnlimitedBuffer::new(&compiled[..]), &mut output, 4096) {
+    Ok(_) => {}
+    Err(e) => panic!("Error {:?}", e),
+  }
+  assert!(output.data[..] == in_buf[..]);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_ir_text_roundtrip_corpus() {
+  let corpus: [&[u8]; 14] = [include_bytes!("../../testdata/10x10y"),
+                             include_bytes!("../../testdata/64x"),
+                             include_bytes!("../../testdata/aaabaaaa"),
+                             include_bytes!("../../testdata/asyoulik.txt"),
+                             include_bytes!("../../testdata/backward65536"),
+                             include_bytes!("../../testdata/compressed_file"),
+                             include_bytes!("../../testdata/empty"),
+                             include_bytes!("../../testdata/monkey"),
+                             include_bytes!("../../testdata/quickfox"),
+                             include_bytes!("../../testdata/quickfox_repeated"),
+                             include_bytes!("../../testdata/random_org_10k.bin"),
+                             include_bytes!("../../testdata/ukkonooa"),
+                             include_bytes!("../../testdata/x"),
+                             include_bytes!("../../testdata/xyzzy")];
+  for data in corpus.iter() {
+    ir_text_roundtrip_helper(data, 9);
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_ir_text_roundtrip_alice29_q11() {
+  ir_text_roundtrip_helper(include_bytes!("../../testdata/alice29.txt"), 11);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_ir_text_roundtrip_random_then_unicode_q5() {
+  ir_text_roundtrip_helper(RANDOM_THEN_UNICODE, 5);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_ir_serialize_roundtrip() {
+  use super::brotli::enc::ir_serialize::{BrotliIRReader, BrotliIRWriter};
+  use super::brotli::interface::{Command, PredictionModeContextMap};
+  use super::brotli::{InputReference, IoReaderWrapper, IoWriterWrapper};
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = 9;
+  params.log_meta_block = true;
+  let mut serialized = Vec::<u8>::new();
+  let mut text_size = 0usize;
+  {
+    let mut writer = BrotliIRWriter::new(IoWriterWrapper(&mut serialized),
+                                         HeapAllocator::<u8> { default_value: 0 }.alloc_cell(4096),
+                                         || io::Error::new(io::ErrorKind::InvalidData, "Invalid IR"));
+    {
+      let mut log = |pm: &mut PredictionModeContextMap<super::brotli::InputReferenceMut>,
+                     data: &mut [super::StaticCommand],
+                     mb: super::brotli::InputPair,
+                     _mfv: &mut HeapAllocator<super::brotli::enc::Mem256f>,
+                     _mpdf: &mut HeapAllocator<super::brotli::enc::PDF>,
+                     _mc: &mut HeapAllocator<super::StaticCommand>| {
+        let mut text = Vec::<u8>::new();
+        for cmd in data.iter() {
+          super::util::write_one_to(&mut text, &super::brotli::thaw_pair(cmd, &mb)).unwrap();
+        }
+        text_size += text.len();
+        writer.write_metablock(pm, data, &mb).unwrap();
+      };
+      match super::compress_with_callback(&mut UnlimitedBuffer::new(alice), &mut io::sink(), 4096, &params, &mut log) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+      }
+    }
+    writer.finish().unwrap();
+  }
+  assert!(serialized.len() * 2 < text_size, "{} vs {}", serialized.len(), text_size);
+  let mut m8 = HeapAllocator::<u8> { default_value: 0 };
+  let mut mc = HeapAllocator::<super::StaticCommand> { default_value: super::StaticCommand::default() };
+  let mut input = &serialized[..];
+  let mut reader = BrotliIRReader::new(IoReaderWrapper(&mut input),
+                                       m8.alloc_cell(4096),
+                                       || io::Error::new(io::ErrorKind::InvalidData, "Invalid IR"));
+  let mut metablocks = Vec::new();
+  while let Some(metablock) = reader.read_metablock(&mut m8, &mut mc).unwrap() {
+    metablocks.push(metablock);
+  }
+  let mut commands = Vec::<Command<InputReference>>::new();
+  for metablock in metablocks.iter() {
+    commands.push(Command::PredictionMode(PredictionModeContextMap::<InputReference> {
+      literal_context_map: InputReference {
+        data: metablock.prediction_mode.literal_context_map.slice(),
+        orig_offset: 0,
+      },
+      predmode_speed_and_distance_context_map: InputReference {
+        data: metablock.prediction_mode.predmode_speed_and_distance_context_map.slice(),
+        orig_offset: 0,
+      },
+    }));
+    for index in 0..metablock.commands.slice().len() {
+      commands.push(metablock.command(index));
+    }
+  }
+  let compiled = super::brotli::enc::ir_compile::BrotliCompileIRToVec(&params, &commands[..]).unwrap();
+  let mut output = UnlimitedBuffer::new(&[]);
+  match super::decompress(&mut UnlimitedBuffer::new(&compiled[..]), &mut output, 4096) {
+    Ok(_) => {}
+    Err(e) => panic!("Error {:?}", e),
+  }
+  assert!(output.data[..] == alice[..]);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_analyze_testdata() {
+  use super::brotli::enc::analyzer::{BrotliAnalyzeStream, BrotliMetaBlockType};
+  let cases: [(&[u8], &[u8]); 7] =
+    [(include_bytes!("../../testdata/64x.compressed"), include_bytes!("../../testdata/64x")),
+     (include_bytes!("../../testdata/alice29.txt.compressed"), include_bytes!("../../testdata/alice29.txt")),
+     (include_bytes!("../../testdata/asyoulik.txt.compressed"), include_bytes!("../../testdata/asyoulik.txt")),
+     (include_bytes!("../../testdata/backward65536.compressed"), include_bytes!("../../testdata/backward65536")),
+     (include_bytes!("../../testdata/compressed_file.compressed"), include_bytes!("../../testdata/compressed_file")),
+     (include_bytes!("../../testdata/compressed_repeated.compressed"),
+      include_bytes!("../../testdata/compressed_repeated")),
+     (include_bytes!("../../testdata/x.compressed.03"), include_bytes!("../../testdata/x"))];
+  for &(compressed, original) in cases.iter() {
+    let report = BrotliAnalyzeStream(compressed).unwrap();
+    assert_eq!(report.output_size, original.len());
+    assert_eq!(report.trailing_bytes, 0);
+    let length: usize = report.metablocks
+      .iter()
+      .filter(|mb| mb.kind != BrotliMetaBlockType::Metadata)
+      .map(|mb| mb.length)
+      .sum();
+    assert_eq!(length, original.len());
+    assert!(format!("{}", report).starts_with("window bits"));
+  }
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = 11;
+  params.large_window = true;
+  params.lgwin = 24;
+  let mut compressed = UnlimitedBuffer::new(&[]);
+  super::compress(&mut UnlimitedBuffer::new(RANDOM_THEN_UNICODE), &mut compressed, 65536, &params).unwrap();
+  let report = BrotliAnalyzeStream(&compressed.data[..]).unwrap();
+  assert!(report.large_window);
+  assert_eq!(report.window_bits, 24);
+  assert_eq!(report.output_size, RANDOM_THEN_UNICODE.len());
+}
+
+#[test]
+fn test_optimal_huffman_codes_never_larger() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let mut input = alice.to_vec();
+  input.extend_from_slice(RANDOM_THEN_UNICODE);
+  for q in [4i32, 5, 9].iter() {
+    let mut sizes = [0usize; 2];
+    for (index, optimal) in [false, true].iter().enumerate() {
+      let mut params = super::brotli::enc::BrotliEncoderInitParams();
+      params.quality = *q;
+      params.optimal_huffman_codes = *optimal;
+      let mut compressed = UnlimitedBuffer::new(&[]);
+      super::compress(&mut UnlimitedBuffer::new(&input[..]), &mut compressed, 65536, &params).unwrap();
+      let mut output = UnlimitedBuffer::new(&[]);
+      match super::decompress(&mut UnlimitedBuffer::new(&compressed.data[..]), &mut output, 65536) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+      }
+      assert!(output.data[..] == input[..]);
+      sizes[index] = compressed.data.len();
+    }
+    assert!(sizes[1] <= sizes[0], "q{}: {} > {}", q, sizes[1], sizes[0]);
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_per_block_type_literal_context_modes() {
+  use super::brotli::enc::analyzer::{BrotliAnalyzeStream, BrotliMetaBlockType};
+  use super::brotli::enc::backward_references::BrotliEncoderMode;
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  // english text around a run of little endian 16 bit samples of a slow wave
+  let mut input = alice[..40000].to_vec();
+  for i in 0..40000i32 {
+    let sample = ((i % 628) - 314) * ((i / 628) % 7 + 1) * 13;
+    input.push(sample as u8);
+    input.push((sample >> 8) as u8);
+  }
+  input.extend_from_slice(&alice[40000..80000]);
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  for q in [10i32, 11].iter() {
+    params.quality = *q;
+    params.mode = BrotliEncoderMode::BROTLI_MODE_GENERIC;
+    let mut compressed = UnlimitedBuffer::new(&[]);
+    super::compress(&mut UnlimitedBuffer::new(&input[..]), &mut compressed, 65536, &params).unwrap();
+    let mut output = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut UnlimitedBuffer::new(&compressed.data[..]), &mut output, 65536) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+    assert!(output.data[..] == input[..]);
+    let report = BrotliAnalyzeStream(&compressed.data[..]).unwrap();
+    let mut modes: Vec<u8> = report.metablocks
+      .iter()
+      .flat_map(|mb| mb.context_modes.iter().map(|mode| *mode as u8))
+      .collect();
+    modes.sort();
+    modes.dedup();
+    assert!(modes.len() > 1, "q{} used only context modes {:?}", q, modes);
+
+    // the logged IR carries the mode of every literal block type
+    let mut logged = Vec::<Vec<u8>>::new();
+    {
+      let mut record = |pm: &mut super::brotli::interface::PredictionModeContextMap<super::brotli::InputReferenceMut>,
+                        _data: &mut [super::StaticCommand],
+                        _mb: super::brotli::InputPair,
+                        _mfv: &mut HeapAllocator<super::brotli::enc::Mem256f>,
+                        _mpdf: &mut HeapAllocator<super::brotli::enc::PDF>,
+                        _mc: &mut HeapAllocator<super::StaticCommand>| {
+        let num_types = pm.literal_context_map.slice().len() >> 6;
+        if num_types != 0 {
+          logged.push((0..num_types).map(|t| pm.literal_prediction_mode_of(t).prediction_mode()).collect());
+        }
+      };
+      let mut log_params = params.clone();
+      log_params.log_meta_block = true;
+      let mut logged_compressed = UnlimitedBuffer::new(&[]);
+      super::compress_with_callback(&mut UnlimitedBuffer::new(&input[..]), &mut logged_compressed, 65536,
+                                    &log_params, &mut record).unwrap();
+      assert!(logged_compressed.data == compressed.data);
+    }
+    let stored: Vec<Vec<u8>> = report.metablocks
+      .iter()
+      .filter(|mb| mb.kind == BrotliMetaBlockType::Compressed)
+      .map(|mb| mb.context_modes.iter().map(|mode| *mode as u8).collect())
+      .collect();
+    assert_eq!(logged, stored);
+    let (encoded, compiled) = ir_compile_roundtrip_helper(&input[..], *q);
+    assert!(compiled < encoded + encoded / 50, "{} vs {}", compiled, encoded);
+
+    // a forced prior still applies to every literal block type
+    params.mode = BrotliEncoderMode::BROTLI_FORCE_MSB_PRIOR;
+    let mut forced = UnlimitedBuffer::new(&[]);
+    super::compress(&mut UnlimitedBuffer::new(&input[..]), &mut forced, 65536, &params).unwrap();
+    let report = BrotliAnalyzeStream(&forced.data[..]).unwrap();
+    for mb in report.metablocks.iter() {
+      for mode in mb.context_modes.iter() {
+        assert_eq!(*mode as u8, 1);
+      }
+    }
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_metablock_stats_from_writer() {
+  use std::sync::{Arc, Mutex};
+  use super::brotli::enc::{BrotliMetaBlockStats, BrotliMetaBlockStoragePath};
+  use super::brotli::enc::analyzer::{BrotliAnalyzeStream, BrotliMetaBlockType};
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let mut input = alice.to_vec();
+  input.extend_from_slice(RANDOM_THEN_UNICODE);
+  for q in [0u32, 1, 2, 3, 5, 9, 10].iter() {
+    let collected: Arc<Mutex<Vec<BrotliMetaBlockStats>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut compressed = UnlimitedBuffer::new(&[]);
+    {
+      let sink = collected.clone();
+      let mut wenc = CompressorWriter::new(&mut compressed, 4096, *q, 18);
+      wenc.set_metablock_stats_callback(move |stats: &BrotliMetaBlockStats| sink.lock().unwrap().push(*stats));
+      for chunk in input.chunks(10000) {
+        wenc.write_all(chunk).unwrap();
+      }
+    }
+    let stats = collected.lock().unwrap();
+    assert_eq!(stats.iter().map(|s| s.input_size).sum::<usize>(), input.len());
+    let total_bits: usize = stats.iter().map(|s| s.total_bits).sum();
+    // everything but the window bits and the final empty metablock
+    assert!(total_bits <= compressed.data.len() * 8 && total_bits + 16 >= compressed.data.len() * 8);
+    if *q <= 1 {
+      assert!(stats.iter().all(|s| s.storage_path == BrotliMetaBlockStoragePath::Fragment));
+      continue;
+    }
+    let report = BrotliAnalyzeStream(&compressed.data[..]).unwrap();
+    let metablocks: Vec<_> = report.metablocks.iter().filter(|mb| mb.kind != BrotliMetaBlockType::LastEmpty).collect();
+    assert_eq!(metablocks.len(), stats.len());
+    for (s, mb) in stats.iter().zip(metablocks.iter()) {
+      assert_eq!(s.header_bits + s.literal_bits + s.command_bits + s.distance_bits, s.total_bits);
+      assert_eq!(s.input_size, mb.length);
+      if s.storage_path == BrotliMetaBlockStoragePath::Uncompressed {
+        assert_eq!(mb.kind, BrotliMetaBlockType::Uncompressed);
+        continue;
+      }
+      assert_eq!(s.storage_path,
+                 if *q == 2 { BrotliMetaBlockStoragePath::Fast } else if *q == 3 {
+                   BrotliMetaBlockStoragePath::Trivial
+                 } else {
+                   BrotliMetaBlockStoragePath::Full
+                 });
+      assert_eq!(s.num_commands, mb.num_commands);
+      assert_eq!(s.num_literals, mb.num_literals);
+      assert_eq!(s.num_literal_block_types, mb.literal.num_block_types);
+      assert_eq!(s.num_command_block_types, mb.command.num_block_types);
+      assert_eq!(s.num_distance_block_types, mb.distance.num_block_types);
+      assert_eq!(s.num_literal_prefix_codes, mb.literal.num_prefix_codes);
+      assert_eq!(s.num_distance_prefix_codes, mb.distance.num_prefix_codes);
+      assert_eq!(s.distance_postfix_bits, mb.npostfix);
+      assert_eq!(s.num_direct_distance_codes, mb.ndirect);
+      assert_eq!(s.literal_bits + s.command_bits + s.distance_bits,
+                 mb.literal_bits + mb.command_bits + mb.distance_bits + mb.block_switch_bits);
+    }
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_exhaustive_distance_prefix_search() {
+  use std::sync::{Arc, Mutex};
+  use super::brotli::enc::BrotliMetaBlockStats;
+  use super::brotli::enc::analyzer::{BrotliAnalyzeStream, BrotliMetaBlockType};
+  // 16 byte records: a counter followed by one of a few hundred random tails, so most
+  // matches sit a whole number of records back
+  let mut seed: u32 = 5;
+  let mut tails = Vec::new();
+  for _ in 0..300 * 12 {
+    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+    tails.push((seed >> 16) as u8);
+  }
+  let mut input = Vec::new();
+  for i in 0..30000u32 {
+    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+    let tail = ((seed >> 16) % 300) as usize * 12;
+    input.extend_from_slice(&[i as u8, (i >> 8) as u8, (i >> 16) as u8, 0]);
+    input.extend_from_slice(&tails[tail..tail + 12]);
+  }
+  let mut sizes = [0usize; 2];
+  for (index, exhaustive) in [false, true].iter().enumerate() {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = 10;
+    params.exhaustive_distance_prefix_search = *exhaustive;
+    let collected: Arc<Mutex<Vec<BrotliMetaBlockStats>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut compressed = UnlimitedBuffer::new(&[]);
+    {
+      let sink = collected.clone();
+      let mut wenc = CompressorWriter::with_params(&mut compressed, 4096, &params);
+      wenc.set_metablock_stats_callback(move |stats: &BrotliMetaBlockStats| sink.lock().unwrap().push(*stats));
+      wenc.write_all(&input[..]).unwrap();
+    }
+    let mut output = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut UnlimitedBuffer::new(&compressed.data[..]), &mut output, 65536) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+    assert!(output.data[..] == input[..]);
+    let stats = collected.lock().unwrap();
+    let report = BrotliAnalyzeStream(&compressed.data[..]).unwrap();
+    let metablocks: Vec<_> = report.metablocks.iter().filter(|mb| mb.kind == BrotliMetaBlockType::Compressed).collect();
+    assert_eq!(metablocks.len(), stats.len());
+    for (s, mb) in stats.iter().zip(metablocks.iter()) {
+      assert_eq!(s.distance_postfix_bits, mb.npostfix);
+      assert_eq!(s.num_direct_distance_codes, mb.ndirect);
+      // record aligned distances want postfix bits
+      assert!(s.distance_postfix_bits >= 2);
+    }
+    sizes[index] = compressed.data.len();
+  }
+  assert!(sizes[1] <= sizes[0], "{} > {}", sizes[1], sizes[0]);
+  // large windows have more distance symbols than a histogram holds
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = 10;
+  params.large_window = true;
+  params.lgwin = 26;
+  params.exhaustive_distance_prefix_search = true;
+  let mut compressed = UnlimitedBuffer::new(&[]);
+  super::compress(&mut UnlimitedBuffer::new(&input[..]), &mut compressed, 4096, &params).unwrap();
+  let report = BrotliAnalyzeStream(&compressed.data[..]).unwrap();
+  assert!(report.large_window);
+  assert!(report.metablocks.iter().any(|mb| mb.npostfix >= 2));
+  let mut output = UnlimitedBuffer::new(&[]);
+  match super::decompress(&mut UnlimitedBuffer::new(&compressed.data[..]), &mut output, 65536) {
+    Ok(_) => {}
+    Err(e) => panic!("Error {:?}", e),
+  }
+  assert!(output.data[..] == input[..]);
+}
+
+// Splits alice29 into messages of increasing size, including an empty one.
+fn message_test_input() -> Vec<&'static [u8]> {
+  let alice: &'static [u8] = include_bytes!("../../testdata/alice29.txt");
+  let mut messages = Vec::new();
+  let mut offset = 0;
+  let mut size = 1;
+  while offset < 60000 {
+    messages.push(&alice[offset..offset + size]);
+    offset += size;
+    size = size * 3 / 2 + 7;
+  }
+  messages.insert(5, &alice[..0]);
+  messages
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_decompress_with_limit() {
+  use super::brotli::{compress_to_vec, decompress_to_vec_with_limit, is_output_limit_exceeded, LimitedDecompressor};
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let params = super::brotli::enc::BrotliEncoderInitParams();
+  let compressed = compress_to_vec(&alice[..], &params).unwrap();
+  assert_eq!(decompress_to_vec_with_limit(&compressed[..], alice.len()).unwrap(), &alice[..]);
+  let e = decompress_to_vec_with_limit(&compressed[..], alice.len() - 1).unwrap_err();
+  assert!(is_output_limit_exceeded(&e));
+  let e = decompress_to_vec_with_limit(&compressed[..compressed.len() - 1], alice.len()).unwrap_err();
+  assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+  assert!(!is_output_limit_exceeded(&e));
+  let mut garbage = compressed.clone();
+  garbage.push(0);
+  let e = decompress_to_vec_with_limit(&garbage[..], alice.len()).unwrap_err();
+  assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+  assert!(!is_output_limit_exceeded(&e));
+  assert_eq!(decompress_to_vec_with_limit(&[6], 0).unwrap().len(), 0);
+
+  for &(max_output, buffer_size) in [(alice.len(), 1usize), (alice.len(), 4096), (alice.len() - 1, 4096), (0, 17)].iter() {
+    let mut reader = LimitedDecompressor::new(&compressed[..], 4096, max_output);
+    let mut output = Vec::new();
+    let mut buffer = vec![0u8; buffer_size];
+    let result = loop {
+      match reader.read(&mut buffer[..]) {
+        Ok(0) => break Ok(()),
+        Ok(size) => output.extend_from_slice(&buffer[..size]),
+        Err(e) => break Err(e),
+      }
+    };
+    assert_eq!(output[..], alice[..output.len()]);
+    assert_eq!(reader.total_out(), output.len());
+    if max_output >= alice.len() {
+      result.unwrap();
+      assert_eq!(output.len(), alice.len());
+    } else {
+      assert!(is_output_limit_exceeded(&result.unwrap_err()));
+      assert_eq!(output.len(), max_output);
+      assert!(is_output_limit_exceeded(&reader.read(&mut buffer[..]).unwrap_err()));
+    }
+  }
+
+  // 256MB of zeros is well under a kilobyte of brotli; the limit stops it long before it is all decoded
+  let mut bomb = Vec::new();
+  {
+    let mut writer = CompressorWriter::new(&mut bomb, 4096, 5, 24);
+    let zeros = vec![0u8; 1 << 20];
+    for _ in 0..256 {
+      writer.write_all(&zeros[..]).unwrap();
+    }
+  }
+  assert!(bomb.len() < 1024, "{}", bomb.len());
+  let e = decompress_to_vec_with_limit(&bomb[..], 1 << 20).unwrap_err();
+  assert!(is_output_limit_exceeded(&e));
+  let mut reader = LimitedDecompressor::new(&bomb[..], 4096, 1 << 20);
+  let mut sink = Vec::new();
+  assert!(is_output_limit_exceeded(&io::copy(&mut reader, &mut sink).unwrap_err()));
+  assert_eq!(sink.len(), 1 << 20);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_verifying_compressor_writer() {
+  use super::brotli::VerifyingCompressorWriter;
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  for q in [0u32, 1, 2, 5, 9, 11].iter() {
+    let mut writer = VerifyingCompressorWriter::new(Vec::new(), 4096, *q, 18);
+    for (index, chunk) in alice.chunks(10000).enumerate() {
+      writer.write_all(chunk).unwrap();
+      if index == 3 {
+        writer.flush().unwrap();
+        assert_eq!(writer.pending(), 0);
+      }
+    }
+    let compressed = writer.finish().unwrap();
+    let mut decompressed = Vec::new();
+    Decompressor::new(&compressed[..], 4096).read_to_end(&mut decompressed).unwrap();
+    assert_eq!(&decompressed[..], &alice[..]);
+  }
+  // the input waiting for verification stays around the encoder's window, not the stream
+  let mut input = Vec::new();
+  while input.len() < 4 << 20 {
+    input.extend_from_slice(&RANDOM_THEN_UNICODE[..]);
+    input.extend_from_slice(&alice[..]);
+  }
+  let mut writer = VerifyingCompressorWriter::new(Vec::new(), 4096, 5, 18);
+  let mut max_pending = 0;
+  for chunk in input.chunks(65536) {
+    writer.write_all(chunk).unwrap();
+    max_pending = cmp::max(max_pending, writer.pending());
+  }
+  assert!(max_pending <= 2 << 20, "{} bytes pending", max_pending);
+  let compressed = writer.finish().unwrap();
+  let mut decompressed = Vec::new();
+  Decompressor::new(&compressed[..], 4096).read_to_end(&mut decompressed).unwrap();
+  assert!(decompressed == input);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_stream_verifier_rejects_bad_streams() {
+  use super::brotli::{compress_to_vec, StreamVerifier, VerificationFailed};
+  let alice = &include_bytes!("../../testdata/alice29.txt")[..];
+  let params = super::brotli::enc::BrotliEncoderInitParams();
+  let compressed = compress_to_vec(alice, &params).unwrap();
+  fn failure_offset(e: io::Error) -> u64 {
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    e.get_ref().unwrap().downcast_ref::<VerificationFailed>().unwrap().offset
+  }
+  let mut verifier = StreamVerifier::new(4096, false);
+  verifier.expect(alice);
+  for chunk in compressed.chunks(333) {
+    verifier.verify(chunk).unwrap();
+  }
+  verifier.finish().unwrap();
+  assert_eq!(verifier.verified(), alice.len() as u64);
+
+  // a stream of different input fails where the two differ
+  let mut changed = alice.to_vec();
+  changed[100000] ^= 1;
+  let mut verifier = StreamVerifier::new(4096, false);
+  verifier.expect(&changed[..]);
+  let e = compressed.chunks(333).map(|chunk| verifier.verify(chunk)).find(|r| r.is_err()).unwrap();
+  assert_eq!(failure_offset(e.unwrap_err()), 100000);
+  assert!(verifier.verify(&[]).is_err());
+  assert!(verifier.finish().is_err());
+
+  // corrupt data fails, whether it still decodes to something or not
+  for position in [20usize, compressed.len() / 2, compressed.len() - 2].iter() {
+    let mut corrupt = compressed.clone();
+    corrupt[*position] ^= 0x10;
+    let mut verifier = StreamVerifier::new(4096, false);
+    verifier.expect(alice);
+    let result = verifier.verify(&corrupt[..]).and_then(|_| verifier.finish());
+    assert!(failure_offset(result.unwrap_err()) < alice.len() as u64);
+  }
+
+  // so do a truncated stream, more output than expected and data after the end of the stream
+  let mut verifier = StreamVerifier::new(4096, false);
+  verifier.expect(alice);
+  verifier.verify(&compressed[..compressed.len() - 1]).unwrap();
+  // the last byte holds the ISLAST bits and perhaps the end of the data
+  assert!(failure_offset(verifier.finish().unwrap_err()) <= alice.len() as u64);
+  let mut verifier = StreamVerifier::new(4096, false);
+  verifier.expect(&alice[..alice.len() - 1]);
+  assert_eq!(failure_offset(verifier.verify(&compressed[..]).unwrap_err()), alice.len() as u64 - 1);
+  let mut verifier = StreamVerifier::new(4096, false);
+  verifier.expect(alice);
+  let mut trailing = compressed.clone();
+  trailing.push(0);
+  assert!(verifier.verify(&trailing[..]).is_err());
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_message_context_takeover() {
+  use super::brotli::{MessageCompressor, MessageDecompressor};
+  use super::brotli::enc::analyzer::{BrotliAnalyzeStream, BrotliMetaBlockType};
+  let messages = message_test_input();
+  for q in 0..12 {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = q;
+    let mut compressor = MessageCompressor::new(&params, true);
+    let mut decompressor = MessageDecompressor::new(true);
+    let mut stream = Vec::new();
+    let mut boundaries = Vec::new();
+    for (index, message) in messages.iter().enumerate() {
+      let compressed = compressor.compress_message(message).unwrap().to_vec();
+      if index != 0 && message.is_empty() {
+        // the previous flush left the stream on a byte boundary
+        assert_eq!(compressed.len(), 0);
+      }
+      assert_eq!(decompressor.decompress_message(&compressed[..]).unwrap(), *message);
+      stream.extend_from_slice(&compressed[..]);
+      boundaries.push(stream.len() * 8);
+    }
+    // every flush ends on a byte boundary, so an ISLAST, ISLASTEMPTY byte completes the stream
+    stream.push(3);
+    let report = BrotliAnalyzeStream(&stream[..]).unwrap();
+    assert_eq!(report.output_size, messages.iter().map(|m| m.len()).sum::<usize>());
+    for mb in report.metablocks.iter() {
+      if mb.kind == BrotliMetaBlockType::Metadata {
+        // the empty metadata block injected by the flush: 6 bits and the padding
+        assert_eq!(mb.length, 0);
+        assert!(mb.header_bits >= 6 && mb.header_bits <= 13, "q{}: {} bits", q, mb.header_bits);
+        assert!(boundaries.contains(&(mb.start_bit + mb.header_bits)));
+      }
+    }
+  }
+  // a message that is cut short is an error rather than partial output
+  for q in [0i32, 5, 11].iter() {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = *q;
+    let mut compressor = MessageCompressor::new(&params, true);
+    let first = compressor.compress_message(messages[0]).unwrap().to_vec();
+    let compressed = compressor.compress_message(messages[messages.len() - 1]).unwrap().to_vec();
+    // (an empty message is a whole one)
+    for len in 1..compressed.len() {
+      let mut decompressor = MessageDecompressor::new(true);
+      assert_eq!(decompressor.decompress_message(&first[..]).unwrap(), messages[0]);
+      match decompressor.decompress_message(&compressed[..len]) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+        Ok(output) => panic!("q{}: {} of {} bytes decoded to {} bytes", q, len, compressed.len(), output.len()),
+      }
+    }
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_message_without_context_takeover() {
+  use super::brotli::{MessageCompressor, MessageDecompressor};
+  let messages = message_test_input();
+  for q in [0i32, 1, 5, 9, 11].iter() {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = *q;
+    let mut takeover_size = 0;
+    let mut compressor = MessageCompressor::new(&params, true);
+    for message in messages.iter() {
+      takeover_size += compressor.compress_message(message).unwrap().len();
+    }
+    let mut reset_size = 0;
+    let mut compressed = Vec::new();
+    let mut compressor = MessageCompressor::new(&params, false);
+    for message in messages.iter() {
+      compressed.push(compressor.compress_message(message).unwrap().to_vec());
+      reset_size += compressed.last().unwrap().len();
+    }
+    assert!(takeover_size < reset_size, "q{}: {} >= {}", q, takeover_size, reset_size);
+    // each message is a whole stream, so they decode in any order
+    let mut decompressor = MessageDecompressor::new(false);
+    for (message, data) in messages.iter().zip(compressed.iter()).rev() {
+      assert_eq!(decompressor.decompress_message(&data[..]).unwrap(), *message);
+    }
+    // a message that is cut short is an error rather than partial output
+    let mut decompressor = MessageDecompressor::new(false);
+    let last = compressed.last().unwrap();
+    assert!(decompressor.decompress_message(&last[..last.len() - 1]).is_err());
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_writer_flush_with_small_buffer() {
+  use super::brotli::MessageDecompressor;
+  let messages = message_test_input();
+  let mut decompressor = MessageDecompressor::new(true);
+  let mut compressed = UnlimitedBuffer::new(&[]);
+  let mut taken = 0;
+  {
+    // the padding block regularly lands past the end of a 16 byte buffer
+    let mut wenc = CompressorWriter::new(&mut compressed, 16, 5, 22);
+    for message in messages.iter() {
+      wenc.write_all(message).unwrap();
+      wenc.flush().unwrap();
+      let written = wenc.get_ref().data.len();
+      assert_eq!(decompressor.decompress_message(&wenc.get_ref().data[taken..written]).unwrap(), *message);
+      taken = written;
+    }
+  }
+  assert_eq!(decompressor.decompress_message(&compressed.data[taken..]).unwrap().len(), 0);
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_pull_encoder() {
+  use super::brotli::{Encoder, MessageDecompressor};
+  let mut input = include_bytes!("../../testdata/alice29.txt").to_vec();
+  input.extend_from_slice(RANDOM_THEN_UNICODE);
+  let flush_at = 100000;
+  for q in 0..12 {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = q;
+    params.lgwin = 20;
+    let mut encoder = Encoder::new(&params);
+    let mut compressed = Vec::new();
+    let mut offset = 0;
+    let mut chunk = 1;
+    while offset < input.len() {
+      let end = cmp::min(cmp::min(offset + chunk, input.len()), if offset < flush_at { flush_at } else { input.len() });
+      let mut pending = &input[offset..end];
+      while !pending.is_empty() {
+        let (consumed, output) = encoder.push(pending).unwrap();
+        assert!(consumed != 0);
+        compressed.extend_from_slice(output);
+        pending = &pending[consumed..];
+      }
+      offset = end;
+      chunk = chunk * 2 + 1;
+      if offset == flush_at {
+        loop {
+          let output = encoder.flush().unwrap();
+          if output.is_empty() {
+            break;
+          }
+          compressed.extend_from_slice(output);
+        }
+        // everything pushed so far decodes from the bytes returned so far
+        let mut decompressor = MessageDecompressor::new(true);
+        assert!(decompressor.decompress_message(&compressed[..]).unwrap() == &input[..flush_at]);
+      }
+    }
+    while !encoder.is_finished() {
+      compressed.extend_from_slice(encoder.finish().unwrap());
+    }
+    assert_eq!(encoder.finish().unwrap().len(), 0);
+    let mut output = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut UnlimitedBuffer::new(&compressed[..]), &mut output, 65536) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+    assert!(output.data[..] == input[..]);
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_whole_buffer_roundtrip() {
+  use super::brotli::BrotliCompressWholeBuffer;
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let mut alice_then_random = alice.to_vec();
+  alice_then_random.extend_from_slice(RANDOM_THEN_UNICODE);
+  // sizes around the input block sizes, where the hashers look at the last bytes of the input
+  let inputs: [&[u8]; 11] = [&[], b"a", &alice[..7], &alice[..75], &alice[..1000], &alice[..65535],
+                             &alice[..65537], &alice[..131073], &alice_then_random[..262142], &alice[..],
+                             &alice_then_random[..]];
+  for input in inputs.iter() {
+    for q in 0..12 {
+      for &(lgwin, large_window, q9_5) in [(22, false, false), (24, true, false), (20, false, true)].iter() {
+        // the zopfli qualities cannot encode large window streams yet, neither can BrotliCompress
+        if (q9_5 && q != 10) || (large_window && q >= 10) {
+          continue;
+        }
+        let mut params = super::brotli::enc::BrotliEncoderInitParams();
+        params.quality = q;
+        params.lgwin = lgwin;
+        params.large_window = large_window;
+        params.q9_5 = q9_5;
+        let mut streamed = UnlimitedBuffer::new(&[]);
+        super::brotli::BrotliCompress(&mut UnlimitedBuffer::new(input), &mut streamed, &params).unwrap();
+        let mut whole = Vec::<u8>::new();
+        let size = BrotliCompressWholeBuffer(input, &mut whole, &params).unwrap();
+        assert_eq!(size, whole.len());
+        // the hashers see the bytes after each block instead of the ring buffer's stale ones, so
+        // the output is not always byte-identical, but it should be no worse
+        assert!(whole.len() <= streamed.data.len() + streamed.data.len() / 1000,
+                "q{} lgwin {} size {}: {} > {}", q, lgwin, input.len(), whole.len(), streamed.data.len());
+        let mut output = UnlimitedBuffer::new(&[]);
+        match super::decompress(&mut UnlimitedBuffer::new(&whole[..]), &mut output, 65536) {
+          Ok(_) => {}
+          Err(e) => panic!("Error {:?}", e),
+        }
+        assert!(output.data[..] == input[..]);
+      }
+    }
+  }
+}
+
+// Every input size up to a few hundred bytes, where the hashers and the match search reach the
+// end of the input (which a whole buffer has no slack after) from every position.
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_whole_buffer_small_sizes() {
+  use super::brotli::BrotliCompressWholeBuffer;
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  // a period of 4 matches the initial last distance, random a and b match at every distance
+  let mut repeated = Vec::new();
+  let mut random = Vec::new();
+  let mut state = 12345u32;
+  for i in 0..160 {
+    repeated.push(b"abcd"[i % 4]);
+    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+    random.push(b'a' + ((state >> 16) & 1) as u8);
+  }
+  let sources: [&[u8]; 3] = [&alice[..160], &repeated[..], &random[..]];
+  for source in sources.iter() {
+    for size in 0..source.len() + 1 {
+      let input = &source[..size];
+      for q in 0..12 {
+        for lgwin in [10, 16, 22].iter() {
+          let mut params = super::brotli::enc::BrotliEncoderInitParams();
+          params.quality = q;
+          params.lgwin = *lgwin;
+          let mut whole = Vec::<u8>::new();
+          BrotliCompressWholeBuffer(input, &mut whole, &params).unwrap();
+          let mut output = UnlimitedBuffer::new(&[]);
+          match super::decompress(&mut UnlimitedBuffer::new(&whole[..]), &mut output, 65536) {
+            Ok(_) => {}
+            Err(e) => panic!("q{} lgwin {} size {}: {:?}", q, lgwin, size, e),
+          }
+          assert!(output.data[..] == input[..], "q{} lgwin {} size {}", q, lgwin, size);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_dcb_roundtrip() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let dictionary = &alice[..65536];
+  let input = &alice[32768..98304];
+  let mut params = super::brotli::enc::BrotliEncoderInitParams();
+  params.quality = 9;
+  let mut compressed = Vec::<u8>::new();
+  let compressed_size = super::brotli::enc::dcb::BrotliCompressDcb(&mut &input[..], &mut compressed, &params, dictionary).unwrap();
+  assert_eq!(compressed_size, compressed.len());
+  assert_eq!(&compressed[..4], &[0xff, b'D', b'C', b'B']);
+  assert!(super::brotli::enc::dcb::BrotliDcbHeaderMatches(&compressed[..], dictionary));
+  let plain_size = roundtrip_helper(input, 9, 22, false);
+  assert!(compressed_size < plain_size, "{} >= {}", compressed_size, plain_size);
+  let mut output = Vec::<u8>::new();
+  super::brotli::enc::dcb::BrotliDecompressDcb(&mut &compressed[..], &mut output, dictionary).unwrap();
+  assert_eq!(&output[..], input);
+  let mut wrong_output = Vec::<u8>::new();
+  match super::brotli::enc::dcb::BrotliDecompressDcb(&mut &compressed[..], &mut wrong_output, &alice[1..65537]) {
+    Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+    Ok(_) => panic!("decompressed with the wrong dictionary"),
+  }
+}
+
 #[test]
 fn test_roundtrip_quickfox_repeated() {
   total_roundtrip_helper(include_bytes!("../../testdata/quickfox_repeated"));
@@ -1217,3 +2511,481 @@ fn test_empty18() {
                                            65536,
                                            65536);
 }
+
+// stand-ins for I/O without std::io
+struct SliceReader<'a>(&'a [u8]);
+impl<'a, ErrType> CustomRead<ErrType> for SliceReader<'a> {
+  fn read(&mut self, data: &mut [u8]) -> Result<usize, ErrType> {
+    let size = cmp::min(data.len(), self.0.len());
+    data[..size].clone_from_slice(&self.0[..size]);
+    self.0 = &self.0[size..];
+    Ok(size)
+  }
+}
+struct VecWriter<'a>(&'a mut Vec<u8>);
+impl<'a, ErrType> CustomWrite<ErrType> for VecWriter<'a> {
+  fn write(&mut self, data: &[u8]) -> Result<usize, ErrType> {
+    self.0.extend_from_slice(data);
+    Ok(data.len())
+  }
+  fn flush(&mut self) -> Result<(), ErrType> {
+    Ok(())
+  }
+}
+
+#[test]
+fn test_stack_profile_roundtrip() {
+  use super::brotli::enc::stack_profile::{BrotliCompressStackProfile, BROTLI_STACK_PROFILE_BUFFER_SIZE,
+                                          BROTLI_STACK_PROFILE_FAST_MEMORY_SIZE, BROTLI_STACK_PROFILE_MEMORY_SIZE};
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  // longer than the ring buffer of the largest window, so the window wraps around
+  let mut long = alice.to_vec();
+  long.extend_from_slice(RANDOM_THEN_UNICODE);
+  long.extend_from_slice(include_bytes!("../../testdata/compressed_file"));
+  long.extend_from_slice(&alice[..]);
+  // inputs that push the pools towards their bounds: random a and b make the most commands, text
+  // then random a and b the largest output storage, stretches of random bytes and of a and b and
+  // letters that change every 512 bytes the most literal block types and random letters the most
+  // command and distance block types
+  let mut state = 1u32;
+  let mut next = || {
+    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+    state >> 8
+  };
+  let random_ab: Vec<u8> = (0..3 << 20).map(|_| b'a' + (next() & 1) as u8).collect();
+  let random_abcd: Vec<u8> = (0..300000).map(|_| b'a' + (next() & 3) as u8).collect();
+  let mixed: Vec<u8> = (0..3 << 20).map(|i| if (i / 70000) % 2 == 0 {
+    next() as u8
+  } else {
+    b'a' + (next() & 1) as u8
+  }).collect();
+  let mut text_then_ab = alice.to_vec();
+  text_then_ab.extend_from_slice(&random_ab[..300000 - alice.len()]);
+  let alphabets: Vec<u8> = (0..1 << 20).map(|i| b'a' + 4 * ((i / 512) % 6) as u8 + (next() & 3) as u8).collect();
+  let inputs: Vec<Vec<u8>> = vec![Vec::new(), b"a".to_vec(), alice[..4097].to_vec(), long, random_ab, random_abcd,
+                                  mixed, text_then_ab, alphabets];
+  // the compressor keeps its pools on the stack, so it gets little more than them: qualities 0
+  // and 1 a thread of their own with the small pools and the others one with the large pools
+  let mut compressed = Vec::new();
+  for &(ref qualities, memory_size) in [(0..2, BROTLI_STACK_PROFILE_FAST_MEMORY_SIZE),
+                                        (2..5, BROTLI_STACK_PROFILE_MEMORY_SIZE)].iter() {
+    let stack_size = memory_size + 2 * BROTLI_STACK_PROFILE_BUFFER_SIZE + 256 * 1024;
+    let qualities = qualities.clone();
+    let inputs = inputs.clone();
+    compressed.extend(std::thread::Builder::new().stack_size(stack_size).spawn(move || {
+      let mut compressed = Vec::new();
+      for input in inputs.iter() {
+        for q in qualities.clone() {
+          for lgwin in [10, 13, 16].iter() {
+            let mut params = super::brotli::enc::BrotliEncoderInitParams();
+            params.quality = q;
+            params.lgwin = *lgwin;
+            let mut output = Vec::new();
+            BrotliCompressStackProfile(&mut SliceReader(&input[..]), &mut VecWriter(&mut output), &params, (), ())
+              .unwrap();
+            compressed.push((input.clone(), output));
+          }
+        }
+      }
+      compressed
+    }).unwrap().join().unwrap());
+  }
+  for &(ref input, ref data) in compressed.iter() {
+    let mut output = UnlimitedBuffer::new(&[]);
+    match super::decompress(&mut UnlimitedBuffer::new(&data[..]), &mut output, 65536) {
+      Ok(_) => {}
+      Err(e) => panic!("Error {:?}", e),
+    }
+    assert!(output.data[..] == input[..]);
+  }
+}
+
+#[test]
+fn test_stack_profile_rejects_params() {
+  use super::brotli::enc::stack_profile::BrotliCompressStackProfile;
+  for &(quality, lgwin, lgblock, large_window) in [(5, 16, 0, false), (-1, 16, 0, false), (4, 17, 0, false),
+                                                   (4, 16, 17, false), (4, 16, 0, true)].iter() {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = quality;
+    params.lgwin = lgwin;
+    params.lgblock = lgblock;
+    params.large_window = large_window;
+    let mut output = Vec::new();
+    match BrotliCompressStackProfile(&mut SliceReader(b"abc"), &mut VecWriter(&mut output), &params, 0, 1) {
+      Err(e) => assert_eq!(e, 1),
+      Ok(_) => panic!("q{} lgwin {} lgblock {} accepted", quality, lgwin, lgblock),
+    }
+    assert_eq!(output.len(), 0);
+  }
+}
+
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+#[test]
+fn test_vec_alloc_roundtrip() {
+  use super::brotli::{compress_to_vec, CompressorReaderVecAlloc, CompressorWriterVecAlloc};
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let input = &alice[..65537];
+  for q in [0, 1, 4, 9].iter() {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = *q;
+    params.lgwin = 16;
+    let whole = compress_to_vec(input, &params).unwrap();
+    let mut read = Vec::<u8>::new();
+    {
+      let mut reader = CompressorReaderVecAlloc::with_params(SliceReader(input), 4096, &params, ());
+      let mut buffer = [0u8; 1000];
+      loop {
+        let size = reader.read(&mut buffer[..]).unwrap();
+        if size == 0 {
+          break;
+        }
+        read.extend_from_slice(&buffer[..size]);
+      }
+    }
+    let mut written = Vec::<u8>::new();
+    {
+      let mut writer = CompressorWriterVecAlloc::with_params(VecWriter(&mut written), 4096, &params, ());
+      for chunk in input.chunks(1000) {
+        super::brotli::enc::writer::write_all(&mut writer, chunk).unwrap();
+      }
+    }
+    for compressed in [whole, read, written].iter() {
+      let mut output = UnlimitedBuffer::new(&[]);
+      match super::decompress(&mut UnlimitedBuffer::new(&compressed[..]), &mut output, 65536) {
+        Ok(_) => {}
+        Err(e) => panic!("Error {:?}", e),
+      }
+      assert!(output.data[..] == input[..]);
+    }
+  }
+}
+
+#[test]
+fn test_bit_writer_fails_instead_of_panicking() {
+  use super::brotli::enc::brotli_bit_stream::{BrotliWriteBits, BrotliWriteBitsFailed, JumpToByteBoundary};
+  let mut storage = [0u8; 16];
+  let mut pos = 3usize;
+  BrotliWriteBits(5, 0x1f, &mut pos, &mut storage[..]);
+  assert_eq!(pos, 8);
+  assert_eq!(storage[0], 0xf8);
+  // wider than its bit count, more than 56 bits, and past the end of the storage
+  for &(n_bits, bits, start) in [(3u8, 8u64, 0usize), (57, 0, 0), (8, 0, 72), (8, 0, 3000)].iter() {
+    let mut pos = start;
+    BrotliWriteBits(n_bits, bits, &mut pos, &mut storage[..]);
+    assert!(BrotliWriteBitsFailed(pos));
+    // later writes are dropped and keep the writer failed
+    BrotliWriteBits(1, 1, &mut pos, &mut storage[..]);
+    JumpToByteBoundary(&mut pos, &mut storage[..]);
+    assert!(BrotliWriteBitsFailed(pos));
+  }
+  let mut pos = 121usize;
+  JumpToByteBoundary(&mut pos, &mut storage[..]);
+  assert!(BrotliWriteBitsFailed(pos));
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_encoder_errors_instead_of_panicking() {
+  use std::panic;
+  use super::brotli::compress_to_vec;
+  // a source and a sink that, once they have passed on `limit` bytes, fail or claim to have
+  // passed on one byte more than they were asked for (the source only once, then it fails)
+  struct FailingReader<'a> {
+    data: &'a [u8],
+    limit: usize,
+    overstate: bool,
+  }
+  impl<'a> Read for FailingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      if self.limit == 0 {
+        if self.overstate {
+          self.overstate = false;
+          return Ok(buf.len() + 1);
+        }
+        return Err(io::Error::new(io::ErrorKind::Other, "source failed"));
+      }
+      let size = cmp::min(cmp::min(buf.len(), self.limit), self.data.len());
+      buf[..size].clone_from_slice(&self.data[..size]);
+      self.data = &self.data[size..];
+      self.limit -= size;
+      Ok(size)
+    }
+  }
+  struct FailingWriter {
+    limit: usize,
+    overstate: bool,
+  }
+  impl Write for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      if self.limit == 0 {
+        if self.overstate {
+          return Ok(buf.len() + 1);
+        }
+        return Err(io::Error::new(io::ErrorKind::Other, "sink failed"));
+      }
+      let size = cmp::min(buf.len(), self.limit);
+      self.limit -= size;
+      Ok(size)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let inputs: [&[u8]; 4] = [&[], b"a", &alice[..4000], &RANDOM_THEN_UNICODE[..4000]];
+  for input in inputs.iter() {
+    for q in [-1, 0, 1, 2, 4, 5, 9, 10, 11, 12].iter() {
+      for lgwin in [0, 10, 16, 31].iter() {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+          let mut params = super::brotli::enc::BrotliEncoderInitParams();
+          params.quality = *q;
+          params.lgwin = *lgwin;
+          let _ = compress_to_vec(input, &params);
+          for &(limit, overstate) in [(0, false), (17, false), (1000, false), (0, true), (1000, true)].iter() {
+            let _ = super::brotli::BrotliCompress(&mut FailingReader { data: input, limit, overstate },
+                                                  &mut FailingWriter { limit, overstate },
+                                                  &params);
+            {
+              let mut writer = CompressorWriter::new(FailingWriter { limit, overstate }, 64, *q as u32, *lgwin as u32);
+              for chunk in input.chunks(1000) {
+                if writer.write_all(chunk).is_err() {
+                  break;
+                }
+              }
+              let _ = writer.flush();
+              let _ = writer.write(b"after the failure");
+            }
+            let mut reader = CompressorReader::new(FailingReader { data: input, limit, overstate },
+                                                   64, *q as u32, *lgwin as u32);
+            let mut buffer = [0u8; 100];
+            for _ in 0..2 {
+              while let Ok(size) = reader.read(&mut buffer[..]) {
+                if size == 0 {
+                  break;
+                }
+              }
+            }
+          }
+        }));
+        assert!(result.is_ok(), "q{} lgwin {} input of {} bytes", q, lgwin, input.len());
+      }
+    }
+  }
+}
+
+// BrotliCreateBackwardReferences without HasherSetup reports it rather than emit the block as literals
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_uninit_hasher_is_an_error() {
+  use super::brotli::enc::backward_references::{BrotliCreateBackwardReferences, UnionHasher};
+  use super::brotli::enc::command::Command;
+  use super::brotli::enc::static_dict::kBrotliEncDictionary;
+  use super::brotli::enc::vec_alloc::{VecAlloc, VecAllocBundle};
+  let mut m = VecAllocBundle::default();
+  let mut hasher = UnionHasher::<VecAlloc<u16>, VecAlloc<u32>>::default();
+  let params = super::brotli::enc::BrotliEncoderInitParams();
+  let ringbuffer = [b'a'; 64];
+  let mut dist_cache = [4i32, 11, 15, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+  let mut commands = [Command::default(); 64];
+  let mut last_insert_len = 0usize;
+  let mut num_commands = 0usize;
+  let mut num_literals = 0usize;
+  assert_eq!(BrotliCreateBackwardReferences(&mut m.alloc_u32, &mut m.alloc_u64, &mut m.alloc_f64, &mut m.alloc_zn,
+                                            &kBrotliEncDictionary, ringbuffer.len(), 0, &ringbuffer[..],
+                                            ringbuffer.len() - 1, &params, &mut hasher, &mut dist_cache[..],
+                                            &mut last_insert_len, &mut commands[..], &mut num_commands,
+                                            &mut num_literals),
+             0);
+  assert_eq!((last_insert_len, num_commands, num_literals), (0, 0, 0));
+}
+
+// Random parameters, including out-of-range ones, over random inputs through the whole buffer,
+// writer and reader entry points, each fed in random sized pieces. None of them may panic, and
+// whatever they accept has to decode back to the input. BROTLI_FUZZ_ITERATIONS runs more rounds
+// and BROTLI_FUZZ_SEED picks other ones.
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_encoder_fuzz_no_panic() {
+  use std::panic;
+  use super::brotli::compress_to_vec;
+  use super::brotli::enc::backward_references::BrotliEncoderMode;
+  let iterations = match ::std::env::var("BROTLI_FUZZ_ITERATIONS") {
+    Ok(value) => value.parse::<u32>().unwrap(),
+    Err(_) => 60,
+  };
+  let mut seed = match ::std::env::var("BROTLI_FUZZ_SEED") {
+    Ok(value) => value.parse::<u64>().unwrap() | 1,
+    Err(_) => 0x9e3779b97f4a7c15u64,
+  };
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let mut next = move |bound: u64| -> u64 {
+    seed ^= seed << 13;
+    seed ^= seed >> 7;
+    seed ^= seed << 17;
+    seed % bound
+  };
+  let modes = [BrotliEncoderMode::BROTLI_MODE_GENERIC,
+               BrotliEncoderMode::BROTLI_MODE_TEXT,
+               BrotliEncoderMode::BROTLI_MODE_FONT,
+               BrotliEncoderMode::BROTLI_FORCE_LSB_PRIOR,
+               BrotliEncoderMode::BROTLI_FORCE_MSB_PRIOR,
+               BrotliEncoderMode::BROTLI_FORCE_UTF8_PRIOR,
+               BrotliEncoderMode::BROTLI_FORCE_SIGNED_PRIOR];
+  for iteration in 0..iterations {
+    let mut params = super::brotli::enc::BrotliEncoderInitParams();
+    params.quality = next(16) as i32 - 2;
+    params.lgwin = next(34) as i32 - 1;
+    params.lgblock = next(28) as i32 - 1;
+    params.mode = modes[next(modes.len() as u64) as usize];
+    params.large_window = next(4) == 0;
+    params.q9_5 = next(4) == 0;
+    params.disable_literal_context_modeling = next(4) as i32 - 1;
+    params.exhaustive_match_search = next(4) == 0;
+    params.zopfli_iterations = next(4) as u32;
+    params.zopfli_max_candidates = next(10) as u32;
+    params.zopfli_max_len = next(300) as u32;
+    // large inputs only at the fast qualities, the zopfli ones take too long in debug builds
+    let max_len = if params.quality >= 10 || params.q9_5 || params.exhaustive_match_search {
+      4096
+    } else {
+      100000
+    };
+    let len = next(max_len) as usize;
+    let input: Vec<u8> = match next(4) {
+      0 => (0..len).map(|_| next(256) as u8).collect(),
+      1 => (0..len).map(|_| b'a' + next(2) as u8).collect(),
+      2 => {
+        let start = next((alice.len() - len) as u64 + 1) as usize;
+        alice[start..start + len].to_vec()
+      }
+      _ => (0..len).map(|i| if next(8) == 0 { next(256) as u8 } else { alice[i % 1000] }).collect(),
+    };
+    params.size_hint = match next(3) {
+      0 => 0,
+      1 => input.len(),
+      _ => next(1 << 32) as usize,
+    };
+    let write_sizes: Vec<usize> = (0..16).map(|_| next(5000) as usize + 1).collect();
+    let buffer_size = next(5000) as usize + 1;
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+      let mut outputs = Vec::new();
+      if let Some(whole) = compress_to_vec(&input[..], &params) {
+        outputs.push(whole);
+      }
+      {
+        let mut written = Vec::new();
+        let mut ok = true;
+        {
+          let mut writer = CompressorWriter::with_params(&mut written, buffer_size, &params);
+          let mut pos = 0;
+          let mut index = 0;
+          while ok && pos < input.len() {
+            let size = cmp::min(write_sizes[index % write_sizes.len()], input.len() - pos);
+            ok = writer.write_all(&input[pos..pos + size]).is_ok();
+            if index % 3 == 2 {
+              ok = ok && writer.flush().is_ok();
+            }
+            pos += size;
+            index += 1;
+          }
+        }
+        if ok {
+          outputs.push(written);
+        }
+      }
+      {
+        let mut read = Vec::new();
+        let mut reader = CompressorReader::with_params(&input[..], buffer_size, &params);
+        if reader.read_to_end(&mut read).is_ok() {
+          outputs.push(read);
+        }
+      }
+      for compressed in outputs.iter() {
+        let mut output = UnlimitedBuffer::new(&[]);
+        match super::decompress(&mut UnlimitedBuffer::new(&compressed[..]), &mut output, 65536) {
+          Ok(_) => {}
+          Err(e) => panic!("Error {:?}", e),
+        }
+        assert!(output.data[..] == input[..]);
+      }
+    }));
+    assert!(result.is_ok(), "iteration {}: q{} lgwin {} lgblock {} large window {} input of {} bytes",
+            iteration, params.quality, params.lgwin, params.lgblock, params.large_window, input.len());
+  }
+}
+
+#[cfg(feature="ffi")]
+#[test]
+fn test_ffi_roundtrip() {
+  use super::brotli::ffi::compressor::*;
+  use super::brotli::ffi::decompressor::*;
+  use super::brotli::ffi::{BROTLI_TRUE, BROTLI_FALSE};
+  use core::ptr;
+  let input = &include_bytes!("../../testdata/alice29.txt")[..20000];
+  unsafe {
+    let mut encoded = vec![0u8; BrotliEncoderMaxCompressedSize(input.len())];
+    let mut encoded_size = encoded.len();
+    assert_eq!(BrotliEncoderCompress(9, 22, 1, input.len(), input.as_ptr(), &mut encoded_size,
+                                     encoded.as_mut_ptr()),
+               BROTLI_TRUE);
+    let mut decoded = vec![0u8; input.len()];
+    let mut decoded_size = decoded.len();
+    assert_eq!(BrotliDecoderDecompress(encoded_size, encoded.as_ptr(), &mut decoded_size, decoded.as_mut_ptr()),
+               BROTLI_DECODER_RESULT_SUCCESS);
+    assert_eq!(&decoded[..decoded_size], input);
+
+    // streams the compressed bytes through the decoder 3 bytes in and 11 bytes out at a time
+    let state = BrotliDecoderCreateInstance(None, None, ptr::null_mut());
+    assert!(!state.is_null());
+    let mut output = Vec::new();
+    let mut in_pos = 0;
+    loop {
+      let mut available_in = cmp::min(3, encoded_size - in_pos);
+      let offered = available_in;
+      let mut next_in = encoded[in_pos..].as_ptr();
+      let mut buffer = [0u8; 11];
+      let mut available_out = buffer.len();
+      let mut next_out = buffer.as_mut_ptr();
+      let mut total_out = 0usize;
+      let result = BrotliDecoderDecompressStream(state, &mut available_in, &mut next_in, &mut available_out,
+                                                 &mut next_out, &mut total_out);
+      in_pos += offered - available_in;
+      output.extend_from_slice(&buffer[..buffer.len() - available_out]);
+      assert_eq!(total_out, output.len());
+      match result {
+        BROTLI_DECODER_RESULT_SUCCESS => break,
+        BROTLI_DECODER_RESULT_ERROR => panic!("decoder error at {}", in_pos),
+        _ => {}
+      }
+    }
+    assert_eq!(BrotliDecoderIsFinished(state), BROTLI_TRUE);
+    assert_eq!(BrotliDecoderHasMoreOutput(state), BROTLI_FALSE);
+    assert_eq!(BrotliDecoderGetErrorCode(state), BROTLI_DECODER_SUCCESS);
+    BrotliDecoderDestroyInstance(state);
+    assert_eq!(&output[..], input);
+
+    // a corrupted stream fails with the generic format error, and keeps failing
+    let state = BrotliDecoderCreateInstance(None, None, ptr::null_mut());
+    assert_eq!(BrotliDecoderGetErrorCode(state), BROTLI_DECODER_NO_ERROR);
+    let garbage = [0xffu8; 8];
+    for _ in 0..2 {
+      let mut available_in = garbage.len();
+      let mut next_in = garbage.as_ptr();
+      let mut buffer = [0u8; 16];
+      let mut available_out = buffer.len();
+      let mut next_out = buffer.as_mut_ptr();
+      assert_eq!(BrotliDecoderDecompressStream(state, &mut available_in, &mut next_in, &mut available_out,
+                                               &mut next_out, ptr::null_mut()),
+                 BROTLI_DECODER_RESULT_ERROR);
+      assert_eq!(BrotliDecoderGetErrorCode(state), BROTLI_DECODER_ERROR_FORMAT);
+    }
+    BrotliDecoderDestroyInstance(state);
+    let name = ::std::ffi::CStr::from_ptr(BrotliDecoderErrorString(BROTLI_DECODER_ERROR_FORMAT));
+    assert_eq!(name.to_bytes(), b"_ERROR_FORMAT");
+
+    decoded_size = decoded.len();
+    assert_eq!(BrotliDecoderDecompress(encoded_size - 1, encoded.as_ptr(), &mut decoded_size, decoded.as_mut_ptr()),
+               BROTLI_DECODER_RESULT_ERROR);
+  }
+}
+
@@ -5,6 +5,7 @@ use brotli::transform::{TransformDictionaryWord};
 use brotli::interface;
 use std::collections::BTreeMap;
 use std::fmt;
+use std::io::{self, Write};
 use alloc_no_stdlib::SliceWrapper;
 
 struct HexSlice<'a>(&'a [u8]);
@@ -42,12 +43,6 @@ pub fn print_dictionary(dict :BTreeMap<Vec<u8>, ()>) {
         println!("{}", HexSlice(&key[..]));
     }
 }
-macro_rules! println_stderr(
-    ($($val:tt)*) => { {
-        writeln!(&mut ::std::io::stderr(), $($val)*).unwrap();
-    } }
-);
-
 fn prediction_mode_str(prediction_mode_nibble:interface::LiteralPredictionModeNibble) -> &'static str {
    match prediction_mode_nibble.prediction_mode() {
          interface::LITERAL_PREDICTION_MODE_SIGN => "sign",
@@ -70,16 +65,20 @@ impl<'a> fmt::LowerHex for SliceU8Ref<'a> {
 }
 
 pub fn write_one<T:SliceWrapper<u8>>(cmd: &interface::Command<T>) {
-    use std::io::Write;
+    write_one_to(&mut io::stderr(), cmd).unwrap();
+}
+
+// Prints cmd in the text format read back by brotli::enc::ir_parse.
+pub fn write_one_to<W:Write, T:SliceWrapper<u8>>(w: &mut W, cmd: &interface::Command<T>) -> io::Result<()> {
     match cmd {
         &interface::Command::BlockSwitchLiteral(ref bsl) => {
-            println_stderr!("ltype {} {}", bsl.0.block_type(), bsl.1);
+            writeln!(w, "ltype {} {}", bsl.0.block_type(), bsl.1)
         },
         &interface::Command::BlockSwitchCommand(ref bsc) => {
-            println_stderr!("ctype {}", bsc.0);
+            writeln!(w, "ctype {}", bsc.0)
         },
         &interface::Command::BlockSwitchDistance(ref bsd) => {
-            println_stderr!("dtype {}", bsd.0);
+            writeln!(w, "dtype {}", bsd.0)
         },
         &interface::Command::PredictionMode(ref prediction) => {
             let prediction_mode = prediction_mode_str(prediction.literal_prediction_mode());
@@ -90,8 +89,13 @@ pub fn write_one<T:SliceWrapper<u8>>(cmd: &interface::Command<T>) {
             let mixing_values = prediction.get_mixing_values().iter().fold(::std::string::String::new(),
                                                                            |res, &val| res + " " + &val.to_string());
             if prediction.has_context_speeds() {
-                println_stderr!("prediction {} lcontextmap{} dcontextmap{} mixingvalues{} cmspeedinc {} {} cmspeedmax {} {} stspeedinc {} {} stspeedmax {} {} mxspeedinc {} {} mxspeedmax {} {}",
+                let num_literal_types = ::std::cmp::min(prediction.literal_context_map.slice().len() >> 6,
+                                                 interface::NUM_LITERAL_PREDICTION_MODES);
+                let literal_modes = (0..num_literal_types).fold(::std::string::String::new(),
+                                                                |res, t| res + " " + prediction_mode_str(prediction.literal_prediction_mode_of(t)));
+                writeln!(w, "prediction {} literalmodes{} lcontextmap{} dcontextmap{} mixingvalues{} cmspeedinc {} {} cmspeedmax {} {} stspeedinc {} {} stspeedmax {} {} mxspeedinc {} {} mxspeedmax {} {}",
                                 prediction_mode,
+                                literal_modes,
                                 lit_cm,
                                 dist_cm,
                                 mixing_values,
@@ -106,19 +110,19 @@ pub fn write_one<T:SliceWrapper<u8>>(cmd: &interface::Command<T>) {
                                 prediction.combined_stride_context_speed()[0].0,
                                 prediction.combined_stride_context_speed()[1].0,
                                 prediction.combined_stride_context_speed()[0].1,
-                                prediction.combined_stride_context_speed()[0].1,
-                                );
-            }else {
-                println_stderr!("prediction {} lcontextmap{} dcontextmap{} mixingvalues{}",
+                                prediction.combined_stride_context_speed()[1].1,
+                                )
+            } else {
+                writeln!(w, "prediction {} lcontextmap{} dcontextmap{} mixingvalues{}",
                                 prediction_mode,
                                 lit_cm,
                                 dist_cm,
                                 mixing_values,
-                );
+                )
             }
         },
         &interface::Command::Copy(ref copy) => {
-            println_stderr!("copy {} from {}", copy.num_bytes, copy.distance);
+            writeln!(w, "copy {} from {}", copy.num_bytes, copy.distance)
         },
         &interface::Command::Dict(ref dict) => {
             let mut transformed_word = [0u8;38];
@@ -132,19 +136,19 @@ pub fn write_one<T:SliceWrapper<u8>>(cmd: &interface::Command<T>) {
             
             transformed_word.split_at(actual_copy_len).0;
             assert_eq!(dict.final_size as usize, actual_copy_len);
-            println_stderr!("dict {} word {},{} {:x} func {} {:x}",
+            writeln!(w, "dict {} word {},{} {:x} func {} {:x}",
                             actual_copy_len,
                             dict.word_size,
                             dict.word_id,
                             SliceU8Ref(raw_word),
                             dict.transform,
-                            SliceU8Ref(transformed_word.split_at(actual_copy_len).0));
+                            SliceU8Ref(transformed_word.split_at(actual_copy_len).0))
         },
         &interface::Command::Literal(ref lit) => {
-            println_stderr!("{} {} {:x}",
+            writeln!(w, "{} {} {:x}",
                             if lit.high_entropy {"rndins"} else {"insert"},
                             lit.data.slice().len(),
-                            SliceU8Ref(lit.data.slice()));
+                            SliceU8Ref(lit.data.slice()))
         },
     }
 }
@@ -0,0 +1,335 @@
+// Walks an existing brotli stream and reports how each metablock was encoded: block splits,
+// context modes and maps, prefix code sizes and where the bits went. The parsing itself is done
+// by stream_decoder.rs.
+use std::fmt;
+use std::vec::Vec;
+use super::histogram::ContextType;
+use super::shared_dictionary::BrotliSharedDictionary;
+use super::stream_decoder::BrotliDecodeStream;
+
+// Each error carries the bit offset in the stream where it was detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrotliAnalyzeError {
+  Truncated(usize),
+  InvalidWindowBits(usize),
+  InvalidMetaBlockHeader(usize),
+  InvalidPrefixCode(usize),
+  InvalidContextMap(usize),
+  // a distance of zero or less, or a copy from before the start of the stream
+  InvalidDistance(usize),
+  InvalidDictionaryWord(usize),
+  // a command that produces more bytes than the metablock holds
+  MetaBlockOverrun(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrotliMetaBlockType {
+  Compressed,
+  Uncompressed,
+  Metadata,
+  // the final ISLAST, ISLASTEMPTY header
+  LastEmpty,
+}
+
+// One of the literal, command and distance categories of a compressed metablock.
+#[derive(Debug, Clone, Default)]
+pub struct BrotliBlockCategoryReport {
+  pub num_block_types: usize,
+  pub num_blocks: usize,
+  pub num_prefix_codes: usize,
+  // literal: 64 entries per block type, distance: 4 entries per block type, command: empty
+  pub context_map: Vec<u8>,
+  // bits spent on the block type and block count codes, the context map and the prefix codes
+  pub block_split_header_bits: usize,
+  pub context_map_bits: usize,
+  pub prefix_code_bits: usize,
+  // number of symbols with a nonzero code length, summed over the prefix codes
+  pub num_used_symbols: usize,
+  pub num_symbols: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct BrotliMetaBlockReport {
+  pub kind: BrotliMetaBlockType,
+  pub is_last: bool,
+  pub start_bit: usize,
+  // bytes of output, or of skipped data for metadata
+  pub length: usize,
+  pub npostfix: u32,
+  pub ndirect: u32,
+  pub context_modes: Vec<ContextType>,
+  pub literal: BrotliBlockCategoryReport,
+  pub command: BrotliBlockCategoryReport,
+  pub distance: BrotliBlockCategoryReport,
+  pub num_commands: usize,
+  pub num_literals: usize,
+  pub num_dictionary_words: usize,
+  pub header_bits: usize,
+  pub literal_bits: usize,
+  // insert-and-copy symbols and their extra bits
+  pub command_bits: usize,
+  // distance symbols and their extra bits
+  pub distance_bits: usize,
+  pub block_switch_bits: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct BrotliStreamReport {
+  pub window_bits: u32,
+  pub large_window: bool,
+  pub metablocks: Vec<BrotliMetaBlockReport>,
+  pub output_size: usize,
+  pub total_bits: usize,
+  // bytes after the end of the stream
+  pub trailing_bytes: usize,
+}
+
+// Parses a whole brotli stream (which must not use a custom dictionary).
+pub fn BrotliAnalyzeStream(data: &[u8]) -> Result<BrotliStreamReport, BrotliAnalyzeError> {
+  let builtin = BrotliSharedDictionary {
+    prefix: &[],
+    words: None,
+    transforms: None,
+    context_based: false,
+  };
+  BrotliDecodeStream(data, &builtin, None)
+}
+
+fn ContextModeName(mode: &ContextType) -> &'static str {
+  match *mode {
+    ContextType::CONTEXT_LSB6 => "lsb6",
+    ContextType::CONTEXT_MSB6 => "msb6",
+    ContextType::CONTEXT_UTF8 => "utf8",
+    ContextType::CONTEXT_SIGNED => "sign",
+  }
+}
+
+impl fmt::Display for BrotliBlockCategoryReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f,
+           "{} block types, {} blocks, {} prefix codes using {} of {} symbols; bits: block split {} \
+            context map {} prefix codes {}",
+           self.num_block_types,
+           self.num_blocks,
+           self.num_prefix_codes,
+           self.num_used_symbols,
+           self.num_symbols * self.num_prefix_codes,
+           self.block_split_header_bits,
+           self.context_map_bits,
+           self.prefix_code_bits)
+  }
+}
+
+impl fmt::Display for BrotliStreamReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f,
+             "window bits {}{}, {} bytes of output from {} bytes",
+             self.window_bits,
+             if self.large_window { " (large window)" } else { "" },
+             self.output_size,
+             (self.total_bits + 7) / 8)?;
+    for (index, mb) in self.metablocks.iter().enumerate() {
+      let kind = match mb.kind {
+        BrotliMetaBlockType::Compressed => "compressed",
+        BrotliMetaBlockType::Uncompressed => "uncompressed",
+        BrotliMetaBlockType::Metadata => "metadata",
+        BrotliMetaBlockType::LastEmpty => "last empty",
+      };
+      writeln!(f,
+               "metablock {}: {}{}, {} bytes at bit {}, header {} bits",
+               index,
+               kind,
+               if mb.is_last { ", last" } else { "" },
+               mb.length,
+               mb.start_bit,
+               mb.header_bits)?;
+      if mb.kind != BrotliMetaBlockType::Compressed {
+        continue;
+      }
+      writeln!(f, "  npostfix {} ndirect {}", mb.npostfix, mb.ndirect)?;
+      write!(f, "  context modes")?;
+      for mode in mb.context_modes.iter() {
+        write!(f, " {}", ContextModeName(mode))?;
+      }
+      writeln!(f)?;
+      writeln!(f, "  literal: {}", mb.literal)?;
+      writeln!(f, "  command: {}", mb.command)?;
+      writeln!(f, "  distance: {}", mb.distance)?;
+      for &(name, map) in [("literal", &mb.literal.context_map), ("distance", &mb.distance.context_map)].iter() {
+        write!(f, "  {} context map", name)?;
+        for value in map.iter() {
+          write!(f, " {}", value)?;
+        }
+        writeln!(f)?;
+      }
+      writeln!(f,
+               "  {} commands, {} literals, {} dictionary words",
+               mb.num_commands,
+               mb.num_literals,
+               mb.num_dictionary_words)?;
+      writeln!(f,
+               "  bits: literals {} commands {} distances {} block switches {}",
+               mb.literal_bits,
+               mb.command_bits,
+               mb.distance_bits,
+               mb.block_switch_bits)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  #[allow(unused_imports)]
+  use std::vec::Vec;
+  #[allow(unused_imports)]
+  use super::{BrotliAnalyzeError, BrotliMetaBlockType};
+  #[allow(unused)]
+  fn compress(input: &[u8], quality: i32, lgwin: i32) -> Vec<u8> {
+    let mut params = super::super::encode::BrotliEncoderInitParams();
+    params.quality = quality;
+    params.lgwin = lgwin;
+    let mut output = Vec::new();
+    super::super::BrotliCompress(&mut &input[..], &mut output, &params).unwrap();
+    output
+  }
+  #[allow(unused)]
+  fn sample_input() -> Vec<u8> {
+    let mut input = Vec::new();
+    for i in 0..4000u32 {
+      input.extend_from_slice(format!("line {} of the sample: {}\n", i, i * i % 977).as_bytes());
+      input.push((i * 7919 % 251) as u8);
+    }
+    input
+  }
+  #[test]
+  fn test_analyze_matches_input() {
+    let input = sample_input();
+    for quality in [0, 1, 5, 9, 11].iter() {
+      let compressed = compress(&input[..], *quality, 18);
+      let report = super::BrotliAnalyzeStream(&compressed[..]).unwrap();
+      assert_eq!(report.window_bits, 18);
+      assert_eq!(report.output_size, input.len());
+      assert_eq!(report.total_bits, compressed.len() * 8);
+      assert_eq!(report.trailing_bytes, 0);
+      let mut length = 0usize;
+      let mut bits = 0usize;
+      for mb in report.metablocks.iter() {
+        if mb.kind != BrotliMetaBlockType::Metadata {
+          length += mb.length;
+        }
+        bits += mb.header_bits + mb.literal_bits + mb.command_bits + mb.distance_bits + mb.block_switch_bits;
+        if mb.kind == BrotliMetaBlockType::Compressed {
+          assert_eq!(mb.context_modes.len(), mb.literal.num_block_types);
+          assert_eq!(mb.literal.context_map.len(), 64 * mb.literal.num_block_types);
+          assert_eq!(mb.distance.context_map.len(), 4 * mb.distance.num_block_types);
+          assert!(mb.num_commands > 0);
+        }
+      }
+      assert_eq!(length, input.len());
+      // only the padding of the last byte and the window bits are unaccounted for
+      assert!(bits <= report.total_bits && bits + 14 + 7 >= report.total_bits);
+    }
+  }
+  #[test]
+  fn test_analyze_uncompressed_and_errors() {
+    let mut random = Vec::new();
+    let mut state = 12345u32;
+    for _ in 0..10000 {
+      state = state.wrapping_mul(1103515245).wrapping_add(12345);
+      random.push((state >> 16) as u8);
+    }
+    let compressed = compress(&random[..], 5, 22);
+    let report = super::BrotliAnalyzeStream(&compressed[..]).unwrap();
+    assert_eq!(report.window_bits, 22);
+    assert_eq!(report.metablocks[0].kind, BrotliMetaBlockType::Uncompressed);
+    assert_eq!(report.output_size, random.len());
+    let compressed = compress(&sample_input()[..], 9, 22);
+    match super::BrotliAnalyzeStream(&compressed[..compressed.len() - 1]) {
+      Err(BrotliAnalyzeError::Truncated(_)) => {}
+      other => panic!("unexpected {:?}", other.map(|r| r.output_size)),
+    }
+    let report = super::BrotliAnalyzeStream(&[0x06, 0x00][..]).unwrap();
+    assert_eq!(report.output_size, 0);
+    assert_eq!(report.trailing_bytes, 1);
+  }
+  // Appends nbits of value to a stream whose next bit is at *bit_pos.
+  #[allow(unused)]
+  fn write_bits(stream: &mut Vec<u8>, bit_pos: &mut usize, nbits: usize, value: u64) {
+    for i in 0..nbits {
+      if *bit_pos >> 3 == stream.len() {
+        stream.push(0);
+      }
+      stream[*bit_pos >> 3] |= (((value >> i) & 1) << (*bit_pos & 7)) as u8;
+      *bit_pos += 1;
+    }
+  }
+  // A metadata block of length bytes, then the empty last metablock.
+  #[allow(unused)]
+  fn metadata_stream(length: usize) -> Vec<u8> {
+    let mut stream = Vec::new();
+    let mut bit_pos = 0usize;
+    write_bits(&mut stream, &mut bit_pos, 1, 0); // WBITS 16
+    write_bits(&mut stream, &mut bit_pos, 1, 0); // ISLAST
+    write_bits(&mut stream, &mut bit_pos, 2, 3); // metadata
+    write_bits(&mut stream, &mut bit_pos, 1, 0); // reserved
+    let num_bytes = if length > 0xffff { 3 } else if length > 0xff { 2 } else { 1 };
+    write_bits(&mut stream, &mut bit_pos, 2, num_bytes);
+    write_bits(&mut stream, &mut bit_pos, num_bytes as usize * 8, length as u64 - 1);
+    bit_pos = (bit_pos + 7) & !7;
+    for i in 0..length {
+      stream.push(i as u8);
+    }
+    stream.push(3); // ISLAST, ISLASTEMPTY
+    stream
+  }
+  #[test]
+  fn test_analyze_metadata() {
+    for length in [1usize, 7, 8, 16, 300, 70000].iter() {
+      let stream = metadata_stream(*length);
+      let report = super::BrotliAnalyzeStream(&stream[..]).unwrap();
+      assert_eq!(report.metablocks[0].kind, BrotliMetaBlockType::Metadata);
+      assert_eq!(report.metablocks[0].length, *length);
+      assert_eq!(report.metablocks[1].kind, BrotliMetaBlockType::LastEmpty);
+      assert_eq!(report.output_size, 0);
+      assert_eq!(report.trailing_bytes, 0);
+      match super::BrotliAnalyzeStream(&stream[..stream.len() - 2]) {
+        Err(BrotliAnalyzeError::Truncated(_)) => {}
+        other => panic!("unexpected {:?}", other.map(|r| r.output_size)),
+      }
+    }
+  }
+  #[test]
+  fn test_analyze_large_window_header() {
+    // a 1GiB window and no data
+    let mut stream = Vec::new();
+    let mut bit_pos = 0usize;
+    write_bits(&mut stream, &mut bit_pos, 7, 0x11);
+    write_bits(&mut stream, &mut bit_pos, 1, 0);
+    write_bits(&mut stream, &mut bit_pos, 6, 30);
+    write_bits(&mut stream, &mut bit_pos, 2, 3);
+    let report = super::BrotliAnalyzeStream(&stream[..]).unwrap();
+    assert!(report.large_window);
+    assert_eq!(report.window_bits, 30);
+    assert_eq!(report.output_size, 0);
+    // and one that decodes past the first growth of the window
+    let mut input = sample_input();
+    input.truncate(200000);
+    let mut params = super::super::encode::BrotliEncoderInitParams();
+    params.quality = 5;
+    params.lgwin = 24;
+    params.large_window = true;
+    let mut compressed = Vec::new();
+    super::super::BrotliCompress(&mut &input[..], &mut compressed, &params).unwrap();
+    let builtin = super::BrotliSharedDictionary {
+      prefix: &[],
+      words: None,
+      transforms: None,
+      context_based: false,
+    };
+    let mut output = Vec::new();
+    let report = super::super::stream_decoder::BrotliDecodeWithSharedDictionary(&compressed[..], &builtin, &mut output).unwrap();
+    assert!(report.large_window);
+    assert!(output == input);
+  }
+}
@@ -1,9 +1,8 @@
 #![allow(dead_code)]
 use super::command::{Command, ComputeDistanceCode, InitCommand, BrotliDistanceParams};
 use super::hash_to_binary_tree::{H10, H10Buckets, H10DefaultParams, ZopfliNode};
-use super::dictionary_hash::kStaticDictionaryHash;
 use super::static_dict::{BROTLI_UNALIGNED_LOAD32, BROTLI_UNALIGNED_LOAD64, FindMatchLengthWithLimit};
-use super::static_dict::BrotliDictionary;
+use super::static_dict::{BrotliDictionary, BrotliDictionaryTransformAllowed};
 use super::super::alloc;
 use super::super::alloc::{SliceWrapper, SliceWrapperMut};
 use super::util::{Log2FloorNonZero, brotli_max_size_t, floatX};
@@ -14,10 +13,6 @@ static kBrotliMaxWindowBits: i32 = 24i32;
 
 pub static kInvalidMatch: u32 = 0xfffffffu32;
 
-static kCutoffTransformsCount: u32 = 10u32;
-
-static kCutoffTransforms: u64 = 0x71b520au64 << 32i32 | 0xda2d3200u32 as (u64);
-
 pub static kHashMul32: u32 = 0x1e35a7bdu32;
 
 pub static kHashMul64: u64 = 0x1e35a7bdu64 << 32i32 | 0x1e35a7bdu64;
@@ -38,7 +33,7 @@ pub enum BrotliEncoderMode {
 }
 
 
-#[derive(Clone,Copy, Debug)]
+#[derive(Clone,Copy, Debug, Default)]
 pub struct BrotliHasherParams {
   // type of hasher to use (default: type 6, but others have tradeoffs of speed/memory)
   pub type_: i32,
@@ -52,6 +47,12 @@ pub struct BrotliHasherParams {
   pub num_last_distances_to_check: i32,
   // how much to weigh distance vs an extra byte of copy match when comparing possible copy srcs
   pub literal_byte_score: i32,
+  // skip the search of the built-in static dictionary altogether
+  pub disable_static_dictionary: bool,
+  // shortest static dictionary word to consider (dictionary words are 4 to 24 bytes long)
+  pub static_dictionary_min_word_len: i32,
+  // bitmask of BROTLI_DICTIONARY_TRANSFORM_* classes the static dictionary search may use
+  pub static_dictionary_transforms: u32,
 }
 
 
@@ -88,6 +89,27 @@ pub struct BrotliEncoderParams {
   pub large_window: bool,
   // avoid search for the best ndirect vs npostfix parameters for distance
   pub avoid_distance_prefix_search: bool,
+  // at quality 10 and 11, price every legal ndirect and npostfix pair instead of walking a few
+  pub exhaustive_distance_prefix_search: bool,
+  // at quality 11, find matches with a suffix array over the whole window instead of the H10 tree
+  // (set by quality 12). Every metablock is then also parsed the plain quality 11 way and the
+  // smaller of the two is written, so it costs the suffix array parse plus a whole quality 11
+  // parse: 3 to 4 times the quality 11 time on testdata.
+  pub exhaustive_match_search: bool,
+  // build optimal length-limited prefix codes with package-merge (always on from quality 10)
+  pub optimal_huffman_codes: bool,
+  // shortest path passes at quality 11, each priced with the commands of the previous one, of which
+  // the cheapest is kept
+  // (0 = 1 at quality 10 and 2 at 11; above 1 at quality 10 switches to the quality 11 search)
+  pub zopfli_iterations: u32,
+  // matches evaluated from each queued start position (0 = 1 at quality 10 and 5 at 11, at most 64)
+  pub zopfli_max_candidates: u32,
+  // matches longer than this are only tried at their full length (0 = 150 at quality 10 and 325 at 11)
+  pub zopfli_max_len: u32,
+  // size of the LZ77 prefix of a shared dictionary, which sits between the window and the static
+  // dictionary, and how much of its tail is loaded in front of the stream (see BrotliDictionaryGap)
+  pub compound_dictionary_size: usize,
+  pub compound_dictionary_loaded: usize,
 }
 
 impl Default for BrotliEncoderParams {
@@ -104,6 +126,7 @@ pub enum HowPrepared {
   ALREADY_PREPARED,
   NEWLY_PREPARED,
 }
+#[derive(Default)]
 pub struct Struct1 {
   pub params: BrotliHasherParams,
   pub is_prepared_: i32,
@@ -111,6 +134,22 @@ pub struct Struct1 {
   pub dict_num_matches: usize,
 }
 
+// The distances past max_distance that the LZ77 prefix of a shared dictionary takes up before the
+// static dictionary starts. A decoder counts its window from the start of the stream and puts the
+// whole prefix right past it, while the encoder loads the tail of the prefix in front of the stream
+// and counts position from there, so the two windows part once the stream fills the encoder's.
+pub fn BrotliDictionaryGap(params: &BrotliEncoderParams,
+                           position: usize,
+                           max_backward_limit: usize)
+                           -> usize {
+  if (*params).compound_dictionary_size == 0 {
+    return 0;
+  }
+  let stream_position = position - core::cmp::min(position, (*params).compound_dictionary_loaded);
+  core::cmp::min(stream_position, max_backward_limit) + (*params).compound_dictionary_size -
+    core::cmp::min(position, max_backward_limit)
+}
+
 fn LiteralSpreeLengthForSparseSearch(params: &BrotliEncoderParams) -> usize {
   (if (*params).quality < 9 {
      64i32
@@ -282,7 +321,8 @@ impl<T: SliceWrapperMut<u32> + SliceWrapper<u32> + BasicHashComputer> AnyHasher
     let best_len_in: usize = (*out).len;
     let cur_ix_masked: usize = cur_ix & ring_buffer_mask;
     let key: u32 = self.HashBytes(&data[(cur_ix_masked as (usize))..]) as u32;
-    let mut compare_char: i32 = data[(cur_ix_masked.wrapping_add(best_len_in) as (usize))] as (i32);
+    // past the end of a whole buffer input (which has no slack) any byte does
+    let mut compare_char: i32 = *data.get(cur_ix_masked.wrapping_add(best_len_in)).unwrap_or(&0) as (i32);
     let mut best_score: usize = (*out).score;
     let mut best_len: usize = best_len_in;
     let cached_backward: usize = distance_cache[(0usize)] as (usize);
@@ -301,7 +341,7 @@ impl<T: SliceWrapperMut<u32> + SliceWrapper<u32> + BasicHashComputer> AnyHasher
           (*out).len = len;
           (*out).distance = cached_backward;
           (*out).score = best_score;
-          compare_char = data[(cur_ix_masked.wrapping_add(best_len) as (usize))] as (i32);
+          compare_char = *data.get(cur_ix_masked.wrapping_add(best_len)).unwrap_or(&0) as (i32);
           if self.buckets_.BUCKET_SWEEP() == 1i32 {
             (*self).buckets_.slice_mut()[key as (usize)] = cur_ix as (u32);
             return true;
@@ -367,7 +407,7 @@ impl<T: SliceWrapperMut<u32> + SliceWrapper<u32> + BasicHashComputer> AnyHasher
                 (*out).len = best_len;
                 (*out).distance = backward;
                 (*out).score = score;
-                compare_char = data[(cur_ix_masked.wrapping_add(best_len) as (usize))] as (i32);
+                compare_char = *data.get(cur_ix_masked.wrapping_add(best_len)).unwrap_or(&0) as (i32);
                 is_match_found = 1i32;
               }
             }
@@ -679,6 +719,7 @@ impl<AllocU16: alloc::Allocator<u16>,
             prev_ix &= ring_buffer_mask;
             if cur_ix_masked.wrapping_add(best_len) > ring_buffer_mask ||
                 prev_ix.wrapping_add(best_len) > ring_buffer_mask ||
+                cur_ix_masked.wrapping_add(best_len) >= data.len() ||
                 data[cur_ix_masked.wrapping_add(best_len)] != data[prev_ix.wrapping_add(best_len)] {
                 continue;
             }
@@ -702,14 +743,14 @@ impl<AllocU16: alloc::Allocator<u16>,
         if max_length >= 4 && cur_ix_masked.wrapping_add(best_len) <= ring_buffer_mask {
             let key = self.HashBytes(&data.split_at(cur_ix_masked).1);
             let bucket = &mut self.buckets_.slice_mut().split_at_mut(key << H9_BLOCK_BITS).1.split_at_mut(H9_BLOCK_SIZE).0;
-            assert!(bucket.len() > H9_BLOCK_MASK);
-            assert_eq!(bucket.len(), H9_BLOCK_MASK + 1);
+            debug_assert!(bucket.len() > H9_BLOCK_MASK);
+            debug_assert_eq!(bucket.len(), H9_BLOCK_MASK + 1);
             let self_num_key = &mut self.num_.slice_mut()[key];
             let down = if *self_num_key > H9_BLOCK_SIZE as u16 {
                 (*self_num_key as usize) - H9_BLOCK_SIZE
             } else {0usize};
             let mut i: usize = *self_num_key as usize;
-            let mut prev_best_val = data[cur_ix_masked.wrapping_add(best_len)];
+            let mut prev_best_val = *data.get(cur_ix_masked.wrapping_add(best_len)).unwrap_or(&0);
             while i > down {
                 i -= 1;
                 let mut prev_ix = bucket[i & H9_BLOCK_MASK] as usize;
@@ -738,7 +779,8 @@ impl<AllocU16: alloc::Allocator<u16>,
                             out.distance = backward;
                             out.score = best_score;
                             is_match_found = 1;
-                            if cur_ix_masked.wrapping_add(best_len) > ring_buffer_mask {
+                            if cur_ix_masked.wrapping_add(best_len) > ring_buffer_mask ||
+                               cur_ix_masked.wrapping_add(best_len) >= data.len() {
                                 break
                             }
                             prev_best_val = data[cur_ix_masked.wrapping_add(best_len) as usize];
@@ -986,6 +1028,7 @@ impl<Specialization: AdvHashSpecialization, AllocU16: alloc::Allocator<u16>, All
           }
           prev_ix = prev_ix & ring_buffer_mask;
           if cur_ix_masked.wrapping_add(best_len) > ring_buffer_mask || prev_ix.wrapping_add(best_len) > ring_buffer_mask ||
+             cur_ix_masked.wrapping_add(best_len) >= data.len() ||
              data[(cur_ix_masked.wrapping_add(best_len) as (usize))] as (i32) !=
              data[(prev_ix.wrapping_add(best_len) as (usize))] as (i32) {
             {
@@ -1027,7 +1070,7 @@ impl<Specialization: AdvHashSpecialization, AllocU16: alloc::Allocator<u16>, All
         if (key << common_block_bits) as usize >= self.buckets.slice().len() {
             let key2: u32 = self.HashBytes(&data[(cur_ix_masked as (usize))..]) as u32;
             let key3: u32 = self.HashBytes(&data[(cur_ix_masked as (usize))..]) as u32;
-            assert_eq!(key2, key3 + 1);
+            debug_assert_eq!(key2, key3 + 1);
         }
       let bucket: &mut [u32] = &mut self.buckets.slice_mut()[((key << common_block_bits) as (usize))..];
       let down: usize = if self.num.slice()[(key as (usize))] as (u64) > (*self).block_size_ {
@@ -1049,6 +1092,7 @@ impl<Specialization: AdvHashSpecialization, AllocU16: alloc::Allocator<u16>, All
         }
         prev_ix = prev_ix & ring_buffer_mask;
         if cur_ix_masked.wrapping_add(best_len) > ring_buffer_mask || prev_ix.wrapping_add(best_len) > ring_buffer_mask ||
+           cur_ix_masked.wrapping_add(best_len) >= data.len() ||
            data[(cur_ix_masked.wrapping_add(best_len) as (usize))] as (i32) !=
            data[(prev_ix.wrapping_add(best_len) as (usize))] as (i32) {
           {
@@ -1199,6 +1243,7 @@ fn TestStaticDictionaryItem(dictionary: &BrotliDictionary,
                             max_backward: usize,
                             max_distance: usize,
                             h9_opts: H9Opts,
+                            hparams: &BrotliHasherParams,
                             out: &mut HasherSearchResult)
                             -> i32 {
   let len: usize;
@@ -1214,13 +1259,15 @@ fn TestStaticDictionaryItem(dictionary: &BrotliDictionary,
     return 0i32;
   }
   matchlen = FindMatchLengthWithLimit(data, &(*dictionary).data[offset..], len);
-  if matchlen.wrapping_add(kCutoffTransformsCount as usize) <= len || matchlen == 0usize {
+  if matchlen.wrapping_add((*dictionary).cutoff_transforms.len()) <= len || matchlen == 0usize {
     return 0i32;
   }
   {
-    let cut: u64 = len.wrapping_sub(matchlen) as u64;
-    let transform_id: usize =
-      (cut << 2i32).wrapping_add(kCutoffTransforms as u64 >> cut.wrapping_mul(6) & 0x3f) as usize;
+    let cut: usize = len.wrapping_sub(matchlen);
+    let transform_id: usize = (*dictionary).cutoff_transforms[cut] as usize;
+    if !BrotliDictionaryTransformAllowed(hparams, dictionary, len, transform_id) {
+      return 0i32;
+    }
     backward = max_backward.wrapping_add(dist)
       .wrapping_add(1usize)
       .wrapping_add(transform_id << (*dictionary).size_bits_by_length[len] as (i32));
@@ -1240,7 +1287,7 @@ fn TestStaticDictionaryItem(dictionary: &BrotliDictionary,
 }
 
 fn SearchInStaticDictionary<HasherType: AnyHasher>(dictionary: &BrotliDictionary,
-                                                   dictionary_hash: &[u16],
+                                                   _dictionary_hash: &[u16],
                                                    handle: &mut HasherType,
                                                    data: &[u8],
                                                    max_length: usize,
@@ -1254,6 +1301,10 @@ fn SearchInStaticDictionary<HasherType: AnyHasher>(dictionary: &BrotliDictionary
   let mut is_match_found: i32 = 0i32;
   let opts = handle.Opts();
   let xself: &mut Struct1 = handle.GetHasherCommon();
+  let hparams = (*xself).params;
+  if hparams.disable_static_dictionary {
+    return 0i32;
+  }
   if (*xself).dict_num_matches < (*xself).dict_num_lookups >> 7i32 {
     return 0i32;
   }
@@ -1261,11 +1312,11 @@ fn SearchInStaticDictionary<HasherType: AnyHasher>(dictionary: &BrotliDictionary
   i = 0usize;
   while i < if shallow != 0 { 1u32 } else { 2u32 } as (usize) {
     {
-      let item: usize = dictionary_hash[(key as (usize))] as (usize);
+      let item: usize = (*dictionary).hash_item(key);
       (*xself).dict_num_lookups = (*xself).dict_num_lookups.wrapping_add(1 as (usize));
       if item != 0usize {
         let item_matches: i32 =
-          TestStaticDictionaryItem(dictionary, item, data, max_length, max_backward, max_distance, opts, out);
+          TestStaticDictionaryItem(dictionary, item, data, max_length, max_backward, max_distance, opts, &hparams, out);
         if item_matches != 0 {
           (*xself).dict_num_matches = (*xself).dict_num_matches.wrapping_add(1 as (usize));
           is_match_found = 1i32;
@@ -1279,7 +1330,8 @@ fn SearchInStaticDictionary<HasherType: AnyHasher>(dictionary: &BrotliDictionary
 }
 
 pub enum UnionHasher<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> {
-  Uninit,
+  // carries its own Struct1 so that GetHasherCommon has something to return before HasherSetup
+  Uninit(Struct1),
   H2(BasicHasher<H2Sub<AllocU32>>),
   H3(BasicHasher<H3Sub<AllocU32>>),
   H4(BasicHasher<H4Sub<AllocU32>>),
@@ -1289,8 +1341,9 @@ pub enum UnionHasher<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator
   H9(H9<AllocU16, AllocU32>),
   H10(H10<AllocU32, H10Buckets<AllocU32>, H10DefaultParams>),
 }
+// an uninitialized hasher stores nothing and finds nothing: each call evaluates $fallback instead
 macro_rules! match_all_hashers_mut {
-    ($xself : expr, $func_call : ident, $( $args:expr),*) => {
+    ($xself : expr, $func_call : ident, $fallback : expr, $( $args:expr),*) => {
         match $xself {
      &mut UnionHasher::H2(ref mut hasher) => hasher.$func_call($($args),*),
      &mut UnionHasher::H3(ref mut hasher) => hasher.$func_call($($args),*),
@@ -1300,12 +1353,12 @@ macro_rules! match_all_hashers_mut {
      &mut UnionHasher::H54(ref mut hasher) => hasher.$func_call($($args),*),
      &mut UnionHasher::H9(ref mut hasher) => hasher.$func_call($($args),*),
      &mut UnionHasher::H10(ref mut hasher) => hasher.$func_call($($args),*),
-     &mut UnionHasher::Uninit => panic!("UNINTIALIZED"),
+     &mut UnionHasher::Uninit(_) => $fallback,
         }
     };
 }
 macro_rules! match_all_hashers {
-    ($xself : expr, $func_call : ident, $( $args:expr),*) => {
+    ($xself : expr, $func_call : ident, $fallback : expr, $( $args:expr),*) => {
         match $xself {
      &UnionHasher::H2(ref hasher) => hasher.$func_call($($args),*),
      &UnionHasher::H3(ref hasher) => hasher.$func_call($($args),*),
@@ -1315,35 +1368,45 @@ macro_rules! match_all_hashers {
      &UnionHasher::H54(ref hasher) => hasher.$func_call($($args),*),
      &UnionHasher::H9(ref hasher) => hasher.$func_call($($args),*),
      &UnionHasher::H10(ref hasher) => hasher.$func_call($($args),*),
-     &UnionHasher::Uninit => panic!("UNINTIALIZED"),
+     &UnionHasher::Uninit(_) => $fallback,
         }
     };
 }
 impl<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> AnyHasher
   for UnionHasher<AllocU16, AllocU32> {
   fn Opts(&self) -> H9Opts {
-    return match_all_hashers!(self, Opts,);
+    return match_all_hashers!(self, Opts, H9Opts::default(),);
   }
   fn GetHasherCommon(&mut self) -> &mut Struct1 {
-    return match_all_hashers_mut!(self, GetHasherCommon,);
+    match *self {
+      UnionHasher::H2(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::H3(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::H4(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::H5(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::H6(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::H54(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::H9(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::H10(ref mut hasher) => hasher.GetHasherCommon(),
+      UnionHasher::Uninit(ref mut common) => common,
+    }
   }/*
   fn GetH10Tree(&mut self) -> Option<&mut H10<AllocU32, H10Buckets, H10DefaultParams>> {
     return match_all_hashers_mut!(self, GetH10Tree,);
   }*/
   fn Prepare(&mut self, one_shot: bool, input_size: usize, data: &[u8]) -> HowPrepared {
-    return match_all_hashers_mut!(self, Prepare, one_shot, input_size, data);
+    return match_all_hashers_mut!(self, Prepare, HowPrepared::ALREADY_PREPARED, one_shot, input_size, data);
   }
   fn HashBytes(&self, data: &[u8]) -> usize {
-    return match_all_hashers!(self, HashBytes, data);
+    return match_all_hashers!(self, HashBytes, 0, data);
   }
   fn HashTypeLength(&self) -> usize {
-    return match_all_hashers!(self, HashTypeLength,);
+    return match_all_hashers!(self, HashTypeLength, 4,);
   }
   fn StoreLookahead(&self) -> usize {
-    return match_all_hashers!(self, StoreLookahead,);
+    return match_all_hashers!(self, StoreLookahead, 4,);
   }
   fn PrepareDistanceCache(&self, distance_cache: &mut [i32]) {
-    return match_all_hashers!(self, PrepareDistanceCache, distance_cache);
+    return match_all_hashers!(self, PrepareDistanceCache, (), distance_cache);
   }
   fn StitchToPreviousBlock(&mut self,
                            num_bytes: usize,
@@ -1352,6 +1415,7 @@ impl<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> AnyHasher
                            ringbuffer_mask: usize) {
     return match_all_hashers_mut!(self,
                                   StitchToPreviousBlock,
+                                  (),
                                   num_bytes,
                                   position,
                                   ringbuffer,
@@ -1372,6 +1436,7 @@ impl<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> AnyHasher
                       -> bool {
     return match_all_hashers_mut!(self,
                                   FindLongestMatch,
+                                  false,
                                   dictionary,
                                   dictionary_hash,
                                   data,
@@ -1385,14 +1450,36 @@ impl<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> AnyHasher
                                   out);
   }
   fn Store(&mut self, data: &[u8], mask: usize, ix: usize) {
-    return match_all_hashers_mut!(self, Store, data, mask, ix);
+    return match_all_hashers_mut!(self, Store, (), data, mask, ix);
   }
   fn StoreRange(&mut self, data: &[u8], mask: usize, ix_start: usize, ix_end: usize) {
-    return match_all_hashers_mut!(self, StoreRange, data, mask, ix_start, ix_end);
+    return match_all_hashers_mut!(self, StoreRange, (), data, mask, ix_start, ix_end);
   }
 }
 
 impl<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> UnionHasher<AllocU16, AllocU32> {
+  // whether the tables of the hasher got the memory they asked for: an allocator that is out of
+  // memory hands back shorter cells
+  pub fn is_allocated(&self) -> bool {
+    match *self {
+      UnionHasher::H2(ref hasher) => hasher.buckets_.buckets_.slice().len() >=
+        (1usize << hasher.buckets_.BUCKET_BITS()) + hasher.buckets_.BUCKET_SWEEP() as usize,
+      UnionHasher::H3(ref hasher) => hasher.buckets_.buckets_.slice().len() >=
+        (1usize << hasher.buckets_.BUCKET_BITS()) + hasher.buckets_.BUCKET_SWEEP() as usize,
+      UnionHasher::H4(ref hasher) => hasher.buckets_.buckets_.slice().len() >=
+        (1usize << hasher.buckets_.BUCKET_BITS()) + hasher.buckets_.BUCKET_SWEEP() as usize,
+      UnionHasher::H54(ref hasher) => hasher.buckets_.buckets_.slice().len() >=
+        (1usize << hasher.buckets_.BUCKET_BITS()) + hasher.buckets_.BUCKET_SWEEP() as usize,
+      UnionHasher::H5(ref hasher) => hasher.num.slice().len() as u64 >= hasher.bucket_size_ &&
+        hasher.buckets.slice().len() as u64 >= hasher.bucket_size_ * hasher.block_size_,
+      UnionHasher::H6(ref hasher) => hasher.num.slice().len() as u64 >= hasher.bucket_size_ &&
+        hasher.buckets.slice().len() as u64 >= hasher.bucket_size_ * hasher.block_size_,
+      UnionHasher::H9(ref hasher) => hasher.num_.slice().len() >= 1 << H9_BUCKET_BITS &&
+        hasher.buckets_.slice().len() >= H9_BLOCK_SIZE << H9_BUCKET_BITS,
+      UnionHasher::H10(ref hasher) => !hasher.buckets_.slice().is_empty(),
+      UnionHasher::Uninit(_) => true,
+    }
+  }
   pub fn free (&mut self, m16: &mut AllocU16, m32: &mut AllocU32) {
     match self {
       &mut UnionHasher::H2(ref mut hasher) => {
@@ -1432,7 +1519,7 @@ impl<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> UnionHash
 impl<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>> Default
   for UnionHasher<AllocU16, AllocU32> {
   fn default() -> Self {
-    UnionHasher::Uninit
+    UnionHasher::Uninit(Struct1::default())
   }
 }
 
@@ -1465,7 +1552,6 @@ fn CreateBackwardReferences<AH: AnyHasher>(dictionary: &BrotliDictionary,
                                            mut commands: &mut [Command],
                                            num_commands: &mut usize,
                                            num_literals: &mut usize) {
-  let gap = 0usize;
   let max_backward_limit: usize = (1usize << (*params).lgwin).wrapping_sub(16usize);
   let mut new_commands_count: usize = 0;
   let mut insert_length: usize = *last_insert_len;
@@ -1484,6 +1570,7 @@ fn CreateBackwardReferences<AH: AnyHasher>(dictionary: &BrotliDictionary,
   while position.wrapping_add(hasher.HashTypeLength()) < pos_end {
     let mut max_length: usize = pos_end.wrapping_sub(position);
     let mut max_distance: usize = brotli_min_size_t(position, max_backward_limit);
+    let mut gap: usize = BrotliDictionaryGap(params, position, max_backward_limit);
     let mut sr = HasherSearchResult {
       len: 0,
       len_x_code: 0,
@@ -1526,6 +1613,7 @@ fn CreateBackwardReferences<AH: AnyHasher>(dictionary: &BrotliDictionary,
           sr2.distance = 0usize;
           sr2.score = kMinScore;
           max_distance = brotli_min_size_t(position.wrapping_add(1usize), max_backward_limit);
+          gap = BrotliDictionaryGap(params, position.wrapping_add(1usize), max_backward_limit);
           is_match_found = hasher.FindLongestMatch(dictionary,
                                                    dictionary_hash,
                                                    ringbuffer,
@@ -1644,11 +1732,12 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
    last_insert_len: &mut usize,
    commands: &mut [Command],
    num_commands: &mut usize,
-   num_literals: &mut usize) {
+   num_literals: &mut usize) -> i32 {
   match (hasher_union) {
-    &mut UnionHasher::Uninit => panic!("working with uninitialized hash map"),
+    // HasherSetup was never run: report it rather than emit the block as literals
+    &mut UnionHasher::Uninit(_) => return 0,
       &mut UnionHasher::H10(ref mut hasher) => {
-          if params.quality >= 11 {
+          if params.quality >= 11 || params.zopfli_iterations > 1 {
               super::backward_references_hq::BrotliCreateHqZopfliBackwardReferences(
                   m32, m64, mf, mz, dictionary,
                   num_bytes,
@@ -1680,7 +1769,7 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
     }
     &mut UnionHasher::H2(ref mut hasher) => {
       CreateBackwardReferences(dictionary,
-                               &kStaticDictionaryHash[..],
+                               dictionary.hash_table,
                                num_bytes,
                                position,
                                ringbuffer,
@@ -1695,7 +1784,7 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
     }
     &mut UnionHasher::H3(ref mut hasher) => {
       CreateBackwardReferences(dictionary,
-                               &kStaticDictionaryHash[..],
+                               dictionary.hash_table,
                                num_bytes,
                                position,
                                ringbuffer,
@@ -1710,7 +1799,7 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
     }
     &mut UnionHasher::H4(ref mut hasher) => {
       CreateBackwardReferences(dictionary,
-                               &kStaticDictionaryHash[..],
+                               dictionary.hash_table,
                                num_bytes,
                                position,
                                ringbuffer,
@@ -1725,7 +1814,7 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
     }
     &mut UnionHasher::H5(ref mut hasher) => {
       CreateBackwardReferences(dictionary,
-                               &kStaticDictionaryHash[..],
+                               dictionary.hash_table,
                                num_bytes,
                                position,
                                ringbuffer,
@@ -1740,7 +1829,7 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
     }
     &mut UnionHasher::H6(ref mut hasher) => {
       CreateBackwardReferences(dictionary,
-                               &kStaticDictionaryHash[..],
+                               dictionary.hash_table,
                                num_bytes,
                                position,
                                ringbuffer,
@@ -1755,7 +1844,7 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
     }
     &mut UnionHasher::H9(ref mut hasher) => {
       CreateBackwardReferences(dictionary,
-                               &kStaticDictionaryHash[..],
+                               dictionary.hash_table,
                                num_bytes,
                                position,
                                ringbuffer,
@@ -1770,7 +1859,7 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
     }
     &mut UnionHasher::H54(ref mut hasher) => {
       CreateBackwardReferences(dictionary,
-                               &kStaticDictionaryHash[..],
+                               dictionary.hash_table,
                                num_bytes,
                                position,
                                ringbuffer,
@@ -1784,4 +1873,5 @@ pub fn BrotliCreateBackwardReferences<AllocU16: alloc::Allocator<u16>,
                                num_literals)
     }
   }
+  1
 }
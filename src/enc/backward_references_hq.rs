@@ -1,15 +1,18 @@
 #![allow(dead_code, unused_imports)]
 use super::command::{Command, ComputeDistanceCode, InitCommand, GetInsertLengthCode, GetCopyLengthCode, CombineLengthCodes, PrefixEncodeCopyDistance, CommandCopyLen, BrotliDistanceParams};
-use super::backward_references::{BrotliEncoderParams, kHashMul32,kHashMul64, kHashMul64Long, BrotliHasherParams, kInvalidMatch, kDistanceCacheIndex, kDistanceCacheOffset, AnyHasher};
+use super::backward_references::{BrotliEncoderParams, BrotliDictionaryGap, kHashMul32,kHashMul64, kHashMul64Long, BrotliHasherParams, kInvalidMatch, kDistanceCacheIndex, kDistanceCacheOffset, AnyHasher, Struct1};
 use super::dictionary_hash::kStaticDictionaryHash;
 use super::static_dict::{BROTLI_UNALIGNED_LOAD32, BROTLI_UNALIGNED_LOAD64, FindMatchLengthWithLimit};
 use super::static_dict::{BrotliDictionary, kBrotliEncDictionary, BrotliFindAllStaticDictionaryMatches};
 use super::literal_cost::BrotliEstimateBitCostsForLiterals;
 use super::constants::{kInsExtra, kCopyExtra};
+use super::brotli_bit_stream::CommandCopyLenCode;
 use super::super::alloc;
 use super::super::alloc::{SliceWrapper, SliceWrapperMut, Allocator};
-use super::util::{Log2FloorNonZero, brotli_max_size_t,FastLog2, FastLog2f64, floatX};
+use super::util::{Log2FloorNonZero, brotli_max_size_t,FastLog2, FastLog2f64, floatX, FixedLog2, FixedToFloat,
+                  FloatToFixed, BROTLI_FIXED_POINT_BITS};
 use super::hash_to_binary_tree::{InitBackwardMatch, BackwardMatch, BackwardMatchMut, StoreAndFindMatchesH10, Allocable, H10Params, H10, ZopfliNode, Union1, kInfinity};
+use super::suffix_array::{SuffixArrayMatchFinder, InitializeSuffixArrayMatchFinder, FindAllMatchesSuffixArray, SUFFIX_ARRAY_MAX_MATCHES};
 use core;
 
 const BROTLI_WINDOW_GAP:usize = 16;
@@ -116,7 +119,6 @@ pub fn BrotliZopfliCreateCommands(
     let mut pos : usize = 0usize;
     let mut offset : u32 = match (nodes[(0usize)]).u { Union1::next(off) => off, _ => 0};
     let mut i : usize;
-    let gap : usize = 0usize;
     i = 0usize;
     while offset != !(0u32) {
         {
@@ -146,6 +148,9 @@ pub fn BrotliZopfliCreateCommands(
                           block_start.wrapping_add(pos),
                           max_backward_limit
                       );
+                let gap
+                    : usize
+                    = BrotliDictionaryGap(params, block_start.wrapping_add(pos), max_backward_limit);
                 let is_dictionary
                     : i32
                     = if !!(distance > max_distance.wrapping_add(gap)) {
@@ -192,6 +197,9 @@ pub fn BrotliZopfliCreateCommands(
 fn MaxZopfliLen(
     params : &BrotliEncoderParams,
 ) -> usize {
+    if (*params).zopfli_max_len != 0 {
+        return (*params).zopfli_max_len as (usize);
+    }
     (if (*params).quality <= 10i32 {
          150i32
      } else {
@@ -200,6 +208,16 @@ fn MaxZopfliLen(
 }
 
 
+#[inline(always)]
+fn ZopfliIterations(
+    params : &BrotliEncoderParams,
+) -> usize {
+    if (*params).zopfli_iterations != 0 {
+        (*params).zopfli_iterations as (usize)
+    } else {
+        2usize
+    }
+}
 
 pub struct ZopfliCostModel<AllocF:Allocator<floatX>> {
     pub cost_cmd_ : [floatX; BROTLI_NUM_COMMAND_SYMBOLS],
@@ -221,17 +239,23 @@ pub struct PosData {
 }
 
 
+// zopfli_max_candidates is limited to the queue size
+pub const MAX_ZOPFLI_CANDIDATES: usize = 64;
+
 #[derive(Copy,Clone,Debug)]
 pub struct StartPosQueue {
-    pub q_ : [PosData;8],
+    pub q_ : [PosData;MAX_ZOPFLI_CANDIDATES],
     pub idx_ : usize,
+    // one less than the number of entries in use, a power of two of at least 8
+    pub mask_ : usize,
 }
 impl Default for StartPosQueue {
     #[inline(always)]
     fn default() -> Self {
         StartPosQueue {
-            q_: [PosData{pos:0,distance_cache:[0;4],costdiff:0.0, cost:0.0};8],
+            q_: [PosData{pos:0,distance_cache:[0;4],costdiff:0.0, cost:0.0};MAX_ZOPFLI_CANDIDATES],
             idx_: 0,
+            mask_: 7,
         }
     }
 }
@@ -285,8 +309,16 @@ fn ZopfliCostModelSetFromLiteralCosts<AllocF:Allocator<floatX>>(
         &mut literal_costs[(1usize)..]
     );
     literal_costs[(0usize) ]= 0.0 as (floatX);
+    if cfg!(feature="fixed-point") {
+        // sum exactly and round each prefix once
+        let mut fixed_sum : u64 = 0;
+        for i in 0..num_bytes {
+            fixed_sum += core::cmp::max(FloatToFixed(literal_costs[i + 1]), 0) as u64;
+            literal_costs[i + 1] = FixedToFloat(fixed_sum);
+        }
+    }
     i = 0usize;
-    while i < num_bytes {
+    while i < num_bytes && !cfg!(feature="fixed-point") {
         {
             literal_carry = literal_carry as floatX + literal_costs[(
                                                  i.wrapping_add(1usize) as (usize)
@@ -324,8 +356,11 @@ fn ZopfliCostModelSetFromLiteralCosts<AllocF:Allocator<floatX>>(
 }
 
 #[inline(always)]
-fn InitStartPosQueue() -> StartPosQueue {
-    StartPosQueue::default()
+fn InitStartPosQueue(params : &BrotliEncoderParams) -> StartPosQueue {
+    StartPosQueue {
+        mask_: core::cmp::max(MaxZopfliCandidates(params), 8).next_power_of_two() - 1,
+        ..StartPosQueue::default()
+    }
 }
 
 
@@ -388,6 +423,84 @@ pub fn StitchToPreviousBlockH10<AllocU32:Allocator<u32>,
     }
   }
 }
+// Appends the static dictionary matches longer than best_len for the bytes at the front of data,
+// counting the lookup and whether it matched in common.
+fn AppendStaticDictionaryMatches(
+    common : &mut Struct1,
+    dictionary : & BrotliDictionary,
+    data : & [u8],
+    best_len : usize,
+    max_length : usize,
+    max_backward : usize,
+    gap : usize,
+    params : & BrotliEncoderParams,
+    matches : &mut [u64]) -> usize {
+    let mut matches_offset = 0usize;
+    let mut dict_matches = [kInvalidMatch;BROTLI_MAX_STATIC_DICTIONARY_MATCH_LEN + 1];
+    let mut i : usize;
+    if common.params.disable_static_dictionary {
+        return 0;
+    }
+    i = 0usize;
+    while i <= 37usize {
+        {
+            dict_matches[(i as (usize)) ]= kInvalidMatch;
+        }
+        i = i.wrapping_add(1 as (usize));
+    }
+    {
+        let minlen
+            : usize
+            = brotli_max_size_t(
+                  4usize,
+                  best_len.wrapping_add(1usize)
+              );
+        common.dict_num_lookups = common.dict_num_lookups.wrapping_add(1);
+        if BrotliFindAllStaticDictionaryMatches(
+               &dictionary,
+               &common.params,
+               data,
+               minlen,
+               max_length,
+               &mut dict_matches[..]
+           ) != 0 {
+            let maxlen
+                : usize
+                = brotli_min_size_t(37usize,max_length);
+            let mut l : usize;
+            l = minlen;
+            while l <= maxlen {
+                {
+                    let dict_id : u32 = dict_matches[(l as (usize))];
+                    if dict_id < kInvalidMatch {
+                        let distance
+                            : usize
+                            = max_backward.wrapping_add(gap).wrapping_add(
+                                  (dict_id >> 5i32) as (usize)
+                              ).wrapping_add(
+                                  1usize
+                              );
+                        if distance <= (*params).dist.max_distance {
+                            InitDictionaryBackwardMatch(
+                                &mut BackwardMatchMut(&mut matches[matches_offset]),
+                                distance,
+                                l,
+                                (dict_id & 31u32) as (usize)
+                            );
+                            matches_offset += 1;
+                        }
+                    }
+                }
+                l = l.wrapping_add(1 as (usize));
+            }
+            if matches_offset != 0 {
+                common.dict_num_matches = common.dict_num_matches.wrapping_add(1);
+            }
+        }
+    }
+    matches_offset
+}
+
 fn FindAllMatchesH10<AllocU32:Allocator<u32>, Buckets: Allocable<u32, AllocU32>+SliceWrapperMut<u32>+SliceWrapper<u32>, Params:H10Params>(
     handle : &mut H10<AllocU32, Buckets, Params>,
     dictionary : & BrotliDictionary,
@@ -412,7 +525,6 @@ fn FindAllMatchesH10<AllocU32:Allocator<u32>, Buckets: Allocable<u32, AllocU32>+
     let mut stop
         : usize
         = cur_ix.wrapping_sub(short_match_max_backward);
-    let mut dict_matches = [kInvalidMatch;BROTLI_MAX_STATIC_DICTIONARY_MATCH_LEN + 1];
     let mut i : usize;
     if cur_ix < short_match_max_backward {
         stop = 0usize;
@@ -476,58 +588,52 @@ fn FindAllMatchesH10<AllocU32:Allocator<u32>, Buckets: Allocable<u32, AllocU32>+
                   );
         matches_offset += loc_offset;
     }
-    i = 0usize;
-    while i <= 37usize {
-        {
-            dict_matches[(i as (usize)) ]= kInvalidMatch;
-        }
-        i = i.wrapping_add(1 as (usize));
-    }
-    {
-        let minlen
-            : usize
-            = brotli_max_size_t(
-                  4usize,
-                  best_len.wrapping_add(1usize)
-              );
-        if BrotliFindAllStaticDictionaryMatches(
-               &dictionary,
-               &data[(cur_ix_masked as (usize))..],
-               minlen,
-               max_length,
-               &mut dict_matches[..]
-           ) != 0 {
-            let maxlen
-                : usize
-                = brotli_min_size_t(37usize,max_length);
-            let mut l : usize;
-            l = minlen;
-            while l <= maxlen {
-                {
-                    let dict_id : u32 = dict_matches[(l as (usize))];
-                    if dict_id < kInvalidMatch {
-                        let distance
-                            : usize
-                            = max_backward.wrapping_add(gap).wrapping_add(
-                                  (dict_id >> 5i32) as (usize)
-                              ).wrapping_add(
-                                  1usize
-                              );
-                        if distance <= (*params).dist.max_distance {
-                            InitDictionaryBackwardMatch(
-                                &mut BackwardMatchMut(&mut matches[matches_offset]),
-                                distance,
-                                l,
-                                (dict_id & 31u32) as (usize)
-                            );
-                            matches_offset += 1;
-                        }
-                    }
-                }
-                l = l.wrapping_add(1 as (usize));
-            }
-        }
-    }
+    matches_offset += AppendStaticDictionaryMatches(
+        &mut handle.common,
+        dictionary,
+        &data[(cur_ix_masked as (usize))..],
+        best_len,
+        max_length,
+        max_backward,
+        gap,
+        params,
+        matches.split_at_mut(matches_offset).1
+    );
+    matches_offset
+}
+
+fn FindAllMatchesSA<AllocU32:Allocator<u32>>(
+    handle : & SuffixArrayMatchFinder<AllocU32>,
+    common : &mut Struct1,
+    dictionary : & BrotliDictionary,
+    data : & [u8],
+    ring_buffer_mask : usize,
+    cur_ix : usize,
+    max_length : usize,
+    max_backward : usize,
+    gap : usize,
+    params : & BrotliEncoderParams,
+    matches : &mut [u64]) -> usize {
+    let mut best_len : usize = 1usize;
+    let mut matches_offset = FindAllMatchesSuffixArray(
+        handle,
+        cur_ix,
+        max_length,
+        max_backward,
+        &mut best_len,
+        matches
+    );
+    matches_offset += AppendStaticDictionaryMatches(
+        common,
+        dictionary,
+        &data[((cur_ix & ring_buffer_mask) as (usize))..],
+        best_len,
+        max_length,
+        max_backward,
+        gap,
+        params,
+        matches.split_at_mut(matches_offset).1
+    );
     matches_offset
 }
 
@@ -541,6 +647,9 @@ fn BackwardMatchLength(
 #[inline(always)]
 fn MaxZopfliCandidates(
     params : & BrotliEncoderParams) -> usize {
+    if (*params).zopfli_max_candidates != 0 {
+        return core::cmp::min((*params).zopfli_max_candidates as usize, MAX_ZOPFLI_CANDIDATES);
+    }
     (if (*params).quality <= 10i32 { 1i32 } else { 5i32 }) as (usize)
 }
 
@@ -549,7 +658,7 @@ fn ComputeDistanceShortcut(
     block_start : usize,
     pos : usize,
     max_backward : usize,
-    gap : usize,
+    params : & BrotliEncoderParams,
     nodes : & [ZopfliNode
 ]) -> u32 {
     let clen
@@ -567,6 +676,10 @@ fn ComputeDistanceShortcut(
         = ZopfliNodeCopyDistance(
               &nodes[(pos as (usize)) ]
           ) as (usize);
+    // the gap where the copy starts, which is where the encoder classified it
+    let gap
+        : usize
+        = BrotliDictionaryGap(params, block_start.wrapping_add(pos).wrapping_sub(clen), max_backward);
     if pos == 0usize {
         0u32
     } else if dist.wrapping_add(clen) <= block_start.wrapping_add(
@@ -648,7 +761,7 @@ fn ComputeDistanceCache(
 fn StartPosQueueSize(
     xself : & StartPosQueue
 ) -> usize {
-    brotli_min_size_t((*xself).idx_,8usize)
+    brotli_min_size_t((*xself).idx_,xself.mask_.wrapping_add(1))
 }
 
 
@@ -660,33 +773,34 @@ fn StartPosQueuePush(
                let _old = (*xself).idx_;
                (*xself).idx_ = (*xself).idx_.wrapping_add(1 as (usize));
                _old
-           } & 7usize;
+           } & xself.mask_;
     let len
         : usize
         = StartPosQueueSize(xself );
     let mut i : usize;
-    let q : &mut [PosData;8] = &mut (*xself).q_;
+    let mask = xself.mask_;
+    let q : &mut [PosData;MAX_ZOPFLI_CANDIDATES] = &mut (*xself).q_;
     q[(offset as (usize)) ]= *posdata;
     i = 1usize;
     while i < len {
         {
             if (q[(
-                     (offset & 7usize) as (usize)
+                     (offset & mask) as (usize)
                  )]).costdiff > (q[(
                                      (offset.wrapping_add(
                                           1usize
-                                      ) & 7usize) as (usize)
+                                      ) & mask) as (usize)
                                  )]).costdiff {
                 let mut __brotli_swap_tmp
                     : PosData
-                    = q[((offset & 7usize) as (usize))];
-                q[((offset & 7usize) as (usize)) ]= q[(
+                    = q[((offset & mask) as (usize))];
+                q[((offset & mask) as (usize)) ]= q[(
                                                                         (offset.wrapping_add(
                                                                              1usize
-                                                                         ) & 7usize) as (usize)
+                                                                         ) & mask) as (usize)
                                                                     )];
                 q[(
-                     (offset.wrapping_add(1usize) & 7usize) as (usize)
+                     (offset.wrapping_add(1usize) & mask) as (usize)
                  ) ]= __brotli_swap_tmp;
             }
             offset = offset.wrapping_add(1 as (usize));
@@ -699,7 +813,7 @@ fn EvaluateNode<AllocF:Allocator<floatX>>(
     block_start : usize,
     pos : usize,
     max_backward_limit : usize,
-    gap : usize,
+    params : & BrotliEncoderParams,
     starting_dist_cache : & [i32],
     model : &ZopfliCostModel<AllocF>,
     queue : &mut StartPosQueue,
@@ -711,7 +825,7 @@ fn EvaluateNode<AllocF:Allocator<floatX>>(
                           block_start,
                           pos,
                           max_backward_limit,
-                          gap,
+                          params,
                           nodes 
                       ));
     if node_cost <= ZopfliCostModelGetLiteralCosts(
@@ -747,7 +861,7 @@ fn StartPosQueueAt(
     xself : & StartPosQueue, k : usize
 ) -> &PosData {
     &(*xself).q_[(
-              (k.wrapping_sub((*xself).idx_) & 7usize) as (usize)
+              (k.wrapping_sub((*xself).idx_) & xself.mask_) as (usize)
           )]
 }
 
@@ -866,12 +980,12 @@ fn UpdateNodes<AllocF:Allocator<floatX>>(
     let min_len : usize;
     let mut result : usize = 0usize;
     let mut k : usize;
-    let gap : usize = 0usize;
+    let gap : usize = BrotliDictionaryGap(params, cur_ix, max_backward_limit);
     EvaluateNode(
         block_start,
         pos,
         max_backward_limit,
-        gap,
+        params,
         starting_dist_cache,
         model,
         queue,
@@ -1198,7 +1312,6 @@ pub fn BrotliZopfliComputeShortestPath<AllocU32:Allocator<u32>,
               position
           };
     let mut i : usize;
-    let gap : usize = 0usize;
     let lz_matches_offset : usize = 0usize;
     (nodes[(0usize)]).length = 0u32;
     (nodes[(0usize)]).u = Union1::cost(0.0);
@@ -1216,7 +1329,7 @@ pub fn BrotliZopfliComputeShortestPath<AllocU32:Allocator<u32>,
         ringbuffer,
         ringbuffer_mask
     );
-    queue = InitStartPosQueue();
+    queue = InitStartPosQueue(params);
     i = 0usize;
     while i.wrapping_add(handle.HashTypeLength()).wrapping_sub(
               1usize
@@ -1226,6 +1339,7 @@ pub fn BrotliZopfliComputeShortestPath<AllocU32:Allocator<u32>,
             let max_distance
                 : usize
                 = brotli_min_size_t(pos,max_backward_limit);
+            let gap : usize = BrotliDictionaryGap(params, pos, max_backward_limit);
             let mut skip : usize;
             let mut num_matches
                 : usize
@@ -1308,7 +1422,7 @@ pub fn BrotliZopfliComputeShortestPath<AllocU32:Allocator<u32>,
                         position,
                         i,
                         max_backward_limit,
-                        gap,
+                        params,
                         dist_cache,
                         &mut model  ,
                         &mut queue ,
@@ -1401,11 +1515,41 @@ pub fn BrotliCreateZopfliBackwardReferences<AllocU32:Allocator<u32>,
     }
 }
 
+// SetCost with integer arithmetic only, in BROTLI_FIXED_POINT_BITS fixed point.
+fn SetFixedCost(
+    histogram : & [u32],
+    histogram_size : usize,
+    literal_histogram : i32,
+    cost : &mut [u64]) {
+    let sum : u64 = histogram[..histogram_size].iter().map(|count| u64::from(*count)).sum();
+    let log2sum = FixedLog2(sum);
+    let mut missing_symbol_sum = sum;
+    if literal_histogram == 0 {
+        missing_symbol_sum += histogram[..histogram_size].iter().filter(|count| **count == 0).count() as u64;
+    }
+    let missing_symbol_cost = FixedLog2(missing_symbol_sum) + (2 << BROTLI_FIXED_POINT_BITS);
+    for (item, count) in cost[..histogram_size].iter_mut().zip(histogram[..histogram_size].iter()) {
+        *item = if *count == 0 {
+            missing_symbol_cost
+        } else {
+            core::cmp::max(log2sum - FixedLog2(u64::from(*count)), 1 << BROTLI_FIXED_POINT_BITS)
+        };
+    }
+}
+
 fn SetCost(
     histogram : & [u32],
     histogram_size : usize,
     literal_histogram : i32,
     cost : &mut [floatX]) {
+    if cfg!(feature="fixed-point") {
+        let mut fixed_cost = [0u64; BROTLI_NUM_COMMAND_SYMBOLS];
+        SetFixedCost(histogram, histogram_size, literal_histogram, &mut fixed_cost[..]);
+        for (item, fixed) in cost[..histogram_size].iter_mut().zip(fixed_cost.iter()) {
+            *item = FixedToFloat(*fixed);
+        }
+        return;
+    }
     let mut sum : u64 = 0;
     let mut missing_symbol_sum : u64;
     let log2sum : floatX;
@@ -1475,7 +1619,7 @@ fn ZopfliCostModelSetFromCommands<AllocF:Allocator<floatX>>(
 ) {
     let mut histogram_literal = [0u32; BROTLI_NUM_LITERAL_SYMBOLS];
     let mut histogram_cmd = [0u32; BROTLI_NUM_COMMAND_SYMBOLS];
-    let mut histogram_dist = [0u32; BROTLI_SIMPLE_DISTANCE_ALPHABET_SIZE];
+    let mut histogram_dist = [0u32; BROTLI_MAX_EFFECTIVE_DISTANCE_ALPHABET_SIZE];
     let mut cost_literal = [0.0 as floatX; BROTLI_NUM_LITERAL_SYMBOLS];
     let mut pos : usize = position.wrapping_sub(last_insert_len);
     let mut min_cost_cmd : floatX = kInfinity;
@@ -1562,8 +1706,17 @@ fn ZopfliCostModelSetFromCommands<AllocF:Allocator<floatX>>(
         let mut literal_carry : floatX = 0.0;
         let num_bytes : usize = (*xself).num_bytes_;
         literal_costs[(0usize) ]= 0.0 as (floatX);
+        if cfg!(feature="fixed-point") {
+            let mut fixed_literal = [0u64; BROTLI_NUM_LITERAL_SYMBOLS];
+            SetFixedCost(&histogram_literal[..], BROTLI_NUM_LITERAL_SYMBOLS, 1i32, &mut fixed_literal[..]);
+            let mut fixed_sum : u64 = 0;
+            for i in 0..num_bytes {
+                fixed_sum += fixed_literal[ringbuffer[(position.wrapping_add(i) & ringbuffer_mask)] as usize];
+                literal_costs[i + 1] = FixedToFloat(fixed_sum);
+            }
+        }
         i = 0usize;
-        while i < num_bytes {
+        while i < num_bytes && !cfg!(feature="fixed-point") {
             {
                 literal_carry = literal_carry + cost_literal[(
                                                      ringbuffer[(
@@ -1584,6 +1737,47 @@ fn ZopfliCostModelSetFromCommands<AllocF:Allocator<floatX>>(
     }
 }
 
+// Prices the parse of one Zopfli pass so that passes searched with different models can be
+// compared: literals with the estimate that the first pass searched with, commands and distances
+// with the entropy of their own histograms, plus the extra bits.
+fn ZopfliCommandsCost(
+    literal_costs : & [floatX],
+    commands : & [Command],
+    first_insert_len : usize,
+    num_bytes : usize,
+    distance_histogram_size : usize
+) -> floatX {
+    let mut histogram_cmd = [0u32; BROTLI_NUM_COMMAND_SYMBOLS];
+    let mut histogram_dist = [0u32; BROTLI_MAX_EFFECTIVE_DISTANCE_ALPHABET_SIZE];
+    let mut cost = [0.0 as floatX; BROTLI_NUM_COMMAND_SYMBOLS];
+    let mut total : floatX = 0.0;
+    let mut extra_bits : u32 = 0;
+    // offset in the metablock, the first insert starts before it
+    let mut pos : usize = 0;
+    for (index, cmd) in commands.iter().enumerate() {
+        let inslength = cmd.insert_len_ as usize - if index == 0 { first_insert_len } else { 0 };
+        histogram_cmd[cmd.cmd_prefix_ as usize] += 1;
+        if cmd.cmd_prefix_ >= 128 {
+            histogram_dist[(cmd.dist_prefix_ & 0x3ff) as usize] += 1;
+            extra_bits += u32::from(cmd.dist_prefix_ >> 10);
+        }
+        extra_bits += GetInsertExtra(GetInsertLengthCode(cmd.insert_len_ as usize)) +
+            GetCopyExtra(GetCopyLengthCode(CommandCopyLenCode(cmd) as usize));
+        total += literal_costs[pos + inslength] - literal_costs[pos];
+        pos += inslength + CommandCopyLen(cmd) as usize;
+    }
+    total += literal_costs[num_bytes] - literal_costs[pos];
+    total += extra_bits as floatX;
+    for &(histogram, size) in [(&histogram_cmd[..], BROTLI_NUM_COMMAND_SYMBOLS),
+                               (&histogram_dist[..], distance_histogram_size)].iter() {
+        SetCost(histogram, size, 0, &mut cost[..]);
+        for (count, bits) in histogram[..size].iter().zip(cost.iter()) {
+            total += *count as floatX * *bits;
+        }
+    }
+    total
+}
+
 fn ZopfliIterate<AllocF:Allocator<floatX>>(
     num_bytes : usize,
     position : usize,
@@ -1591,7 +1785,6 @@ fn ZopfliIterate<AllocF:Allocator<floatX>>(
     ringbuffer_mask : usize,
     params : & BrotliEncoderParams,
     max_backward_limit : usize,
-    gap : usize,
     dist_cache : & [i32],
     model : &ZopfliCostModel<AllocF>,
     num_matches : & [u32],
@@ -1604,7 +1797,7 @@ fn ZopfliIterate<AllocF:Allocator<floatX>>(
     let mut i : usize;
     (nodes[(0usize)]).length = 0u32;
     (nodes[(0usize)]).u = Union1::cost(0.0);
-    queue = InitStartPosQueue();
+    queue = InitStartPosQueue(params);
     i = 0usize;
     while i.wrapping_add(3usize) < num_bytes {
         {
@@ -1662,7 +1855,7 @@ fn ZopfliIterate<AllocF:Allocator<floatX>>(
                         position,
                         i,
                         max_backward_limit,
-                        gap,
+                        params,
                         dist_cache,
                         model,
                         &mut queue ,
@@ -1745,8 +1938,44 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
           } else {
               AllocU64::AllocatedMemory::default()
           };
-    let gap : usize = 0usize;
     let shadow_matches : usize = 0usize;
+    // A ring buffer holds nothing past the block, so its index only lasts for the block. When the
+    // whole input stands in for the ring buffer, the index reaches up to a window past the block
+    // and later blocks reuse it until they run past its end.
+    let whole_input = ringbuffer.len() <= ringbuffer_mask.wrapping_add(1);
+    let mut suffix_array : Option<SuffixArrayMatchFinder<AllocU32>> = None;
+    if (*params).exhaustive_match_search {
+        let window_start = position.wrapping_sub(brotli_min_size_t(position, max_backward_limit));
+        suffix_array = hasher.suffix_array_.take();
+        let covered = match suffix_array {
+            Some(ref sa) => sa.covers(window_start, position.wrapping_add(num_bytes)),
+            None => false,
+        };
+        if !covered {
+            if let Some(mut sa) = suffix_array.take() {
+                sa.free(m32);
+            }
+            let index_end = if whole_input {
+                brotli_min_size_t(ringbuffer.len(),
+                                  position.wrapping_add(num_bytes).wrapping_add(max_backward_limit))
+            } else {
+                position.wrapping_add(num_bytes)
+            };
+            suffix_array = Some(InitializeSuffixArrayMatchFinder(
+                m32,
+                ringbuffer,
+                ringbuffer_mask,
+                window_start,
+                index_end
+            ));
+        }
+    }
+    let max_matches_per_position = if suffix_array.is_some() {
+        // room for the dictionary matches as well
+        SUFFIX_ARRAY_MAX_MATCHES + 128
+    } else {
+        128
+    };
     i = 0usize;
     while i.wrapping_add(hasher.HashTypeLength()).wrapping_sub(
               1usize
@@ -1756,20 +1985,21 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
             let max_distance
                 : usize
                 = brotli_min_size_t(pos,max_backward_limit);
+            let gap : usize = BrotliDictionaryGap(params, pos, max_backward_limit);
             let max_length : usize = num_bytes.wrapping_sub(i);
             let num_found_matches : usize;
             let cur_match_end : usize;
             let mut j : usize;
             {
                 if matches_size < cur_match_pos.wrapping_add(
-                                      128usize
+                                      max_matches_per_position
                                   ).wrapping_add(
                                       shadow_matches
                                   ) {
                     let mut _new_size
                         : usize
                         = if matches_size == 0usize {
-                              cur_match_pos.wrapping_add(128usize).wrapping_add(
+                              cur_match_pos.wrapping_add(max_matches_per_position).wrapping_add(
                                   shadow_matches
                               )
                           } else {
@@ -1777,7 +2007,7 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
                           };
                     let mut new_array : AllocU64::AllocatedMemory;
                     while _new_size < cur_match_pos.wrapping_add(
-                                          128usize
+                                          max_matches_per_position
                                       ).wrapping_add(
                                           shadow_matches
                                       ) {
@@ -1804,7 +2034,24 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
             if !(0i32 == 0) {
                 return;
             }
-            num_found_matches = FindAllMatchesH10(
+            num_found_matches = if let Some(ref sa) = suffix_array {
+                FindAllMatchesSA(
+                    sa,
+                    &mut hasher.common,
+                    dictionary,
+                    ringbuffer,
+                    ringbuffer_mask,
+                    pos,
+                    max_length,
+                    max_distance,
+                    gap,
+                    params,
+                    &mut matches.slice_mut()[(
+                              cur_match_pos.wrapping_add(shadow_matches) as (usize)
+                          )..]
+                )
+            } else {
+                FindAllMatchesH10(
                                     hasher,
                                     dictionary,//&(*params).dictionary ,
                                     ringbuffer,
@@ -1817,7 +2064,8 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
                                     &mut matches.slice_mut()[(
                                               cur_match_pos.wrapping_add(shadow_matches) as (usize)
                                           )..]
-                                );
+                                )
+            };
             cur_match_end = cur_match_pos.wrapping_add(num_found_matches);
             j = cur_match_pos;
             while j.wrapping_add(1usize) < cur_match_end {
@@ -1846,12 +2094,14 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
                          } as (usize)
                      ) ]= tmp;
                     num_matches.slice_mut()[(i as (usize)) ]= 1u32;
-                    hasher.StoreRange(
-                        ringbuffer,
-                        ringbuffer_mask,
-                        pos.wrapping_add(1usize),
-                        brotli_min_size_t(pos.wrapping_add(match_len),store_end)
-                    );
+                    if suffix_array.is_none() {
+                        hasher.StoreRange(
+                            ringbuffer,
+                            ringbuffer_mask,
+                            pos.wrapping_add(1usize),
+                            brotli_min_size_t(pos.wrapping_add(match_len),store_end)
+                        );
+                    }
                     for item in num_matches.slice_mut().split_at_mut(i.wrapping_add(1)).1.split_at_mut(skip).0.iter_mut() {
                         *item = 0;
                     }
@@ -1863,6 +2113,13 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
         }
         i = i.wrapping_add(1 as (usize));
     }
+    if let Some(mut sa) = suffix_array.take() {
+        if whole_input {
+            hasher.suffix_array_ = Some(sa);
+        } else {
+            sa.free(m32);
+        }
+    }
     orig_num_literals = *num_literals;
     orig_last_insert_len = *last_insert_len;
     for (i, j) in orig_dist_cache.split_at_mut(4).0.iter_mut().zip(dist_cache.split_at(4).0) {
@@ -1887,8 +2144,15 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
     if !(0i32 == 0) {
         return;
     }
+    // each pass is searched with a model built from the commands of the one before, which does not
+    // always make it cheaper, so the nodes of the cheapest pass so far are kept
+    let mut best_nodes = AllocZN::AllocatedMemory::default();
+    let mut best_cost : floatX = 0.0;
+    let mut first_literal_costs = AllocF::AllocatedMemory::default();
+    let mut best_pass : usize = 0;
+    let mut best_pass_commands : usize = 0;
     i = 0usize;
-    while i < 2usize {
+    while i < ZopfliIterations(params) {
         {
             BrotliInitZopfliNodes(
                 nodes.slice_mut(),
@@ -1901,6 +2165,10 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
                     ringbuffer,
                     ringbuffer_mask
                 );
+                if ZopfliIterations(params) > 1 {
+                    first_literal_costs = mf.alloc_cell(num_bytes.wrapping_add(1));
+                    first_literal_costs.slice_mut().clone_from_slice(&model.literal_costs_.slice()[..num_bytes + 1]);
+                }
             } else {
                 ZopfliCostModelSetFromCommands(
                     &mut model ,
@@ -1918,22 +2186,20 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
             for (i, j) in dist_cache.split_at_mut(4).0.iter_mut().zip(orig_dist_cache.split_at(4).0) {
                 *i = *j;
             }
-            *num_commands = (*num_commands).wrapping_add(
-                                ZopfliIterate(
+            let pass_commands = ZopfliIterate(
                                     num_bytes,
                                     position,
                                     ringbuffer,
                                     ringbuffer_mask,
                                     params,
                                     max_backward_limit,
-                                    gap,
                                     dist_cache ,
                                     &mut model  ,
                                     num_matches.slice() ,
                                     matches.slice(),
                                     nodes.slice_mut()
-                                )
-                            );
+                                );
+            *num_commands = (*num_commands).wrapping_add(pass_commands);
             BrotliZopfliCreateCommands(
                 num_bytes,
                 position,
@@ -1945,9 +2211,51 @@ pub fn BrotliCreateHqZopfliBackwardReferences<AllocU32:Allocator<u32>,
                 commands,
                 num_literals
             );
+            if ZopfliIterations(params) > 1 {
+                let cost = ZopfliCommandsCost(
+                    first_literal_costs.slice(),
+                    &commands[..pass_commands],
+                    orig_last_insert_len,
+                    num_bytes,
+                    model.distance_histogram_size as usize
+                );
+                // ties go to the later pass
+                if i == 0 || cost <= best_cost {
+                    best_cost = cost;
+                    best_pass = i;
+                    best_pass_commands = pass_commands;
+                    if i + 1 < ZopfliIterations(params) {
+                        if best_nodes.slice().is_empty() {
+                            best_nodes = mz.alloc_cell(num_bytes.wrapping_add(1));
+                        }
+                        best_nodes.slice_mut().clone_from_slice(nodes.slice());
+                    }
+                }
+            }
         }
         i = i.wrapping_add(1 as (usize));
     }
+    if best_pass + 1 < ZopfliIterations(params) {
+        *num_commands = orig_num_commands.wrapping_add(best_pass_commands);
+        *num_literals = orig_num_literals;
+        *last_insert_len = orig_last_insert_len;
+        for (i, j) in dist_cache.split_at_mut(4).0.iter_mut().zip(orig_dist_cache.split_at(4).0) {
+            *i = *j;
+        }
+        BrotliZopfliCreateCommands(
+            num_bytes,
+            position,
+            max_backward_limit,
+            best_nodes.slice(),
+            dist_cache,
+            last_insert_len,
+            params,
+            commands,
+            num_literals
+        );
+    }
+    mz.free_cell(best_nodes);
+    mf.free_cell(first_literal_costs);
     CleanupZopfliCostModel(mf,&mut model );
     {
         mz.free_cell(nodes);
@@ -3,7 +3,8 @@ use core;
 use super::histogram::CostAccessors;
 use super::super::alloc::SliceWrapper;
 
-use super::util::{brotli_max_uint32_t, FastLog2, floatX, FastLog2u16};
+use super::util::{brotli_max_uint32_t, FastLog2, floatX, FastLog2u16, FixedLog2, FixedToFloat,
+                  BROTLI_FIXED_POINT_BITS};
 
 use super::vectorization::{v256,v128,v256i,v128i, Mem256i, sum8};
 
@@ -19,7 +20,29 @@ static kBrotliMaxWindowBits: i32 = 24i32;
 
 
 
+// ShannonEntropy with integer arithmetic only, in BROTLI_FIXED_POINT_BITS fixed point.
+pub fn FixedShannonEntropy(population: &[u32], size: usize, total: &mut usize) -> u64 {
+  let mut sum: u64 = 0;
+  let mut symbol_bits: u64 = 0;
+  for p in population.split_at(core::cmp::min(size, population.len())).0 {
+    sum += u64::from(*p);
+    symbol_bits += u64::from(*p) * FixedLog2(u64::from(*p));
+  }
+  *total = sum as usize;
+  // FixedLog2 never decreases, so each count costs at least nothing
+  sum * FixedLog2(sum) - symbol_bits
+}
+
+pub fn FixedBitsEntropy(population: &[u32], size: usize) -> u64 {
+  let mut sum: usize = 0;
+  let retval = FixedShannonEntropy(population, size, &mut sum);
+  core::cmp::max(retval, (sum as u64) << BROTLI_FIXED_POINT_BITS)
+}
+
 pub fn ShannonEntropy(mut population: &[u32], size: usize, total: &mut usize) -> super::util::floatX {
+  if cfg!(feature="fixed-point") {
+    return FixedToFloat(FixedShannonEntropy(population, size, total));
+  }
   let mut sum: usize = 0usize;
   let mut retval: super::util::floatX = 0i32 as super::util::floatX;
   let mut p: usize;
@@ -43,6 +66,9 @@ pub fn ShannonEntropy(mut population: &[u32], size: usize, total: &mut usize) ->
 
 #[inline(always)]
 pub fn BitsEntropy(population: &[u32], size: usize) -> super::util::floatX {
+  if cfg!(feature="fixed-point") {
+    return FixedToFloat(FixedBitsEntropy(population, size));
+  }
   let mut sum: usize = 0;
   let mut retval: super::util::floatX = ShannonEntropy(population, size, &mut sum);
   if retval < sum as super::util::floatX {
@@ -79,7 +105,29 @@ fn CostComputation<T:SliceWrapper<Mem256i> >(depth_histo: &mut [u32;BROTLI_CODE_
     let mut bits : super::util::floatX = 0.0 as super::util::floatX;
     if true {
       let mut max_depth : usize = 1;
-      for i in 0..nnz {
+      let mut start : usize = 0;
+      // whole vectors may have their per-symbol terms computed by the simd kernels
+      for nnz_data_vec in nnz_data.slice().split_at(nnz >> 3).0.iter() {
+         let mut log2_counts = [0.0 as super::util::floatX; 8];
+         for (log2_count, element) in log2_counts.iter_mut().zip(nnz_data_vec.0.iter()) {
+            *log2_count = FastLog2u16(*element as u16);
+         }
+         let mut log2p = [0.0 as super::util::floatX; 8];
+         let mut terms = [0.0 as super::util::floatX; 8];
+         if !super::simd::EntropyTerms(nnz_data_vec, &log2_counts, log2total, &mut log2p, &mut terms) {
+            break;
+         }
+         for k in 0..8 {
+            let depth = core::cmp::min((log2p[k] + 0.5) as u8, 15u8);
+            bits += terms[k];
+            if (depth as usize > max_depth) {
+               max_depth = depth as usize;
+            }
+            depth_histo[depth as usize] += 1;
+         }
+         start += 8;
+      }
+      for i in start..nnz {
           // Compute -log2(P(symbol)) = -log2(count(symbol)/total_count) =
           //                            = log2(total_count) - log2(count(symbol))
          let element = nnz_data.slice()[i>>3].0[i&7];
@@ -231,6 +279,71 @@ fn CostComputation<T:SliceWrapper<Mem256i> >(depth_histo: &mut [u32;BROTLI_CODE_
 }
 use alloc::SliceWrapperMut;
 
+// BrotliPopulationCost with integer arithmetic only, in BROTLI_FIXED_POINT_BITS fixed point.
+pub fn FixedPopulationCost(histogram: &[u32], total_count: usize) -> u64 {
+  const kOneSymbolHistogramCost: u64 = 12;
+  const kTwoSymbolHistogramCost: u64 = 20;
+  const kThreeSymbolHistogramCost: u64 = 28;
+  const kFourSymbolHistogramCost: u64 = 37;
+  let mut histo = [0u64; 4];
+  let mut count: usize = 0;
+  for item in histogram.iter() {
+    if *item != 0 {
+      if count < 4 {
+        histo[count] = u64::from(*item);
+      }
+      count += 1;
+    }
+  }
+  let total = total_count as u64;
+  match count {
+    0 | 1 => return kOneSymbolHistogramCost << BROTLI_FIXED_POINT_BITS,
+    2 => return (kTwoSymbolHistogramCost + total) << BROTLI_FIXED_POINT_BITS,
+    3 => {
+      let histomax = core::cmp::max(histo[0], core::cmp::max(histo[1], histo[2]));
+      return (kThreeSymbolHistogramCost + 2 * (histo[0] + histo[1] + histo[2]) - histomax) << BROTLI_FIXED_POINT_BITS;
+    }
+    4 => {
+      histo.sort_unstable_by(|a, b| b.cmp(a));
+      let h23 = histo[2] + histo[3];
+      let histomax = core::cmp::max(h23, histo[0]);
+      return (kFourSymbolHistogramCost + 3 * h23 + 2 * (histo[0] + histo[1]) - histomax) << BROTLI_FIXED_POINT_BITS;
+    }
+    _ => {}
+  }
+  let mut bits: u64 = 0;
+  let mut max_depth: u64 = 1;
+  let mut depth_histo = [0u32; BROTLI_CODE_LENGTH_CODES];
+  let log2total = FixedLog2(total);
+  let mut reps: u32 = 0;
+  for item in histogram.iter() {
+    if *item != 0 {
+      if reps != 0 {
+        if reps < 3 {
+          depth_histo[0] += reps;
+        } else {
+          reps -= 2;
+          while reps > 0 {
+            depth_histo[BROTLI_REPEAT_ZERO_CODE_LENGTH] += 1;
+            bits += 3 << BROTLI_FIXED_POINT_BITS;
+            reps >>= 3;
+          }
+        }
+        reps = 0;
+      }
+      let log2p = log2total.saturating_sub(FixedLog2(u64::from(*item)));
+      let depth = core::cmp::min((log2p + (1 << (BROTLI_FIXED_POINT_BITS - 1))) >> BROTLI_FIXED_POINT_BITS, 15);
+      bits += u64::from(*item) * log2p;
+      max_depth = core::cmp::max(depth, max_depth);
+      depth_histo[depth as usize] += 1;
+    } else {
+      reps += 1;
+    }
+  }
+  bits += (18 + 2 * max_depth) << BROTLI_FIXED_POINT_BITS;
+  bits + FixedBitsEntropy(&depth_histo[..], BROTLI_CODE_LENGTH_CODES)
+}
+
 pub fn BrotliPopulationCost<HistogramType:SliceWrapper<u32>+CostAccessors>(
     histogram : &HistogramType,
     nnz_data : &mut HistogramType::i32vec
@@ -243,6 +356,9 @@ pub fn BrotliPopulationCost<HistogramType:SliceWrapper<u32>+CostAccessors>(
   let mut count: i32 = 0i32;
   let mut s: [usize; 5] = [0; 5];
 
+  if cfg!(feature="fixed-point") {
+    return FixedToFloat(FixedPopulationCost((*histogram).slice(), (*histogram).total_count()));
+  }
   let mut bits: super::util::floatX = 0.0 as super::util::floatX;
   let mut i: usize;
   if (*histogram).total_count() == 0usize {
@@ -74,6 +74,13 @@ fn update_cost_and_signal(num_histograms32: u32,
         }
         return;
     }
+    let num_vectors = (num_histograms32 as usize + 7) >> 3;
+    if super::simd::UpdateCostAndSignal(&mut cost[..num_vectors],
+                                        min_cost,
+                                        block_switch_cost,
+                                        &mut switch_signal[ix..(ix + num_vectors)]) {
+        return;
+    }
     let ymm_min_cost = bcast256!(min_cost);
     let ymm_block_switch_cost = bcast256!(block_switch_cost);
     let ymm_and_mask = v256i::setr(1<<0,
@@ -334,16 +341,28 @@ fn FindBlocks<HistogramType: SliceWrapper<u32> + SliceWrapperMut<u32> + CostAcce
       } else {
         // main (vectorized) loop
         let insert_cost_slice = insert_cost.split_at(insert_cost_ix).1;
-        for (v_index, cost_iter) in cost.split_at_mut(num_histograms >> 3).0.iter_mut().enumerate() {
-          let base_index = v_index << 3;
-          let mut local_insert_cost = [0.0 as super::util::floatX; 8];
-          local_insert_cost.clone_from_slice(insert_cost_slice.split_at(base_index).1.split_at(8).0);
-          for sub_index in 0usize .. 8usize {
-            (*cost_iter).0[sub_index] += local_insert_cost[sub_index];
-            let final_cost = (*cost_iter).0[sub_index];
-            if final_cost < min_cost {
-              min_cost = final_cost;
-              *block_id_ptr = (base_index + sub_index) as u8;
+        if super::simd::AddInsertCosts(cost.split_at_mut(num_histograms >> 3).0,
+                                       insert_cost_slice.split_at((num_histograms >> 3) << 3).0) {
+          for (v_index, cost_iter) in cost.split_at(num_histograms >> 3).0.iter().enumerate() {
+            for (sub_index, final_cost) in cost_iter.0.iter().enumerate() {
+              if *final_cost < min_cost {
+                min_cost = *final_cost;
+                *block_id_ptr = ((v_index << 3) + sub_index) as u8;
+              }
+            }
+          }
+        } else {
+          for (v_index, cost_iter) in cost.split_at_mut(num_histograms >> 3).0.iter_mut().enumerate() {
+            let base_index = v_index << 3;
+            let mut local_insert_cost = [0.0 as super::util::floatX; 8];
+            local_insert_cost.clone_from_slice(insert_cost_slice.split_at(base_index).1.split_at(8).0);
+            for sub_index in 0usize .. 8usize {
+              (*cost_iter).0[sub_index] += local_insert_cost[sub_index];
+              let final_cost = (*cost_iter).0[sub_index];
+              if final_cost < min_cost {
+                min_cost = final_cost;
+                *block_id_ptr = (base_index + sub_index) as u8;
+              }
             }
           }
         }
@@ -24,6 +24,7 @@ use super::constants::{BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS, BROTLI_NUM_LITERAL
                        kStaticDistanceCodeBits, kSigned3BitContextLookup, kUTF8ContextLookup,
                        kInsBase, kInsExtra, kCopyBase, kCopyExtra};
 use super::entropy_encode::{HuffmanTree, BrotliWriteHuffmanTree, BrotliCreateHuffmanTree,
+                            BrotliCreateOptimalHuffmanTree,
                             BrotliConvertBitDepthsToSymbols, NewHuffmanTree, InitHuffmanTree,
                             SortHuffmanTreeItems, HuffmanComparator, BrotliSetDepth};
 use super::histogram::{HistogramAddItem, HistogramLiteral, HistogramCommand, HistogramDistance,
@@ -307,7 +308,7 @@ fn process_command_queue<'a, CmdProcessor: interface::CommandProcessor<'a> > (
         let copy_len = copylen_code as usize;
         let actual_copy_len : usize;
         let max_distance = core::cmp::min(recoder_state.num_bytes_encoded, window_size_from_lgwin(params.lgwin));
-        assert!(inserts.len() <= mb_len);
+        debug_assert!(inserts.len() <= mb_len);
         if inserts.len() != 0 {
             let mut tmp_inserts = inserts;
             while tmp_inserts.len() > btypel_sub as usize {
@@ -345,8 +346,8 @@ fn process_command_queue<'a, CmdProcessor: interface::CommandProcessor<'a> > (
             }
         }
         if final_distance > max_distance { // is dictionary
-            assert!(copy_len >= 4);
-            assert!(copy_len < 25);
+            debug_assert!(copy_len >= 4);
+            debug_assert!(copy_len < 25);
             let dictionary_offset = final_distance - max_distance - 1;
             let ndbits = kBrotliDictionarySizeBitsByLength[copy_len] as usize;
             let action = dictionary_offset >> ndbits;
@@ -368,7 +369,7 @@ fn process_command_queue<'a, CmdProcessor: interface::CommandProcessor<'a> > (
                         word_id: word_sub_index as u32,
                     }));
                 mb_len -= actual_copy_len;
-                assert_eq!(InputPair(InputReference{data:transformed_word.split_at(actual_copy_len).0, orig_offset:0},
+                debug_assert_eq!(InputPair(InputReference{data:transformed_word.split_at(actual_copy_len).0, orig_offset:0},
                                      InputReference::default()),
                            interim.split_at(actual_copy_len).0);
             } else if mb_len != 0 {
@@ -376,7 +377,7 @@ fn process_command_queue<'a, CmdProcessor: interface::CommandProcessor<'a> > (
                 // won't be random noise since it fits in the dictionary, so we won't check for rand
                 command_queue.push_literals(&interim.split_at(mb_len).0);
                 mb_len = 0;
-                assert_eq!(InputPair(InputReference{data:transformed_word.split_at(mb_len).0, orig_offset:0},
+                debug_assert_eq!(InputPair(InputReference{data:transformed_word.split_at(mb_len).0, orig_offset:0},
                                      InputReference::default()),
                            interim.split_at(mb_len).0);
             }
@@ -432,6 +433,7 @@ fn process_command_queue<'a, CmdProcessor: interface::CommandProcessor<'a> > (
     recoder_state
 }
 
+// Returns false when the commands overflowed the queue, so the callback saw a truncated metablock.
 fn LogMetaBlock<'a,
                 AllocU8:alloc::Allocator<u8>,
                 AllocU16:alloc::Allocator<u16>,
@@ -453,16 +455,17 @@ fn LogMetaBlock<'a,
                     block_type: MetaBlockSplitRefs,
                     params: &BrotliEncoderParams,
                     context_type:Option<ContextType>,
-                    callback: &mut Cb) where Cb:FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
-                                                      &mut [interface::StaticCommand],
-                                                      InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand){
+                    literal_context_modes: &[ContextType],
+                    callback: &mut Cb) -> bool where Cb:FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                              &mut [interface::StaticCommand],
+                                                              InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand) {
     let mut local_literal_context_map = [0u8; 256 * 64];
     let mut local_distance_context_map = [0u8; 256 * 64 + interface::DISTANCE_CONTEXT_MAP_OFFSET];
-    assert_eq!(*block_type.btypel.types.iter().max().unwrap_or(&0) as u32 + 1,
+    debug_assert_eq!(*block_type.btypel.types.iter().max().unwrap_or(&0) as u32 + 1,
                block_type.btypel.num_types);
-    assert_eq!(*block_type.btypec.types.iter().max().unwrap_or(&0) as u32 + 1,
+    debug_assert_eq!(*block_type.btypec.types.iter().max().unwrap_or(&0) as u32 + 1,
                block_type.btypec.num_types);
-    assert_eq!(*block_type.btyped.types.iter().max().unwrap_or(&0) as u32 + 1,
+    debug_assert_eq!(*block_type.btyped.types.iter().max().unwrap_or(&0) as u32 + 1,
                block_type.btyped.num_types);
     if block_type.literal_context_map.len() <= 256 * 64 {
         for (index, item) in block_type.literal_context_map.iter().enumerate() {
@@ -492,6 +495,9 @@ fn LogMetaBlock<'a,
     prediction_mode.set_combined_stride_context_speed([params.literal_adaptation[0], params.literal_adaptation[1]]);
 
     prediction_mode.set_literal_prediction_mode(interface::LiteralPredictionModeNibble(context_type.unwrap_or(ContextType::CONTEXT_LSB6) as u8));
+    for (block_type, item) in prediction_mode.literal_prediction_modes_mut().iter_mut().enumerate() {
+        *item = literal_context_modes.get(block_type).cloned().or(context_type).unwrap_or(ContextType::CONTEXT_LSB6) as u8;
+    }
     let mut entropy_tally_scratch;
     let mut entropy_pyramid;
     if params.stride_detection_quality == 1 || params.stride_detection_quality == 2 {
@@ -596,12 +602,12 @@ fn LogMetaBlock<'a,
                                            &block_type,
                                            params,
                                            context_type);
-    command_queue.free(m8, m16, m32, mf, mfv, mpdf, callback).unwrap();
+    command_queue.free(m8, m16, m32, mf, mfv, mpdf, callback).is_ok()
 //   ::std::io::stderr().write(input0).unwrap();
 //   ::std::io::stderr().write(input1).unwrap();
 }
 
-static kBlockLengthPrefixCode: [PrefixCodeRange; BROTLI_NUM_BLOCK_LEN_SYMBOLS] =
+pub static kBlockLengthPrefixCode: [PrefixCodeRange; BROTLI_NUM_BLOCK_LEN_SYMBOLS] =
   [PrefixCodeRange {
      offset: 1u32,
      nbits: 2u32,
@@ -707,10 +713,46 @@ static kBlockLengthPrefixCode: [PrefixCodeRange; BROTLI_NUM_BLOCK_LEN_SYMBOLS] =
      nbits: 24u32,
    }];
 
-fn BrotliWriteBits(n_bits: u8, bits: u64, pos: &mut usize, array: &mut [u8]) {
-  assert!((bits >> n_bits as usize) == 0);
-  assert!(n_bits <= 56);
-  let ptr_offset: usize = ((*pos >> 3) as u32) as usize;
+// The position the bit writer moves to instead of writing bits that break its invariants
+// or run past the end of the storage. Later writes are dropped and the encoder fails the
+// stream when it sees it.
+pub const BROTLI_WRITE_BITS_FAILED: usize = !0usize >> 1;
+
+pub fn BrotliWriteBitsFailed(pos: usize) -> bool {
+  pos >= BROTLI_WRITE_BITS_FAILED
+}
+
+// With the no-panic feature, an unwind out of the bit writer drops this guard, whose destructor
+// calls a symbol that is never defined. A release build only links if the optimizer removed
+// every such unwind; debug builds keep the panic paths, so the guard is off there.
+struct NoPanicGuard;
+
+impl Drop for NoPanicGuard {
+  fn drop(&mut self) {
+    #[cfg(all(feature="no-panic", not(debug_assertions)))]
+    {
+      extern "C" {
+        #[link_name = "\n\nbrotli: the bit writer can panic, see the no-panic feature in README.md\n\n"]
+        fn brotli_bit_writer_can_panic() -> !;
+      }
+      unsafe { brotli_bit_writer_can_panic() }
+    }
+  }
+}
+
+pub fn BrotliWriteBits(n_bits: u8, bits: u64, pos: &mut usize, array: &mut [u8]) {
+  let guard = NoPanicGuard;
+  WriteBitsUnguarded(n_bits, bits, pos, array);
+  core::mem::forget(guard);
+}
+
+#[inline(always)]
+fn WriteBitsUnguarded(n_bits: u8, bits: u64, pos: &mut usize, array: &mut [u8]) {
+  let ptr_offset: usize = *pos >> 3;
+  if n_bits > 56 || (bits >> n_bits) != 0 || ptr_offset.wrapping_add(8) > array.len() {
+    *pos = BROTLI_WRITE_BITS_FAILED;
+    return;
+  }
   let mut v = array[ptr_offset] as u64;
   v |= bits << ((*pos) as u64 & 7);
   array[ptr_offset + 7] = (v >> 56) as u8;
@@ -724,9 +766,14 @@ fn BrotliWriteBits(n_bits: u8, bits: u64, pos: &mut usize, array: &mut [u8]) {
   *pos += n_bits as usize
 }
 
-fn BrotliWriteBitsPrepareStorage(pos: usize, array: &mut [u8]) {
-  assert_eq!(pos & 7, 0);
-  array[pos >> 3] = 0;
+fn BrotliWriteBitsPrepareStorage(pos: &mut usize, array: &mut [u8]) {
+  let guard = NoPanicGuard;
+  if (*pos & 7) != 0 || (*pos >> 3) >= array.len() {
+    *pos = BROTLI_WRITE_BITS_FAILED;
+  } else {
+    array[*pos >> 3] = 0;
+  }
+  core::mem::forget(guard);
 }
 
 fn BrotliStoreHuffmanTreeOfHuffmanTreeToBitMask(num_codes: i32,
@@ -832,6 +879,36 @@ pub fn BrotliStoreHuffmanTree(depths: &[u8],
                               tree: &mut [HuffmanTree],
                               storage_ix: &mut usize,
                               storage: &mut [u8]) {
+  StoreHuffmanTree(depths, num, tree, false, storage_ix, storage)
+}
+
+// Whether the prefix codes of a metablock may use the package-merge depths.
+fn UseOptimalHuffmanCodes(params: &BrotliEncoderParams) -> bool {
+  params.optimal_huffman_codes || params.quality >= 10
+}
+
+// Bits of the code length code plus the code length symbols coded with it, extra bits aside.
+fn CodeLengthCodeBits(num_codes: i32, code_length_bitdepth: &[u8], histogram: &[u32]) -> usize {
+  let mut scratch = [0u8; 24];
+  let mut scratch_ix: usize = 0;
+  BrotliStoreHuffmanTreeOfHuffmanTreeToBitMask(num_codes, code_length_bitdepth, &mut scratch_ix, &mut scratch);
+  scratch_ix + histogram.iter().zip(code_length_bitdepth.iter()).map(|(count, bits)| *count as usize * *bits as usize).sum::<usize>()
+}
+
+// Bits of the description of depth plus the symbols of histogram coded with it.
+fn PrefixCodeBits(histogram: &[u32], depth: &[u8], tree: &mut [HuffmanTree]) -> usize {
+  let mut scratch = [0u8; 1024];
+  let mut scratch_ix: usize = 0;
+  StoreHuffmanTree(depth, depth.len(), tree, true, &mut scratch_ix, &mut scratch);
+  scratch_ix + histogram.iter().zip(depth.iter()).map(|(count, bits)| *count as usize * *bits as usize).sum::<usize>()
+}
+
+fn StoreHuffmanTree(depths: &[u8],
+                    num: usize,
+                    tree: &mut [HuffmanTree],
+                    optimal_codes: bool,
+                    storage_ix: &mut usize,
+                    storage: &mut [u8]) {
   let mut huffman_tree: [u8; 704] = [0; 704];
   let mut huffman_tree_extra_bits: [u8; 704] = [0; 704];
   let mut huffman_tree_size: usize = 0usize;
@@ -899,6 +976,15 @@ pub fn BrotliStoreHuffmanTree(depths: &[u8],
                           5i32,
                           tree,
                           &mut code_length_bitdepth);
+  if optimal_codes {
+    // the optimal depths save code length symbol bits, but may take more to describe
+    let mut optimal_bitdepth: [u8; 18] = [0; 18];
+    BrotliCreateOptimalHuffmanTree(&huffman_tree_histogram, 18, 5, tree, &mut optimal_bitdepth);
+    if CodeLengthCodeBits(num_codes, &optimal_bitdepth, &huffman_tree_histogram) <
+       CodeLengthCodeBits(num_codes, &code_length_bitdepth, &huffman_tree_histogram) {
+      code_length_bitdepth = optimal_bitdepth;
+    }
+  }
   BrotliConvertBitDepthsToSymbols(&mut code_length_bitdepth,
                                   18usize,
                                   &mut code_length_bitdepth_symbols);
@@ -1163,6 +1249,9 @@ pub struct MetaBlockSplit<AllocU8: alloc::Allocator<u8>,
   pub literal_split: BlockSplit<AllocU8, AllocU32>,
   pub command_split: BlockSplit<AllocU8, AllocU32>,
   pub distance_split: BlockSplit<AllocU8, AllocU32>,
+  // one LITERAL_PREDICTION_MODE_* per literal block type; empty when every type
+  // uses the context mode of the metablock
+  pub literal_context_modes: AllocU8::AllocatedMemory,
   pub literal_context_map: AllocU32::AllocatedMemory,
   pub literal_context_map_size: usize,
   pub distance_context_map: AllocU32::AllocatedMemory,
@@ -1185,6 +1274,7 @@ impl <AllocU8: alloc::Allocator<u8>,
             literal_split:BlockSplit::<AllocU8, AllocU32>::new(),
             command_split:BlockSplit::<AllocU8, AllocU32>::new(),
             distance_split:BlockSplit::<AllocU8, AllocU32>::new(),
+            literal_context_modes : AllocU8::AllocatedMemory::default(),
             literal_context_map : AllocU32::AllocatedMemory::default(),
             literal_context_map_size : 0,
             distance_context_map : AllocU32::AllocatedMemory::default(),
@@ -1206,6 +1296,8 @@ impl <AllocU8: alloc::Allocator<u8>,
         self.literal_split.destroy(m8,m32);
         self.command_split.destroy(m8,m32);
         self.distance_split.destroy(m8,m32);
+        m8.free_cell(core::mem::replace(&mut self.literal_context_modes,
+                                        AllocU8::AllocatedMemory::default()));
         m32.free_cell(core::mem::replace(&mut self.literal_context_map,
                                          AllocU32::AllocatedMemory::default()));
         self.literal_context_map_size = 0;
@@ -1223,6 +1315,13 @@ impl <AllocU8: alloc::Allocator<u8>,
                                          AllocHD::AllocatedMemory::default()));
         self.distance_histograms_size = 0;
     }
+    // the context mode of the given literal block type, default_mode if none was chosen per type
+    pub fn literal_context_mode(&self, block_type: usize, default_mode: ContextType) -> ContextType {
+        match self.literal_context_modes.slice().get(block_type) {
+            Some(mode) => interface::LiteralPredictionModeNibble(*mode).to_context_enum().unwrap_or(default_mode),
+            None => default_mode,
+        }
+    }
 }
 #[derive(Clone, Copy)]
 pub struct BlockTypeCodeCalculator {
@@ -1287,9 +1386,9 @@ fn BrotliEncodeMlen(length: u32,
        lg.wrapping_add(3u32)
      })
     .wrapping_div(4u32);
-  assert!(length > 0);
-  assert!(length <= (1 << 24));
-  assert!(lg <= 24);
+  debug_assert!(length > 0);
+  debug_assert!(length <= (1 << 24));
+  debug_assert!(lg <= 24);
   *nibblesbits = mnibbles.wrapping_sub(4u32);
   *numbits = mnibbles.wrapping_mul(4u32);
   *bits = length.wrapping_sub(1u32) as u64;
@@ -1485,6 +1584,7 @@ fn BuildAndStoreHuffmanTree(histogram: &[u32],
                             histogram_length: usize,
                             alphabet_size: usize,
                             tree: &mut [HuffmanTree],
+                            optimal_codes: bool,
                             depth: &mut [u8],
                             bits: &mut [u16],
                             storage_ix: &mut usize,
@@ -1528,12 +1628,57 @@ fn BuildAndStoreHuffmanTree(histogram: &[u32],
     *depth_elem = 0; // memset
   }
   BrotliCreateHuffmanTree(histogram, histogram_length, 15i32, tree, depth);
+  if optimal_codes && count > 4 && histogram_length <= 704 {
+    // keep the package-merge depths only when they win after paying for their description
+    let mut optimal_depth: [u8; 704] = [0; 704];
+    BrotliCreateOptimalHuffmanTree(histogram, histogram_length, 15i32, tree, &mut optimal_depth[..]);
+    if PrefixCodeBits(&histogram[..histogram_length], &optimal_depth[..histogram_length], tree) <
+       PrefixCodeBits(&histogram[..histogram_length], &depth[..histogram_length], tree) {
+      depth[..histogram_length].clone_from_slice(&optimal_depth[..histogram_length]);
+    }
+  }
   BrotliConvertBitDepthsToSymbols(depth, histogram_length, bits);
   if count <= 4usize {
     StoreSimpleHuffmanTree(depth, &mut s4[..], count, max_bits, storage_ix, storage);
   } else {
-    BrotliStoreHuffmanTree(depth, histogram_length, tree, storage_ix, storage);
-  }
+    StoreHuffmanTree(depth, histogram_length, tree, optimal_codes, storage_ix, storage);
+  }
+}
+
+// Sizes of the buffers BrotliPrefixCodeStoredBits works in, which the caller allocates
+// once for all the histograms it prices.
+pub const BROTLI_PREFIX_CODE_TREE_SIZE: usize = 2 * 704 + 1;
+pub const BROTLI_PREFIX_CODE_DEPTH_SIZE: usize = 704;
+pub const BROTLI_PREFIX_CODE_STORAGE_SIZE: usize = 1024;
+
+// Bits BuildAndStoreHuffmanTree spends on the code for histogram plus the symbols of histogram
+// coded with it, for pricing a layout of the symbols without writing anything.
+pub fn BrotliPrefixCodeStoredBits(histogram: &[u32],
+                                  optimal_codes: bool,
+                                  tree: &mut [HuffmanTree],
+                                  depth: &mut [u8],
+                                  bits: &mut [u16],
+                                  storage: &mut [u8])
+                                  -> usize {
+  let histogram_length = core::cmp::min(histogram.len(), BROTLI_PREFIX_CODE_DEPTH_SIZE);
+  for item in depth[..histogram_length].iter_mut() {
+    *item = 0;
+  }
+  for item in storage.iter_mut() {
+    *item = 0;
+  }
+  let mut storage_ix: usize = 0;
+  BuildAndStoreHuffmanTree(&histogram[..histogram_length],
+                           histogram_length,
+                           histogram_length,
+                           tree,
+                           optimal_codes,
+                           depth,
+                           bits,
+                           &mut storage_ix,
+                           storage);
+  storage_ix + histogram[..histogram_length].iter().zip(depth.iter()).map(
+    |(count, bits)| *count as usize * *bits as usize).sum::<usize>()
 }
 
 fn GetBlockLengthPrefixCode(len: u32,
@@ -1574,6 +1719,7 @@ fn BuildAndStoreBlockSplitCode(types: &[u8],
                                num_blocks: usize,
                                num_types: usize,
                                tree: &mut [HuffmanTree],
+                               optimal_codes: bool,
                                code: &mut BlockSplitCode,
                                storage_ix: &mut usize,
                                storage: &mut [u8]) {
@@ -1604,6 +1750,7 @@ fn BuildAndStoreBlockSplitCode(types: &[u8],
                              num_types.wrapping_add(2usize),
                              num_types.wrapping_add(2usize),
                              tree,
+                             optimal_codes,
                              &mut (*code).type_depths[0usize..],
                              &mut (*code).type_bits[0usize..],
                              storage_ix,
@@ -1612,6 +1759,7 @@ fn BuildAndStoreBlockSplitCode(types: &[u8],
                              super::constants::BROTLI_NUM_BLOCK_LEN_SYMBOLS, // 26
                              super::constants::BROTLI_NUM_BLOCK_LEN_SYMBOLS,
                              tree,
+                             optimal_codes,
                              &mut (*code).length_depths[0usize..],
                              &mut (*code).length_bits[0usize..],
                              storage_ix,
@@ -1630,6 +1778,7 @@ fn BuildAndStoreBlockSwitchEntropyCodes<'a,
                                         AllocU16: alloc::Allocator<u16>>
   (xself: &mut BlockEncoder<'a, AllocU8, AllocU16>,
    tree: &mut [HuffmanTree],
+   optimal_codes: bool,
    storage_ix: &mut usize,
    storage: &mut [u8]) {
   BuildAndStoreBlockSplitCode((*xself).block_types_,
@@ -1637,6 +1786,7 @@ fn BuildAndStoreBlockSwitchEntropyCodes<'a,
                               (*xself).num_blocks_,
                               (*xself).num_block_types_,
                               tree,
+                              optimal_codes,
                               &mut (*xself).block_split_code_,
                               storage_ix,
                               storage);
@@ -1645,6 +1795,7 @@ fn BuildAndStoreBlockSwitchEntropyCodes<'a,
 fn StoreTrivialContextMap(num_types: usize,
                           context_bits: usize,
                           tree: &mut [HuffmanTree],
+                          optimal_codes: bool,
                           storage_ix: &mut usize,
                           storage: &mut [u8]) {
   StoreVarLenUint8(num_types.wrapping_sub(1usize) as u64, storage_ix, storage);
@@ -1674,6 +1825,7 @@ fn StoreTrivialContextMap(num_types: usize,
                              alphabet_size,
                              alphabet_size,
                              tree,
+                             optimal_codes,
                              &mut depths[..],
                              &mut bits[..],
                              storage_ix,
@@ -1850,6 +2002,7 @@ fn EncodeContextMap<AllocU32: alloc::Allocator<u32>>(m: &mut AllocU32,
                                                      context_map_size: usize,
                                                      num_clusters: usize,
                                                      tree: &mut [HuffmanTree],
+                                                     optimal_codes: bool,
                                                      storage_ix: &mut usize,
                                                      storage: &mut [u8]) {
   let mut i: usize;
@@ -1903,6 +2056,7 @@ fn EncodeContextMap<AllocU32: alloc::Allocator<u32>>(m: &mut AllocU32,
                            num_clusters.wrapping_add(max_run_length_prefix as (usize)),
                            num_clusters.wrapping_add(max_run_length_prefix as (usize)),
                            tree,
+                           optimal_codes,
                            &mut depths[..],
                            &mut bits[..],
                            storage_ix,
@@ -1939,6 +2093,7 @@ fn BuildAndStoreEntropyCodes<AllocU8: alloc::Allocator<u8>,
    histograms_size: usize,
    alphabet_size: usize,
    tree: &mut [HuffmanTree],
+   optimal_codes: bool,
    storage_ix: &mut usize,
    storage: &mut [u8]) {
   let table_size: usize = histograms_size.wrapping_mul(xself.histogram_length_);
@@ -1962,6 +2117,7 @@ fn BuildAndStoreEntropyCodes<AllocU8: alloc::Allocator<u8>,
                                    xself.histogram_length_,
                                    alphabet_size,
                                    tree,
+                                   optimal_codes,
                                    &mut (*xself).depths_.slice_mut()[(ix as (usize))..],
                                    &mut (*xself).bits_.slice_mut()[(ix as (usize))..],
                                    storage_ix,
@@ -2004,7 +2160,7 @@ fn StoreSymbol<AllocU8: alloc::Allocator<u8>,
   }
 }
 
-fn CommandCopyLenCode(xself: &Command) -> u32 {
+pub fn CommandCopyLenCode(xself: &Command) -> u32 {
     let modifier = xself.copy_len_ >> 25;
     let delta: i32 = ((modifier | ((modifier & 0x40) << 1)) as u8) as i8 as i32;
     ((xself.copy_len_ & 0x1ffffff) as i32 + delta) as u32
@@ -2039,7 +2195,7 @@ fn StoreCommandExtra(cmd: &Command, storage_ix: &mut usize, storage: &mut [u8])
                   storage);
 }
 
-fn Context(p1: u8, p2: u8, mode: ContextType) -> u8 {
+pub fn Context(p1: u8, p2: u8, mode: ContextType) -> u8 {
   match mode {
     ContextType::CONTEXT_LSB6 => {
       return (p1 as (i32) & 0x3fi32) as (u8);
@@ -2059,6 +2215,16 @@ fn Context(p1: u8, p2: u8, mode: ContextType) -> u8 {
   //  0i32 as (u8)
 }
 
+// The block type that the next symbol stored through xself will be coded with.
+fn NextBlockType<AllocU8: alloc::Allocator<u8>,
+                 AllocU16: alloc::Allocator<u16>>(xself: &BlockEncoder<AllocU8, AllocU16>) -> usize {
+  if (*xself).block_len_ == 0usize {
+    (*xself).block_types_[(*xself).block_ix_ + 1] as usize
+  } else {
+    (*xself).block_types_[(*xself).block_ix_] as usize
+  }
+}
+
 fn StoreSymbolWithContext<AllocU8: alloc::Allocator<u8>,
                           AllocU16: alloc::Allocator<u16>>(xself: &mut BlockEncoder<AllocU8,
                                                                                         AllocU16>,
@@ -2116,9 +2282,16 @@ m16 : &mut AllocU16, xself: &mut BlockEncoder<AllocU8, AllocU16>){
   m16.free_cell(core::mem::replace(&mut (*xself).bits_, AllocU16::AllocatedMemory::default()));
 }
 
-fn JumpToByteBoundary(storage_ix: &mut usize, storage: &mut [u8]) {
-  *storage_ix = (*storage_ix).wrapping_add(7u32 as (usize)) & !7u32 as (usize);
-  storage[((*storage_ix >> 3i32) as (usize))] = 0i32 as (u8);
+pub fn JumpToByteBoundary(storage_ix: &mut usize, storage: &mut [u8]) {
+  let guard = NoPanicGuard;
+  let next_byte = (*storage_ix).wrapping_add(7u32 as (usize)) >> 3i32;
+  if BrotliWriteBitsFailed(*storage_ix) || next_byte >= storage.len() {
+    *storage_ix = BROTLI_WRITE_BITS_FAILED;
+  } else {
+    *storage_ix = next_byte << 3i32;
+    storage[next_byte] = 0i32 as (u8);
+  }
+  core::mem::forget(guard);
 }
 
 
@@ -2163,15 +2336,21 @@ pub fn BrotliStoreMetaBlock<'a,
                                                   &mut[interface::StaticCommand],
                                                   InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand) {
   let (input0,input1) = InputPairFromMaskedInput(input, start_pos, length, mask);
-  if params.log_meta_block {
-      LogMetaBlock(m8, m16, m32, mf, mfv, mpdf, mc, commands.split_at(n_commands).0, input0, input1,
-                   distance_cache,
-                   recoder_state,
-                   block_split_reference(mb),
-                   params,
-                   Some(literal_context_mode),
-                   callback);
-  }
+  let mut literal_context_modes = [literal_context_mode; 256];
+  for (block_type, mode) in literal_context_modes.iter_mut().enumerate().take((*mb).literal_split.num_types) {
+    *mode = (*mb).literal_context_mode(block_type, literal_context_mode);
+  }
+  if params.log_meta_block && !LogMetaBlock(m8, m16, m32, mf, mfv, mpdf, mc, commands.split_at(n_commands).0, input0, input1,
+                                             distance_cache,
+                                             recoder_state,
+                                             block_split_reference(mb),
+                                             params,
+                                             Some(literal_context_modes[0]),
+                                             &literal_context_modes[..(*mb).literal_split.num_types],
+                                             callback) {
+    *storage_ix = BROTLI_WRITE_BITS_FAILED;
+  }
+  let optimal_codes = UseOptimalHuffmanCodes(params);
   let mut pos: usize = start_pos;
   let mut i: usize;
   let num_distance_symbols = params.dist.alphabet_size;
@@ -2185,6 +2364,22 @@ pub fn BrotliStoreMetaBlock<'a,
   if params.large_window && num_effective_distance_symbols > BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS {
       num_effective_distance_symbols = BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS;
   }
+  let start_ix = *storage_ix;
+  let mut stats = BrotliMetaBlockStats {
+    storage_path: BrotliMetaBlockStoragePath::Full,
+    input_size: length,
+    num_commands: n_commands,
+    num_literal_block_types: (*mb).literal_split.num_types,
+    num_command_block_types: (*mb).command_split.num_types,
+    num_distance_block_types: (*mb).distance_split.num_types,
+    literal_context_map_size: (*mb).literal_context_map_size,
+    distance_context_map_size: (*mb).distance_context_map_size,
+    num_literal_prefix_codes: (*mb).literal_histograms_size,
+    num_distance_prefix_codes: (*mb).distance_histograms_size,
+    distance_postfix_bits: params.dist.distance_postfix_bits,
+    num_direct_distance_codes: params.dist.num_direct_distance_codes,
+    ..BrotliMetaBlockStats::default()
+  };
   StoreCompressedMetaBlockHeader(is_last, length, storage_ix, storage);
   tree = if 2i32 * 704i32 + 1i32 != 0 {
     mht.alloc_cell((2i32 * 704i32 + 1i32) as (usize))
@@ -2206,9 +2401,9 @@ pub fn BrotliStoreMetaBlock<'a,
                                                       (*mb).distance_split.types.slice(),
                                                       (*mb).distance_split.lengths.slice(),
                                                       (*mb).distance_split.num_blocks);
-  BuildAndStoreBlockSwitchEntropyCodes(&mut literal_enc, tree.slice_mut(), storage_ix, storage);
-  BuildAndStoreBlockSwitchEntropyCodes(&mut command_enc, tree.slice_mut(), storage_ix, storage);
-  BuildAndStoreBlockSwitchEntropyCodes(&mut distance_enc, tree.slice_mut(), storage_ix, storage);
+  BuildAndStoreBlockSwitchEntropyCodes(&mut literal_enc, tree.slice_mut(), optimal_codes, storage_ix, storage);
+  BuildAndStoreBlockSwitchEntropyCodes(&mut command_enc, tree.slice_mut(), optimal_codes, storage_ix, storage);
+  BuildAndStoreBlockSwitchEntropyCodes(&mut distance_enc, tree.slice_mut(), optimal_codes, storage_ix, storage);
   BrotliWriteBits(2, dist.distance_postfix_bits as (u64), storage_ix, storage);
   BrotliWriteBits(4,
                   (dist.num_direct_distance_codes >> dist.distance_postfix_bits) as (u64),
@@ -2217,7 +2412,7 @@ pub fn BrotliStoreMetaBlock<'a,
   i = 0usize;
   while i < (*mb).literal_split.num_types {
     {
-      BrotliWriteBits(2, literal_context_mode as (u64), storage_ix, storage);
+      BrotliWriteBits(2, literal_context_modes[i] as (u64), storage_ix, storage);
     }
     i = i.wrapping_add(1 as (usize));
   }
@@ -2225,6 +2420,7 @@ pub fn BrotliStoreMetaBlock<'a,
     StoreTrivialContextMap((*mb).literal_histograms_size,
                            6,
                            tree.slice_mut(),
+                           optimal_codes,
                            storage_ix,
                            storage);
   } else {
@@ -2233,6 +2429,7 @@ pub fn BrotliStoreMetaBlock<'a,
                      (*mb).literal_context_map_size,
                      (*mb).literal_histograms_size,
                      tree.slice_mut(),
+                     optimal_codes,
                      storage_ix,
                      storage);
   }
@@ -2240,6 +2437,7 @@ pub fn BrotliStoreMetaBlock<'a,
     StoreTrivialContextMap((*mb).distance_histograms_size,
                            2usize,
                            tree.slice_mut(),
+                           optimal_codes,
                            storage_ix,
                            storage);
   } else {
@@ -2248,6 +2446,7 @@ pub fn BrotliStoreMetaBlock<'a,
                      (*mb).distance_context_map_size,
                      (*mb).distance_histograms_size,
                      tree.slice_mut(),
+                     optimal_codes,
                      storage_ix,
                      storage);
   }
@@ -2258,6 +2457,7 @@ pub fn BrotliStoreMetaBlock<'a,
                             (*mb).literal_histograms_size,
                             BROTLI_NUM_LITERAL_SYMBOLS,
                             tree.slice_mut(),
+                            optimal_codes,
                             storage_ix,
                             storage);
   BuildAndStoreEntropyCodes(m8,
@@ -2267,6 +2467,7 @@ pub fn BrotliStoreMetaBlock<'a,
                             (*mb).command_histograms_size,
                             BROTLI_NUM_COMMAND_SYMBOLS,
                             tree.slice_mut(),
+                            optimal_codes,
                             storage_ix,
                             storage);
   BuildAndStoreEntropyCodes(m8,
@@ -2276,18 +2477,23 @@ pub fn BrotliStoreMetaBlock<'a,
                             (*mb).distance_histograms_size,
                             num_distance_symbols as usize,
                             tree.slice_mut(),
+                            optimal_codes,
                             storage_ix,
                             storage);
   {
     mht.free_cell(core::mem::replace(&mut tree, AllocHT::AllocatedMemory::default()));
   }
+  let data_start_ix = *storage_ix;
   i = 0usize;
   while i < n_commands {
     {
       let cmd: Command = commands[(i as (usize))].clone();
       let cmd_code: usize = cmd.cmd_prefix_ as (usize);
+      let cmd_start_ix = *storage_ix;
       StoreSymbol(&mut command_enc, cmd_code, storage_ix, storage);
       StoreCommandExtra(&cmd, storage_ix, storage);
+      stats.command_bits += *storage_ix - cmd_start_ix;
+      stats.num_literals += cmd.insert_len_ as usize;
       if (*mb).literal_context_map_size == 0usize {
         let mut j: usize;
         j = cmd.insert_len_ as (usize);
@@ -2306,7 +2512,8 @@ pub fn BrotliStoreMetaBlock<'a,
         j = cmd.insert_len_ as (usize);
         while j != 0usize {
           {
-            let context: usize = Context(prev_byte, prev_byte2, literal_context_mode) as (usize);
+            let block_type = NextBlockType(&literal_enc);
+            let context: usize = Context(prev_byte, prev_byte2, literal_context_modes[block_type]) as (usize);
             let literal: u8 = input[((pos & mask) as (usize))];
             StoreSymbolWithContext(&mut literal_enc,
                                    literal as (usize),
@@ -2330,6 +2537,7 @@ pub fn BrotliStoreMetaBlock<'a,
           let dist_code: usize = cmd.dist_prefix_ as (usize) & 0x3ff;
           let distnumextra: u32 = u32::from(cmd.dist_prefix_) >> 10i32; //FIXME: from command
           let distextra: u64 = cmd.dist_extra_ as (u64);
+          let dist_start_ix = *storage_ix;
           if (*mb).distance_context_map_size == 0usize {
             StoreSymbol(&mut distance_enc, dist_code, storage_ix, storage);
           } else {
@@ -2343,17 +2551,21 @@ pub fn BrotliStoreMetaBlock<'a,
                                    2usize);
           }
           BrotliWriteBits(distnumextra as (u8), distextra, storage_ix, storage);
+          stats.distance_bits += *storage_ix - dist_start_ix;
         }
       }
     }
     i = i.wrapping_add(1 as (usize));
   }
+  stats.literal_bits = (*storage_ix - data_start_ix).saturating_sub(stats.command_bits + stats.distance_bits);
   CleanupBlockEncoder(m8, m16, &mut distance_enc);
   CleanupBlockEncoder(m8, m16, &mut command_enc);
   CleanupBlockEncoder(m8, m16, &mut literal_enc);
   if is_last != 0 {
     JumpToByteBoundary(storage_ix, storage);
   }
+  stats.finish(start_ix, *storage_ix);
+  recoder_state.metablock_stats = stats;
 }
 
 fn BuildHistograms(input: &[u8],
@@ -2400,20 +2612,25 @@ fn StoreDataWithHuffmanCodes(input: &[u8],
                              dist_depth: &[u8],
                              dist_bits: &[u16],
                              storage_ix: &mut usize,
-                             storage: &mut [u8]) {
+                             storage: &mut [u8],
+                             stats: &mut BrotliMetaBlockStats) {
   let mut pos: usize = start_pos;
   let mut i: usize;
+  let data_start_ix = *storage_ix;
   i = 0usize;
   while i < n_commands {
     {
       let cmd: Command = commands[(i as (usize))].clone();
       let cmd_code: usize = cmd.cmd_prefix_ as (usize);
       let mut j: usize;
+      let cmd_start_ix = *storage_ix;
       BrotliWriteBits(cmd_depth[(cmd_code as (usize))] as (u8),
                       cmd_bits[(cmd_code as (usize))] as (u64),
                       storage_ix,
                       storage);
       StoreCommandExtra(&cmd, storage_ix, storage);
+      stats.command_bits += *storage_ix - cmd_start_ix;
+      stats.num_literals += cmd.insert_len_ as usize;
       j = cmd.insert_len_ as (usize);
       while j != 0usize {
         {
@@ -2431,6 +2648,7 @@ fn StoreDataWithHuffmanCodes(input: &[u8],
         let dist_code: usize = cmd.dist_prefix_ as (usize) & 0x3ff;
         let distnumextra: u32 = u32::from(cmd.dist_prefix_) >> 10i32;
         let distextra: u32 = cmd.dist_extra_;
+        let dist_start_ix = *storage_ix;
         BrotliWriteBits(dist_depth[(dist_code as (usize))] as (u8),
                         dist_bits[(dist_code as (usize))] as (u64),
                         storage_ix,
@@ -2439,10 +2657,12 @@ fn StoreDataWithHuffmanCodes(input: &[u8],
                         distextra as (u64),
                         storage_ix,
                         storage);
+        stats.distance_bits += *storage_ix - dist_start_ix;
       }
     }
     i = i.wrapping_add(1 as (usize));
   }
+  stats.literal_bits += (*storage_ix - data_start_ix).saturating_sub(stats.command_bits + stats.distance_bits);
 }
 
 fn nop<'a>(_data:&[interface::Command<InputReference>]){
@@ -2479,23 +2699,24 @@ pub fn BrotliStoreMetaBlockTrivial<'a,
                                &mut [interface::StaticCommand],
                                InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand) {
   let (input0,input1) = InputPairFromMaskedInput(input, start_pos, length, mask);
-  if params.log_meta_block {
-      LogMetaBlock(m8,
-                   m16,
-                   m32,
-                   mf,
-                   mfv,
-                   mpdf,
-                   mc,
-                   commands.split_at(n_commands).0,
-                   input0,
-                   input1,
-                   distance_cache,
-                   recoder_state,
-                   block_split_nop(),
-                   params,
-                   Some(ContextType::CONTEXT_LSB6),
-                   f);
+  if params.log_meta_block && !LogMetaBlock(m8,
+                                             m16,
+                                             m32,
+                                             mf,
+                                             mfv,
+                                             mpdf,
+                                             mc,
+                                             commands.split_at(n_commands).0,
+                                             input0,
+                                             input1,
+                                             distance_cache,
+                                             recoder_state,
+                                             block_split_nop(),
+                                             params,
+                                             Some(ContextType::CONTEXT_LSB6),
+                                             &[],
+                                             f) {
+    *storage_ix = BROTLI_WRITE_BITS_FAILED;
   }
   let mut lit_histo: HistogramLiteral = HistogramLiteral::default();
   let mut cmd_histo: HistogramCommand = HistogramCommand::default();
@@ -2513,6 +2734,8 @@ pub fn BrotliStoreMetaBlockTrivial<'a,
     index_right_or_value_: 0,
   }; MAX_HUFFMAN_TREE_SIZE];
   let num_distance_symbols = params.dist.alphabet_size;
+  let start_ix = *storage_ix;
+  let mut stats = BrotliMetaBlockStats::single_block_type(BrotliMetaBlockStoragePath::Trivial, length, n_commands);
   StoreCompressedMetaBlockHeader(is_last, length, storage_ix, storage);
   BuildHistograms(input,
                   start_pos,
@@ -2527,6 +2750,7 @@ pub fn BrotliStoreMetaBlockTrivial<'a,
                            BROTLI_NUM_LITERAL_SYMBOLS,
                            BROTLI_NUM_LITERAL_SYMBOLS,
                            &mut tree[..],
+                           UseOptimalHuffmanCodes(params),
                            &mut lit_depth[..],
                            &mut lit_bits[..],
                            storage_ix,
@@ -2535,6 +2759,7 @@ pub fn BrotliStoreMetaBlockTrivial<'a,
                            BROTLI_NUM_COMMAND_SYMBOLS,
                            BROTLI_NUM_COMMAND_SYMBOLS,
                            &mut tree[..],
+                           UseOptimalHuffmanCodes(params),
                            &mut cmd_depth[..],
                            &mut cmd_bits[..],
                            storage_ix,
@@ -2543,6 +2768,7 @@ pub fn BrotliStoreMetaBlockTrivial<'a,
                            MAX_SIMPLE_DISTANCE_ALPHABET_SIZE,
                            num_distance_symbols as usize,
                            &mut tree[..],
+                           UseOptimalHuffmanCodes(params),
                            &mut dist_depth[..],
                            &mut dist_bits[..],
                            storage_ix,
@@ -2559,10 +2785,13 @@ pub fn BrotliStoreMetaBlockTrivial<'a,
                             &mut dist_depth[..],
                             &mut dist_bits[..],
                             storage_ix,
-                            storage);
+                            storage,
+                            &mut stats);
   if is_last != 0 {
     JumpToByteBoundary(storage_ix, storage);
   }
+  stats.finish(start_ix, *storage_ix);
+  recoder_state.metablock_stats = stats;
 }
 
 fn StoreStaticCommandHuffmanTree(storage_ix: &mut usize, storage: &mut [u8]) {
@@ -2636,15 +2865,92 @@ fn block_split_reference<'a,
         }
 }
      
+// How WriteMetaBlockInternal chose to store a metablock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BrotliMetaBlockStoragePath {
+    Uncompressed,
+    // a single prefix code per category (quality 3)
+    Trivial,
+    // fast prefix codes built without block splitting (quality 2 and below)
+    Fast,
+    // block splits, clustering and context maps (quality 4 and up)
+    Full,
+    // the one-pass or two-pass fragment compressors of quality 0 and 1, which may write several
+    // metablocks per record and do not break the bits down
+    Fragment,
+}
+
+impl Default for BrotliMetaBlockStoragePath {
+    fn default() -> Self {
+        BrotliMetaBlockStoragePath::Full
+    }
+}
+
+// What one metablock cost. header_bits covers the metablock header, block split codes, context
+// maps, prefix codes and padding; a block switch counts toward the literal, command or distance
+// bits of the symbol it precedes, so the four add up to total_bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BrotliMetaBlockStats {
+    pub storage_path: BrotliMetaBlockStoragePath,
+    pub input_size: usize,
+    pub num_commands: usize,
+    pub num_literals: usize,
+    pub num_literal_block_types: usize,
+    pub num_command_block_types: usize,
+    pub num_distance_block_types: usize,
+    // entries in the context maps (zero when every block type uses a single prefix code)
+    pub literal_context_map_size: usize,
+    pub distance_context_map_size: usize,
+    pub num_literal_prefix_codes: usize,
+    pub num_distance_prefix_codes: usize,
+    // NPOSTFIX and NDIRECT of the distance codes (both zero outside the full storage path)
+    pub distance_postfix_bits: u32,
+    pub num_direct_distance_codes: u32,
+    pub header_bits: usize,
+    pub literal_bits: usize,
+    pub command_bits: usize,
+    pub distance_bits: usize,
+    pub total_bits: usize,
+}
+
+impl BrotliMetaBlockStats {
+    // stats of a metablock with one block type and prefix code per category
+    fn single_block_type(storage_path: BrotliMetaBlockStoragePath,
+                         input_size: usize,
+                         num_commands: usize) -> Self {
+        BrotliMetaBlockStats {
+            storage_path: storage_path,
+            input_size: input_size,
+            num_commands: num_commands,
+            num_literal_block_types: 1,
+            num_command_block_types: 1,
+            num_distance_block_types: 1,
+            num_literal_prefix_codes: 1,
+            num_distance_prefix_codes: 1,
+            ..BrotliMetaBlockStats::default()
+        }
+    }
+    // Fills in total_bits and makes header_bits the remainder once the data bits are known.
+    fn finish(&mut self, start_ix: usize, storage_ix: usize) {
+        self.total_bits = storage_ix.saturating_sub(start_ix);
+        self.header_bits = self.total_bits.saturating_sub(self.literal_bits + self.command_bits + self.distance_bits);
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct RecoderState {
     pub num_bytes_encoded : usize,
+    // incremented each time a metablock is stored, which also overwrites metablock_stats
+    pub num_metablocks : usize,
+    pub metablock_stats : BrotliMetaBlockStats,
 }
 
 impl RecoderState {
     pub fn new() -> Self {
         RecoderState{
             num_bytes_encoded:0,
+            num_metablocks:0,
+            metablock_stats:BrotliMetaBlockStats::default(),
         }
     }
 }
@@ -2682,22 +2988,25 @@ pub fn BrotliStoreMetaBlockFast<Cb,
                                                   &mut [interface::StaticCommand],
                                                   InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand) {
   let (input0,input1) = InputPairFromMaskedInput(input, start_pos, length, mask);
-  if params.log_meta_block {
-      LogMetaBlock(m8,
-                   m16,
-                   m32,
-                   mf,
-                   mfv,
-                   mpdf,
-                   mc,
-                   commands.split_at(n_commands).0, input0, input1, dist_cache, recoder_state,
-                   block_split_nop(),
-                   params,
-                   Some(ContextType::CONTEXT_LSB6),
-                   cb);
+  if params.log_meta_block && !LogMetaBlock(m8,
+                                             m16,
+                                             m32,
+                                             mf,
+                                             mfv,
+                                             mpdf,
+                                             mc,
+                                             commands.split_at(n_commands).0, input0, input1, dist_cache, recoder_state,
+                                             block_split_nop(),
+                                             params,
+                                             Some(ContextType::CONTEXT_LSB6),
+                                             &[],
+                                             cb) {
+    *storage_ix = BROTLI_WRITE_BITS_FAILED;
   }
   let num_distance_symbols = params.dist.alphabet_size;
   let distance_alphabet_bits = Log2FloorNonZero(u64::from(num_distance_symbols) - 1) + 1;
+  let start_ix = *storage_ix;
+  let mut stats = BrotliMetaBlockStats::single_block_type(BrotliMetaBlockStoragePath::Fast, length, n_commands);
   StoreCompressedMetaBlockHeader(is_last, length, storage_ix, storage);
   BrotliWriteBits(13, 0, storage_ix, storage);
   if n_commands <= 128usize {
@@ -2751,7 +3060,8 @@ pub fn BrotliStoreMetaBlockFast<Cb,
                               &kStaticDistanceCodeDepth[..],
                               &kStaticDistanceCodeBits[..],
                               storage_ix,
-                              storage);
+                              storage,
+                              &mut stats);
   } else {
     let mut lit_histo: HistogramLiteral = HistogramLiteral::default();
     let mut cmd_histo: HistogramCommand = HistogramCommand::default();
@@ -2806,11 +3116,14 @@ pub fn BrotliStoreMetaBlockFast<Cb,
                               &mut dist_depth[..],
                               &mut dist_bits[..],
                               storage_ix,
-                              storage);
+                              storage,
+                              &mut stats);
   }
   if is_last != 0 {
     JumpToByteBoundary(storage_ix, storage);
   }
+  stats.finish(start_ix, *storage_ix);
+  recoder_state.metablock_stats = stats;
 }
 fn BrotliStoreUncompressedMetaBlockHeader(length: usize,
                                           storage_ix: &mut usize,
@@ -2864,15 +3177,27 @@ pub fn BrotliStoreUncompressedMetaBlock<Cb,
                                   &mut [interface::StaticCommand],
                                   InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand){
   let (input0,input1) = InputPairFromMaskedInput(input, position, len, mask);
+  let start_ix = *storage_ix;
+  let mut stats = BrotliMetaBlockStats {
+    storage_path: BrotliMetaBlockStoragePath::Uncompressed,
+    input_size: len,
+    num_literals: len,
+    literal_bits: len << 3,
+    ..BrotliMetaBlockStats::default()
+  };
   BrotliStoreUncompressedMetaBlockHeader(len, storage_ix, storage);
   JumpToByteBoundary(storage_ix, storage);
   let dst_start0 = ((*storage_ix >> 3i32) as (usize));
-  storage[dst_start0..(dst_start0 + input0.len())].clone_from_slice(input0);
-  *storage_ix = (*storage_ix).wrapping_add(input0.len() << 3i32);
-  let dst_start1 = ((*storage_ix >> 3i32) as (usize));
-  storage[dst_start1..(dst_start1 + input1.len())].clone_from_slice(input1);
-  *storage_ix = (*storage_ix).wrapping_add(input1.len() << 3i32);
-  BrotliWriteBitsPrepareStorage(*storage_ix, storage);
+  if dst_start0.wrapping_add(input0.len()).wrapping_add(input1.len()) >= storage.len() {
+    *storage_ix = BROTLI_WRITE_BITS_FAILED;
+  } else {
+    storage[dst_start0..(dst_start0 + input0.len())].clone_from_slice(input0);
+    *storage_ix = (*storage_ix).wrapping_add(input0.len() << 3i32);
+    let dst_start1 = ((*storage_ix >> 3i32) as (usize));
+    storage[dst_start1..(dst_start1 + input1.len())].clone_from_slice(input1);
+    *storage_ix = (*storage_ix).wrapping_add(input1.len() << 3i32);
+    BrotliWriteBitsPrepareStorage(storage_ix, storage);
+  }
   if params.log_meta_block && !suppress_meta_block_logging {
     let cmds = [Command{insert_len_:len as u32,
                         copy_len_:0,
@@ -2881,17 +3206,22 @@ pub fn BrotliStoreUncompressedMetaBlock<Cb,
                         dist_prefix_:0
     }];
 
-    LogMetaBlock(m8, m16, m32, mf, mfv, mpdf, mc, &cmds, input0, input1, &[0i32, 0i32, 0i32, 0i32], recoder_state,
-                 block_split_nop(),
-                 params,
-                 None,
-                 cb);
+    if !LogMetaBlock(m8, m16, m32, mf, mfv, mpdf, mc, &cmds, input0, input1, &[0i32, 0i32, 0i32, 0i32], recoder_state,
+                     block_split_nop(),
+                     params,
+                     None,
+                     &[],
+                     cb) {
+      *storage_ix = BROTLI_WRITE_BITS_FAILED;
+    }
   }
   if is_final_block != 0 {
     BrotliWriteBits(1u8, 1u64, storage_ix, storage);
     BrotliWriteBits(1u8, 1u64, storage_ix, storage);
     JumpToByteBoundary(storage_ix, storage);
   }
+  stats.finish(start_ix, *storage_ix);
+  recoder_state.metablock_stats = stats;
 }
 
 
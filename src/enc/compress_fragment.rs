@@ -878,7 +878,7 @@ fn BrotliCompressFragmentFastImpl<AllocHT:alloc::Allocator<HuffmanTree>>(m: &mut
               }
             }
             {
-              assert!(ip_index >= 3);
+              debug_assert!(ip_index >= 3);
               let input_bytes: u64 = BROTLI_UNALIGNED_LOAD64(&input_ptr[((ip_index - 3) as
                                                                (usize))..]);
               let mut prev_hash: u32 = HashBytesAtOffset(input_bytes, 0i32, shift);
@@ -934,7 +934,7 @@ fn BrotliCompressFragmentFastImpl<AllocHT:alloc::Allocator<HuffmanTree>>(m: &mut
               }
             }
             {
-              assert!(ip_index >= 3);
+              debug_assert!(ip_index >= 3);
               let input_bytes: u64 = BROTLI_UNALIGNED_LOAD64(&input_ptr[(ip_index as (usize) -
                                                                3)..]);
               let mut prev_hash: u32 = HashBytesAtOffset(input_bytes, 0i32, shift);
@@ -302,7 +302,7 @@ fn CreateCommands(input_index: usize,
               prev_hash = HashBytesAtOffset(input_bytes, 0i32, shift, min_match);
               table[(prev_hash as (usize))] = ip_index.wrapping_sub(1usize) as (i32);
           }else {
-              assert!(ip_index >= 5);
+              debug_assert!(ip_index >= 5);
               // could this be off the end FIXME
               input_bytes = BROTLI_UNALIGNED_LOAD64(&base_ip[(ip_index as (usize) - 5)..]);
               prev_hash = HashBytesAtOffset(input_bytes, 0i32, shift, min_match);
@@ -311,7 +311,7 @@ fn CreateCommands(input_index: usize,
               table[(prev_hash as (usize))] = ip_index.wrapping_sub(4usize) as (i32);
               prev_hash = HashBytesAtOffset(input_bytes, 2i32, shift, min_match);
               table[(prev_hash as (usize))] = ip_index.wrapping_sub(3usize) as (i32);
-              assert!(ip_index >= 2);
+              debug_assert!(ip_index >= 2);
               input_bytes = BROTLI_UNALIGNED_LOAD64(&base_ip[(ip_index as (usize) - 2)..]);
               cur_hash = HashBytesAtOffset(input_bytes, 2i32, shift, min_match);
               prev_hash = HashBytesAtOffset(input_bytes, 0i32, shift, min_match);
@@ -348,7 +348,7 @@ fn CreateCommands(input_index: usize,
           }
         }
         {
-          assert!(ip_index >= 5);
+          debug_assert!(ip_index >= 5);
           let mut input_bytes: u64;
           
           let cur_hash: u32;
@@ -370,7 +370,7 @@ fn CreateCommands(input_index: usize,
               table[(prev_hash as (usize))] = ip_index.wrapping_sub(4usize) as (i32);
               prev_hash = HashBytesAtOffset(input_bytes, 2i32, shift, min_match);
               table[(prev_hash as (usize))] = ip_index.wrapping_sub(3usize) as (i32);
-              assert!(ip_index >= 2);
+              debug_assert!(ip_index >= 2);
               input_bytes = BROTLI_UNALIGNED_LOAD64(&base_ip[(ip_index as (usize) - 2)..]);
               cur_hash = HashBytesAtOffset(input_bytes, 2i32, shift, min_match);
               prev_hash = HashBytesAtOffset(input_bytes, 0i32, shift, min_match);
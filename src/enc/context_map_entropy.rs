@@ -69,7 +69,7 @@ fn get_cm_cdf_high(data: &mut [u16], cm_prior: usize) -> &mut [u16] {
     data.split_at_mut(NUM_SPEEDS_TO_TRY * index << 4).1.split_at_mut(16 * NUM_SPEEDS_TO_TRY).0
 }
 fn init_cdfs(cdfs: &mut [u16]) {
-    assert_eq!(cdfs.len() % (16 * NUM_SPEEDS_TO_TRY), 0);
+    debug_assert_eq!(cdfs.len() % (16 * NUM_SPEEDS_TO_TRY), 0);
     let mut total_index = 0usize;
     let len = cdfs.len();
     loop {
@@ -90,7 +90,7 @@ fn compute_combined_cost(singleton_cost: &mut [floatX;NUM_SPEEDS_TO_TRY],
                 mixing_cdf: [u16;16],
                 nibble_u8: u8,
                 _weights: &mut [Weights; NUM_SPEEDS_TO_TRY]) {
-    assert_eq!(cdfs.len(), 16 * NUM_SPEEDS_TO_TRY);
+    debug_assert_eq!(cdfs.len(), 16 * NUM_SPEEDS_TO_TRY);
     let nibble = nibble_u8 as usize & 0xf;
     let mut stride_pdf = [0u16; NUM_SPEEDS_TO_TRY];
     stride_pdf.clone_from_slice(cdfs.split_at(NUM_SPEEDS_TO_TRY * nibble).1.split_at(NUM_SPEEDS_TO_TRY).0);
@@ -108,10 +108,10 @@ fn compute_combined_cost(singleton_cost: &mut [floatX;NUM_SPEEDS_TO_TRY],
     let cm_max = mixing_cdf[15];
     for i in 0..NUM_SPEEDS_TO_TRY {
         if stride_pdf[i] == 0 { 
-            assert!(stride_pdf[i] != 0);
+            debug_assert!(stride_pdf[i] != 0);
         }
         if stride_max[i] == 0 {
-            assert!(stride_max[i] != 0);
+            debug_assert!(stride_max[i] != 0);
         }
         let w;
         w = (1<<(BLEND_FIXED_POINT_PRECISION - 2)) ; // a quarter of weight to stride
@@ -124,7 +124,7 @@ fn compute_combined_cost(singleton_cost: &mut [floatX;NUM_SPEEDS_TO_TRY],
 fn compute_cost(singleton_cost: &mut [floatX;NUM_SPEEDS_TO_TRY],
                 cdfs: &[u16],
                 nibble_u8: u8) {
-    assert_eq!(cdfs.len(), 16 * NUM_SPEEDS_TO_TRY);
+    debug_assert_eq!(cdfs.len(), 16 * NUM_SPEEDS_TO_TRY);
     let nibble = nibble_u8 as usize & 0xf;
     let mut pdf = [0u16; NUM_SPEEDS_TO_TRY];
     pdf.clone_from_slice(cdfs.split_at(NUM_SPEEDS_TO_TRY * nibble).1.split_at(NUM_SPEEDS_TO_TRY).0);
@@ -139,10 +139,10 @@ fn compute_cost(singleton_cost: &mut [floatX;NUM_SPEEDS_TO_TRY],
     max.clone_from_slice(cdfs.split_at(NUM_SPEEDS_TO_TRY * 15).1);
     for i in 0..NUM_SPEEDS_TO_TRY {
         if pdf[i] == 0 { 
-            assert!(pdf[i] != 0);
+            debug_assert!(pdf[i] != 0);
         }
         if max[i] == 0 {
-            assert!(max[i] != 0);
+            debug_assert!(max[i] != 0);
         }
         let del = FastLog2u16(pdf[i]) - FastLog2u16(max[i]);
         singleton_cost[i] -= del;
@@ -150,7 +150,7 @@ fn compute_cost(singleton_cost: &mut [floatX;NUM_SPEEDS_TO_TRY],
 }
 fn update_cdf(cdfs: &mut [u16],
               nibble_u8: u8) {
-    assert_eq!(cdfs.len(), 16 * NUM_SPEEDS_TO_TRY);
+    debug_assert_eq!(cdfs.len(), 16 * NUM_SPEEDS_TO_TRY);
     let mut overall_index = nibble_u8 as usize * NUM_SPEEDS_TO_TRY;
     for _nibble in (nibble_u8 as usize & 0xf) .. 16 {
         for speed_index in 0..NUM_SPEEDS_TO_TRY {
@@ -162,9 +162,9 @@ fn update_cdf(cdfs: &mut [u16],
     for nibble in 0 .. 16 {
         for speed_index in 0..NUM_SPEEDS_TO_TRY {
             if nibble == 0 {
-                assert!(cdfs[overall_index + speed_index] != 0);
+                debug_assert!(cdfs[overall_index + speed_index] != 0);
             } else {
-                assert!(cdfs[overall_index + speed_index]  - cdfs[overall_index + speed_index - NUM_SPEEDS_TO_TRY]  != 0);
+                debug_assert!(cdfs[overall_index + speed_index]  - cdfs[overall_index + speed_index - NUM_SPEEDS_TO_TRY]  != 0);
             }
         }
         overall_index += NUM_SPEEDS_TO_TRY;
@@ -182,9 +182,9 @@ fn update_cdf(cdfs: &mut [u16],
     for nibble in 0 .. 16 {
         for speed_index in 0..NUM_SPEEDS_TO_TRY {
             if nibble == 0 {
-                assert!(cdfs[overall_index + speed_index] != 0);
+                debug_assert!(cdfs[overall_index + speed_index] != 0);
             } else {
-                assert!(cdfs[overall_index + speed_index]  - cdfs[overall_index + speed_index - NUM_SPEEDS_TO_TRY]  != 0);
+                debug_assert!(cdfs[overall_index + speed_index]  - cdfs[overall_index + speed_index - NUM_SPEEDS_TO_TRY]  != 0);
             }
         }
         overall_index += NUM_SPEEDS_TO_TRY;
@@ -192,8 +192,8 @@ fn update_cdf(cdfs: &mut [u16],
 }
 
 fn extract_single_cdf(cdf_bundle:&[u16], index:usize) -> [u16;16] {
-    assert_eq!(cdf_bundle.len(), 16 * NUM_SPEEDS_TO_TRY);
-    assert!(index < NUM_SPEEDS_TO_TRY);
+    debug_assert_eq!(cdf_bundle.len(), 16 * NUM_SPEEDS_TO_TRY);
+    debug_assert!(index < NUM_SPEEDS_TO_TRY);
     [
         cdf_bundle[index + 0 * NUM_SPEEDS_TO_TRY],
         cdf_bundle[index + 1 * NUM_SPEEDS_TO_TRY],
@@ -215,7 +215,7 @@ fn extract_single_cdf(cdf_bundle:&[u16], index:usize) -> [u16;16] {
 }
 
 fn min_cost_index_for_speed(cost: &[floatX]) -> usize {
-    assert_eq!(cost.len(), NUM_SPEEDS_TO_TRY);
+    debug_assert_eq!(cost.len(), NUM_SPEEDS_TO_TRY);
     let mut min_cost = cost[0];
     let mut best_choice = 0;
     for i in 1..NUM_SPEEDS_TO_TRY {
@@ -0,0 +1,64 @@
+// Dictionary-compressed brotli ("dcb") from the Compression Dictionary Transport spec:
+// a magic number and the SHA-256 of the dictionary, followed by a brotli stream that
+// was compressed with the dictionary as a custom (prefix) dictionary.
+use super::sha256::{sha256, SHA256_DIGEST_SIZE};
+#[cfg(not(feature="no-stdlib"))]
+use super::backward_references::BrotliEncoderParams;
+#[cfg(not(feature="no-stdlib"))]
+use super::BrotliCompressCustomDict;
+#[cfg(not(feature="no-stdlib"))]
+use brotli_decompressor::BrotliDecompressCustomDict;
+#[cfg(not(feature="no-stdlib"))]
+use std::io::{self, Read, Write, Error, ErrorKind};
+
+pub const DCB_MAGIC: [u8; 4] = [0xff, 0x44, 0x43, 0x42];
+pub const DCB_HEADER_SIZE: usize = 4 + SHA256_DIGEST_SIZE;
+
+pub fn BrotliDcbHeader(dictionary: &[u8]) -> [u8; DCB_HEADER_SIZE] {
+  let mut header = [0u8; DCB_HEADER_SIZE];
+  header[..4].clone_from_slice(&DCB_MAGIC[..]);
+  header[4..].clone_from_slice(&sha256(dictionary)[..]);
+  header
+}
+
+// Whether header starts a dcb stream compressed with dictionary.
+pub fn BrotliDcbHeaderMatches(header: &[u8], dictionary: &[u8]) -> bool {
+  header.len() >= DCB_HEADER_SIZE && header[..DCB_HEADER_SIZE] == BrotliDcbHeader(dictionary)[..]
+}
+
+// Writes the dcb header and the input compressed against dictionary.
+// Returns the number of bytes written, header included.
+#[cfg(not(feature="no-stdlib"))]
+pub fn BrotliCompressDcb<InputType, OutputType>(r: &mut InputType,
+                                                w: &mut OutputType,
+                                                params: &BrotliEncoderParams,
+                                                dictionary: &[u8])
+                                                -> Result<usize, io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  w.write_all(&BrotliDcbHeader(dictionary)[..])?;
+  let compressed_size = BrotliCompressCustomDict(r, w, params, dictionary)?;
+  Ok(DCB_HEADER_SIZE + compressed_size)
+}
+
+// Checks the dcb header against dictionary and decompresses the stream that follows.
+// Fails with InvalidData if the stream was not compressed with this dictionary.
+#[cfg(not(feature="no-stdlib"))]
+pub fn BrotliDecompressDcb<InputType, OutputType>(r: &mut InputType,
+                                                  w: &mut OutputType,
+                                                  dictionary: &[u8])
+                                                  -> Result<(), io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  let mut header = [0u8; DCB_HEADER_SIZE];
+  r.read_exact(&mut header[..])?;
+  if header[..4] != DCB_MAGIC[..] {
+    return Err(Error::new(ErrorKind::InvalidData, "Not a dictionary-compressed brotli stream"));
+  }
+  if !BrotliDcbHeaderMatches(&header[..], dictionary) {
+    return Err(Error::new(ErrorKind::InvalidData, "Stream was compressed with a different dictionary"));
+  }
+  BrotliDecompressCustomDict(r, w, &mut [], &mut [], dictionary.to_vec())
+}
@@ -0,0 +1,253 @@
+// Builds a custom (prefix) dictionary from a set of representative samples.
+// Segments of the samples are picked greedily by how many samples share their
+// 8-byte substrings (as in zstd's COVER trainer), and among the dictionaries
+// built with several segment sizes the one that compresses the samples best
+// with BrotliEncoderSetCustomDictionary wins.
+use super::backward_references::BrotliEncoderParams;
+use super::encode::BrotliEncoderInitParams;
+use super::BrotliCompressCustomDict;
+use core;
+use std::collections::HashMap;
+use std::io;
+use std::vec::Vec;
+
+const DMER_SIZE: usize = 8;
+static kSegmentSizes: [usize; 5] = [32, 64, 128, 256, 512];
+
+fn Dmer(data: &[u8], pos: usize) -> u64 {
+  let mut key = 0u64;
+  for byte in data[pos..pos + DMER_SIZE].iter() {
+    key = (key << 8) | *byte as u64;
+  }
+  key
+}
+
+// Number of samples each dmer occurs in. With several samples, dmers found in
+// only one of them can't help any other sample and are dropped.
+fn CountDmerFrequencies(samples: &[&[u8]]) -> HashMap<u64, u32> {
+  let mut freqs = HashMap::<u64, u32>::new();
+  let mut last_seen = HashMap::<u64, usize>::new();
+  for (index, sample) in samples.iter().enumerate() {
+    if sample.len() < DMER_SIZE {
+      continue;
+    }
+    for pos in 0..sample.len() - DMER_SIZE + 1 {
+      let dmer = Dmer(sample, pos);
+      let seen = last_seen.entry(dmer).or_insert(usize::max_value());
+      if *seen != index {
+        *seen = index;
+        *freqs.entry(dmer).or_insert(0) += 1;
+      }
+    }
+  }
+  if samples.len() > 1 {
+    freqs.retain(|_, freq| *freq > 1);
+  }
+  freqs
+}
+
+// The segment of sample[begin..end] whose distinct dmers have the highest total frequency.
+// Returns (score, start, length).
+fn BestSegment(sample: &[u8],
+               begin: usize,
+               end: usize,
+               segment_size: usize,
+               freqs: &HashMap<u64, u32>)
+               -> (u64, usize, usize) {
+  let mut best = (0u64, begin, 0usize);
+  if end < begin + DMER_SIZE {
+    return best;
+  }
+  let dmers_per_segment = segment_size - DMER_SIZE + 1;
+  let mut active = HashMap::<u64, u32>::new();
+  let mut score = 0u64;
+  let last = end - DMER_SIZE;
+  for pos in begin..last + 1 {
+    let dmer = Dmer(sample, pos);
+    let count = active.entry(dmer).or_insert(0);
+    if *count == 0 {
+      score += *freqs.get(&dmer).unwrap_or(&0) as u64;
+    }
+    *count += 1;
+    if pos >= begin + dmers_per_segment {
+      let old = Dmer(sample, pos - dmers_per_segment);
+      let count = active.get_mut(&old).unwrap();
+      *count -= 1;
+      if *count == 0 {
+        score -= *freqs.get(&old).unwrap_or(&0) as u64;
+      }
+    }
+    if score > best.0 {
+      let start = if pos + 1 >= begin + dmers_per_segment {
+        pos + 1 - dmers_per_segment
+      } else {
+        begin
+      };
+      best = (score, start, pos + DMER_SIZE - start);
+    }
+  }
+  if best.0 == 0 {
+    return best;
+  }
+  // trim dmers that contribute nothing from both ends
+  let (score, mut start, mut len) = best;
+  while len > DMER_SIZE && *freqs.get(&Dmer(sample, start)).unwrap_or(&0) == 0 {
+    start += 1;
+    len -= 1;
+  }
+  while len > DMER_SIZE && *freqs.get(&Dmer(sample, start + len - DMER_SIZE)).unwrap_or(&0) == 0 {
+    len -= 1;
+  }
+  (score, start, len)
+}
+
+// Greedily picks one segment per epoch, round robin over the epochs, until the
+// dictionary is full or no segment is worth anything. Segments picked first are
+// placed at the end of the dictionary where they are cheapest to reference.
+fn BuildDictionary(samples: &[&[u8]],
+                   max_size: usize,
+                   segment_size: usize,
+                   freqs: &HashMap<u64, u32>)
+                   -> Vec<u8> {
+  let mut freqs = freqs.clone();
+  let total_size: usize = samples.iter().map(|s| s.len()).sum();
+  let num_epochs = core::cmp::max(1, core::cmp::min(max_size / segment_size, total_size / segment_size));
+  let epoch_size = core::cmp::max(segment_size, total_size / num_epochs);
+  // each epoch is a list of (sample, begin, end) ranges
+  let mut epochs = vec![Vec::<(usize, usize, usize)>::new(); num_epochs];
+  let mut offset = 0usize;
+  for (index, sample) in samples.iter().enumerate() {
+    let mut pos = 0usize;
+    while pos < sample.len() {
+      let epoch = core::cmp::min((offset + pos) / epoch_size, num_epochs - 1);
+      let end = if epoch + 1 == num_epochs {
+        sample.len()
+      } else {
+        core::cmp::min(sample.len(), (epoch + 1) * epoch_size - offset)
+      };
+      epochs[epoch].push((index, pos, end));
+      pos = end;
+    }
+    offset += sample.len();
+  }
+  let mut segments = Vec::<&[u8]>::new();
+  let mut size = 0usize;
+  let mut idle_epochs = 0usize;
+  let mut epoch = 0usize;
+  while size < max_size && idle_epochs < num_epochs {
+    let mut best = (0u64, 0usize, 0usize, 0usize);
+    for &(index, begin, end) in epochs[epoch].iter() {
+      let (score, start, len) = BestSegment(samples[index], begin, end, segment_size, &freqs);
+      if score > best.0 {
+        best = (score, index, start, len);
+      }
+    }
+    epoch = (epoch + 1) % num_epochs;
+    if best.0 == 0 {
+      idle_epochs += 1;
+      continue;
+    }
+    idle_epochs = 0;
+    let segment = &samples[best.1][best.2..best.2 + best.3];
+    for pos in 0..segment.len() - DMER_SIZE + 1 {
+      freqs.remove(&Dmer(segment, pos));
+    }
+    size += segment.len();
+    segments.push(segment);
+  }
+  let mut dictionary = Vec::<u8>::with_capacity(size);
+  for segment in segments.iter().rev() {
+    dictionary.extend_from_slice(segment);
+  }
+  if dictionary.len() > max_size {
+    let excess = dictionary.len() - max_size;
+    dictionary.drain(..excess);
+  }
+  dictionary
+}
+
+// Total compressed size of the samples without and with the dictionary.
+pub fn evaluate_dictionary(samples: &[&[u8]],
+                           dictionary: &[u8],
+                           params: &BrotliEncoderParams)
+                           -> Result<(usize, usize), io::Error> {
+  let mut baseline = 0usize;
+  let mut with_dictionary = 0usize;
+  for sample in samples.iter() {
+    baseline += BrotliCompressCustomDict(&mut &sample[..], &mut io::sink(), params, &[])?;
+    with_dictionary += BrotliCompressCustomDict(&mut &sample[..], &mut io::sink(), params, dictionary)?;
+  }
+  Ok((baseline, with_dictionary))
+}
+
+// Trains a dictionary of at most max_size bytes, scoring candidates by trial
+// compression with params.
+pub fn train_dictionary_with_params(samples: &[&[u8]],
+                                    max_size: usize,
+                                    params: &BrotliEncoderParams)
+                                    -> Result<Vec<u8>, io::Error> {
+  let freqs = CountDmerFrequencies(samples);
+  let mut best = Vec::<u8>::new();
+  let mut best_size = usize::max_value();
+  for segment_size in kSegmentSizes.iter() {
+    if *segment_size > max_size && !best.is_empty() {
+      break;
+    }
+    let dictionary = BuildDictionary(samples, max_size, core::cmp::min(*segment_size, core::cmp::max(max_size, DMER_SIZE)), &freqs);
+    let mut compressed_size = 0usize;
+    for sample in samples.iter() {
+      compressed_size += BrotliCompressCustomDict(&mut &sample[..], &mut io::sink(), params, &dictionary[..])?;
+    }
+    if compressed_size < best_size {
+      best_size = compressed_size;
+      best = dictionary;
+    }
+  }
+  Ok(best)
+}
+
+pub fn train_dictionary(samples: &[&[u8]], max_size: usize) -> Result<Vec<u8>, io::Error> {
+  let mut params = BrotliEncoderInitParams();
+  params.quality = 9;
+  train_dictionary_with_params(samples, max_size, &params)
+}
+
+mod test {
+  #[allow(unused_imports)]
+  use std::vec::Vec;
+  #[allow(unused)]
+  fn make_samples() -> Vec<Vec<u8>> {
+    let mut samples = Vec::new();
+    for i in 0..24 {
+      let mut sample = Vec::new();
+      sample.extend_from_slice(b"{\"status\": \"ok\", \"content-type\": \"application/json\", \"records\": [");
+      for j in 0..(i % 5) + 1 {
+        let entry = format!("{{\"id\": {}, \"owner\": \"user{}\", \"permissions\": [\"read\", \"write\"]}}, ",
+                            i * 31 + j * 7, (i * 13 + j) % 17);
+        sample.extend_from_slice(entry.as_bytes());
+      }
+      sample.extend_from_slice(b"], \"pagination\": {\"next\": null, \"previous\": null}}");
+      samples.push(sample);
+    }
+    samples
+  }
+  #[test]
+  fn test_train_dictionary_saves_space() {
+    let samples = make_samples();
+    let refs: Vec<&[u8]> = samples.iter().map(|s| &s[..]).collect();
+    let dictionary = super::train_dictionary(&refs[..], 1024).unwrap();
+    assert!(!dictionary.is_empty());
+    assert!(dictionary.len() <= 1024);
+    let mut params = super::BrotliEncoderInitParams();
+    params.quality = 9;
+    let (baseline, with_dictionary) = super::evaluate_dictionary(&refs[..], &dictionary[..], &params).unwrap();
+    assert!(with_dictionary * 2 < baseline);
+  }
+  #[test]
+  fn test_train_dictionary_tiny_inputs() {
+    let refs: [&[u8]; 2] = [b"abc", b""];
+    assert_eq!(super::train_dictionary(&refs[..], 1024).unwrap().len(), 0);
+    let dictionary = super::train_dictionary(&[&b"abcdefghijabcdefghij"[..]; 3][..], 4).unwrap();
+    assert!(dictionary.len() <= 4);
+  }
+}
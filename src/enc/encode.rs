@@ -6,7 +6,8 @@ use super::backward_references::{BrotliCreateBackwardReferences, Struct1, UnionH
                                  BrotliEncoderParams, BrotliEncoderMode, BrotliHasherParams, H2Sub,
                                  H3Sub, H4Sub, H5Sub, H6Sub, H54Sub, AdvHasher, BasicHasher, H9,
                                  H9_BUCKET_BITS, H9_BLOCK_SIZE, H9_BLOCK_BITS, H9_NUM_LAST_DISTANCES_TO_CHECK,
-                                 AnyHasher, HowPrepared, StoreLookaheadThenStore};
+                                 AnyHasher, HowPrepared, StoreLookaheadThenStore,
+                                 kDistanceCacheIndex, kDistanceCacheOffset};
 
 use super::vectorization::Mem256f;
 use super::interface;
@@ -18,17 +19,22 @@ use super::block_split::BlockSplit;
 use super::brotli_bit_stream::{BrotliBuildAndStoreHuffmanTreeFast, BrotliStoreHuffmanTree,
                                BrotliStoreMetaBlock, BrotliStoreMetaBlockFast,
                                BrotliStoreMetaBlockTrivial, BrotliStoreUncompressedMetaBlock,
-                               MetaBlockSplit, RecoderState};
+                               MetaBlockSplit, RecoderState, BrotliMetaBlockStats,
+                               BrotliMetaBlockStoragePath, BrotliWriteBitsFailed,
+                               BROTLI_WRITE_BITS_FAILED};
                                
 use enc::input_pair::InputReferenceMut;
-use super::command::{Command, GetLengthCode, BrotliDistanceParams};
+use super::command::{Command, GetLengthCode, BrotliDistanceParams, CommandCopyLen, CommandRestoreDistanceCode,
+                     ComputeDistanceCode, InitCommand};
 use super::compress_fragment::BrotliCompressFragmentFast;
 use super::compress_fragment_two_pass::{BrotliCompressFragmentTwoPass, BrotliWriteBits};
 #[allow(unused_imports)]
 use super::entropy_encode::{BrotliConvertBitDepthsToSymbols, BrotliCreateHuffmanTree, HuffmanTree};
 use super::cluster::{HistogramPair};
 use super::metablock::{BrotliBuildMetaBlock, BrotliBuildMetaBlockGreedy, BrotliOptimizeHistograms, BrotliInitDistanceParams};
-use super::static_dict::{BrotliGetDictionary, kNumDistanceCacheEntries};
+use super::shared_dictionary::{SharedDictionaryWords, BrotliParseSharedDictionary,
+                               InitializeSharedDictionaryWords};
+use super::static_dict::{BrotliGetDictionary, kNumDistanceCacheEntries, BROTLI_DICTIONARY_TRANSFORM_ALL};
 use super::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance, CostAccessors};
 use super::super::alloc;
 use super::super::alloc::{SliceWrapper, SliceWrapperMut};
@@ -89,6 +95,8 @@ pub enum BrotliEncoderParameter {
   BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING = 4,
     BROTLI_PARAM_SIZE_HINT = 5,
     BROTLI_PARAM_LARGE_WINDOW = 6,
+  BROTLI_PARAM_NPOSTFIX = 7,
+  BROTLI_PARAM_NDIRECT = 8,
   BROTLI_PARAM_Q9_5 = 150,
   BROTLI_METABLOCK_CALLBACK = 151,
   BROTLI_PARAM_STRIDE_DETECTION_QUALITY = 152,
@@ -105,6 +113,15 @@ pub enum BrotliEncoderParameter {
   BROTLI_PARAM_CM_SPEED_LOW = 164,
   BROTLI_PARAM_CM_SPEED_LOW_MAX = 165,
   BROTLI_PARAM_AVOID_DISTANCE_PREFIX_SEARCH = 166,
+  BROTLI_PARAM_EXHAUSTIVE_MATCH_SEARCH = 167,
+  BROTLI_PARAM_DISABLE_STATIC_DICTIONARY = 168,
+  BROTLI_PARAM_STATIC_DICTIONARY_MIN_WORD_LEN = 169,
+  BROTLI_PARAM_STATIC_DICTIONARY_TRANSFORMS = 170,
+  BROTLI_PARAM_OPTIMAL_HUFFMAN_CODES = 171,
+  BROTLI_PARAM_EXHAUSTIVE_DISTANCE_PREFIX_SEARCH = 172,
+  BROTLI_PARAM_ZOPFLI_ITERATIONS = 173,
+  BROTLI_PARAM_ZOPFLI_MAX_CANDIDATES = 174,
+  BROTLI_PARAM_ZOPFLI_MAX_LEN = 175,
 }
 
 pub struct RingBuffer<AllocU8: alloc::Allocator<u8>> {
@@ -144,7 +161,7 @@ fn GetNextOutInternal<'a>(
             return &mut storage[offset as usize..],
         &NextOut::TinyBuf(offset) =>
             return &mut tiny_buf[offset as usize..],
-        &NextOut::None => panic!("Next out: Null ptr deref"),
+        &NextOut::None => &mut [],
     }
 }
 macro_rules! GetNextOut {
@@ -160,7 +177,7 @@ fn NextOutIncrement(next_out :&NextOut, inc : i32) -> NextOut{
             return NextOut::DynamicStorage((offset as i32 + inc) as u32),
         &NextOut::TinyBuf(offset) =>
             return NextOut::TinyBuf((offset as i32 + inc) as u32),
-        &NextOut::None => panic!("Next out: Null ptr deref"),
+        &NextOut::None => NextOut::None,
     }
 }
 fn IsNextOutNull(next_out :&NextOut) -> bool {
@@ -186,6 +203,8 @@ pub struct BrotliEncoderStateStruct<AllocU8: alloc::Allocator<u8>,
   pub m32: AllocU32,
   pub mc: AllocCommand,
   pub hasher_: UnionHasher<AllocU16, AllocU32>,
+  // word list of a shared dictionary, searched instead of the built-in one when set
+  pub shared_words_: SharedDictionaryWords<AllocU8, AllocU16>,
   pub input_pos_: u64,
   pub ringbuffer_: RingBuffer<AllocU8>,
   pub cmd_alloc_size_: usize,
@@ -224,6 +243,21 @@ pub struct BrotliEncoderStateStruct<AllocU8: alloc::Allocator<u8>,
   pub command_scratch_space: <HistogramCommand as CostAccessors>::i32vec,
   pub distance_scratch_space: <HistogramDistance as CostAccessors>::i32vec,
   pub recoder_state: RecoderState,
+  // the quality 11 parse kept next to the exhaustive one, see exhaustive_match_search
+  pub fallback_parse_: FallbackParse<AllocCommand>,
+}
+
+// The commands of a second parse of the pending input and the state they leave behind. With
+// exhaustive_match_search, the suffix array parse and the quality 11 parse of every metablock are
+// both priced by storing them, and the smaller one is written: the exhaustive parse has more
+// matches to choose from, but the zopfli cost model can misjudge them, so it is not always the
+// smaller of the two.
+pub struct FallbackParse<AllocCommand: alloc::Allocator<Command>> {
+  pub commands_: AllocCommand::AllocatedMemory,
+  pub num_commands_: usize,
+  pub num_literals_: usize,
+  pub last_insert_len_: usize,
+  pub dist_cache_: [i32; 16],
 }
 
 
@@ -352,10 +386,58 @@ pub fn BrotliEncoderSetParameter<AllocU8: alloc::Allocator<u8>,
     (*state).params.large_window = value != 0;
     return 1i32;
   }
+  // an invalid NPOSTFIX, NDIRECT pair falls back to 0, 0 in ChooseDistanceParams
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_NPOSTFIX as (i32) {
+    (*state).params.dist.distance_postfix_bits = value;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_NDIRECT as (i32) {
+    (*state).params.dist.num_direct_distance_codes = value;
+    return 1i32;
+  }
   if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_AVOID_DISTANCE_PREFIX_SEARCH as (i32) {
     (*state).params.avoid_distance_prefix_search = value != 0;
     return 1i32;
   }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_EXHAUSTIVE_DISTANCE_PREFIX_SEARCH as (i32) {
+    (*state).params.exhaustive_distance_prefix_search = value != 0;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_EXHAUSTIVE_MATCH_SEARCH as (i32) {
+    (*state).params.exhaustive_match_search = value != 0;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_OPTIMAL_HUFFMAN_CODES as (i32) {
+    (*state).params.optimal_huffman_codes = value != 0;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_ZOPFLI_ITERATIONS as (i32) {
+    (*state).params.zopfli_iterations = value;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_ZOPFLI_MAX_CANDIDATES as (i32) {
+    (*state).params.zopfli_max_candidates = value;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_ZOPFLI_MAX_LEN as (i32) {
+    (*state).params.zopfli_max_len = value;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_DISABLE_STATIC_DICTIONARY as (i32) {
+    (*state).params.hasher.disable_static_dictionary = value != 0;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_STATIC_DICTIONARY_MIN_WORD_LEN as (i32) {
+    (*state).params.hasher.static_dictionary_min_word_len = value as i32;
+    return 1i32;
+  }
+  if p as (i32) == BrotliEncoderParameter::BROTLI_PARAM_STATIC_DICTIONARY_TRANSFORMS as (i32) {
+    if value & !BROTLI_DICTIONARY_TRANSFORM_ALL != 0 {
+      return 0i32;
+    }
+    (*state).params.hasher.static_dictionary_transforms = value;
+    return 1i32;
+  }
   0i32
 }
 /* "Large Window Brotli" */
@@ -391,6 +473,14 @@ pub fn BrotliEncoderInitParams() -> BrotliEncoderParams {
            log_meta_block: false,
            large_window:false,
            avoid_distance_prefix_search:false,
+           exhaustive_distance_prefix_search:false,
+           exhaustive_match_search:false,
+           optimal_huffman_codes:false,
+           zopfli_iterations:0,
+           zopfli_max_candidates:0,
+           zopfli_max_len:0,
+           compound_dictionary_size:0,
+           compound_dictionary_loaded:0,
            quality: 11,
            q9_5: false,
            lgwin: 22i32,
@@ -409,6 +499,9 @@ pub fn BrotliEncoderInitParams() -> BrotliEncoderParams {
              hash_len: 5,
              num_last_distances_to_check: 16,
              literal_byte_score: 0,
+             disable_static_dictionary: false,
+             static_dictionary_min_word_len: 0,
+             static_dictionary_transforms: BROTLI_DICTIONARY_TRANSFORM_ALL,
            },
          };
 }
@@ -419,12 +512,13 @@ fn ExtendLastCommand<AllocU8:alloc::Allocator<u8>,
                      AllocI32:alloc::Allocator<i32>,
                      AllocCommand:alloc::Allocator<Command>>(
     s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
+    data: &[u8],
+    mask: u32,
     bytes: &mut u32,
     wrapped_last_processed_pos: &mut u32
 ) {
     let last_command = &mut s.commands_.slice_mut()[s.num_commands_ - 1];
    
-    let mask = s.ringbuffer_.mask_;
     let max_backward_distance:u64 = (1u64 << s.params.lgwin) - BROTLI_WINDOW_GAP as u64;
     let last_copy_len = u64::from(last_command.copy_len_) & 0x1ffffff;
     let last_processed_pos:u64 = s.last_processed_pos_ - last_copy_len;
@@ -439,8 +533,8 @@ fn ExtendLastCommand<AllocU8:alloc::Allocator<u8>,
         distance_code as u64 - (BROTLI_NUM_DISTANCE_SHORT_CODES - 1) as u64 == cmd_dist) {
         if (cmd_dist <= max_distance) {
             while (*bytes != 0 &&
-                   s.ringbuffer_.data_mo.slice()[s.ringbuffer_.buffer_index + (*wrapped_last_processed_pos as usize & mask as usize)] ==
-                   s.ringbuffer_.data_mo.slice()[s.ringbuffer_.buffer_index + (((*wrapped_last_processed_pos as usize).wrapping_sub(cmd_dist as usize)) & mask as usize)]) {
+                   data[*wrapped_last_processed_pos as usize & mask as usize] ==
+                   data[((*wrapped_last_processed_pos as usize).wrapping_sub(cmd_dist as usize)) & mask as usize]) {
                 last_command.copy_len_+=1;
                 (*bytes)-=1;
                 (*wrapped_last_processed_pos)+=1;
@@ -495,6 +589,7 @@ pub fn BrotliEncoderCreateInstance<AllocU8: alloc::Allocator<u8>,
     storage_size_: 0usize,
     storage_: AllocU8::AllocatedMemory::default(),
     hasher_: UnionHasher::<AllocU16, AllocU32>::default(),
+    shared_words_: SharedDictionaryWords::<AllocU8, AllocU16>::default(),
     large_table_: AllocI32::AllocatedMemory::default(),
 //    large_table_size_: 0usize,
     cmd_code_numbits_: 0usize,
@@ -528,6 +623,13 @@ pub fn BrotliEncoderCreateInstance<AllocU8: alloc::Allocator<u8>,
     command_scratch_space: HistogramCommand::make_nnz_storage(),
     distance_scratch_space: HistogramDistance::make_nnz_storage(),
     recoder_state: RecoderState::new(),
+    fallback_parse_: FallbackParse::<AllocCommand> {
+      commands_: AllocCommand::AllocatedMemory::default(),
+      num_commands_: 0,
+      num_literals_: 0,
+      last_insert_len_: 0,
+      dist_cache_: cache,
+    },
   }
 }
 
@@ -585,9 +687,12 @@ fn BrotliEncoderCleanupState<AllocU8: alloc::Allocator<u8>,
   {
     s.mc.free_cell(core::mem::replace(&mut (*s).commands_,
                                       AllocCommand::AllocatedMemory::default()));
+    s.mc.free_cell(core::mem::replace(&mut (*s).fallback_parse_.commands_,
+                                      AllocCommand::AllocatedMemory::default()));
   }
   RingBufferFree(&mut s.m8, &mut (*s).ringbuffer_);
   DestroyHasher(&mut s.m16, &mut s.m32, &mut (*s).hasher_);
+  (*s).shared_words_.free(&mut s.m8, &mut s.m16);
   {
     s.mi32.free_cell(core::mem::replace(&mut (*s).large_table_,
                                        AllocI32::AllocatedMemory::default()));
@@ -619,7 +724,17 @@ fn brotli_max_int(a: i32, b: i32) -> i32 {
 }
 
 fn SanitizeParams(params: &mut BrotliEncoderParams) {
+  if (*params).quality > 11i32 && !(*params).q9_5 {
+    // qualities past 11 select the suffix array match finder and the full distance parameter
+    // search on top of quality 11
+    (*params).exhaustive_match_search = true;
+    (*params).exhaustive_distance_prefix_search = true;
+  }
   (*params).quality = brotli_min_int(11i32, brotli_max_int(0i32, (*params).quality));
+  if (*params).quality < 11i32 || (*params).q9_5 {
+    // the suffix array and the fallback parse only work on the quality 11 zopfli path
+    (*params).exhaustive_match_search = false;
+  }
   if (*params).lgwin < 10i32 {
     (*params).lgwin = 10i32;
   } else if (*params).lgwin > 24i32 {
@@ -658,7 +773,7 @@ fn RingBufferSetup<AllocU8: alloc::Allocator<u8>>(params: &BrotliEncoderParams,
   *(&mut (*rb).total_size_) = (*rb).size_.wrapping_add((*rb).tail_size_);
 }
 
-fn EncodeWindowBits(lgwin: i32, large_window: bool, last_bytes: &mut u16, last_bytes_bits: &mut u8) {
+pub fn EncodeWindowBits(lgwin: i32, large_window: bool, last_bytes: &mut u16, last_bytes_bits: &mut u8) {
     if large_window {
         *last_bytes = (((lgwin & 0x3F) << 8) | 0x11) as u16;
         *last_bytes_bits = 14;
@@ -1037,12 +1152,16 @@ fn EnsureInitialized<AllocU8: alloc::Allocator<u8>,
 
 fn RingBufferInitBuffer<AllocU8: alloc::Allocator<u8>>(m: &mut AllocU8,
                                                        buflen: u32,
-                                                       rb: &mut RingBuffer<AllocU8>) {
+                                                       rb: &mut RingBuffer<AllocU8>) -> bool {
   static kSlackForEightByteHashingEverywhere: usize = 7usize;
-  let mut new_data =
-    m.alloc_cell(((2u32).wrapping_add(buflen) as (usize))
-                   .wrapping_add(kSlackForEightByteHashingEverywhere));
+  let new_size = ((2u32).wrapping_add(buflen) as (usize)).wrapping_add(kSlackForEightByteHashingEverywhere);
+  let mut new_data = m.alloc_cell(new_size);
   let mut i: usize;
+  if new_data.slice().len() < new_size {
+    // the allocator is out of memory: keep the old buffer
+    m.free_cell(new_data);
+    return false;
+  }
   if (*rb).data_mo.slice().len() != 0 {
     let lim: usize = ((2u32).wrapping_add((*rb).cur_size_) as (usize))
       .wrapping_add(kSlackForEightByteHashingEverywhere);
@@ -1064,6 +1183,7 @@ fn RingBufferInitBuffer<AllocU8: alloc::Allocator<u8>>(m: &mut AllocU8,
     }
     i = i.wrapping_add(1 as (usize));
   }
+  true
 }
 
 
@@ -1082,18 +1202,19 @@ fn RingBufferWriteTail<AllocU8: alloc::Allocator<u8>>(bytes: &[u8],
 fn RingBufferWrite<AllocU8: alloc::Allocator<u8>>(m: &mut AllocU8,
                                                   bytes: &[u8],
                                                   n: usize,
-                                                  rb: &mut RingBuffer<AllocU8>) {
+                                                  rb: &mut RingBuffer<AllocU8>) -> bool {
   if (*rb).pos_ == 0u32 && (n < (*rb).tail_size_ as (usize)) {
+    if !RingBufferInitBuffer(m, n as (u32), rb) {
+      return false;
+    }
     (*rb).pos_ = n as (u32);
-    RingBufferInitBuffer(m, (*rb).pos_, rb);
     (*rb).data_mo.slice_mut()[((*rb).buffer_index as (usize))..(((*rb).buffer_index as (usize)) + n)]
       .clone_from_slice(&bytes[..n]);
-    return;
+    return true;
   }
   if (*rb).cur_size_ < (*rb).total_size_ {
-    RingBufferInitBuffer(m, (*rb).total_size_, rb);
-    if !(0i32 == 0) {
-      return;
+    if !RingBufferInitBuffer(m, (*rb).total_size_, rb) {
+      return false;
     }
     (*rb).data_mo.slice_mut()[((*rb)
        .buffer_index
@@ -1139,6 +1260,7 @@ fn RingBufferWrite<AllocU8: alloc::Allocator<u8>>(m: &mut AllocU8,
   if (*rb).pos_ > 1u32 << 30i32 {
     (*rb).pos_ = (*rb).pos_ & (1u32 << 30i32).wrapping_sub(1u32) | 1u32 << 30i32;
   }
+  true
 }
 
 fn CopyInputToRingBuffer<AllocU8: alloc::Allocator<u8>,
@@ -1148,13 +1270,12 @@ fn CopyInputToRingBuffer<AllocU8: alloc::Allocator<u8>,
                          AllocCommand: alloc::Allocator<Command>>
   (s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
    input_size: usize,
-   input_buffer: &[u8]) {
+   input_buffer: &[u8]) -> bool {
   if EnsureInitialized(s) == 0 {
-    return;
+    return false;
   }
-  RingBufferWrite(&mut s.m8, input_buffer, input_size, &mut s.ringbuffer_);
-  if !(0i32 == 0) {
-    return;
+  if !RingBufferWrite(&mut s.m8, input_buffer, input_size, &mut s.ringbuffer_) {
+    return false;
   }
   (*s).input_pos_ = (*s).input_pos_.wrapping_add(input_size as u64);
   if (s.ringbuffer_).pos_ <= (s.ringbuffer_).mask_ {
@@ -1164,6 +1285,7 @@ fn CopyInputToRingBuffer<AllocU8: alloc::Allocator<u8>,
       *item = 0;
     }
   }
+  true
 }
 
 
@@ -1389,7 +1511,7 @@ fn BrotliMakeHasher<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<
 fn HasherReset<AllocU16:alloc::Allocator<u16>,
 AllocU32:alloc::Allocator<u32>>(t: &mut UnionHasher<AllocU16, AllocU32>){
   match t {
-    &mut UnionHasher::Uninit => {}
+    &mut UnionHasher::Uninit(_) => {}
     _ => (t.GetHasherCommon()).is_prepared_ = 0i32,
   };
 }
@@ -1407,10 +1529,10 @@ fn HasherSetup<AllocU16:alloc::Allocator<u16>,
                                                data: &[u8],
                                                position: usize,
                                                input_size: usize,
-                                               is_last: i32){
+                                               is_last: i32) -> bool {
   let one_shot: i32 = (position == 0usize && (is_last != 0)) as (i32);
   let is_uninit = match (handle) {
-    &mut UnionHasher::Uninit => true,
+    &mut UnionHasher::Uninit(_) => true,
     _ => false,
   };
   if is_uninit {
@@ -1419,6 +1541,10 @@ fn HasherSetup<AllocU16:alloc::Allocator<u16>,
     //alloc_size = HasherSize(params, one_shot, input_size);
     //xself = BrotliAllocate(m, alloc_size.wrapping_mul(::std::mem::size_of::<u8>()))
     *handle = BrotliMakeHasher(m16, m32, params);
+    if !handle.is_allocated() {
+      handle.free(m16, m32);
+      return false;
+    }
     handle.GetHasherCommon().params = (*params).hasher;
     HasherReset(handle); // this sets everything to zero, unlike in C
     handle.GetHasherCommon().is_prepared_ = 1;
@@ -1434,6 +1560,7 @@ fn HasherSetup<AllocU16:alloc::Allocator<u16>,
       }
     }
   }
+  true
 }
 
 fn HasherPrependCustomDictionary<AllocU16: alloc::Allocator<u16>, AllocU32: alloc::Allocator<u32>>
@@ -1443,7 +1570,9 @@ fn HasherPrependCustomDictionary<AllocU16: alloc::Allocator<u16>, AllocU32: allo
    params: &mut BrotliEncoderParams,
    size: usize,
    dict: &[u8]) {
-  HasherSetup(m16, m32, handle, params, dict, 0usize, size, 0i32);
+  if !HasherSetup(m16, m32, handle, params, dict, 0usize, size, 0i32) {
+    return;
+  }
   match handle {
     &mut UnionHasher::H2(ref mut hasher) => StoreLookaheadThenStore(hasher, size, dict),
     &mut UnionHasher::H3(ref mut hasher) => StoreLookaheadThenStore(hasher, size, dict),
@@ -1453,7 +1582,7 @@ fn HasherPrependCustomDictionary<AllocU16: alloc::Allocator<u16>, AllocU32: allo
     &mut UnionHasher::H9(ref mut hasher) => StoreLookaheadThenStore(hasher, size, dict),
     &mut UnionHasher::H54(ref mut hasher) => StoreLookaheadThenStore(hasher, size, dict),
     &mut UnionHasher::H10(ref mut hasher) => StoreLookaheadThenStore(hasher, size, dict),
-    &mut UnionHasher::Uninit => panic!("Uninitialized"),
+    &mut UnionHasher::Uninit(_) => {}
   }
 }
 
@@ -1477,7 +1606,9 @@ pub fn BrotliEncoderSetCustomDictionary<AllocU8: alloc::Allocator<u8>,
     dict = &dict[(size.wrapping_sub(max_dict_size) as (usize))..];
     dict_size = max_dict_size;
   }
-  CopyInputToRingBuffer(s, dict_size, dict);
+  if !CopyInputToRingBuffer(s, dict_size, dict) {
+    return;
+  }
   (*s).last_flush_pos_ = dict_size as u64;
   (*s).last_processed_pos_ = dict_size as u64;
   if dict_size > 0 {
@@ -1497,6 +1628,41 @@ pub fn BrotliEncoderSetCustomDictionary<AllocU8: alloc::Allocator<u8>,
 }
 
 
+// Sets a shared dictionary in the serialized Shared Brotli format: its word list and
+// transforms replace the built-in ones in the dictionary search, and its LZ77 prefix is the
+// compound dictionary, which distances past the window reach before the static dictionary.
+// Only the part of the prefix within a window of the stream start is searched. Context
+// dependent dictionaries are not supported. The stream can only be decoded with the same
+// shared dictionary.
+pub fn BrotliEncoderSetSharedDictionary<AllocU8: alloc::Allocator<u8>,
+                                        AllocU16: alloc::Allocator<u16>,
+                                        AllocU32: alloc::Allocator<u32>,
+                                        AllocI32: alloc::Allocator<i32>,
+                                        AllocCommand: alloc::Allocator<Command>>
+  (s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
+   serialized: &[u8]) -> i32 {
+  let dict = match BrotliParseSharedDictionary(serialized) {
+    Some(dict) => dict,
+    None => return 0i32,
+  };
+  if dict.context_based || (*s).input_pos_ != 0 {
+    return 0i32;
+  }
+  (*s).shared_words_.free(&mut s.m8, &mut s.m16);
+  (*s).shared_words_ = InitializeSharedDictionaryWords(&mut s.m8, &mut s.m16, &dict);
+  if dict.prefix.len() != 0 {
+    // the matchers find the tail of the prefix in front of the stream, but literal contexts
+    // start from zeros as the prefix is not in the decoder's window
+    BrotliEncoderSetCustomDictionary(s, dict.prefix.len(), dict.prefix);
+    (*s).prev_byte_ = 0;
+    (*s).prev_byte2_ = 0;
+    (*s).params.compound_dictionary_size = dict.prefix.len();
+    (*s).params.compound_dictionary_loaded = (*s).last_processed_pos_ as usize;
+  }
+  1i32
+}
+
+
 pub fn BrotliEncoderMaxCompressedSize(input_size: usize) -> usize {
   let num_large_blocks: usize = input_size >> 14i32;
   let tail: usize = input_size.wrapping_sub(num_large_blocks << 24i32);
@@ -1525,16 +1691,19 @@ fn InitOrStitchToPreviousBlock<AllocU16: alloc::Allocator<u16>, AllocU32: alloc:
    params: &mut BrotliEncoderParams,
    position: usize,
    input_size: usize,
-   is_last: i32) {
-  HasherSetup(m16,
-              m32,
-              handle,
-              params,
-              data,
-              position,
-              input_size,
-              is_last);
+   is_last: i32) -> bool {
+  if !HasherSetup(m16,
+                  m32,
+                  handle,
+                  params,
+                  data,
+                  position,
+                  input_size,
+                  is_last) {
+    return false;
+  }
   handle.StitchToPreviousBlock(input_size, position, data, mask);
+  true
 }
 
 pub fn InitInsertCommand(xself: &mut Command, insertlen: usize) {
@@ -2089,7 +2258,7 @@ pub fn BrotliEncoderCompress<AllocU8: alloc::Allocator<u8>,
   let input_start = input_buffer;
   let output_start = encoded_buffer;
   let max_out_size: usize = BrotliEncoderMaxCompressedSize(input_size);
-  if out_size == 0usize {
+  if out_size == 0usize || out_size > output_start.len() || input_size > input_start.len() {
     return 0i32;
   }
   if input_size == 0usize {
@@ -2097,21 +2266,7 @@ pub fn BrotliEncoderCompress<AllocU8: alloc::Allocator<u8>,
     output_start[0] = 6;
     return 1i32;
   }
-  let mut is_fallback: i32 = 0i32;
-    if quality == 10i32 {
-    //let lg_win: i32 = brotli_min_int(BROTLI_LARGE_MAX_WINDOW_BITS, brotli_max_int(16i32, lgwin));
-      panic!("Unimplemented: need to set 9.5 here");
-    //let mut ok: i32 = BrotliCompressBufferQuality10(lg_win,
-    //                                                input_size,
-    //                                                input_buffer,
-    //                                                encoded_size,
-    //                                                encoded_buffer);
-    //if ok == 0 || max_out_size != 0 && (*encoded_size > max_out_size) {
-    //  is_fallback = 1i32;
-    //} else {
-    //  return 1i32;
-    //}
-  }
+  let is_fallback: i32 = 0i32;
   if is_fallback == 0 {
     
     let mut s_orig = BrotliEncoderCreateInstance(core::mem::replace(m8, empty_m8),
@@ -2119,16 +2274,11 @@ pub fn BrotliEncoderCompress<AllocU8: alloc::Allocator<u8>,
                                                  core::mem::replace(mi32, empty_mi32),
                                                  core::mem::replace(m32, empty_m32),
                                                  core::mem::replace(mc, empty_mc));
-    let mut result: i32;
+    let mut result: i32 = 1i32;
     {
       let s = &mut s_orig;
-      let mut available_in: usize = input_size;
-      let mut next_in_array: &[u8] = input_buffer;
-      let mut next_in_offset: usize = 0;  
-      let mut available_out: usize = *encoded_size;
-      let mut next_out_array: &mut [u8] = output_start;
-      let mut next_out_offset: usize = 0;
-      let mut total_out = Some(0usize);
+      let mut next_in_offset: usize = 0;
+      let mut total_out: usize = 0;
       BrotliEncoderSetParameter(s,
                                 BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
                                 quality as (u32));
@@ -2142,23 +2292,52 @@ pub fn BrotliEncoderCompress<AllocU8: alloc::Allocator<u8>,
       if lgwin > BROTLI_MAX_WINDOW_BITS as i32 {
           BrotliEncoderSetParameter(s, BrotliEncoderParameter::BROTLI_PARAM_LARGE_WINDOW, 1);
       }
-      result = BrotliEncoderCompressStream(s,
+      if input_size <= BROTLI_MAX_WHOLE_BUFFER_SIZE {
+        // the input is encoded in place, without a copy into the ring buffer
+        while BrotliEncoderIsFinished(s) == 0 {
+          if BrotliEncoderCompressWholeBuffer(s,
+                                              m64,
+                                              mf64, mfv, mpdf, msc, mhl, mhc, mhd, mhp, mct, mht, mzn,
+                                              &input_buffer[..input_size],
+                                              &mut next_in_offset,
+                                              metablock_callback,
+                                              &mut |_stats: &BrotliMetaBlockStats| ()) == 0 {
+            result = 0i32;
+            break;
+          }
+          let mut size: usize = 0;
+          let output = BrotliEncoderTakeOutput(s, &mut size);
+          if total_out + size > out_size {
+            result = 0i32;
+            break;
+          }
+          output_start[total_out..total_out + size].clone_from_slice(output);
+          total_out += size;
+        }
+      } else {
+        let mut available_in: usize = input_size;
+        let mut next_in_array: &[u8] = input_buffer;
+        let mut available_out: usize = out_size;
+        let mut next_out_offset: usize = 0;
+        let mut stream_total_out = Some(0usize);
+        result = BrotliEncoderCompressStream(s,
                                              m64,
                                              mf64, mfv, mpdf, msc, mhl, mhc, mhd, mhp, mct, mht, mzn,
                                              BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
                                              &mut available_in,
                                              &mut next_in_array,
-                                             &mut next_in_offset,  
+                                             &mut next_in_offset,
                                              &mut available_out,
-                                             &mut next_out_array,
+                                             &mut &mut output_start[..],
                                              &mut next_out_offset,
-                                             &mut total_out,
+                                             &mut stream_total_out,
                                              metablock_callback);
-      if BrotliEncoderIsFinished(s) == 0 {
-        result = 0i32;
+        if BrotliEncoderIsFinished(s) == 0 {
+          result = 0i32;
+        }
+        total_out = next_out_offset;
       }
-       
-      *encoded_size = total_out.unwrap();
+      *encoded_size = total_out;
       BrotliEncoderDestroyInstance(s);
     }
     core::mem::replace(m8, s_orig.m8);
@@ -2166,13 +2345,11 @@ pub fn BrotliEncoderCompress<AllocU8: alloc::Allocator<u8>,
     core::mem::replace(m32, s_orig.m32);
     core::mem::replace(mi32, s_orig.mi32);
     core::mem::replace(mc, s_orig.mc); // unreplace these things so the empty ones are the ones thrown out
-    if result == 0 || max_out_size != 0 && (*encoded_size > max_out_size) {
-        is_fallback = 1i32;
-    } else {
+    if result != 0 && (max_out_size == 0 || *encoded_size <= max_out_size) {
       return 1i32;
     }
   }
-  assert!(is_fallback != 0);
+  // fall back to an uncompressed stream
   *encoded_size = 0usize;
   if max_out_size == 0 {
     return 0i32;
@@ -2318,12 +2495,14 @@ fn GetBrotliStorage<AllocU8: alloc::Allocator<u8>,
                                                                                                   AllocU32,
                                                                                                   AllocI32, 
                                                                                                   AllocCommand>,
-                                                                    size: usize) {
+                                                                    size: usize) -> bool {
   if (*s).storage_size_ < size {
     (*s).m8.free_cell(core::mem::replace(&mut (*s).storage_, AllocU8::AllocatedMemory::default()));
     (*s).storage_ = (*s).m8.alloc_cell(size);
-    (*s).storage_size_ = size;
+    // an allocator out of memory hands back a shorter cell
+    s.storage_size_ = core::cmp::min(size, s.storage_.slice().len());
   }
+  s.storage_size_ >= size
 }
 
 fn MaxHashTableSize(quality: i32) -> usize {
@@ -2376,6 +2555,11 @@ fn GetHashTableInternal<'a, AllocI32: alloc::Allocator<i32>>(mi32: &mut AllocI32
     }
     table = large_table_.slice_mut();
   }
+  if table.len() < htsize {
+    // the allocator is out of memory
+    *table_size = 0;
+    return table;
+  }
   *table_size = htsize;
   for item in table[..htsize].iter_mut() {
       *item = 0;
@@ -2556,7 +2740,6 @@ fn ShouldUseComplexStaticContextMap(input: &[u8],
     entropy[1] = ShannonEntropy(&combined_histo[..], 32, &mut dummy);
     entropy[2] = 0.0 as super::util::floatX;
     for i in 0..13 {
-      assert!(i < 13);
       entropy[2] += ShannonEntropy(&context_histo[i][..], 32, &mut dummy);
     }
     entropy[0] = (1.0 as super::util::floatX) / (total as super::util::floatX);
@@ -2711,6 +2894,7 @@ fn WriteMetaBlockInternal<AllocU8: alloc::Allocator<u8>,
                                      storage,
                                      false,
                                      cb);
+    recoder_state.num_metablocks += 1;
     return;
   }
   last_bytes = ((storage[1] as u16) << 8) | storage[0] as u16;
@@ -2796,7 +2980,7 @@ fn WriteMetaBlockInternal<AllocU8: alloc::Allocator<u8>,
                                  num_commands,
                                  &mut mb);
     } else {
-      BrotliBuildMetaBlock(m8, m16, m32, mf64, mfv, mpdf, mhl, mhc, mhd, mhp, mct,
+      BrotliBuildMetaBlock(m8, m16, m32, mf64, mfv, mpdf, mhl, mhc, mhd, mhp, mct, mht,
                            data,
                            wrapped_last_flush_pos as (usize),
                            mask,
@@ -2839,7 +3023,7 @@ fn WriteMetaBlockInternal<AllocU8: alloc::Allocator<u8>,
                          cb);
     mb.destroy(m8, m32, mhl, mhc, mhd);
   }
-  if bytes.wrapping_add(4usize) < *storage_ix >> 3i32 {
+  if !BrotliWriteBitsFailed(*storage_ix) && bytes.wrapping_add(4usize) < *storage_ix >> 3i32 {
       dist_cache[..4].clone_from_slice(&saved_dist_cache[..4]);
       //memcpy(dist_cache,
       //     saved_dist_cache,
@@ -2866,6 +3050,7 @@ fn WriteMetaBlockInternal<AllocU8: alloc::Allocator<u8>,
                                        true,
                                        cb);
   }
+  recoder_state.num_metablocks += 1;
 }
 
 fn ChooseDistanceParams(params: &mut BrotliEncoderParams) {
@@ -2947,18 +3132,303 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
     out_size: &mut usize,
     callback: &mut MetablockCallback
 //              mut output: &'a mut &'a mut [u8]
+) -> i32 where MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                        &mut [interface::StaticCommand],
+                                        interface::InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand){
+  if EnsureInitialized(s) == 0 {
+    return 0i32;
+  }
+  // the ring buffer is moved out while the input it holds is encoded
+  let ringbuffer_data = core::mem::replace(&mut (*s).ringbuffer_.data_mo, AllocU8::AllocatedMemory::default());
+  let buffer_index = (*s).ringbuffer_.buffer_index;
+  let mask = (*s).ringbuffer_.mask_;
+  let ret = EncodeDataInternal(s, m64, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht, mzn,
+                               &ringbuffer_data.slice()[buffer_index..],
+                               mask,
+                               is_last,
+                               force_flush,
+                               out_size,
+                               callback);
+  (*s).ringbuffer_.data_mo = ringbuffer_data;
+  ret
+}
+
+fn GrowCommands<AllocCommand: alloc::Allocator<Command>>(mc: &mut AllocCommand,
+                                                        commands: &mut AllocCommand::AllocatedMemory,
+                                                        num_commands: usize,
+                                                        new_size: usize) -> bool {
+  let mut new_commands = mc.alloc_cell(new_size);
+  if new_commands.slice().len() < new_size {
+    // the allocator is out of memory: keep the old commands
+    mc.free_cell(new_commands);
+    return false;
+  }
+  if commands.slice().len() != 0 {
+    new_commands.slice_mut()[..num_commands].clone_from_slice(&commands.slice()[..num_commands]);
+    mc.free_cell(core::mem::replace(commands, AllocCommand::AllocatedMemory::default()));
+  }
+  *commands = new_commands;
+  true
+}
+
+// The distance cmd copies from, given the last four distances before it.
+fn CommandDistance(cmd: &Command, dist: &BrotliDistanceParams, dist_cache: &[i32]) -> usize {
+  let distance_code = CommandRestoreDistanceCode(cmd, dist) as usize;
+  if distance_code < BROTLI_NUM_DISTANCE_SHORT_CODES as usize {
+    (dist_cache[kDistanceCacheIndex[distance_code] as usize] +
+     i32::from(kDistanceCacheOffset[distance_code])) as usize
+  } else {
+    distance_code - (BROTLI_NUM_DISTANCE_SHORT_CODES as usize - 1)
+  }
+}
+
+// Splits the copies since the last flush that start in the loaded tail of a shared dictionary
+// prefix and run on into the stream. The matchers see the prefix and the stream as one buffer, but
+// a decoder copies from the prefix apart from its window and rejects a copy that leaves the prefix.
+// Both parts keep the distance, and a part of a single byte becomes a literal. Distance codes from
+// the first split copy on are chosen again, as a copy that turns into literals leaves the distance
+// cache as it was.
+fn SplitCompoundDictionaryCopies<AllocU8: alloc::Allocator<u8>,
+                                 AllocU16: alloc::Allocator<u16>,
+                                 AllocU32: alloc::Allocator<u32>,
+                                 AllocI32: alloc::Allocator<i32>,
+                                 AllocCommand: alloc::Allocator<Command>>(
+    s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>) {
+  let prefix_end = (*s).params.compound_dictionary_loaded;
+  let max_backward_limit = (1usize << (*s).params.lgwin).wrapping_sub(BROTLI_WINDOW_GAP);
+  // no copy reaches the prefix from past a window after it
+  if prefix_end == 0 || (*s).last_flush_pos_ >= (prefix_end + max_backward_limit) as u64 {
+    return;
+  }
+  let num_commands = (*s).num_commands_;
+  let mut dist_cache = [(*s).saved_dist_cache_[0], (*s).saved_dist_cache_[1],
+                        (*s).saved_dist_cache_[2], (*s).saved_dist_cache_[3]];
+  let mut pos = (*s).last_flush_pos_ as usize;
+  let mut first_split = num_commands;
+  for (index, cmd) in (*s).commands_.slice()[..num_commands].iter().enumerate() {
+    let copy_pos = pos + cmd.insert_len_ as usize;
+    let copy_len = CommandCopyLen(cmd) as usize;
+    let distance = CommandDistance(cmd, &(*s).params.dist, &dist_cache[..]);
+    // past max_distance is the static dictionary
+    if distance <= core::cmp::min(copy_pos, max_backward_limit) {
+      if copy_pos - distance < prefix_end && copy_pos - distance + copy_len > prefix_end {
+        first_split = index;
+        break;
+      }
+      if CommandRestoreDistanceCode(cmd, &(*s).params.dist) != 0 {
+        dist_cache = [distance as i32, dist_cache[0], dist_cache[1], dist_cache[2]];
+      }
+    }
+    pos = copy_pos + copy_len;
+  }
+  if first_split == num_commands {
+    return;
+  }
+  // each command from first_split on becomes two at most
+  let mut new_commands = (*s).mc.alloc_cell((*s).commands_.slice().len() + num_commands - first_split);
+  new_commands.slice_mut()[..first_split].clone_from_slice(&(*s).commands_.slice()[..first_split]);
+  let mut num_new_commands = first_split;
+  let mut new_dist_cache = dist_cache;
+  // literals in front of the next copy, with the bytes of the parts that became literals
+  let mut pending_insert: usize = 0;
+  for cmd in (*s).commands_.slice()[first_split..num_commands].iter() {
+    pending_insert += cmd.insert_len_ as usize;
+    pos += cmd.insert_len_ as usize;
+    let copy_len = CommandCopyLen(cmd) as usize;
+    let distance = CommandDistance(cmd, &(*s).params.dist, &dist_cache[..]);
+    let distance_code = CommandRestoreDistanceCode(cmd, &(*s).params.dist) as usize;
+    if distance > core::cmp::min(pos, max_backward_limit) {
+      // static dictionary words never enter the distance cache
+      let copy_len_code = (copy_len as i32 + i32::from((cmd.copy_len_ >> 25) as i8)) as usize;
+      InitCommand(&mut new_commands.slice_mut()[num_new_commands], &(*s).params.dist,
+                  pending_insert, copy_len, copy_len_code, distance_code);
+      num_new_commands += 1;
+      pending_insert = 0;
+      pos += copy_len;
+      continue;
+    }
+    if distance_code != 0 {
+      dist_cache = [distance as i32, dist_cache[0], dist_cache[1], dist_cache[2]];
+    }
+    let source = pos - distance;
+    let head = if source < prefix_end && source + copy_len > prefix_end {
+      prefix_end - source
+    } else {
+      copy_len
+    };
+    for part in [head, copy_len - head].iter() {
+      if *part == 1 {
+        pending_insert += 1;
+      } else if *part != 0 {
+        let part_code = ComputeDistanceCode(distance, core::cmp::min(pos, max_backward_limit),
+                                            &new_dist_cache[..]);
+        InitCommand(&mut new_commands.slice_mut()[num_new_commands], &(*s).params.dist,
+                    pending_insert, *part, *part, part_code);
+        num_new_commands += 1;
+        pending_insert = 0;
+        if part_code != 0 {
+          new_dist_cache = [distance as i32, new_dist_cache[0], new_dist_cache[1], new_dist_cache[2]];
+        }
+      }
+      pos += *part;
+    }
+  }
+  (*s).mc.free_cell(core::mem::replace(&mut (*s).commands_, new_commands));
+  (*s).num_commands_ = num_new_commands;
+  (*s).num_literals_ = (*s).commands_.slice()[..num_new_commands].iter().fold(
+    0usize, |sum, cmd| sum + cmd.insert_len_ as usize);
+  (*s).last_insert_len_ += pending_insert;
+  (*s).dist_cache_[..4].clone_from_slice(&new_dist_cache[..]);
+}
+
+// Exchanges the parse in the state with the fallback parse, so that the code working on the
+// former can be run on the latter.
+fn SwapFallbackParse<AllocU8: alloc::Allocator<u8>,
+                     AllocU16: alloc::Allocator<u16>,
+                     AllocU32: alloc::Allocator<u32>,
+                     AllocI32: alloc::Allocator<i32>,
+                     AllocCommand: alloc::Allocator<Command>>(
+    s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>) {
+  core::mem::swap(&mut (*s).commands_, &mut (*s).fallback_parse_.commands_);
+  core::mem::swap(&mut (*s).num_commands_, &mut (*s).fallback_parse_.num_commands_);
+  core::mem::swap(&mut (*s).num_literals_, &mut (*s).fallback_parse_.num_literals_);
+  core::mem::swap(&mut (*s).last_insert_len_, &mut (*s).fallback_parse_.last_insert_len_);
+  core::mem::swap(&mut (*s).dist_cache_, &mut (*s).fallback_parse_.dist_cache_);
+}
+
+// The parameters of plain quality 11, which the fallback parse is made and stored with.
+fn FallbackParams(params: &BrotliEncoderParams) -> BrotliEncoderParams {
+  let mut fallback_params = params.clone();
+  fallback_params.exhaustive_match_search = false;
+  fallback_params.exhaustive_distance_prefix_search = false;
+  fallback_params
+}
+
+// Stores the metablock of the parse in the state into scratch memory, the way
+// WriteMetaBlockInternal stores it for real but without calling back, and returns its size in bits.
+fn MetaBlockTrialBits<AllocU8: alloc::Allocator<u8>,
+                      AllocU16: alloc::Allocator<u16>,
+                      AllocU32: alloc::Allocator<u32>,
+                      AllocI32: alloc::Allocator<i32>,
+                      AllocF64: alloc::Allocator<super::util::floatX>,
+                      AllocFV: alloc::Allocator<Mem256f>,
+                      AllocPDF: alloc::Allocator<PDF>,
+                      AllocStaticCommand: alloc::Allocator<StaticCommand>,
+                      AllocHL: alloc::Allocator<HistogramLiteral>,
+                      AllocHC: alloc::Allocator<HistogramCommand>,
+                      AllocHD: alloc::Allocator<HistogramDistance>,
+                      AllocHP: alloc::Allocator<HistogramPair>,
+                      AllocCT: alloc::Allocator<ContextType>,
+                      AllocCommand: alloc::Allocator<Command>,
+                      AllocHT: alloc::Allocator<HuffmanTree>>(
+    s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
+    mf64: &mut AllocF64,
+    mfv: &mut AllocFV,
+    mpdf: &mut AllocPDF,
+    mc: &mut AllocStaticCommand,
+    mhl: &mut AllocHL,
+    mhc: &mut AllocHC,
+    mhd: &mut AllocHD,
+    mhp: &mut AllocHP,
+    mct: &mut AllocCT,
+    mht: &mut AllocHT,
+    data: &[u8],
+    mask: u32,
+    metablock_size: usize,
+    is_last: i32,
+    literal_context_mode: ContextType,
+    params: &BrotliEncoderParams) -> usize {
+  let mut storage = s.m8.alloc_cell(2 * metablock_size + 503);
+  if storage.slice().len() < 2 * metablock_size + 503 {
+    s.m8.free_cell(storage);
+    return BROTLI_WRITE_BITS_FAILED;
+  }
+  storage.slice_mut()[0] = (*s).last_bytes_ as u8;
+  storage.slice_mut()[1] = ((*s).last_bytes_ >> 8) as u8;
+  let mut storage_ix: usize = (*s).last_bytes_bits_ as usize;
+  // storing a metablock may move its commands over to other distance parameters
+  let mut commands = s.mc.alloc_cell((*s).num_commands_);
+  commands.slice_mut().clone_from_slice(&(*s).commands_.slice()[..(*s).num_commands_]);
+  let mut dist_cache = (*s).dist_cache_;
+  let mut recoder_state = (*s).recoder_state;
+  let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<InputReferenceMut>,
+                          _cmds: &mut [interface::StaticCommand],
+                          _mb: interface::InputPair,
+                          _mfv: &mut AllocFV,
+                          _mpdf: &mut AllocPDF,
+                          _mc: &mut AllocStaticCommand| ();
+  WriteMetaBlockInternal(&mut s.m8, &mut s.m16, &mut s.m32, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht,
+                         data,
+                         mask as (usize),
+                         (*s).last_flush_pos_,
+                         metablock_size,
+                         is_last,
+                         literal_context_mode,
+                         params,
+                         &mut (*s).literal_scratch_space,
+                         &mut (*s).command_scratch_space,
+                         &mut (*s).distance_scratch_space,
+                         (*s).prev_byte_,
+                         (*s).prev_byte2_,
+                         (*s).num_literals_,
+                         (*s).num_commands_,
+                         commands.slice_mut(),
+                         &(*s).saved_dist_cache_,
+                         &mut dist_cache,
+                         &mut recoder_state,
+                         &mut storage_ix,
+                         storage.slice_mut(),
+                         &mut nop_callback);
+  s.m8.free_cell(storage);
+  s.mc.free_cell(commands);
+  storage_ix
+}
+
+fn EncodeDataInternal<AllocU8: alloc::Allocator<u8>,
+              AllocU16: alloc::Allocator<u16>,
+              AllocU32: alloc::Allocator<u32>,
+              AllocI32: alloc::Allocator<i32>,
+              AllocU64: alloc::Allocator<u64>,
+              AllocF64: alloc::Allocator<super::util::floatX>,
+              AllocFV: alloc::Allocator<Mem256f>,
+              AllocPDF: alloc::Allocator<PDF>,
+              AllocStaticCommand: alloc::Allocator<StaticCommand>,
+              AllocHL: alloc::Allocator<HistogramLiteral>,
+              AllocHC: alloc::Allocator<HistogramCommand>,
+              AllocHD: alloc::Allocator<HistogramDistance>,
+              AllocHP: alloc::Allocator<HistogramPair>,
+              AllocCT: alloc::Allocator<ContextType>,
+              AllocCommand: alloc::Allocator<Command>,
+              AllocHT:alloc::Allocator<HuffmanTree>,
+              AllocZN: alloc::Allocator<ZopfliNode>,
+              MetablockCallback>(
+    s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
+    m64: &mut AllocU64,
+    mf64: &mut AllocF64,
+    mfv: &mut AllocFV,
+    mpdf: &mut AllocPDF,
+    mc: &mut AllocStaticCommand,
+    mhl: &mut AllocHL,
+    mhc: &mut AllocHC,
+    mhd: &mut AllocHD,
+    mhp: &mut AllocHP,
+    mct: &mut AllocCT,
+    mht: &mut AllocHT,
+    mzn: &mut AllocZN,
+    data: &[u8],
+    mask: u32,
+    is_last: i32,
+    force_flush: i32,
+    out_size: &mut usize,
+    callback: &mut MetablockCallback
+//              mut output: &'a mut &'a mut [u8]
 ) -> i32 where MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
                                         &mut [interface::StaticCommand],
                                         interface::InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand){
   let delta: u64 = UnprocessedInputSize(s);
   let mut bytes: u32 = delta as (u32);
   let mut wrapped_last_processed_pos: u32 = WrapPosition((*s).last_processed_pos_);
-  let mask: u32;
   let dictionary = BrotliGetDictionary();
-  if EnsureInitialized(s) == 0 {
-    return 0i32;
-  }
-  mask = (*s).ringbuffer_.mask_;
   if (*s).is_last_block_emitted_ != 0 {
     return 0i32;
   }
@@ -2973,6 +3443,12 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
     (*s).command_buf_ = new_buf;
     let new_buf8 = (*s).m8.alloc_cell(kCompressFragmentTwoPassBlockSize);
     (*s).literal_buf_ = new_buf8;
+    if s.command_buf_.slice().len() < kCompressFragmentTwoPassBlockSize ||
+       s.literal_buf_.slice().len() < kCompressFragmentTwoPassBlockSize {
+      s.m32.free_cell(core::mem::take(&mut s.command_buf_));
+      s.m8.free_cell(core::mem::take(&mut s.literal_buf_));
+      return 0i32;
+    }
   }
   if (*s).params.quality == 0i32 || (*s).params.quality == 1i32 {
     let mut storage_ix: usize = (*s).last_bytes_bits_ as (usize);
@@ -2983,16 +3459,18 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
       *out_size = 0usize;
       return 1i32;
     }
-    GetBrotliStorage(s,
-                     (2u32).wrapping_mul(bytes).wrapping_add(502u32) as (usize));
-    let data = &mut (*s).ringbuffer_.data_mo.slice_mut ()[((*s).ringbuffer_.buffer_index as (usize))..];
-      
+    if !GetBrotliStorage(s, (2u32).wrapping_mul(bytes).wrapping_add(502u32) as (usize)) {
+      return 0i32;
+    }
     (*s).storage_.slice_mut()[0] = (*s).last_bytes_ as u8;
     (*s).storage_.slice_mut()[1] = ((*s).last_bytes_ >> 8) as u8;
     table = GetHashTable!(s, (*s).params.quality, bytes as (usize), &mut table_size);
+    if table_size == 0 {
+      return 0i32;
+    }
     if (*s).params.quality == 0i32 {
       BrotliCompressFragmentFast(mht,
-                                 &mut data[((wrapped_last_processed_pos & mask) as (usize))..],
+                                 &data[((wrapped_last_processed_pos & mask) as (usize))..],
                                  bytes as (usize),
                                  is_last,
                                  table,
@@ -3005,7 +3483,7 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
                                  (*s).storage_.slice_mut());
     } else {
       BrotliCompressFragmentTwoPass(mht,
-                                    &mut data[((wrapped_last_processed_pos & mask) as (usize))..],
+                                    &data[((wrapped_last_processed_pos & mask) as (usize))..],
                                     bytes as (usize),
                                     is_last,
                                     (*s).command_buf_.slice_mut(),
@@ -3015,6 +3493,7 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
                                     &mut storage_ix,
                                     (*s).storage_.slice_mut());
     }
+    RecordFragmentStats(&mut (*s).recoder_state, bytes as usize, storage_ix - (*s).last_bytes_bits_ as usize);
     (*s).last_bytes_ = (*s).storage_.slice()[((storage_ix >> 3i32) as (usize))] as u16 | ((
         (*s).storage_.slice()[((storage_ix >> 3i32) as (usize)) + 1] as u16) << 8);
     (*s).last_bytes_bits_ = (storage_ix & 7u32 as (usize)) as (u8);
@@ -3025,77 +3504,101 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
     *out_size = storage_ix >> 3i32;
     return 1i32;
   }
+  let with_fallback = (*s).params.exhaustive_match_search;
   {
+    let num_commands = if with_fallback {
+      core::cmp::max((*s).num_commands_, (*s).fallback_parse_.num_commands_)
+    } else {
+      (*s).num_commands_
+    };
     let mut newsize: usize =
-      (*s).num_commands_.wrapping_add(bytes.wrapping_div(2u32) as (usize)).wrapping_add(1usize);
+      num_commands.wrapping_add(bytes.wrapping_div(2u32) as (usize)).wrapping_add(1usize);
     if newsize > (*s).cmd_alloc_size_ {
       newsize = newsize.wrapping_add(bytes.wrapping_div(4u32).wrapping_add(16u32) as (usize));
-      (*s).cmd_alloc_size_ = newsize;
-      let mut new_commands = s.mc.alloc_cell(newsize);
-      if (*s).commands_.slice().len() != 0 {
-        new_commands.slice_mut()[..(*s).num_commands_].clone_from_slice(&(*s).commands_.slice()[..(*s).num_commands_]);
-        s.mc.free_cell(core::mem::replace(&mut (*s).commands_, AllocCommand::AllocatedMemory::default()));
+      if !GrowCommands(&mut s.mc, &mut (*s).commands_, (*s).num_commands_, newsize) {
+        return 0i32;
+      }
+      if with_fallback &&
+         !GrowCommands(&mut s.mc, &mut (*s).fallback_parse_.commands_, (*s).fallback_parse_.num_commands_, newsize) {
+        return 0i32;
       }
-      (*s).commands_ = new_commands;
+      (*s).cmd_alloc_size_ = newsize;
     }
   }
-  InitOrStitchToPreviousBlock(&mut (*s).m16, &mut (*s).m32,
-                              &mut (*s).hasher_,
-                              &mut (*s).ringbuffer_.data_mo.slice_mut()[((*s).ringbuffer_.buffer_index as (usize))..],
-                              mask as (usize),
-                              &mut (*s).params,
-                              wrapped_last_processed_pos as (usize),
-                              bytes as (usize),
-                              is_last);
+  if !InitOrStitchToPreviousBlock(&mut (*s).m16, &mut (*s).m32,
+                                  &mut (*s).hasher_,
+                                  data,
+                                  mask as (usize),
+                                  &mut (*s).params,
+                                  wrapped_last_processed_pos as (usize),
+                                  bytes as (usize),
+                                  is_last) {
+    return 0i32;
+  }
   let literal_context_mode = ChooseContextMode(
-      &s.params, (*s).ringbuffer_.data_mo.slice(), WrapPosition(s.last_flush_pos_) as usize,
+      &s.params, data, WrapPosition(s.last_flush_pos_) as usize,
       mask as usize, (s.input_pos_.wrapping_sub(s.last_flush_pos_)) as usize);
-  if s.num_commands_ != 0 && s.last_insert_len_ == 0 {
-      ExtendLastCommand(s, &mut bytes, &mut wrapped_last_processed_pos);
-  }
-  if false { // we are remapping 10 as quality=9.5 since Zopfli doesn't seem to offer much benefits here
-    panic!(r####"
-    BrotliCreateZopfliBackwardReferences(m,
-                                         dictionary,
-                                         bytes as (usize),
-                                         wrapped_last_processed_pos as (usize),
-                                         data,
-                                         mask as (usize),
-                                         &mut (*s).params,
-                                         (*s).hasher_,
-                                         (*s).dist_cache_.as_mut_ptr(),
-                                         &mut (*s).last_insert_len_,
-                                         &mut *(*s).commands_[((*s).num_commands_ as (usize))..],
-                                         &mut (*s).num_commands_,
-                                         &mut (*s).num_literals_);"####);
-  } else if false && (*s).params.quality == 11i32 {
-    panic!(r####"BrotliCreateHqZopfliBackwardReferences(m,
-                                           dictionary,
-                                           bytes as (usize),
-                                           wrapped_last_processed_pos as (usize),
-                                           data,
-                                           mask as (usize),
-                                           &mut (*s).params,
-                                           (*s).hasher_,
-                                           (*s).dist_cache_.as_mut_ptr(),
-                                           &mut (*s).last_insert_len_,
-                                           &mut *(*s).commands_[((*s).num_commands_ as (usize))..],
-                                           &mut (*s).num_commands_,
-                                           &mut (*s).num_literals_);"####);
-  } else {
-    BrotliCreateBackwardReferences(&mut (*s).m32, m64, mf64, mzn, &dictionary,
-                                   bytes as (usize),
-                                   wrapped_last_processed_pos as (usize),
-                                   &mut (*s).ringbuffer_.data_mo.slice_mut()[((*s).ringbuffer_.buffer_index as usize)..],
+  if with_fallback {
+    // the quality 11 parse goes first, as it keeps the binary tree hasher up to date
+    SwapFallbackParse(s);
+    let mut fallback_bytes = bytes;
+    let mut fallback_pos = wrapped_last_processed_pos;
+    if s.num_commands_ != 0 && s.last_insert_len_ == 0 {
+      ExtendLastCommand(s, data, mask, &mut fallback_bytes, &mut fallback_pos);
+    }
+    let mut fallback_params = FallbackParams(&(*s).params);
+    let shared_dictionary = (*s).shared_words_.dictionary();
+    if BrotliCreateBackwardReferences(&mut (*s).m32, m64, mf64, mzn,
+                                   match shared_dictionary {
+                                     Some(ref shared) => shared,
+                                     None => dictionary,
+                                   },
+                                   fallback_bytes as (usize),
+                                   fallback_pos as (usize),
+                                   data,
                                    mask as (usize),
-                                   &mut (*s).params,
+                                   &mut fallback_params,
                                    &mut (*s).hasher_,
                                    &mut (*s).dist_cache_,
                                    &mut (*s).last_insert_len_,
                                    &mut (*s).commands_.slice_mut()[((*s).num_commands_ as (usize))..],
                                    &mut (*s).num_commands_,
-                                   &mut (*s).num_literals_);
+                                   &mut (*s).num_literals_) == 0 {
+      SwapFallbackParse(s);
+      return 0i32;
+    }
+    SplitCompoundDictionaryCopies(s);
+    SwapFallbackParse(s);
   }
+  if s.num_commands_ != 0 && s.last_insert_len_ == 0 {
+      ExtendLastCommand(s, data, mask, &mut bytes, &mut wrapped_last_processed_pos);
+  }
+  let shared_dictionary = (*s).shared_words_.dictionary();
+  if BrotliCreateBackwardReferences(&mut (*s).m32, m64, mf64, mzn,
+                                 match shared_dictionary {
+                                   Some(ref shared) => shared,
+                                   None => dictionary,
+                                 },
+                                 bytes as (usize),
+                                 wrapped_last_processed_pos as (usize),
+                                 data,
+                                 mask as (usize),
+                                 &mut (*s).params,
+                                 &mut (*s).hasher_,
+                                 &mut (*s).dist_cache_,
+                                 &mut (*s).last_insert_len_,
+                                 &mut (*s).commands_.slice_mut()[((*s).num_commands_ as (usize))..],
+                                 &mut (*s).num_commands_,
+                                 &mut (*s).num_literals_) == 0 {
+    return 0i32;
+  }
+  SplitCompoundDictionaryCopies(s);
+  let (num_literals, num_commands) = if with_fallback {
+    (core::cmp::max((*s).num_literals_, (*s).fallback_parse_.num_literals_),
+     core::cmp::max((*s).num_commands_, (*s).fallback_parse_.num_commands_))
+  } else {
+    ((*s).num_literals_, (*s).num_commands_)
+  };
   {
     let max_length: usize = MaxMetablockSize(&mut (*s).params);
     let max_literals: usize = max_length.wrapping_div(8usize);
@@ -3108,15 +3611,15 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
       0i32
     };
     let should_flush: i32 = if !!((*s).params.quality < 4i32 &&
-                                  ((*s).num_literals_.wrapping_add((*s).num_commands_) >=
+                                  (num_literals.wrapping_add(num_commands) >=
                                    0x2fffusize)) {
       1i32
     } else {
       0i32
     };
     if is_last == 0 && (force_flush == 0) && (should_flush == 0) &&
-       (next_input_fits_metablock != 0) && ((*s).num_literals_ < max_literals) &&
-       ((*s).num_commands_ < max_commands) {
+       (next_input_fits_metablock != 0) && (num_literals < max_literals) &&
+       (num_commands < max_commands) {
       if UpdateLastProcessedPos(s) != 0 {
         HasherReset(&mut (*s).hasher_);
       }
@@ -3124,15 +3627,20 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
       return 1i32;
     }
   }
-  if (*s).last_insert_len_ > 0usize {
-    InitInsertCommand(&mut (*s).commands_.slice_mut()[({
-                               let _old = (*s).num_commands_;
-                               (*s).num_commands_ = (*s).num_commands_.wrapping_add(1 as (usize));
-                               _old
-                             } as (usize))],
-                      (*s).last_insert_len_);
-    (*s).num_literals_ = (*s).num_literals_.wrapping_add((*s).last_insert_len_);
-    (*s).last_insert_len_ = 0usize;
+  for _parse in 0..(if with_fallback { 2 } else { 1 }) {
+    if (*s).last_insert_len_ > 0usize {
+      InitInsertCommand(&mut (*s).commands_.slice_mut()[({
+                                 let _old = (*s).num_commands_;
+                                 (*s).num_commands_ = (*s).num_commands_.wrapping_add(1 as (usize));
+                                 _old
+                               } as (usize))],
+                        (*s).last_insert_len_);
+      (*s).num_literals_ = (*s).num_literals_.wrapping_add((*s).last_insert_len_);
+      (*s).last_insert_len_ = 0usize;
+    }
+    if with_fallback {
+      SwapFallbackParse(s);
+    }
   }
   if is_last == 0 && ((*s).input_pos_ == (*s).last_flush_pos_) {
     *out_size = 0usize;
@@ -3140,19 +3648,40 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
   }
   {
     let metablock_size: u32 = (*s).input_pos_.wrapping_sub((*s).last_flush_pos_) as (u32);
-    GetBrotliStorage(s,
-                     (2u32).wrapping_mul(metablock_size).wrapping_add(503) as (usize));
+    if !GetBrotliStorage(s, (2u32).wrapping_mul(metablock_size).wrapping_add(503) as (usize)) {
+      return 0i32;
+    }
+    let mut fallback_params: Option<BrotliEncoderParams> = None;
+    if with_fallback {
+      let exhaustive_params = (*s).params.clone();
+      let exhaustive_bits = MetaBlockTrialBits(s, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht,
+                                               data, mask, metablock_size as usize, is_last,
+                                               literal_context_mode, &exhaustive_params);
+      SwapFallbackParse(s);
+      let params = FallbackParams(&(*s).params);
+      let fallback_bits = MetaBlockTrialBits(s, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht,
+                                             data, mask, metablock_size as usize, is_last,
+                                             literal_context_mode, &params);
+      if fallback_bits < exhaustive_bits {
+        fallback_params = Some(params);
+      } else {
+        SwapFallbackParse(s);
+      }
+    }
     let mut storage_ix: usize = (*s).last_bytes_bits_ as (usize);
     (*s).storage_.slice_mut()[(0usize)] = (*s).last_bytes_ as u8;
     (*s).storage_.slice_mut()[(1usize)] = ((*s).last_bytes_ >> 8) as u8;
     WriteMetaBlockInternal(&mut (*s).m8, &mut (*s).m16, &mut (*s).m32, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht,
-                           &mut (*s).ringbuffer_.data_mo.slice_mut()[((*s).ringbuffer_.buffer_index as usize)..],
+                           data,
                            mask as (usize),
                            (*s).last_flush_pos_,
                            metablock_size as (usize),
                            is_last,
                            literal_context_mode,
-                           &mut (*s).params,
+                           match fallback_params {
+                             Some(ref params) => params,
+                             None => &(*s).params,
+                           },
                            &mut (*s).literal_scratch_space,
                            &mut (*s).command_scratch_space,
                            &mut (*s).distance_scratch_space,
@@ -3167,7 +3696,9 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
                            &mut storage_ix,
                            (*s).storage_.slice_mut(),
                            callback);
-
+    if BrotliWriteBitsFailed(storage_ix) {
+      return 0i32;
+    }
     (*s).last_bytes_ = (*s).storage_.slice()[((storage_ix >> 3i32) as (usize))] as u16 | (
           ((*s).storage_.slice()[1 + ((storage_ix >> 3i32) as (usize))] as u16)<<8);
     (*s).last_bytes_bits_ = (storage_ix & 7u32 as (usize)) as (u8);
@@ -3175,18 +3706,25 @@ fn EncodeData<AllocU8: alloc::Allocator<u8>,
     if UpdateLastProcessedPos(s) != 0 {
       HasherReset(&mut (*s).hasher_);
     }
-    let data = &(*s).ringbuffer_.data_mo.slice()[(*s).ringbuffer_.buffer_index as usize..];
-    if (*s).last_flush_pos_ > 0 {
+    // a shared dictionary prefix in front of the stream is not in the decoder's window
+    let stream_start = (*s).params.compound_dictionary_loaded as u64;
+    if (*s).last_flush_pos_ > stream_start {
       (*s).prev_byte_ = data[((((*s).last_flush_pos_ as (u32)).wrapping_sub(1u32) & mask) as
        (usize))];
     }
-    if (*s).last_flush_pos_ > 1 {
+    if (*s).last_flush_pos_ > stream_start + 1 {
       (*s).prev_byte2_ = data[(((*s).last_flush_pos_.wrapping_sub(2) as (u32) & mask) as
        (usize))];
     }
     (*s).num_commands_ = 0usize;
     (*s).num_literals_ = 0usize;
     (*s).saved_dist_cache_.clone_from_slice(&(*s).dist_cache_.split_at(4).0);
+    if with_fallback {
+      // both parses go on from the metablock that was written
+      (*s).fallback_parse_.num_commands_ = 0usize;
+      (*s).fallback_parse_.num_literals_ = 0usize;
+      (*s).fallback_parse_.dist_cache_ = (*s).dist_cache_;
+    }
     // *output = &mut storage[(0usize)];
     (*s).next_out_ = NextOut::DynamicStorage(0); // this always returns that
     *out_size = storage_ix >> 3i32;
@@ -3389,12 +3927,28 @@ fn CheckFlushComplete<AllocU8: alloc::Allocator<u8>,
 }
 
 
+// Quality 0 and 1 hand whole fragments to the fragment compressors, which may split them into
+// several metablocks, so only the input and output sizes are recorded.
+fn RecordFragmentStats(recoder_state: &mut RecoderState, input_size: usize, total_bits: usize) {
+  if input_size == 0 {
+    return;
+  }
+  recoder_state.metablock_stats = BrotliMetaBlockStats {
+    storage_path: BrotliMetaBlockStoragePath::Fragment,
+    input_size: input_size,
+    total_bits: total_bits,
+    ..BrotliMetaBlockStats::default()
+  };
+  recoder_state.num_metablocks += 1;
+}
+
 fn BrotliEncoderCompressStreamFast<AllocU8: alloc::Allocator<u8>,
                      AllocU16: alloc::Allocator<u16>,
                      AllocU32: alloc::Allocator<u32>,
                      AllocI32: alloc::Allocator<i32>,
                                    AllocCommand: alloc::Allocator<Command>,
-                                   AllocHT: alloc::Allocator<HuffmanTree>>(
+                                   AllocHT: alloc::Allocator<HuffmanTree>,
+                                   StatsCallback: FnMut(&BrotliMetaBlockStats)>(
     s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
     mht: &mut AllocHT,
     op: BrotliEncoderOperation,
@@ -3404,7 +3958,8 @@ fn BrotliEncoderCompressStreamFast<AllocU8: alloc::Allocator<u8>,
     available_out: &mut usize,
     next_out_array: &mut [u8],
     next_out_offset: &mut usize,
-    total_out: &mut Option<usize>)
+    total_out: &mut Option<usize>,
+    stats_callback: &mut StatsCallback)
             -> i32 {
   let block_size_limit: usize = 1usize << (*s).params.lgwin;
   let buf_size: usize = brotli_min_size_t(kCompressFragmentTwoPassBlockSize,
@@ -3426,6 +3981,11 @@ fn BrotliEncoderCompressStreamFast<AllocU8: alloc::Allocator<u8>,
       command_buf = s.m32.alloc_cell(buf_size);
       literal_buf = s.m8.alloc_cell(buf_size);
     }
+    if command_buf.slice().len() < buf_size || literal_buf.slice().len() < buf_size {
+      s.m32.free_cell(command_buf);
+      s.m8.free_cell(literal_buf);
+      return 0i32;
+    }
   }
   while 1i32 != 0 {
     if InjectFlushOrPushOutput(s, available_out, next_out_array, next_out_offset, total_out) != 0 {
@@ -3464,12 +4024,21 @@ fn BrotliEncoderCompressStreamFast<AllocU8: alloc::Allocator<u8>,
         storage = &mut next_out_array[*next_out_offset..];//GetNextOut!(s);
       } else {
         inplace = 0i32;
-        GetBrotliStorage(s, max_out_size);
+        if !GetBrotliStorage(s, max_out_size) {
+          s.m32.free_cell(command_buf);
+          s.m8.free_cell(literal_buf);
+          return 0i32;
+        }
         storage = (*s).storage_.slice_mut();
       }
       storage[(0usize)] = (*s).last_bytes_ as u8;
       storage[(1usize)] = ((*s).last_bytes_  >> 8) as u8;
       table = GetHashTable!(s, (*s).params.quality, block_size, &mut table_size);
+      if table_size == 0 {
+        s.m32.free_cell(command_buf);
+        s.m8.free_cell(literal_buf);
+        return 0i32;
+      }
       if (*s).params.quality == 0i32 {
         BrotliCompressFragmentFast(mht,
                                    &(next_in_array)[*next_in_offset..],
@@ -3495,6 +4064,10 @@ fn BrotliEncoderCompressStreamFast<AllocU8: alloc::Allocator<u8>,
                                       &mut storage_ix,
                                       storage);
       }
+      RecordFragmentStats(&mut (*s).recoder_state, block_size, storage_ix - (*s).last_bytes_bits_ as usize);
+      if block_size != 0 {
+        stats_callback(&(*s).recoder_state.metablock_stats);
+      }
       *next_in_offset += block_size as usize;
       *available_in = (*available_in).wrapping_sub(block_size);
       if inplace != 0 {
@@ -3600,9 +4173,67 @@ pub fn BrotliEncoderCompressStream<AllocU8: alloc::Allocator<u8>,
     total_out: &mut Option<usize>,
     metablock_callback: &mut MetablockCallback)
             -> i32 {
+  BrotliEncoderCompressStreamWithStats(s, m64, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht, mzn, op,
+                                       available_in, next_in_array, next_in_offset,
+                                       available_out, next_out_array, next_out_offset, total_out,
+                                       metablock_callback, &mut |_: &BrotliMetaBlockStats| ())
+}
+
+// Like BrotliEncoderCompressStream, but also calls stats_callback with the statistics of each
+// metablock as soon as it has been stored.
+pub fn BrotliEncoderCompressStreamWithStats<AllocU8: alloc::Allocator<u8>,
+                                   AllocU16: alloc::Allocator<u16>,
+                                   AllocU32: alloc::Allocator<u32>,
+                                   AllocI32: alloc::Allocator<i32>,
+                                   AllocU64: alloc::Allocator<u64>,
+                                   AllocF64: alloc::Allocator<super::util::floatX>,
+                                   AllocFV: alloc::Allocator<Mem256f>,
+                                   AllocPDF: alloc::Allocator<PDF>,
+                                   AllocStaticCommand: alloc::Allocator<StaticCommand>,
+                                   AllocHL: alloc::Allocator<HistogramLiteral>,
+                                   AllocHC: alloc::Allocator<HistogramCommand>,
+                                   AllocHD: alloc::Allocator<HistogramDistance>,
+                                   AllocHP: alloc::Allocator<HistogramPair>,
+                                   AllocCT: alloc::Allocator<ContextType>,
+                                   AllocCommand: alloc::Allocator<Command>,
+                                   AllocHT:alloc::Allocator<HuffmanTree>,
+                                   AllocZN: alloc::Allocator<ZopfliNode>,
+                                   MetablockCallback:FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                           &mut [interface::StaticCommand],
+                                                           interface::InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand),
+                                   StatsCallback: FnMut(&BrotliMetaBlockStats)>(
+    s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
+    m64: &mut AllocU64,
+    mf64: &mut AllocF64,
+    mfv: &mut AllocFV,
+    mpdf: &mut AllocPDF,
+    mc: &mut AllocStaticCommand,
+    mhl: &mut AllocHL,
+    mhc: &mut AllocHC,
+    mhd: &mut AllocHD,
+    mhp: &mut AllocHP,
+    mct: &mut AllocCT,
+    mht: &mut AllocHT,
+    mzn: &mut AllocZN,
+    op: BrotliEncoderOperation,
+    available_in: &mut usize,
+    next_in_array: &[u8],
+    next_in_offset: &mut usize,
+    available_out: &mut usize,
+    next_out_array: &mut [u8],
+    next_out_offset: &mut usize,
+    total_out: &mut Option<usize>,
+    metablock_callback: &mut MetablockCallback,
+    stats_callback: &mut StatsCallback)
+            -> i32 {
   if EnsureInitialized(s) == 0 {
     return 0i32;
   }
+  // the offsets and counts come from the caller: reject any that would slice past either array
+  if *next_in_offset > next_in_array.len() || *available_in > next_in_array.len() - *next_in_offset ||
+     *next_out_offset > next_out_array.len() || *available_out > next_out_array.len() - *next_out_offset {
+    return 0i32;
+  }
   if (*s).remaining_metadata_bytes_ != !(0u32) {
     if *available_in != (*s).remaining_metadata_bytes_ as (usize) {
       return 0i32;
@@ -3613,7 +4244,13 @@ pub fn BrotliEncoderCompressStream<AllocU8: alloc::Allocator<u8>,
   }
   if op as (i32) == BrotliEncoderOperation::BROTLI_OPERATION_EMIT_METADATA as (i32) {
     UpdateSizeHint(s, 0usize);
-    return ProcessMetadata(s, m64, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht, mzn, available_in, next_in_array, next_in_offset, available_out, next_out_array, next_out_offset, total_out, metablock_callback);
+    let num_metablocks = (*s).recoder_state.num_metablocks;
+    let ret = ProcessMetadata(s, m64, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht, mzn, available_in, next_in_array, next_in_offset, available_out, next_out_array, next_out_offset, total_out, metablock_callback);
+    // the pending input is flushed as (at most) one metablock ahead of the metadata
+    if (*s).recoder_state.num_metablocks != num_metablocks {
+      stats_callback(&(*s).recoder_state.metablock_stats);
+    }
+    return ret;
   }
   if (*s).stream_state_ as (i32) ==
      BrotliEncoderStreamState::BROTLI_STREAM_METADATA_HEAD as (i32) ||
@@ -3634,13 +4271,16 @@ pub fn BrotliEncoderCompressStream<AllocU8: alloc::Allocator<u8>,
                                            available_out,
                                            next_out_array,
                                            next_out_offset,
-                                           total_out);
+                                           total_out,
+                                           stats_callback);
   }
   while 1i32 != 0 {
     let remaining_block_size: usize = RemainingInputBlockSize(s);
     if remaining_block_size != 0usize && (*available_in != 0usize) {
       let copy_input_size: usize = brotli_min_size_t(remaining_block_size, *available_in);
-      CopyInputToRingBuffer(s, copy_input_size, &next_in_array[*next_in_offset..]);
+      if !CopyInputToRingBuffer(s, copy_input_size, &next_in_array[*next_in_offset..]) {
+        return 0i32;
+      }
       *next_in_offset += copy_input_size as (usize);
       *available_in = (*available_in).wrapping_sub(copy_input_size);
       {
@@ -3676,6 +4316,7 @@ pub fn BrotliEncoderCompressStream<AllocU8: alloc::Allocator<u8>,
         let result: i32;
         UpdateSizeHint(s, *available_in);
         let mut avail_out = (*s).available_out_;
+        let num_metablocks = (*s).recoder_state.num_metablocks;
         result = EncodeData(s,
                             m64, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht, mzn,
                             is_last,
@@ -3683,6 +4324,9 @@ pub fn BrotliEncoderCompressStream<AllocU8: alloc::Allocator<u8>,
                             &mut avail_out,
                             metablock_callback);
         (*s).available_out_ = avail_out;
+        if (*s).recoder_state.num_metablocks != num_metablocks {
+          stats_callback(&(*s).recoder_state.metablock_stats);
+        }
         //this function set next_out to &storage[0]
         if result == 0 {
           return 0i32;
@@ -3710,6 +4354,114 @@ pub fn BrotliEncoderCompressStream<AllocU8: alloc::Allocator<u8>,
   1i32
 }
 
+// Largest input BrotliEncoderCompressWholeBuffer takes. The input stands in for the ring buffer
+// under a 31 bit mask, and positions this small are never wrapped.
+pub const BROTLI_MAX_WHOLE_BUFFER_SIZE: usize = 1 << 31;
+const BROTLI_WHOLE_BUFFER_MASK: u32 = (1u32 << 31) - 1;
+
+// Encodes the next block of a stream whose whole input the caller holds in memory (for example a
+// memory mapped file). The hasher, the backward reference search and the metablock builder read
+// the input in place, so it is never copied into the ring buffer, which is not even allocated.
+// Call with the same input and offset until BrotliEncoderIsFinished, taking the output of each
+// call with BrotliEncoderTakeOutput. The hashers see the real bytes that follow each block rather
+// than stale ring buffer contents, so the output may differ slightly from streaming the same input.
+// The state must not have been given any input or a custom dictionary.
+pub fn BrotliEncoderCompressWholeBuffer<AllocU8: alloc::Allocator<u8>,
+                                        AllocU16: alloc::Allocator<u16>,
+                                        AllocU32: alloc::Allocator<u32>,
+                                        AllocI32: alloc::Allocator<i32>,
+                                        AllocU64: alloc::Allocator<u64>,
+                                        AllocF64: alloc::Allocator<super::util::floatX>,
+                                        AllocFV: alloc::Allocator<Mem256f>,
+                                        AllocPDF: alloc::Allocator<PDF>,
+                                        AllocStaticCommand: alloc::Allocator<StaticCommand>,
+                                        AllocHL: alloc::Allocator<HistogramLiteral>,
+                                        AllocHC: alloc::Allocator<HistogramCommand>,
+                                        AllocHD: alloc::Allocator<HistogramDistance>,
+                                        AllocHP: alloc::Allocator<HistogramPair>,
+                                        AllocCT: alloc::Allocator<ContextType>,
+                                        AllocCommand: alloc::Allocator<Command>,
+                                        AllocHT:alloc::Allocator<HuffmanTree>,
+                                        AllocZN: alloc::Allocator<ZopfliNode>,
+                                        MetablockCallback:FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                                &mut [interface::StaticCommand],
+                                                                interface::InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand),
+                                        StatsCallback: FnMut(&BrotliMetaBlockStats)>(
+    s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
+    m64: &mut AllocU64,
+    mf64: &mut AllocF64,
+    mfv: &mut AllocFV,
+    mpdf: &mut AllocPDF,
+    mc: &mut AllocStaticCommand,
+    mhl: &mut AllocHL,
+    mhc: &mut AllocHC,
+    mhd: &mut AllocHD,
+    mhp: &mut AllocHP,
+    mct: &mut AllocCT,
+    mht: &mut AllocHT,
+    mzn: &mut AllocZN,
+    input: &[u8],
+    next_in_offset: &mut usize,
+    metablock_callback: &mut MetablockCallback,
+    stats_callback: &mut StatsCallback)
+            -> i32 {
+  if EnsureInitialized(s) == 0 {
+    return 0i32;
+  }
+  if input.len() > BROTLI_MAX_WHOLE_BUFFER_SIZE || *next_in_offset > input.len() ||
+     (*s).input_pos_ != *next_in_offset as u64 || (*s).available_out_ != 0 ||
+     (*s).remaining_metadata_bytes_ != !(0u32) ||
+     (*s).stream_state_ as (i32) != BrotliEncoderStreamState::BROTLI_STREAM_PROCESSING as (i32) {
+    return 0i32;
+  }
+  let mut available_in: usize = input.len() - *next_in_offset;
+  if (*s).params.quality == 0i32 || (*s).params.quality == 1i32 {
+    // the fragment compressors already read the caller's input in place
+    let mut available_out: usize = 0;
+    let mut next_out_offset: usize = 0;
+    let ret = BrotliEncoderCompressStreamFast(s,
+                                              mht,
+                                              BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+                                              &mut available_in,
+                                              input,
+                                              next_in_offset,
+                                              &mut available_out,
+                                              &mut [],
+                                              &mut next_out_offset,
+                                              &mut None,
+                                              stats_callback);
+    (*s).input_pos_ = *next_in_offset as u64;
+    return ret;
+  }
+  UpdateSizeHint(s, available_in);
+  let block_size: usize = brotli_min_size_t(InputBlockSize(s), available_in);
+  *next_in_offset += block_size;
+  available_in -= block_size;
+  (*s).input_pos_ = *next_in_offset as u64;
+  let is_last: i32 = (available_in == 0) as i32;
+  let mut out_size: usize = 0;
+  let num_metablocks = (*s).recoder_state.num_metablocks;
+  let result = EncodeDataInternal(s,
+                                  m64, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht, mzn,
+                                  input,
+                                  BROTLI_WHOLE_BUFFER_MASK,
+                                  is_last,
+                                  0,
+                                  &mut out_size,
+                                  metablock_callback);
+  if (*s).recoder_state.num_metablocks != num_metablocks {
+    stats_callback(&(*s).recoder_state.metablock_stats);
+  }
+  if result == 0 {
+    return 0i32;
+  }
+  (*s).available_out_ = out_size;
+  if is_last != 0 {
+    (*s).stream_state_ = BrotliEncoderStreamState::BROTLI_STREAM_FINISHED;
+  }
+  1i32
+}
+
 pub fn BrotliEncoderIsFinished<AllocU8: alloc::Allocator<u8>,
                      AllocU16: alloc::Allocator<u16>,
                      AllocU32: alloc::Allocator<u32>,
@@ -3747,11 +4499,13 @@ pub fn BrotliEncoderTakeOutput<'a, AllocU8: alloc::Allocator<u8>,
                                size: &mut usize)
                                -> &'a [u8] {
   let mut consumed_size: usize = (*s).available_out_;
-  let mut result: &[u8] = GetNextOut!(*s);
+  let result: &[u8];
   if *size != 0 {
     consumed_size = brotli_min_size_t(*size, (*s).available_out_);
   }
   if consumed_size != 0 {
+    // next_out_ is only set while there is output to take
+    result = &GetNextOut!(*s)[..consumed_size];
     (*s).next_out_ = NextOutIncrement(&(*s).next_out_, consumed_size as i32);
     (*s).available_out_ = (*s).available_out_.wrapping_sub(consumed_size);
     (*s).total_out_ = (*s).total_out_.wrapping_add(consumed_size);
@@ -3781,6 +4535,23 @@ pub fn BrotliEncoderInputBlockSize<AllocU8: alloc::Allocator<u8>,
 }
 
 
+// Returns how many positions searched the static dictionary and how many of
+// those searches produced a usable match, as (lookups, matches).
+pub fn BrotliEncoderGetStaticDictionaryStats<AllocU8: alloc::Allocator<u8>,
+                     AllocU16: alloc::Allocator<u16>,
+                     AllocU32: alloc::Allocator<u32>,
+                     AllocI32: alloc::Allocator<i32>,
+                     AllocCommand: alloc::Allocator<Command>>(s: &mut BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>) -> (usize, usize) {
+  match (*s).hasher_ {
+    UnionHasher::Uninit(_) => (0usize, 0usize),
+    _ => {
+      let common = GetHasherCommon(&mut (*s).hasher_);
+      (common.dict_num_lookups, common.dict_num_matches)
+    }
+  }
+}
+
+
 pub fn BrotliEncoderCopyInputToRingBuffer<AllocU8: alloc::Allocator<u8>,
                      AllocU16: alloc::Allocator<u16>,
                      AllocU32: alloc::Allocator<u32>,
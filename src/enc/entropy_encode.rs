@@ -257,6 +257,95 @@ pub fn BrotliCreateHuffmanTree(data: &[u32],
     count_limit = count_limit.wrapping_mul(2u32);
   }
 }
+
+const kMaxPackageMergeSymbols: usize = 704;
+const kMaxPackageMergeDepth: usize = 15;
+const kPackageMergeMaskWords: usize = (2 * kMaxPackageMergeSymbols + 63) / 64;
+
+/* Same contract as BrotliCreateHuffmanTree, but the depths are the optimal
+   length-limited code for the counts, found with the package-merge algorithm
+   of Larmore and Hirschberg instead of by raising count_limit.
+
+   The symbols, sorted by count, are the items of every one of the tree_limit
+   lists. Each list above the deepest also gets the pairwise sums ("packages")
+   of the list below it merged in. The cheapest 2 * n - 2 items of the top list
+   are then chosen: every chosen leaf adds one bit to its symbol's depth and
+   every chosen package pulls the two items it was made of from the list below.
+   Only a bit per item saying whether it is a leaf is kept for each list.
+
+   Alphabets above 704 symbols or limits above 15 bits use BrotliCreateHuffmanTree. */
+pub fn BrotliCreateOptimalHuffmanTree(data: &[u32],
+                                      length: usize,
+                                      tree_limit: i32,
+                                      tree: &mut [HuffmanTree],
+                                      depth: &mut [u8]) {
+  let mut n: usize = 0;
+  for i in (0..length).rev() {
+    if data[i] != 0 {
+      InitHuffmanTree(&mut tree[n], data[i], -1i32 as (i16), i as (i16));
+      n += 1;
+    }
+  }
+  if n <= 1 {
+    if n == 1 {
+      depth[tree[0].index_right_or_value_ as usize] = 1;
+    }
+    return;
+  }
+  if tree_limit <= 0 || tree_limit as usize > kMaxPackageMergeDepth || n > kMaxPackageMergeSymbols ||
+     n > 1usize << tree_limit {
+    return BrotliCreateHuffmanTree(data, length, tree_limit, tree, depth);
+  }
+  SortHuffmanTreeItems(tree, n, SortHuffmanTree {});
+  let num_levels = tree_limit as usize;
+  let mut is_leaf = [[0u64; kPackageMergeMaskWords]; kMaxPackageMergeDepth];
+  let mut weights = [0u64; 2 * kMaxPackageMergeSymbols];
+  let mut packages = [0u64; kMaxPackageMergeSymbols];
+  let mut list_size = n;
+  for (i, item) in tree[..n].iter().enumerate() {
+    weights[i] = u64::from(item.total_count_);
+    is_leaf[num_levels - 1][i >> 6] |= 1u64 << (i & 63);
+  }
+  for level in (0..num_levels - 1).rev() {
+    let num_packages = list_size >> 1;
+    for k in 0..num_packages {
+      packages[k] = weights[2 * k] + weights[2 * k + 1];
+    }
+    let mut leaf_ix: usize = 0;
+    let mut package_ix: usize = 0;
+    list_size = 0;
+    while leaf_ix < n || package_ix < num_packages {
+      if package_ix == num_packages ||
+         (leaf_ix < n && u64::from(tree[leaf_ix].total_count_) <= packages[package_ix]) {
+        weights[list_size] = u64::from(tree[leaf_ix].total_count_);
+        is_leaf[level][list_size >> 6] |= 1u64 << (list_size & 63);
+        leaf_ix += 1;
+      } else {
+        weights[list_size] = packages[package_ix];
+        package_ix += 1;
+      }
+      list_size += 1;
+    }
+  }
+  for item in tree[..n].iter() {
+    depth[item.index_right_or_value_ as usize] = 0;
+  }
+  let mut num_chosen = 2 * n - 2;
+  for level in 0..num_levels {
+    let mut num_leaves: usize = 0;
+    for ix in 0..num_chosen {
+      if is_leaf[level][ix >> 6] & (1u64 << (ix & 63)) != 0 {
+        num_leaves += 1;
+      }
+    }
+    // the leaves of a list come in increasing count order, so the chosen ones are the rarest
+    for item in tree[..num_leaves].iter() {
+      depth[item.index_right_or_value_ as usize] += 1;
+    }
+    num_chosen = 2 * (num_chosen - num_leaves);
+  }
+}
+
 pub fn BrotliOptimizeHuffmanCountsForRle(mut length: usize,
                                          counts: &mut [u32],
                                          good_for_rle: &mut [u8]) {
@@ -690,3 +779,71 @@ pub fn BrotliConvertBitDepthsToSymbols(depth: &[u8], len: usize, bits: &mut [u16
     i = i.wrapping_add(1 as (usize));
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::{BrotliCreateHuffmanTree, BrotliCreateOptimalHuffmanTree, HuffmanTree};
+
+  fn cost(data: &[u32], depth: &[u8]) -> u64 {
+    data.iter().zip(depth.iter()).map(|(count, bits)| u64::from(*count) * u64::from(*bits)).sum()
+  }
+
+  fn check_depths(data: &[u32], depth: &[u8], tree_limit: i32) {
+    let mut kraft: u64 = 0;
+    for (count, bits) in data.iter().zip(depth.iter()) {
+      if *count == 0 {
+        assert_eq!(*bits, 0);
+      } else {
+        assert!(*bits >= 1 && i32::from(*bits) <= tree_limit);
+        kraft += 1u64 << (15 - *bits);
+      }
+    }
+    assert_eq!(kraft, 1u64 << 15);
+  }
+
+  #[test]
+  fn test_package_merge_never_worse() {
+    let mut seed: u32 = 0x1234_5678;
+    let mut tree = [HuffmanTree::default(); 2 * 704 + 1];
+    let mut num_better: usize = 0;
+    for round in 0..200 {
+      let length = [18usize, 26, 64, 256, 704][round % 5];
+      let tree_limit = if length == 18 { 5 } else { 15 };
+      let mut data = [0u32; 704];
+      for count in data[..length].iter_mut() {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        // skewed counts, many of them zero, so the depth limit matters
+        let r = seed >> 16;
+        *count = if r & 3 == 0 { 0 } else { (1u32 << (r % 20)) + (r & 7) };
+      }
+      data[round % length] = 1;
+      data[(round * 7 + 1) % length] = 1;
+      let mut heuristic = [0u8; 704];
+      let mut optimal = [0u8; 704];
+      BrotliCreateHuffmanTree(&data, length, tree_limit, &mut tree[..], &mut heuristic);
+      BrotliCreateOptimalHuffmanTree(&data, length, tree_limit, &mut tree[..], &mut optimal);
+      check_depths(&data[..length], &optimal[..length], tree_limit);
+      let optimal_cost = cost(&data[..length], &optimal[..length]);
+      let heuristic_cost = cost(&data[..length], &heuristic[..length]);
+      assert!(optimal_cost <= heuristic_cost);
+      if optimal_cost < heuristic_cost {
+        num_better += 1;
+      }
+    }
+    assert!(num_better > 0);
+  }
+
+  #[test]
+  fn test_package_merge_fibonacci() {
+    // Fibonacci counts make an unlimited Huffman code as deep as the alphabet
+    let data: [u32; 8] = [1, 1, 2, 3, 5, 8, 13, 21];
+    let mut tree = [HuffmanTree::default(); 17];
+    let mut heuristic = [0u8; 8];
+    let mut optimal = [0u8; 8];
+    BrotliCreateHuffmanTree(&data, 8, 4, &mut tree[..], &mut heuristic);
+    BrotliCreateOptimalHuffmanTree(&data, 8, 4, &mut tree[..], &mut optimal);
+    check_depths(&data, &optimal, 4);
+    assert_eq!(optimal, [4, 4, 4, 4, 3, 3, 2, 2]);
+    assert!(cost(&data, &optimal) <= cost(&data, &heuristic));
+  }
+}
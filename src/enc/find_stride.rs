@@ -11,7 +11,7 @@ pub type floatY = f64;
 // the cost of storing a particular population of data including the approx
 // cost of a huffman table to describe the frequencies of each symbol
 pub fn HuffmanCost(population: &[u32]) -> floatY{
-    assert_eq!(population.len(), 256 * 256);
+    debug_assert_eq!(population.len(), 256 * 256);
     let mut cost : floatY = 0.0 as floatY;
     let mut sum : floatY = 0.0 as floatY;
     let mut buckets : floatY = 0.0 as floatY;
@@ -51,7 +51,7 @@ impl<AllocU32:alloc::Allocator<u32>> EntropyBucketPopulation<AllocU32> {
         self.bucket_populations.slice_mut().clone_from_slice(other.bucket_populations.slice());
    }
    fn add_assign(&mut self, other: &EntropyBucketPopulation<AllocU32>) {
-       assert_eq!(self.bucket_populations.slice().len(), other.bucket_populations.slice().len());
+       debug_assert_eq!(self.bucket_populations.slice().len(), other.bucket_populations.slice().len());
        for (item, other_item) in self.bucket_populations.slice_mut().iter_mut().zip(other.bucket_populations.slice().iter()) {
            *item += *other_item;
        }
@@ -100,7 +100,7 @@ impl<AllocU32:alloc::Allocator<u32>> EntropyBucketPopulation<AllocU32> {
        scratch.bucket_populations.slice_mut()[65535] += 1; // to demonstrate that we have
        scratch.bucket_populations.slice_mut()[65535] -= 1; // to demonstrate that we have write capability
        let mut stray_count = 0.0 as floatY;
-       assert_eq!((NUM_STRIDES - 1) & NUM_STRIDES, 0); // must be power of two
+       debug_assert_eq!((NUM_STRIDES - 1) & NUM_STRIDES, 0); // must be power of two
        for (index,val) in data0.iter().enumerate() {
            let prior_byte = prev_bytes[(index + (NUM_STRIDES - stride as usize)) & (NUM_STRIDES - 1)];
            let loc = &mut scratch.bucket_populations.slice_mut()[prior_byte as usize * 256 + *val as usize];
@@ -337,7 +337,7 @@ impl<AllocU32:alloc::Allocator<u32>> EntropyPyramid<AllocU32> {
                                 stride:u8,
                                 previous_bytes: [u8; NUM_STRIDES],
                                 scratch: &mut EntropyTally<AllocU32>) -> floatY {
-        assert!(stride as usize <= NUM_STRIDES);
+        debug_assert!(stride as usize <= NUM_STRIDES);
         let cost = self.pop[self.byte_index_to_pyramid_index(start_index as usize,  metablock_len)].bit_cost_of_data_subset(
             data0,
             stride,
@@ -349,7 +349,7 @@ impl<AllocU32:alloc::Allocator<u32>> EntropyPyramid<AllocU32> {
         let mut prev_val = 0;
         let pyr_item = &mut self.pop[index as usize];
         pyr_item.bzero();
-        assert_eq!(pyr_item.bucket_populations.slice()[65535], 0);
+        debug_assert_eq!(pyr_item.bucket_populations.slice()[65535], 0);
         for val in input.0.slice().iter().chain(input.1.slice().iter()) {
             pyr_item.bucket_populations.slice_mut()[prev_val as usize * 256 + *val as usize] += 1;
             prev_val = *val;
@@ -423,7 +423,7 @@ impl<AllocU32:alloc::Allocator<u32>> EntropyPyramid<AllocU32> {
                         self.populate_entry_stride1(third_range, 7 + ((i << 2) + (j << 1) + k));
                     }
                 } else {
-                    assert_eq!(NUM_LEVELS, 3); // we hard coded the 3 levels for now... we can add more later or make this into some kind of recursion
+                    debug_assert_eq!(NUM_LEVELS, 3); // we hard coded the 3 levels for now... we can add more later or make this into some kind of recursion
                     self.populate_entry_stride1(second_range, 3 + ((i << 1) + j));
                 }
             }
@@ -456,7 +456,7 @@ impl<AllocU32:alloc::Allocator<u32>> EntropyPyramid<AllocU32> {
             self.populate_entry(input.split_at(input.len() >> 1).1.split_at(input.len() >> 2).1.split_at(input.len() >>3).1, scratch, 0xe, None, Some(7..0xe));
 
         } else {
-            assert_eq!(NUM_LEVELS, 3); // we hard coded the 3 levels for now... we can add more later or make this into some kind of recursion
+            debug_assert_eq!(NUM_LEVELS, 3); // we hard coded the 3 levels for now... we can add more later or make this into some kind of recursion
         }
     }
 }
@@ -622,7 +622,7 @@ impl<AllocU32:alloc::Allocator<u32> > EntropyTally<AllocU32> {
                         for (lindex, val) in lit.data.slice().iter().enumerate() {
                             if lindex == NUM_STRIDES  {
                                 let vpriors = self.get_previous_bytes(input0, input1, NUM_STRIDES+*bytes_processed);
-                                assert_eq!(vpriors, priors);
+                                debug_assert_eq!(vpriors, priors);
                             }
                             for (index, prior) in priors.iter().enumerate() {
                                 self.pop[index].bucket_populations.slice_mut()[256 * (*prior as usize) + *val as usize] += 1;
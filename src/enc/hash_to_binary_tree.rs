@@ -9,6 +9,7 @@ use super::constants::{kInsExtra, kCopyExtra};
 use super::super::alloc;
 use super::super::alloc::{SliceWrapper, SliceWrapperMut, Allocator};
 use super::util::{Log2FloorNonZero, brotli_max_size_t,FastLog2, floatX};
+use super::suffix_array::SuffixArrayMatchFinder;
 use core;
 
 pub const kInfinity: floatX = 1.7e38 as floatX;
@@ -97,6 +98,8 @@ pub struct H10<AllocU32:Allocator<u32>, Buckets: Allocable<u32, AllocU32>+SliceW
     pub buckets_: Buckets,
     pub invalid_pos_:u32,
     pub forest: AllocU32::AllocatedMemory,
+    // the exhaustive search's index, kept between blocks while it still covers them
+    pub suffix_array_: Option<SuffixArrayMatchFinder<AllocU32>>,
     pub _params: core::marker::PhantomData<Params>,
 }
 
@@ -127,6 +130,7 @@ fn initialize_h10<AllocU32:Allocator<u32>, Buckets:SliceWrapperMut<u32>+SliceWra
         invalid_pos_: invalid_pos,
         buckets_: buckets,
         forest: m32.alloc_cell(num_nodes * 2),
+        suffix_array_: None,
     }
 }
 
@@ -136,6 +140,9 @@ impl<AllocU32: Allocator<u32>,
     pub fn free(&mut self, m32: &mut AllocU32) {
         m32.free_cell(core::mem::replace(&mut self.forest, AllocU32::AllocatedMemory::default()));
         self.buckets_.free(m32);
+        if let Some(mut suffix_array) = self.suffix_array_.take() {
+            suffix_array.free(m32);
+        }
     }
 }
 impl<AllocU32: Allocator<u32>,
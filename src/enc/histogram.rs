@@ -66,7 +66,7 @@ impl Default for HistogramCommand {
 }
 //#[derive(Clone)] // #derive is broken for arrays > 32
 pub struct HistogramDistance {
-  pub data_: [u32; 520],
+  pub data_: [u32; 544],
   pub total_count_: usize,
   pub bit_cost_: super::util::floatX,
 }
@@ -82,7 +82,7 @@ impl Clone for HistogramDistance {
 impl Default for HistogramDistance {
   fn default() -> HistogramDistance {
     return HistogramDistance {
-             data_: [0; 520],
+             data_: [0; 544],
              total_count_: 0,
              bit_cost_: 3.402e+38 as super::util::floatX,
            };
@@ -133,23 +133,23 @@ impl Default for Array264i {
         return Array264i([Mem256i::default().clone();33]);
     }
 }
-pub struct Array528i([Mem256i;66]);
-impl SliceWrapperMut<Mem256i> for Array528i {
+pub struct Array552i([Mem256i;69]);
+impl SliceWrapperMut<Mem256i> for Array552i {
   #[inline(always)]
     fn slice_mut(&mut self) -> &mut [Mem256i] {
         return &mut self.0[..]
     }
 }
-impl SliceWrapper<Mem256i> for Array528i {
+impl SliceWrapper<Mem256i> for Array552i {
   #[inline(always)]
     fn slice(&self) -> & [Mem256i] {
         return &self.0[..]
     }
 }
-impl Default for Array528i {
+impl Default for Array552i {
   #[inline(always)]
-    fn default() -> Array528i {
-        return Array528i([Mem256i::default();66]);
+    fn default() -> Array552i {
+        return Array552i([Mem256i::default();69]);
     }
 }
 
@@ -282,7 +282,7 @@ impl SliceWrapperMut<u32> for HistogramDistance {
 }
 
 #[cfg(feature="vector_scratch_space")]
-pub type HistogramDistanceScratch = Array528i;
+pub type HistogramDistanceScratch = Array552i;
 
 #[cfg(not(feature="vector_scratch_space"))]
 pub type HistogramDistanceScratch = EmptyIVec;
@@ -314,7 +314,7 @@ impl CostAccessors for HistogramDistance {
 
 
 
-#[derive(Copy,Clone)]
+#[derive(Copy,Clone,Debug)]
 pub enum ContextType {
   CONTEXT_LSB6 = 0,
   CONTEXT_MSB6 = 1,
@@ -403,6 +403,9 @@ pub fn HistogramAddVector<HistogramType: SliceWrapper<u32> + SliceWrapperMut<u32
 {
   let new_tc = (*xself).total_count().wrapping_add(n);
   (*xself).set_total_count(new_tc);
+  if super::simd::HistogramAddVector((*xself).slice_mut(), &p[..n]) {
+    return;
+  }
   for p_item in p[..n].iter() {
     let _rhs = 1;
     let index: usize = u64::from(p_item.clone()) as usize;
@@ -545,3 +548,39 @@ pub fn BrotliBuildHistogramsWithContext<'a,
     i = i.wrapping_add(1 as (usize));
   }
 }
+
+// Same as the literal part of BrotliBuildHistogramsWithContext: fills 64 context histograms
+// per literal block type, the context of each literal picked by its block type's mode.
+pub fn BrotliBuildLiteralHistogramsWithContext<'a,
+                                               AllocU8: alloc::Allocator<u8>,
+                                               AllocU32: alloc::Allocator<u32>>
+  (cmds: &[Command],
+   num_commands: usize,
+   literal_split: &BlockSplit<AllocU8, AllocU32>,
+   ringbuffer: &[u8],
+   start_pos: usize,
+   mask: usize,
+   mut prev_byte: u8,
+   mut prev_byte2: u8,
+   context_modes: &[ContextType],
+   literal_histograms: &mut [HistogramLiteral]) {
+  let mut pos: usize = start_pos;
+  let mut literal_it = NewBlockSplitIterator(literal_split);
+  for cmd in cmds.split_at(num_commands).0.iter() {
+    for _ in 0..cmd.insert_len_ {
+      BlockSplitIteratorNext(&mut literal_it);
+      let literal = ringbuffer[pos & mask];
+      let context = (literal_it.type_ << 6) +
+                    Context(prev_byte, prev_byte2, context_modes[literal_it.type_]) as usize;
+      HistogramAddItem(&mut literal_histograms[context], literal as usize);
+      prev_byte2 = prev_byte;
+      prev_byte = literal;
+      pos = pos.wrapping_add(1);
+    }
+    pos = pos.wrapping_add(CommandCopyLen(cmd) as usize);
+    if CommandCopyLen(cmd) != 0 {
+      prev_byte2 = ringbuffer[pos.wrapping_sub(2) & mask];
+      prev_byte = ringbuffer[pos.wrapping_sub(1) & mask];
+    }
+  }
+}
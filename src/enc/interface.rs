@@ -98,7 +98,10 @@ pub const MIXING_MATH_OFFSET: usize = 1;
 pub const PREDMODE_OFFSET: usize = 0;
 pub const MIXING_OFFSET:usize = NUM_PREDMODE_SETUP_VALUES + PREDMODE_OFFSET;
 pub const SPEED_OFFSET: usize = MIXING_OFFSET + NUM_MIXING_VALUES;
-pub const DISTANCE_CONTEXT_MAP_OFFSET: usize = SPEED_OFFSET + NUM_SPEED_VALUES;
+// one LITERAL_PREDICTION_MODE_* per literal block type
+pub const NUM_LITERAL_PREDICTION_MODES: usize = 256;
+pub const LITERAL_PREDICTION_MODES_OFFSET: usize = SPEED_OFFSET + NUM_SPEED_VALUES;
+pub const DISTANCE_CONTEXT_MAP_OFFSET: usize = LITERAL_PREDICTION_MODES_OFFSET + NUM_LITERAL_PREDICTION_MODES;
 pub const MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE: usize = DISTANCE_CONTEXT_MAP_OFFSET + 256 * 4;
 pub const MAX_LITERAL_CONTEXT_MAP_SIZE: usize = 256 * 64;
 pub const MAX_ADV_LITERAL_CONTEXT_MAP_SIZE: usize = 256 * 64 * 2;
@@ -158,6 +161,11 @@ impl<SliceType:SliceWrapper<u8>+SliceWrapperMut<u8>> PredictionModeContextMap<Sl
         let cm_slice = self.predmode_speed_and_distance_context_map.slice_mut();
         cm_slice[PREDMODE_OFFSET] = val.0;
     }
+    #[inline]
+    pub fn literal_prediction_modes_mut(&mut self) -> &mut [u8] {
+        let cm_slice = self.predmode_speed_and_distance_context_map.slice_mut();
+        &mut cm_slice[LITERAL_PREDICTION_MODES_OFFSET..DISTANCE_CONTEXT_MAP_OFFSET]
+    }
 }
 impl<SliceType:SliceWrapper<u8>> PredictionModeContextMap<SliceType> {
     #[inline]
@@ -202,7 +210,7 @@ impl<SliceType:SliceWrapper<u8>> PredictionModeContextMap<SliceType> {
     }
     #[inline]
     pub fn context_speeds_f8(&self) -> &[u8] {
-        &self.predmode_speed_and_distance_context_map.slice()[SPEED_OFFSET..DISTANCE_CONTEXT_MAP_OFFSET]
+        &self.predmode_speed_and_distance_context_map.slice()[SPEED_OFFSET..LITERAL_PREDICTION_MODES_OFFSET]
     }
     #[inline]
     pub fn distance_context_map(&self) -> &[u8] {
@@ -249,6 +257,17 @@ impl<SliceType:SliceWrapper<u8>> PredictionModeContextMap<SliceType> {
            LiteralPredictionModeNibble::default()
         }
     }
+    // the mode of the given literal block type, which is the mode of the whole
+    // metablock unless the per type modes are present
+    #[inline]
+    pub fn literal_prediction_mode_of(&self, block_type: usize) -> LiteralPredictionModeNibble {
+        let cm_slice = self.predmode_speed_and_distance_context_map.slice();
+        if self.has_context_speeds() && block_type < NUM_LITERAL_PREDICTION_MODES {
+           LiteralPredictionModeNibble(cm_slice[LITERAL_PREDICTION_MODES_OFFSET + block_type])
+        } else {
+           self.literal_prediction_mode()
+        }
+    }
     pub fn stride_context_speed(&self) -> [(u16, u16);2] {
        let v = self.stride_context_speed_f8();
        [(self::u8_to_speed(v[0].0), self::u8_to_speed(v[0].1)),
@@ -0,0 +1,711 @@
+// Compiles a list of IR commands (the interface::Command stream handed to the metablock
+// callback) back into a brotli stream. Literals, copies and dictionary words become
+// insert-and-copy commands, block switches become the block splits of each metablock and
+// each PredictionMode command starts a new metablock using its literal context modes.
+// Metablocks of more than 16MiB are split, continuing with the same block types.
+// Histograms and context maps are rebuilt from the data, as BrotliBuildMetaBlock does.
+use super::backward_references::BrotliEncoderParams;
+use super::block_split::BlockSplit;
+use super::brotli_bit_stream::{BrotliStoreMetaBlock, BrotliWriteBits, BrotliWriteBitsFailed, JumpToByteBoundary,
+                               MetaBlockSplit, RecoderState};
+use super::cluster::HistogramPair;
+use super::command::{Command, ComputeDistanceCode, InitCommand};
+use super::constants::{BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS, BROTLI_WINDOW_GAP};
+use super::encode::{EncodeWindowBits, InitInsertCommand};
+use super::entropy_encode::HuffmanTree;
+use super::histogram::{ContextType, CostAccessors, HistogramLiteral, HistogramCommand, HistogramDistance};
+use super::input_pair::{InputPair, InputReferenceMut};
+use super::interface;
+use super::metablock::{BrotliBuildMetaBlockHistograms, BrotliOptimizeHistograms};
+use super::pdf::PDF;
+use super::static_dict::kNumDistanceCacheEntries;
+use super::util::floatX;
+use super::vectorization::Mem256f;
+use super::super::alloc;
+use super::super::alloc::{SliceWrapper, SliceWrapperMut};
+use super::super::dictionary::{kBrotliDictionary, kBrotliDictionarySizeBitsByLength,
+                               kBrotliDictionaryOffsetsByLength};
+use super::super::transform::{TransformDictionaryWord, kNumTransforms};
+use core;
+#[cfg(not(feature="no-stdlib"))]
+use super::super::alloc::HeapAlloc;
+#[cfg(not(feature="no-stdlib"))]
+use std::vec::Vec;
+
+const BROTLI_MAX_METABLOCK_LENGTH: usize = 1 << 24;
+
+// The index in the command list of the command that could not be compiled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrotliIRError {
+  // a copy of fewer than 2 bytes or from before the start of the window
+  InvalidCopy(usize),
+  // a dictionary reference that doesn't exist or doesn't have the given final size
+  InvalidDictionaryWord(usize),
+  InvalidPredictionMode(usize),
+  OutputTooSmall,
+  // the bit writer ran past its storage or was handed a symbol wider than its code
+  BitWriterFailed,
+}
+
+// Number of bytes of output the command produces.
+pub fn BrotliIRCommandSize<SliceType: SliceWrapper<u8>>(cmd: &interface::Command<SliceType>) -> usize {
+  match *cmd {
+    interface::Command::Literal(ref lit) => lit.data.slice().len(),
+    interface::Command::Copy(ref copy) => copy.num_bytes as usize,
+    interface::Command::Dict(ref dict) => dict.final_size as usize,
+    interface::Command::BlockSwitchCommand(_) |
+    interface::Command::BlockSwitchLiteral(_) |
+    interface::Command::BlockSwitchDistance(_) |
+    interface::Command::PredictionMode(_) => 0,
+  }
+}
+
+// Upper bound on the compressed size of the commands.
+pub fn BrotliIRMaxCompressedSize<SliceType: SliceWrapper<u8>>(commands: &[interface::Command<SliceType>]) -> usize {
+  let mut num_metablocks = 1usize;
+  let mut total = 0usize;
+  for cmd in commands.iter() {
+    if let interface::Command::PredictionMode(_) = *cmd {
+      num_metablocks += 1;
+    }
+    total += BrotliIRCommandSize(cmd);
+  }
+  2 * total + 503 * (num_metablocks + total / BROTLI_MAX_METABLOCK_LENGTH) + 16
+}
+
+// Block types and lengths of one category, in the order the switches arrive.
+struct IRBlockSplit<'a> {
+  types: &'a mut [u8],
+  lengths: &'a mut [u32],
+  num_blocks: usize,
+}
+
+impl<'a> IRBlockSplit<'a> {
+  fn new(types: &'a mut [u8], lengths: &'a mut [u32], block_type: u8) -> Self {
+    types[0] = block_type;
+    lengths[0] = 0;
+    IRBlockSplit {
+      types: types,
+      lengths: lengths,
+      num_blocks: 1,
+    }
+  }
+  // the type the next block of this category is coded with
+  fn current(&self) -> u8 {
+    self.types[self.num_blocks - 1]
+  }
+  fn add(&mut self, count: usize) {
+    self.lengths[self.num_blocks - 1] += count as u32;
+  }
+  fn switch(&mut self, block_type: u8) {
+    let last = self.num_blocks - 1;
+    if self.lengths[last] == 0 {
+      // nothing was coded with the current type: retype it, merging with the block before
+      self.types[last] = block_type;
+      if last > 0 && self.types[last - 1] == block_type {
+        self.num_blocks -= 1;
+      }
+    } else if self.types[last] != block_type {
+      self.types[self.num_blocks] = block_type;
+      self.lengths[self.num_blocks] = 0;
+      self.num_blocks += 1;
+    }
+  }
+  // Drops a trailing empty block and relabels so the first block has type 0,
+  // which the format implies. Returns (num_types, num_blocks).
+  fn finish(&mut self) -> (usize, usize) {
+    if self.num_blocks > 1 && self.lengths[self.num_blocks - 1] == 0 {
+      self.num_blocks -= 1;
+    }
+    let first = self.types[0];
+    let mut max_type = 0u8;
+    for block_type in self.types[..self.num_blocks].iter_mut() {
+      if *block_type == first {
+        *block_type = 0;
+      } else if *block_type == 0 {
+        *block_type = first;
+      }
+      max_type = core::cmp::max(max_type, *block_type);
+    }
+    (max_type as usize + 1, self.num_blocks)
+  }
+}
+
+struct IRCompileState {
+  pos: usize,
+  dist_cache: [i32; kNumDistanceCacheEntries],
+  max_backward: usize,
+  storage_ix: usize,
+  recoder_state: RecoderState,
+}
+
+// Compiles commands from start (a command index and how many of its bytes earlier
+// metablocks took) into one metablock of at most 16MiB, starting with the block types of
+// block_types and leaving the last ones there. Returns where the next metablock starts,
+// which is commands.len() once they all fit.
+fn CompileMetaBlock<SliceType: SliceWrapper<u8>,
+                    AllocU8: alloc::Allocator<u8>,
+                    AllocU16: alloc::Allocator<u16>,
+                    AllocU32: alloc::Allocator<u32>,
+                    AllocF64: alloc::Allocator<floatX>,
+                    AllocFV: alloc::Allocator<Mem256f>,
+                    AllocPDF: alloc::Allocator<PDF>,
+                    AllocStaticCommand: alloc::Allocator<interface::StaticCommand>,
+                    AllocHL: alloc::Allocator<HistogramLiteral>,
+                    AllocHC: alloc::Allocator<HistogramCommand>,
+                    AllocHD: alloc::Allocator<HistogramDistance>,
+                    AllocHP: alloc::Allocator<HistogramPair>,
+                    AllocCT: alloc::Allocator<ContextType>,
+                    AllocHT: alloc::Allocator<HuffmanTree>,
+                    AllocCommand: alloc::Allocator<Command>>
+  (m8: &mut AllocU8,
+   m16: &mut AllocU16,
+   m32: &mut AllocU32,
+   mf64: &mut AllocF64,
+   mfv: &mut AllocFV,
+   mpdf: &mut AllocPDF,
+   mc: &mut AllocStaticCommand,
+   mhl: &mut AllocHL,
+   mhc: &mut AllocHC,
+   mhd: &mut AllocHD,
+   mhp: &mut AllocHP,
+   mct: &mut AllocCT,
+   mht: &mut AllocHT,
+   mcommand: &mut AllocCommand,
+   params: &BrotliEncoderParams,
+   commands: &[interface::Command<SliceType>],
+   first_index: usize,
+   start: (usize, usize),
+   block_types: &mut [u8; 3],
+   literal_context_mode: ContextType,
+   literal_context_modes: &[ContextType; 256],
+   history: &mut [u8],
+   mask: usize,
+   state: &mut IRCompileState,
+   storage: &mut [u8])
+   -> Result<(usize, usize), BrotliIRError> {
+  let start_pos = state.pos;
+  let saved_dist_cache = state.dist_cache;
+  let mut cmds = mcommand.alloc_cell(commands.len() + 1);
+  let mut literal_types = m8.alloc_cell(commands.len() + 1);
+  let mut literal_lengths = m32.alloc_cell(commands.len() + 1);
+  let mut command_types = m8.alloc_cell(commands.len() + 1);
+  let mut command_lengths = m32.alloc_cell(commands.len() + 1);
+  let mut distance_types = m8.alloc_cell(commands.len() + 1);
+  let mut distance_lengths = m32.alloc_cell(commands.len() + 1);
+  let mut num_commands = 0usize;
+  let mut result = Ok((commands.len(), 0));
+  let literal_split_size;
+  let command_split_size;
+  let distance_split_size;
+  let first_literal_type;
+  {
+    let mut literal_split = IRBlockSplit::new(literal_types.slice_mut(), literal_lengths.slice_mut(), block_types[0]);
+    let mut command_split = IRBlockSplit::new(command_types.slice_mut(), command_lengths.slice_mut(), block_types[1]);
+    let mut distance_split = IRBlockSplit::new(distance_types.slice_mut(),
+                                               distance_lengths.slice_mut(),
+                                               block_types[2]);
+    let mut insert_len = 0usize;
+    for (index, cmd) in commands.iter().enumerate().skip(start.0) {
+      let offset = if index == start.0 { start.1 } else { 0 };
+      let max_distance = core::cmp::min(state.pos, state.max_backward);
+      // the bytes this metablock can still hold
+      let room = BROTLI_MAX_METABLOCK_LENGTH - (state.pos - start_pos);
+      // set when the command only partly fits
+      let mut next_offset = None;
+      let (copy_len, copy_len_code, distance) = match *cmd {
+        interface::Command::Literal(ref lit) => {
+          let data = &lit.data.slice()[offset..];
+          let len = core::cmp::min(data.len(), room);
+          history[state.pos..state.pos + len].clone_from_slice(&data[..len]);
+          state.pos += len;
+          insert_len += len;
+          literal_split.add(len);
+          if len < data.len() {
+            result = Ok((index, offset + len));
+            break;
+          }
+          continue;
+        }
+        interface::Command::Copy(ref copy) => {
+          let distance = copy.distance as usize;
+          if offset == 0 && (copy.num_bytes < 2 || distance == 0 || distance > max_distance) {
+            result = Err(BrotliIRError::InvalidCopy(first_index + index));
+            break;
+          }
+          // a copy that does not fit is split, leaving at least 2 bytes to each part
+          let remaining = copy.num_bytes as usize - offset;
+          let len = if remaining <= room {
+            remaining
+          } else {
+            core::cmp::min(room, remaining - 2)
+          };
+          if len < 2 {
+            result = Ok((index, offset));
+            break;
+          }
+          if len < remaining {
+            next_offset = Some(offset + len);
+          }
+          for i in state.pos..state.pos + len {
+            history[i] = history[i - distance];
+          }
+          (len, len, distance)
+        }
+        interface::Command::Dict(ref dict) => {
+          let word_size = dict.word_size as usize;
+          let ndbits = if word_size < kBrotliDictionarySizeBitsByLength.len() {
+            kBrotliDictionarySizeBitsByLength[word_size] as usize
+          } else {
+            0
+          };
+          if ndbits == 0 || dict.word_id as usize >= (1 << ndbits) ||
+             i32::from(dict.transform) >= kNumTransforms {
+            result = Err(BrotliIRError::InvalidDictionaryWord(first_index + index));
+            break;
+          }
+          let word_index = dict.word_id as usize * word_size +
+                           kBrotliDictionaryOffsetsByLength[word_size] as usize;
+          let mut transformed_word = [0u8; 38];
+          let final_size = TransformDictionaryWord(&mut transformed_word[..],
+                                                   &kBrotliDictionary[word_index..word_index + word_size],
+                                                   word_size as i32,
+                                                   i32::from(dict.transform)) as usize;
+          if final_size != dict.final_size as usize || final_size == 0 {
+            result = Err(BrotliIRError::InvalidDictionaryWord(first_index + index));
+            break;
+          }
+          if final_size > room {
+            result = Ok((index, 0));
+            break;
+          }
+          history[state.pos..state.pos + final_size].clone_from_slice(&transformed_word[..final_size]);
+          let distance = max_distance + 1 + ((dict.transform as usize) << ndbits | dict.word_id as usize);
+          (final_size, word_size, distance)
+        }
+        interface::Command::BlockSwitchLiteral(ref switch) => {
+          literal_split.switch(switch.block_type());
+          continue;
+        }
+        interface::Command::BlockSwitchCommand(ref switch) => {
+          command_split.switch(switch.block_type());
+          continue;
+        }
+        interface::Command::BlockSwitchDistance(ref switch) => {
+          distance_split.switch(switch.block_type());
+          continue;
+        }
+        interface::Command::PredictionMode(_) => continue,
+      };
+      let distance_code = ComputeDistanceCode(distance, max_distance, &state.dist_cache[..]);
+      InitCommand(&mut cmds.slice_mut()[num_commands],
+                  &params.dist,
+                  insert_len,
+                  copy_len,
+                  copy_len_code,
+                  distance_code);
+      if distance <= max_distance && distance_code > 0 {
+        state.dist_cache[3] = state.dist_cache[2];
+        state.dist_cache[2] = state.dist_cache[1];
+        state.dist_cache[1] = state.dist_cache[0];
+        state.dist_cache[0] = distance as i32;
+      }
+      command_split.add(1);
+      if cmds.slice()[num_commands].cmd_prefix_ >= 128 {
+        distance_split.add(1);
+      }
+      num_commands += 1;
+      insert_len = 0;
+      state.pos += copy_len;
+      if let Some(next_offset) = next_offset {
+        result = Ok((index, next_offset));
+        break;
+      }
+    }
+    if insert_len != 0 {
+      InitInsertCommand(&mut cmds.slice_mut()[num_commands], insert_len);
+      command_split.add(1);
+      num_commands += 1;
+    }
+    block_types[0] = literal_split.current();
+    block_types[1] = command_split.current();
+    block_types[2] = distance_split.current();
+    first_literal_type = literal_split.types[0] as usize;
+    literal_split_size = literal_split.finish();
+    command_split_size = command_split.finish();
+    distance_split_size = distance_split.finish();
+  }
+  let mut mb = MetaBlockSplit::<AllocU8, AllocU32, AllocHL, AllocHC, AllocHD>::new();
+  mb.literal_split = BlockSplit::<AllocU8, AllocU32> {
+    num_types: literal_split_size.0,
+    num_blocks: literal_split_size.1,
+    types: literal_types,
+    lengths: literal_lengths,
+  };
+  if literal_context_modes.iter().any(|mode| *mode as u8 != literal_context_mode as u8) {
+    // finish() swapped the first type with type 0
+    mb.literal_context_modes = m8.alloc_cell(literal_split_size.0);
+    for (block_type, item) in mb.literal_context_modes.slice_mut().iter_mut().enumerate() {
+      let ir_type = if block_type == 0 {
+        first_literal_type
+      } else if block_type == first_literal_type {
+        0
+      } else {
+        block_type
+      };
+      *item = literal_context_modes[ir_type] as u8;
+    }
+  }
+  mb.command_split = BlockSplit::<AllocU8, AllocU32> {
+    num_types: command_split_size.0,
+    num_blocks: command_split_size.1,
+    types: command_types,
+    lengths: command_lengths,
+  };
+  mb.distance_split = BlockSplit::<AllocU8, AllocU32> {
+    num_types: distance_split_size.0,
+    num_blocks: distance_split_size.1,
+    types: distance_types,
+    lengths: distance_lengths,
+  };
+  if result.is_ok() && state.pos != start_pos {
+    let prev_byte = if start_pos > 0 { history[start_pos - 1] } else { 0 };
+    let prev_byte2 = if start_pos > 1 { history[start_pos - 2] } else { 0 };
+    let mut lit_scratch_space = HistogramLiteral::make_nnz_storage();
+    let mut dst_scratch_space = HistogramDistance::make_nnz_storage();
+    BrotliBuildMetaBlockHistograms(m32, mhl, mhc, mhd, mhp, mct,
+                                   history,
+                                   start_pos,
+                                   mask,
+                                   params,
+                                   prev_byte,
+                                   prev_byte2,
+                                   cmds.slice(),
+                                   num_commands,
+                                   literal_context_mode,
+                                   &mut lit_scratch_space,
+                                   &mut dst_scratch_space,
+                                   &mut mb);
+    BrotliOptimizeHistograms(core::cmp::min(params.dist.alphabet_size as usize,
+                                            BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS),
+                             &mut mb);
+    let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<InputReferenceMut>,
+                            _cmds: &mut [interface::StaticCommand],
+                            _mb: InputPair,
+                            _mfv: &mut AllocFV,
+                            _mpdf: &mut AllocPDF,
+                            _mc: &mut AllocStaticCommand| ();
+    BrotliStoreMetaBlock(m8, m16, m32, mf64, mfv, mpdf, mc, mht,
+                         history,
+                         start_pos,
+                         state.pos - start_pos,
+                         mask,
+                         prev_byte,
+                         prev_byte2,
+                         0,
+                         params,
+                         literal_context_mode,
+                         &saved_dist_cache,
+                         cmds.slice(),
+                         num_commands,
+                         &mut mb,
+                         &mut state.recoder_state,
+                         &mut state.storage_ix,
+                         storage,
+                         &mut nop_callback);
+  }
+  mb.destroy(m8, m32, mhl, mhc, mhd);
+  mcommand.free_cell(cmds);
+  result
+}
+
+// Writes the brotli stream the commands describe to output and returns its size.
+// The window size and distance parameters come from params; a copy or dictionary
+// reference must be representable in that window.
+pub fn BrotliCompileIR<SliceType: SliceWrapper<u8>,
+                       AllocU8: alloc::Allocator<u8>,
+                       AllocU16: alloc::Allocator<u16>,
+                       AllocU32: alloc::Allocator<u32>,
+                       AllocF64: alloc::Allocator<floatX>,
+                       AllocFV: alloc::Allocator<Mem256f>,
+                       AllocPDF: alloc::Allocator<PDF>,
+                       AllocStaticCommand: alloc::Allocator<interface::StaticCommand>,
+                       AllocHL: alloc::Allocator<HistogramLiteral>,
+                       AllocHC: alloc::Allocator<HistogramCommand>,
+                       AllocHD: alloc::Allocator<HistogramDistance>,
+                       AllocHP: alloc::Allocator<HistogramPair>,
+                       AllocCT: alloc::Allocator<ContextType>,
+                       AllocHT: alloc::Allocator<HuffmanTree>,
+                       AllocCommand: alloc::Allocator<Command>>
+  (m8: &mut AllocU8,
+   m16: &mut AllocU16,
+   m32: &mut AllocU32,
+   mf64: &mut AllocF64,
+   mfv: &mut AllocFV,
+   mpdf: &mut AllocPDF,
+   mc: &mut AllocStaticCommand,
+   mhl: &mut AllocHL,
+   mhc: &mut AllocHC,
+   mhd: &mut AllocHD,
+   mhp: &mut AllocHP,
+   mct: &mut AllocCT,
+   mht: &mut AllocHT,
+   mcommand: &mut AllocCommand,
+   params: &BrotliEncoderParams,
+   commands: &[interface::Command<SliceType>],
+   output: &mut [u8])
+   -> Result<usize, BrotliIRError> {
+  let mut block_params = params.clone();
+  block_params.log_meta_block = false;
+  let total_size: usize = commands.iter().map(BrotliIRCommandSize).sum();
+  let history_size = core::cmp::max(total_size, 1).next_power_of_two();
+  let mut history = m8.alloc_cell(history_size);
+  let mut storage = m8.alloc_cell(BrotliIRMaxCompressedSize(commands));
+  let mut state = IRCompileState {
+    pos: 0,
+    dist_cache: [4, 11, 15, 16],
+    max_backward: (1usize << params.lgwin) - BROTLI_WINDOW_GAP,
+    storage_ix: 0,
+    recoder_state: RecoderState::new(),
+  };
+  let mut last_bytes = 0u16;
+  let mut last_bytes_bits = 0u8;
+  EncodeWindowBits(params.lgwin, params.large_window, &mut last_bytes, &mut last_bytes_bits);
+  BrotliWriteBits(last_bytes_bits, u64::from(last_bytes), &mut state.storage_ix, storage.slice_mut());
+  let mut literal_context_mode = ContextType::CONTEXT_UTF8;
+  let mut literal_context_modes = [ContextType::CONTEXT_UTF8; 256];
+  let mut result = Ok(());
+  let mut metablock_start = 0usize;
+  for index in 0..commands.len() + 1 {
+    let next_mode = if index == commands.len() {
+      None
+    } else if let interface::Command::PredictionMode(ref pm) = commands[index] {
+      let mut modes = [ContextType::CONTEXT_UTF8; 256];
+      let mut valid = true;
+      for (block_type, mode) in modes.iter_mut().enumerate() {
+        match pm.literal_prediction_mode_of(block_type).to_context_enum() {
+          Ok(block_mode) => *mode = block_mode,
+          Err(()) => valid = false,
+        }
+      }
+      match pm.literal_prediction_mode().to_context_enum() {
+        Ok(mode) if valid => Some((mode, modes)),
+        _ => {
+          result = Err(BrotliIRError::InvalidPredictionMode(index));
+          break;
+        }
+      }
+    } else {
+      continue;
+    };
+    let mut block_types = [0u8; 3];
+    let mut start = (0usize, 0usize);
+    while start.0 < index - metablock_start {
+      match CompileMetaBlock(m8, m16, m32, mf64, mfv, mpdf, mc, mhl, mhc, mhd, mhp, mct, mht, mcommand,
+                             &block_params,
+                             &commands[metablock_start..index],
+                             metablock_start,
+                             start,
+                             &mut block_types,
+                             literal_context_mode,
+                             &literal_context_modes,
+                             history.slice_mut(),
+                             history_size - 1,
+                             &mut state,
+                             storage.slice_mut()) {
+        Ok(next) => start = next,
+        Err(e) => {
+          result = Err(e);
+          break;
+        }
+      }
+    }
+    if result.is_err() {
+      break;
+    }
+    if let Some((mode, modes)) = next_mode {
+      literal_context_mode = mode;
+      literal_context_modes = modes;
+    }
+    metablock_start = index + 1;
+  }
+  // ISLAST and ISLASTEMPTY
+  BrotliWriteBits(2, 3, &mut state.storage_ix, storage.slice_mut());
+  JumpToByteBoundary(&mut state.storage_ix, storage.slice_mut());
+  let output_size = state.storage_ix >> 3;
+  if result.is_ok() {
+    if BrotliWriteBitsFailed(state.storage_ix) {
+      result = Err(BrotliIRError::BitWriterFailed);
+    } else if output_size > output.len() {
+      result = Err(BrotliIRError::OutputTooSmall);
+    } else {
+      output[..output_size].clone_from_slice(&storage.slice()[..output_size]);
+    }
+  }
+  m8.free_cell(storage);
+  m8.free_cell(history);
+  result.map(|_| output_size)
+}
+
+#[cfg(not(feature="no-stdlib"))]
+pub fn BrotliCompileIRToVec<SliceType: SliceWrapper<u8>>(params: &BrotliEncoderParams,
+                                                         commands: &[interface::Command<SliceType>])
+                                                         -> Result<Vec<u8>, BrotliIRError> {
+  let mut output = vec![0u8; BrotliIRMaxCompressedSize(commands)];
+  let output_size = BrotliCompileIR(&mut HeapAlloc::<u8> { default_value: 0 },
+                                    &mut HeapAlloc::<u16> { default_value: 0 },
+                                    &mut HeapAlloc::<u32> { default_value: 0 },
+                                    &mut HeapAlloc::<floatX> { default_value: 0.0 as floatX },
+                                    &mut HeapAlloc::<Mem256f> { default_value: Mem256f::default() },
+                                    &mut HeapAlloc::<PDF> { default_value: PDF::default() },
+                                    &mut HeapAlloc::<interface::StaticCommand> {
+                                      default_value: interface::StaticCommand::default(),
+                                    },
+                                    &mut HeapAlloc::<HistogramLiteral> {
+                                      default_value: HistogramLiteral::default(),
+                                    },
+                                    &mut HeapAlloc::<HistogramCommand> {
+                                      default_value: HistogramCommand::default(),
+                                    },
+                                    &mut HeapAlloc::<HistogramDistance> {
+                                      default_value: HistogramDistance::default(),
+                                    },
+                                    &mut HeapAlloc::<HistogramPair> { default_value: HistogramPair::default() },
+                                    &mut HeapAlloc::<ContextType> { default_value: ContextType::default() },
+                                    &mut HeapAlloc::<HuffmanTree> { default_value: HuffmanTree::default() },
+                                    &mut HeapAlloc::<Command> { default_value: Command::default() },
+                                    params,
+                                    commands,
+                                    &mut output[..])?;
+  output.truncate(output_size);
+  Ok(output)
+}
+
+#[cfg(all(test, not(feature="no-stdlib")))]
+mod test {
+  #[allow(unused_imports)]
+  use std::vec::Vec;
+  #[allow(unused_imports)]
+  use super::super::interface::{Command, CopyCommand, DictCommand, LiteralCommand, BlockSwitch,
+                                LiteralBlockSwitch, PredictionModeContextMap, FeatureFlagSliceType};
+  #[allow(unused_imports)]
+  use super::super::input_pair::InputReference;
+  #[allow(unused)]
+  fn literal<'a>(data: &'a [u8]) -> Command<InputReference<'a>> {
+    Command::Literal(LiteralCommand {
+      data: InputReference { data: data, orig_offset: 0 },
+      prob: FeatureFlagSliceType::<InputReference>::default(),
+      high_entropy: false,
+    })
+  }
+  #[allow(unused)]
+  fn prediction_mode<'a>(mode: &'a [u8]) -> Command<InputReference<'a>> {
+    Command::PredictionMode(PredictionModeContextMap::<InputReference> {
+      literal_context_map: InputReference { data: &[], orig_offset: 0 },
+      predmode_speed_and_distance_context_map: InputReference { data: mode, orig_offset: 0 },
+    })
+  }
+  #[allow(unused)]
+  fn copy(distance: u32, num_bytes: u32) -> Command<InputReference<'static>> {
+    Command::Copy(CopyCommand { distance: distance, num_bytes: num_bytes })
+  }
+  #[allow(unused)]
+  fn dict(word_size: u8, final_size: u8) -> Command<InputReference<'static>> {
+    Command::Dict(DictCommand {
+      word_size: word_size,
+      transform: 0,
+      final_size: final_size,
+      empty: 0,
+      word_id: 0,
+    })
+  }
+  #[allow(unused)]
+  fn expected_output(commands: &[Command<InputReference>]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for cmd in commands.iter() {
+      match *cmd {
+        Command::Literal(ref lit) => output.extend_from_slice(lit.data.data),
+        Command::Copy(ref c) => {
+          for _ in 0..c.num_bytes {
+            let byte = output[output.len() - c.distance as usize];
+            output.push(byte);
+          }
+        }
+        Command::Dict(ref d) => {
+          let offset = super::kBrotliDictionaryOffsetsByLength[d.word_size as usize] as usize;
+          output.extend_from_slice(&super::kBrotliDictionary[offset..offset + d.word_size as usize]);
+        }
+        _ => {}
+      }
+    }
+    output
+  }
+  #[test]
+  fn test_compile_ir_block_switches() {
+    let commands = [prediction_mode(&[1]),
+                    literal(b"hello brotli "),
+                    copy(13, 13),
+                    Command::BlockSwitchLiteral(LiteralBlockSwitch::new(2, 0)),
+                    literal(b"0123"),
+                    dict(4, 4),
+                    Command::BlockSwitchCommand(BlockSwitch::new(1)),
+                    copy(4, 8),
+                    Command::BlockSwitchDistance(BlockSwitch::new(1)),
+                    literal(b"xyz"),
+                    copy(30, 5),
+                    prediction_mode(&[2]),
+                    literal(b"tail"),
+                    copy(17, 6)];
+    let params = super::super::encode::BrotliEncoderInitParams();
+    let compressed = super::BrotliCompileIRToVec(&params, &commands[..]).unwrap();
+    let mut output = Vec::new();
+    ::BrotliDecompress(&mut &compressed[..], &mut output).unwrap();
+    assert_eq!(output, expected_output(&commands[..]));
+  }
+  #[test]
+  fn test_compile_ir_empty() {
+    let commands: [Command<InputReference>; 0] = [];
+    let params = super::super::encode::BrotliEncoderInitParams();
+    let compressed = super::BrotliCompileIRToVec(&params, &commands[..]).unwrap();
+    let mut output = Vec::new();
+    ::BrotliDecompress(&mut &compressed[..], &mut output).unwrap();
+    assert_eq!(output.len(), 0);
+  }
+  #[test]
+  #[cfg(not(feature="no-stdlib"))]
+  fn test_compile_ir_splits_large_metablocks() {
+    let mut data = vec![0u8; (16 << 20) + 10];
+    for (i, byte) in data.iter_mut().enumerate() {
+      *byte = b"brotli ir test "[(i * 7 + (i >> 12)) % 15];
+    }
+    let commands = [Command::BlockSwitchLiteral(LiteralBlockSwitch::new(1, 0)),
+                    literal(&data[..]),
+                    copy(7, 18 << 20),
+                    literal(b"tail")];
+    let params = super::super::encode::BrotliEncoderInitParams();
+    let compressed = super::BrotliCompileIRToVec(&params, &commands[..]).unwrap();
+    let mut output = Vec::new();
+    ::BrotliDecompress(&mut &compressed[..], &mut output).unwrap();
+    assert!(output == expected_output(&commands[..]));
+    let report = super::super::analyzer::BrotliAnalyzeStream(&compressed[..]).unwrap();
+    let metablocks = report.metablocks
+      .iter()
+      .filter(|mb| mb.kind == super::super::analyzer::BrotliMetaBlockType::Compressed)
+      .count();
+    assert!(metablocks >= 3);
+  }
+  #[test]
+  fn test_compile_ir_errors() {
+    let params = super::super::encode::BrotliEncoderInitParams();
+    assert_eq!(super::BrotliCompileIRToVec(&params, &[literal(b"abc"), copy(4, 2)][..]),
+               Err(super::BrotliIRError::InvalidCopy(1)));
+    assert_eq!(super::BrotliCompileIRToVec(&params, &[literal(b"abc"), copy(1, 1)][..]),
+               Err(super::BrotliIRError::InvalidCopy(1)));
+    assert_eq!(super::BrotliCompileIRToVec(&params, &[dict(4, 5)][..]),
+               Err(super::BrotliIRError::InvalidDictionaryWord(0)));
+    assert_eq!(super::BrotliCompileIRToVec(&params, &[dict(3, 3)][..]),
+               Err(super::BrotliIRError::InvalidDictionaryWord(0)));
+    assert_eq!(super::BrotliCompileIRToVec(&params, &[literal(b"abc"), prediction_mode(&[7])][..]),
+               Err(super::BrotliIRError::InvalidPredictionMode(1)));
+  }
+}
@@ -0,0 +1,496 @@
+// Reads back the text IR printed by `brotli -i` (one interface::Command per line).
+//
+// Grammar, with tokens separated by one or more spaces or tabs:
+//
+//   ir         = { [ command ] newline } ;
+//   command    = window | literal | copy | dict | ltype | ctype | dtype | prediction ;
+//   window     = "window" uint { uint } ;                      (header, skipped)
+//   literal    = ( "insert" | "rndins" ) uint [ hex ] ;        (length, then the bytes)
+//   copy       = "copy" uint "from" uint ;                     (num_bytes, distance)
+//   dict       = "dict" uint "word" uint "," uint hex "func" uint hex ;
+//                (final_size, word_size, word_id, raw word, transform, transformed word)
+//   ltype      = "ltype" uint uint ;                           (block type, stride)
+//   ctype      = "ctype" uint ;
+//   dtype      = "dtype" uint ;
+//   prediction = "prediction" mode [ "literalmodes" { mode } ]
+//                "lcontextmap" { uint } "dcontextmap" { uint } "mixingvalues" { uint } [ speeds ] ;
+//   speeds     = "cmspeedinc" uint uint "cmspeedmax" uint uint
+//                "stspeedinc" uint uint "stspeedmax" uint uint
+//                "mxspeedinc" uint uint "mxspeedmax" uint uint ;
+//   mode       = "lsb6" | "msb6" | "utf8" | "sign" ;
+//   hex        = { hexdigit hexdigit } ;
+//
+// Blank lines are skipped. Context map and mixing values are bytes, speeds are u16.
+// A prediction with no literal modes, distance context map, mixing values or speeds only
+// carries its mode; otherwise mixingvalues holds either nothing (all zero) or all of them,
+// and literal block types without a mode of their own use the mode of the prediction.
+use super::interface;
+use super::interface::{BlockSwitch, CopyCommand, DictCommand, FeatureFlagSliceType, LiteralBlockSwitch,
+                       LiteralCommand, LiteralPredictionModeNibble, PredictionModeContextMap};
+use super::super::alloc;
+use super::super::alloc::SliceWrapperMut;
+use super::super::dictionary::{kBrotliDictionary, kBrotliDictionarySizeBitsByLength,
+                               kBrotliDictionaryOffsetsByLength};
+use super::super::transform::{TransformDictionaryWord, kNumTransforms};
+#[cfg(not(feature="no-stdlib"))]
+use super::super::alloc::HeapAlloc;
+#[cfg(not(feature="no-stdlib"))]
+use std::vec::Vec;
+
+// Each error carries the (1-based) line number it was found on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrotliIRParseError {
+  UnknownCommand(usize),
+  // a missing, extra or misplaced field
+  Malformed(usize),
+  // a field that isn't a decimal number or doesn't fit its type
+  InvalidNumber(usize),
+  InvalidHex(usize),
+  // a length that disagrees with the data following it
+  LengthMismatch(usize),
+  // a dictionary reference that doesn't exist or doesn't match the printed words
+  InvalidDictionaryWord(usize),
+  InvalidPredictionMode(usize),
+}
+
+#[derive(Clone)]
+struct Tokens<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Iterator for Tokens<'a> {
+  type Item = &'a [u8];
+  fn next(&mut self) -> Option<&'a [u8]> {
+    let is_space = |b: &u8| *b == b' ' || *b == b'\t' || *b == b'\r';
+    let start = match self.data.iter().position(|b| !is_space(b)) {
+      Some(start) => start,
+      None => {
+        self.data = &[];
+        return None;
+      }
+    };
+    let rest = &self.data[start..];
+    let len = rest.iter().position(|b| is_space(b)).unwrap_or(rest.len());
+    self.data = &rest[len..];
+    Some(&rest[..len])
+  }
+}
+
+fn ParseNumber(token: Option<&[u8]>, max: u32, line: usize) -> Result<u32, BrotliIRParseError> {
+  let token = match token {
+    Some(token) => token,
+    None => return Err(BrotliIRParseError::Malformed(line)),
+  };
+  if token.is_empty() || !token.iter().all(|b| *b >= b'0' && *b <= b'9') {
+    return Err(BrotliIRParseError::InvalidNumber(line));
+  }
+  let mut value = 0u64;
+  for digit in token.iter() {
+    value = value * 10 + u64::from(*digit - b'0');
+    if value > u64::from(max) {
+      return Err(BrotliIRParseError::InvalidNumber(line));
+    }
+  }
+  Ok(value as u32)
+}
+
+fn Expect(token: Option<&[u8]>, keyword: &[u8], line: usize) -> Result<(), BrotliIRParseError> {
+  if token != Some(keyword) {
+    return Err(BrotliIRParseError::Malformed(line));
+  }
+  Ok(())
+}
+
+fn HexDigit(digit: u8) -> Option<u8> {
+  if digit >= b'0' && digit <= b'9' {
+    Some(digit - b'0')
+  } else if digit >= b'a' && digit <= b'f' {
+    Some(digit - b'a' + 10)
+  } else if digit >= b'A' && digit <= b'F' {
+    Some(digit - b'A' + 10)
+  } else {
+    None
+  }
+}
+
+// Checks that hex holds exactly len bytes.
+fn CheckHex(hex: &[u8], len: usize, line: usize) -> Result<(), BrotliIRParseError> {
+  if !hex.iter().all(|digit| HexDigit(*digit).is_some()) {
+    return Err(BrotliIRParseError::InvalidHex(line));
+  }
+  if hex.len() != len * 2 {
+    return Err(BrotliIRParseError::LengthMismatch(line));
+  }
+  Ok(())
+}
+
+fn DecodeHex(hex: &[u8], output: &mut [u8]) {
+  for (byte, pair) in output.iter_mut().zip(hex.chunks(2)) {
+    *byte = (HexDigit(pair[0]).unwrap() << 4) | HexDigit(pair[1]).unwrap();
+  }
+}
+
+// Number of leading decimal fields (each at most 255), which are left in tokens.
+fn CountBytes(tokens: &Tokens, line: usize) -> Result<usize, BrotliIRParseError> {
+  let mut count = 0usize;
+  for token in tokens.clone() {
+    if !token[0].is_ascii_digit() {
+      break;
+    }
+    ParseNumber(Some(token), 255, line)?;
+    count += 1;
+  }
+  Ok(count)
+}
+
+fn Skip(tokens: &mut Tokens, count: usize) {
+  for _ in 0..count {
+    tokens.next();
+  }
+}
+
+fn FillBytes(tokens: &mut Tokens, output: &mut [u8]) {
+  for byte in output.iter_mut() {
+    *byte = ParseNumber(tokens.next(), 255, 0).unwrap() as u8;
+  }
+}
+
+fn ParseSpeeds(tokens: &mut Tokens, line: usize) -> Result<[(u16, u16); 6], BrotliIRParseError> {
+  let keywords: [&[u8]; 6] = [b"cmspeedinc", b"cmspeedmax", b"stspeedinc", b"stspeedmax", b"mxspeedinc", b"mxspeedmax"];
+  let mut speeds = [(0u16, 0u16); 6];
+  for (speed, keyword) in speeds.iter_mut().zip(keywords.iter()) {
+    Expect(tokens.next(), keyword, line)?;
+    speed.0 = ParseNumber(tokens.next(), 0xffff, line)? as u16;
+    speed.1 = ParseNumber(tokens.next(), 0xffff, line)? as u16;
+  }
+  Ok(speeds)
+}
+
+fn ParseMode(token: Option<&[u8]>, line: usize) -> Result<LiteralPredictionModeNibble, BrotliIRParseError> {
+  match token {
+    Some(b"lsb6") => Ok(LiteralPredictionModeNibble::lsb6()),
+    Some(b"msb6") => Ok(LiteralPredictionModeNibble::msb6()),
+    Some(b"utf8") => Ok(LiteralPredictionModeNibble::utf8()),
+    Some(b"sign") => Ok(LiteralPredictionModeNibble::signed()),
+    Some(_) => Err(BrotliIRParseError::InvalidPredictionMode(line)),
+    None => Err(BrotliIRParseError::Malformed(line)),
+  }
+}
+
+fn ParsePrediction<AllocU8: alloc::Allocator<u8>>(m8: &mut AllocU8,
+                                                 tokens: &mut Tokens,
+                                                 line: usize)
+                                                 -> Result<interface::Command<AllocU8::AllocatedMemory>,
+                                                           BrotliIRParseError> {
+  let mode = ParseMode(tokens.next(), line)?;
+  let mut literal_modes = [mode.0; interface::NUM_LITERAL_PREDICTION_MODES];
+  let has_literal_modes = tokens.clone().next() == Some(&b"literalmodes"[..]);
+  if has_literal_modes {
+    tokens.next();
+    let mut num_literal_modes = 0usize;
+    while tokens.clone().next() != Some(&b"lcontextmap"[..]) {
+      if num_literal_modes == literal_modes.len() {
+        return Err(BrotliIRParseError::LengthMismatch(line));
+      }
+      literal_modes[num_literal_modes] = ParseMode(tokens.next(), line)?.0;
+      num_literal_modes += 1;
+    }
+  }
+  Expect(tokens.next(), b"lcontextmap", line)?;
+  let literal_tokens = tokens.clone();
+  let literal_len = CountBytes(tokens, line)?;
+  Skip(tokens, literal_len);
+  Expect(tokens.next(), b"dcontextmap", line)?;
+  let distance_tokens = tokens.clone();
+  let distance_len = CountBytes(tokens, line)?;
+  Skip(tokens, distance_len);
+  Expect(tokens.next(), b"mixingvalues", line)?;
+  let mixing_tokens = tokens.clone();
+  let mixing_len = CountBytes(tokens, line)?;
+  Skip(tokens, mixing_len);
+  let speeds = if tokens.clone().next().is_some() {
+    Some(ParseSpeeds(tokens, line)?)
+  } else {
+    None
+  };
+  if tokens.next().is_some() {
+    return Err(BrotliIRParseError::Malformed(line));
+  }
+  if mixing_len != 0 && mixing_len != interface::NUM_MIXING_VALUES {
+    return Err(BrotliIRParseError::LengthMismatch(line));
+  }
+  let full = has_literal_modes || speeds.is_some() || distance_len != 0 || mixing_len != 0;
+  let mut ret = PredictionModeContextMap::<AllocU8::AllocatedMemory> {
+    literal_context_map: m8.alloc_cell(literal_len),
+    predmode_speed_and_distance_context_map: m8.alloc_cell(if full {
+      interface::DISTANCE_CONTEXT_MAP_OFFSET + distance_len
+    } else {
+      interface::PREDMODE_OFFSET + 1
+    }),
+  };
+  FillBytes(&mut literal_tokens.clone(), ret.literal_context_map.slice_mut());
+  ret.set_literal_prediction_mode(mode);
+  if full {
+    ret.literal_prediction_modes_mut().clone_from_slice(&literal_modes[..]);
+    FillBytes(&mut distance_tokens.clone(), ret.distance_context_map_mut());
+    FillBytes(&mut mixing_tokens.clone(), &mut ret.get_mixing_values_mut()[..mixing_len]);
+  }
+  if let Some(speeds) = speeds {
+    ret.set_context_map_speed([(speeds[0].0, speeds[1].0), (speeds[0].1, speeds[1].1)]);
+    ret.set_stride_context_speed([(speeds[2].0, speeds[3].0), (speeds[2].1, speeds[3].1)]);
+    ret.set_combined_stride_context_speed([(speeds[4].0, speeds[5].0), (speeds[4].1, speeds[5].1)]);
+  }
+  Ok(interface::Command::PredictionMode(ret))
+}
+
+fn ParseDict(tokens: &mut Tokens, line: usize) -> Result<DictCommand, BrotliIRParseError> {
+  let final_size = ParseNumber(tokens.next(), 255, line)?;
+  Expect(tokens.next(), b"word", line)?;
+  let (word_size, word_id) = match tokens.next() {
+    Some(word) => {
+      match word.iter().position(|b| *b == b',') {
+        Some(comma) => (ParseNumber(Some(&word[..comma]), 255, line)? as usize,
+                        ParseNumber(Some(&word[comma + 1..]), 0xffffffff, line)?),
+        None => return Err(BrotliIRParseError::Malformed(line)),
+      }
+    }
+    None => return Err(BrotliIRParseError::Malformed(line)),
+  };
+  let raw_hex = tokens.next().unwrap_or(&[]);
+  Expect(tokens.next(), b"func", line)?;
+  let transform = ParseNumber(tokens.next(), 255, line)?;
+  let final_hex = tokens.next().unwrap_or(&[]);
+  let ndbits = if word_size < kBrotliDictionarySizeBitsByLength.len() {
+    kBrotliDictionarySizeBitsByLength[word_size] as usize
+  } else {
+    0
+  };
+  if ndbits == 0 || word_id as usize >= (1 << ndbits) || transform as i32 >= kNumTransforms {
+    return Err(BrotliIRParseError::InvalidDictionaryWord(line));
+  }
+  CheckHex(raw_hex, word_size, line)?;
+  CheckHex(final_hex, final_size as usize, line)?;
+  let word_index = word_id as usize * word_size + kBrotliDictionaryOffsetsByLength[word_size] as usize;
+  let raw_word = &kBrotliDictionary[word_index..word_index + word_size];
+  let mut printed_word = [0u8; 24];
+  DecodeHex(raw_hex, &mut printed_word[..word_size]);
+  let mut transformed_word = [0u8; 38];
+  let transformed_size = TransformDictionaryWord(&mut transformed_word[..], raw_word, word_size as i32, transform as i32) as usize;
+  let mut printed_transformed_word = [0u8; 38];
+  if transformed_size != final_size as usize {
+    return Err(BrotliIRParseError::InvalidDictionaryWord(line));
+  }
+  DecodeHex(final_hex, &mut printed_transformed_word[..transformed_size]);
+  if &printed_word[..word_size] != raw_word ||
+     printed_transformed_word[..transformed_size] != transformed_word[..transformed_size] {
+    return Err(BrotliIRParseError::InvalidDictionaryWord(line));
+  }
+  Ok(DictCommand {
+    word_size: word_size as u8,
+    transform: transform as u8,
+    final_size: final_size as u8,
+    empty: 0,
+    word_id: word_id,
+  })
+}
+
+// Parses one line of text IR; line_number is only used to report errors. Blank lines and
+// the window header yield None.
+pub fn BrotliParseIRLine<AllocU8: alloc::Allocator<u8>>(m8: &mut AllocU8,
+                                                       line: &[u8],
+                                                       line_number: usize)
+                                                       -> Result<Option<interface::Command<AllocU8::AllocatedMemory>>,
+                                                                 BrotliIRParseError> {
+  let mut tokens = Tokens { data: line };
+  let keyword = match tokens.next() {
+    Some(keyword) => keyword,
+    None => return Ok(None),
+  };
+  let cmd = match keyword {
+    b"window" => {
+      ParseNumber(tokens.next(), 0xffffffff, line_number)?;
+      while let Some(token) = tokens.next() {
+        ParseNumber(Some(token), 0xffffffff, line_number)?;
+      }
+      return Ok(None);
+    }
+    b"insert" | b"rndins" => {
+      let len = ParseNumber(tokens.next(), 0xffffffff, line_number)? as usize;
+      let hex = tokens.next().unwrap_or(&[]);
+      CheckHex(hex, len, line_number)?;
+      if tokens.next().is_some() {
+        return Err(BrotliIRParseError::Malformed(line_number));
+      }
+      let mut data = m8.alloc_cell(len);
+      DecodeHex(hex, data.slice_mut());
+      return Ok(Some(interface::Command::Literal(LiteralCommand::<AllocU8::AllocatedMemory> {
+        data: data,
+        prob: FeatureFlagSliceType::<AllocU8::AllocatedMemory>::default(),
+        high_entropy: keyword == b"rndins",
+      })));
+    }
+    b"prediction" => return ParsePrediction(m8, &mut tokens, line_number).map(Some),
+    b"copy" => {
+      let num_bytes = ParseNumber(tokens.next(), 0xffffffff, line_number)?;
+      Expect(tokens.next(), b"from", line_number)?;
+      let distance = ParseNumber(tokens.next(), 0xffffffff, line_number)?;
+      interface::Command::Copy(CopyCommand {
+        distance: distance,
+        num_bytes: num_bytes,
+      })
+    }
+    b"dict" => interface::Command::Dict(ParseDict(&mut tokens, line_number)?),
+    b"ltype" => {
+      let block_type = ParseNumber(tokens.next(), 255, line_number)? as u8;
+      let stride = ParseNumber(tokens.next(), 255, line_number)? as u8;
+      interface::Command::BlockSwitchLiteral(LiteralBlockSwitch::new(block_type, stride))
+    }
+    b"ctype" => {
+      interface::Command::BlockSwitchCommand(BlockSwitch::new(ParseNumber(tokens.next(), 255, line_number)? as u8))
+    }
+    b"dtype" => {
+      interface::Command::BlockSwitchDistance(BlockSwitch::new(ParseNumber(tokens.next(), 255, line_number)? as u8))
+    }
+    _ => return Err(BrotliIRParseError::UnknownCommand(line_number)),
+  };
+  if tokens.next().is_some() {
+    return Err(BrotliIRParseError::Malformed(line_number));
+  }
+  Ok(Some(cmd))
+}
+
+// Parses a whole text IR, one command per line.
+#[cfg(not(feature="no-stdlib"))]
+pub fn BrotliParseIR(text: &[u8])
+                     -> Result<Vec<interface::Command<<HeapAlloc<u8> as alloc::Allocator<u8>>::AllocatedMemory>>,
+                               BrotliIRParseError> {
+  let mut m8 = HeapAlloc::<u8> { default_value: 0 };
+  let mut commands = Vec::new();
+  for (index, line) in text.split(|b| *b == b'\n').enumerate() {
+    if let Some(cmd) = BrotliParseIRLine(&mut m8, line, index + 1)? {
+      commands.push(cmd);
+    }
+  }
+  Ok(commands)
+}
+
+#[cfg(all(test, not(feature="no-stdlib")))]
+mod test {
+  #[allow(unused_imports)]
+  use std::vec::Vec;
+  #[allow(unused_imports)]
+  use super::super::interface::Command;
+  #[allow(unused_imports)]
+  use super::super::super::alloc::SliceWrapper;
+  #[allow(unused_imports)]
+  use super::BrotliIRParseError;
+  #[test]
+  fn test_parse_ir() {
+    let text = b"window 22 0 0 0\n\
+                 prediction msb6 lcontextmap 0 1 2 dcontextmap mixingvalues\n\
+                 insert 3 41420a\n\
+                 \n\
+                 rndins 0 \n\
+                 copy 12 from 3\n\
+                 dict 4 word 4,0 74696d65 func 0 74696d65\n\
+                 ltype 2 1\r\n\
+                 ctype 1\n\
+                 dtype 3\n";
+    let commands = super::BrotliParseIR(&text[..]).unwrap();
+    assert_eq!(commands.len(), 8);
+    match commands[0] {
+      Command::PredictionMode(ref pm) => {
+        assert_eq!(pm.literal_prediction_mode().prediction_mode(), 1);
+        assert_eq!(pm.literal_context_map.slice(), &[0u8, 1, 2][..]);
+        assert!(!pm.has_context_speeds());
+      }
+      _ => panic!("expected prediction"),
+    }
+    match commands[1] {
+      Command::Literal(ref lit) => {
+        assert_eq!(lit.data.slice(), &b"AB\n"[..]);
+        assert!(!lit.high_entropy);
+      }
+      _ => panic!("expected literal"),
+    }
+    match commands[2] {
+      Command::Literal(ref lit) => assert!(lit.high_entropy && lit.data.slice().is_empty()),
+      _ => panic!("expected literal"),
+    }
+    match commands[3] {
+      Command::Copy(ref copy) => assert_eq!((copy.num_bytes, copy.distance), (12, 3)),
+      _ => panic!("expected copy"),
+    }
+    match commands[4] {
+      Command::Dict(ref dict) => {
+        assert_eq!((dict.word_size, dict.word_id, dict.transform, dict.final_size), (4, 0, 0, 4))
+      }
+      _ => panic!("expected dict"),
+    }
+    match commands[5] {
+      Command::BlockSwitchLiteral(ref switch) => assert_eq!((switch.block_type(), switch.stride()), (2, 1)),
+      _ => panic!("expected ltype"),
+    }
+    match (&commands[6], &commands[7]) {
+      (&Command::BlockSwitchCommand(ref c), &Command::BlockSwitchDistance(ref d)) => {
+        assert_eq!((c.block_type(), d.block_type()), (1, 3))
+      }
+      _ => panic!("expected ctype and dtype"),
+    }
+  }
+  #[test]
+  fn test_parse_ir_speeds() {
+    let text = b"prediction utf8 lcontextmap dcontextmap 5 6 mixingvalues cmspeedinc 1 2 cmspeedmax 3 4 \
+                 stspeedinc 5 6 stspeedmax 8 10 mxspeedinc 12 16 mxspeedmax 24 32";
+    let commands = super::BrotliParseIR(&text[..]).unwrap();
+    match commands[0] {
+      Command::PredictionMode(ref pm) => {
+        assert_eq!(pm.literal_prediction_mode().prediction_mode(), 2);
+        assert_eq!(pm.distance_context_map(), &[5u8, 6][..]);
+        assert_eq!(pm.context_map_speed(), [(1, 3), (2, 4)]);
+        assert_eq!(pm.stride_context_speed(), [(5, 8), (6, 10)]);
+        assert_eq!(pm.combined_stride_context_speed(), [(12, 24), (16, 32)]);
+        assert!(pm.get_mixing_values().iter().all(|v| *v == 0));
+        assert_eq!(pm.literal_prediction_mode_of(1).prediction_mode(), 2);
+      }
+      _ => panic!("expected prediction"),
+    }
+  }
+  #[test]
+  fn test_parse_ir_literal_modes() {
+    let text = b"prediction utf8 literalmodes lsb6 sign lcontextmap dcontextmap mixingvalues";
+    let commands = super::BrotliParseIR(&text[..]).unwrap();
+    match commands[0] {
+      Command::PredictionMode(ref pm) => {
+        assert_eq!(pm.literal_prediction_mode().prediction_mode(), 2);
+        assert_eq!(pm.literal_prediction_mode_of(0).prediction_mode(), 0);
+        assert_eq!(pm.literal_prediction_mode_of(1).prediction_mode(), 3);
+        assert_eq!(pm.literal_prediction_mode_of(2).prediction_mode(), 2);
+      }
+      _ => panic!("expected prediction"),
+    }
+  }
+  #[test]
+  fn test_parse_ir_errors() {
+    let cases: [(&[u8], BrotliIRParseError); 15] =
+      [(b"copy 1 from 2\nmove 3", BrotliIRParseError::UnknownCommand(2)),
+       (b"copy 1 to 2", BrotliIRParseError::Malformed(1)),
+       (b"copy 1 from", BrotliIRParseError::Malformed(1)),
+       (b"ctype 1 2", BrotliIRParseError::Malformed(1)),
+       (b"ctype 256", BrotliIRParseError::InvalidNumber(1)),
+       (b"copy -1 from 2", BrotliIRParseError::InvalidNumber(1)),
+       (b"insert 2 4g41", BrotliIRParseError::InvalidHex(1)),
+       (b"insert 3 4141", BrotliIRParseError::LengthMismatch(1)),
+       (b"dict 4 word 4,0 74696d66 func 0 74696d66", BrotliIRParseError::InvalidDictionaryWord(1)),
+       (b"dict 5 word 4,0 74696d65 func 0 74696d6520", BrotliIRParseError::InvalidDictionaryWord(1)),
+       (b"dict 3 word 3,0 746966 func 0 746966", BrotliIRParseError::InvalidDictionaryWord(1)),
+       (b"prediction fast lcontextmap dcontextmap mixingvalues", BrotliIRParseError::InvalidPredictionMode(1)),
+       (b"prediction sign lcontextmap dcontextmap mixingvalues 1 2", BrotliIRParseError::LengthMismatch(1)),
+       (b"prediction sign literalmodes utf8 fast lcontextmap dcontextmap mixingvalues",
+        BrotliIRParseError::InvalidPredictionMode(1)),
+       (b"prediction sign literalmodes utf8", BrotliIRParseError::Malformed(1))];
+    for &(text, error) in cases.iter() {
+      assert_eq!(super::BrotliParseIR(text).err(), Some(error));
+    }
+  }
+}
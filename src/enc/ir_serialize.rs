@@ -0,0 +1,553 @@
+// Compact binary encoding of the IR handed to the metablock callback, so it can be stored
+// and replayed later. Each metablock record carries its prediction mode, the bytes its
+// literals refer to and its StaticCommands; literal offsets are implied by the order of the
+// literals, so only lengths are stored.
+//
+//   stream     = magic "BrIR", version (1 byte), { metablock }, 0 ;
+//   metablock  = 1, bytes (literal context map), bytes (predmode/speed/distance map),
+//                bytes (literal data), varint (command count), { command } ;
+//   bytes      = varint (length), the bytes ;
+//   command    = 0 (literal) | 1 (high entropy literal), varint (length)
+//              | 2 (copy), varint (num_bytes), varint (distance)
+//              | 3 (dict), word_size, transform, final_size, empty, varint (word_id)
+//              | 4 (ltype), block type, stride | 5 (ctype), block type | 6 (dtype), block type
+//              | 7 (prediction mode), varint (literal context map length), varint (other length) ;
+//
+// Varints are LEB128. The two maps of a prediction mode inside the command list are stored
+// in the literal data like literals.
+use super::input_pair::InputPair;
+use super::interface;
+use super::interface::{BlockSwitch, CopyCommand, DictCommand, FeatureFlagSliceType, LiteralBlockSwitch,
+                       LiteralCommand, PredictionModeContextMap, SliceOffset, StaticCommand, Unfreezable};
+use super::super::alloc;
+use super::super::alloc::{SliceWrapper, SliceWrapperMut};
+use super::writer::write_all;
+use brotli_decompressor::{CustomRead, CustomWrite};
+use core;
+
+pub static BROTLI_IR_MAGIC: [u8; 4] = [b'B', b'r', b'I', b'R'];
+pub const BROTLI_IR_VERSION: u8 = 2;
+
+const RECORD_END: u8 = 0;
+const RECORD_METABLOCK: u8 = 1;
+const TAG_LITERAL: u8 = 0;
+const TAG_RANDOM_LITERAL: u8 = 1;
+const TAG_COPY: u8 = 2;
+const TAG_DICT: u8 = 3;
+const TAG_LTYPE: u8 = 4;
+const TAG_CTYPE: u8 = 5;
+const TAG_DTYPE: u8 = 6;
+const TAG_PREDICTION_MODE: u8 = 7;
+// no metablock holds more than 16MiB of data, so larger sizes mean a corrupt stream
+const MAX_RECORD_SIZE: u64 = 1 << 25;
+const MAX_VARINT_SIZE: usize = 10;
+// commands are read into a buffer that starts this large and doubles as they arrive, so a
+// corrupt count cannot make the reader allocate ahead of the input
+const INITIAL_COMMAND_CAPACITY: usize = 1024;
+
+pub struct BrotliIRWriter<ErrType, W: CustomWrite<ErrType>, BufferType: SliceWrapperMut<u8>> {
+  output: W,
+  buffer: BufferType,
+  buffer_len: usize,
+  header_written: bool,
+  invalid_data_error: fn() -> ErrType,
+}
+
+impl<ErrType, W: CustomWrite<ErrType>, BufferType: SliceWrapperMut<u8>> BrotliIRWriter<ErrType, W, BufferType> {
+  // buffer collects small writes and must hold at least 16 bytes; invalid_data_error makes the
+  // error returned for commands that refer to data outside their metablock
+  pub fn new(w: W, buffer: BufferType, invalid_data_error: fn() -> ErrType) -> Self {
+    assert!(buffer.slice().len() >= 16);
+    BrotliIRWriter {
+      output: w,
+      buffer: buffer,
+      buffer_len: 0,
+      header_written: false,
+      invalid_data_error,
+    }
+  }
+  fn flush_buffer(&mut self) -> Result<(), ErrType> {
+    let len = self.buffer_len;
+    self.buffer_len = 0;
+    write_all(&mut self.output, &self.buffer.slice()[..len])
+  }
+  fn put(&mut self, data: &[u8]) -> Result<(), ErrType> {
+    if self.buffer_len + data.len() > self.buffer.slice().len() {
+      self.flush_buffer()?;
+      if data.len() > self.buffer.slice().len() {
+        return write_all(&mut self.output, data);
+      }
+    }
+    self.buffer.slice_mut()[self.buffer_len..self.buffer_len + data.len()].clone_from_slice(data);
+    self.buffer_len += data.len();
+    Ok(())
+  }
+  fn put_varint(&mut self, mut value: u64) -> Result<(), ErrType> {
+    let mut bytes = [0u8; MAX_VARINT_SIZE];
+    let mut len = 0usize;
+    loop {
+      bytes[len] = (value & 0x7f) as u8;
+      len += 1;
+      value >>= 7;
+      if value == 0 {
+        break;
+      }
+      bytes[len - 1] |= 0x80;
+    }
+    self.put(&bytes[..len])
+  }
+  fn put_header(&mut self) -> Result<(), ErrType> {
+    if !self.header_written {
+      self.header_written = true;
+      self.put(&BROTLI_IR_MAGIC[..])?;
+      self.put(&[BROTLI_IR_VERSION])?;
+    }
+    Ok(())
+  }
+  fn invalid_data(&self) -> ErrType {
+    (self.invalid_data_error)()
+  }
+  // Appends one metablock, as passed to the metablock callback.
+  pub fn write_metablock<SliceType: SliceWrapper<u8>>(&mut self,
+                                                      prediction_mode: &PredictionModeContextMap<SliceType>,
+                                                      commands: &[StaticCommand],
+                                                      mb: &InputPair)
+                                                      -> Result<(), ErrType> {
+    self.put_header()?;
+    self.put(&[RECORD_METABLOCK])?;
+    self.put_varint(prediction_mode.literal_context_map.slice().len() as u64)?;
+    self.put(prediction_mode.literal_context_map.slice())?;
+    self.put_varint(prediction_mode.predmode_speed_and_distance_context_map.slice().len() as u64)?;
+    self.put(prediction_mode.predmode_speed_and_distance_context_map.slice())?;
+    let mut literal_size = 0usize;
+    for cmd in commands.iter() {
+      match *cmd {
+        interface::Command::Literal(ref lit) => literal_size += lit.data.len(),
+        interface::Command::PredictionMode(ref pm) => {
+          literal_size += pm.literal_context_map.len() + pm.predmode_speed_and_distance_context_map.len()
+        }
+        _ => {}
+      }
+    }
+    self.put_varint(literal_size as u64)?;
+    for cmd in commands.iter() {
+      let (first, second) = match *cmd {
+        interface::Command::Literal(ref lit) => (lit.data, SliceOffset::default()),
+        interface::Command::PredictionMode(ref pm) => {
+          (pm.literal_context_map, pm.predmode_speed_and_distance_context_map)
+        }
+        _ => continue,
+      };
+      for slice in [first, second].iter() {
+        if slice.len() == 0 {
+          continue;
+        }
+        match slice.thaw_pair(mb) {
+          Ok(data) => self.put(data.slice())?,
+          Err(_) => return Err(self.invalid_data()),
+        }
+      }
+    }
+    self.put_varint(commands.len() as u64)?;
+    for cmd in commands.iter() {
+      match *cmd {
+        interface::Command::Literal(ref lit) => {
+          self.put(&[if lit.high_entropy { TAG_RANDOM_LITERAL } else { TAG_LITERAL }])?;
+          self.put_varint(lit.data.len() as u64)?;
+        }
+        interface::Command::Copy(ref copy) => {
+          self.put(&[TAG_COPY])?;
+          self.put_varint(copy.num_bytes as u64)?;
+          self.put_varint(copy.distance as u64)?;
+        }
+        interface::Command::Dict(ref dict) => {
+          self.put(&[TAG_DICT, dict.word_size, dict.transform, dict.final_size, dict.empty])?;
+          self.put_varint(dict.word_id as u64)?;
+        }
+        interface::Command::BlockSwitchLiteral(ref switch) => {
+          self.put(&[TAG_LTYPE, switch.block_type(), switch.stride()])?
+        }
+        interface::Command::BlockSwitchCommand(ref switch) => self.put(&[TAG_CTYPE, switch.block_type()])?,
+        interface::Command::BlockSwitchDistance(ref switch) => self.put(&[TAG_DTYPE, switch.block_type()])?,
+        interface::Command::PredictionMode(ref pm) => {
+          self.put(&[TAG_PREDICTION_MODE])?;
+          self.put_varint(pm.literal_context_map.len() as u64)?;
+          self.put_varint(pm.predmode_speed_and_distance_context_map.len() as u64)?;
+        }
+      }
+    }
+    Ok(())
+  }
+  // Ends the stream and flushes the output. Nothing may be written afterwards.
+  pub fn finish(&mut self) -> Result<(), ErrType> {
+    self.put_header()?;
+    self.put(&[RECORD_END])?;
+    self.flush_buffer()?;
+    self.output.flush()
+  }
+  pub fn get_ref(&self) -> &W {
+    &self.output
+  }
+  pub fn into_inner(self) -> W {
+    self.output
+  }
+}
+
+pub struct BrotliIRMetaBlock<AllocU8: alloc::Allocator<u8>, AllocSC: alloc::Allocator<StaticCommand>> {
+  pub prediction_mode: PredictionModeContextMap<AllocU8::AllocatedMemory>,
+  // the bytes the literals (and prediction modes) in commands refer to
+  pub literals: AllocU8::AllocatedMemory,
+  pub commands: AllocSC::AllocatedMemory,
+}
+
+impl<AllocU8: alloc::Allocator<u8>, AllocSC: alloc::Allocator<StaticCommand>> BrotliIRMetaBlock<AllocU8, AllocSC> {
+  pub fn command<'a>(&'a self, index: usize) -> interface::Command<interface::InputReference<'a>> {
+    interface::thaw(&self.commands.slice()[index], self.literals.slice())
+  }
+  pub fn free(self, m8: &mut AllocU8, mc: &mut AllocSC) {
+    m8.free_cell(self.prediction_mode.literal_context_map);
+    m8.free_cell(self.prediction_mode.predmode_speed_and_distance_context_map);
+    m8.free_cell(self.literals);
+    mc.free_cell(self.commands);
+  }
+}
+
+pub struct BrotliIRReader<ErrType, R: CustomRead<ErrType>, BufferType: SliceWrapperMut<u8>> {
+  input: R,
+  buffer: BufferType,
+  buffer_start: usize,
+  buffer_end: usize,
+  header_read: bool,
+  finished: bool,
+  invalid_data_error: fn() -> ErrType,
+}
+
+impl<ErrType, R: CustomRead<ErrType>, BufferType: SliceWrapperMut<u8>> BrotliIRReader<ErrType, R, BufferType> {
+  // invalid_data_error makes the error returned for truncated or corrupt streams
+  pub fn new(r: R, buffer: BufferType, invalid_data_error: fn() -> ErrType) -> Self {
+    assert!(buffer.slice().len() != 0);
+    BrotliIRReader {
+      input: r,
+      buffer: buffer,
+      buffer_start: 0,
+      buffer_end: 0,
+      header_read: false,
+      finished: false,
+      invalid_data_error,
+    }
+  }
+  fn invalid_data(&self) -> ErrType {
+    (self.invalid_data_error)()
+  }
+  fn get(&mut self, output: &mut [u8]) -> Result<(), ErrType> {
+    let mut pos = 0usize;
+    while pos < output.len() {
+      if self.buffer_start == self.buffer_end {
+        self.buffer_start = 0;
+        self.buffer_end = self.input.read(self.buffer.slice_mut())?;
+        if self.buffer_end == 0 {
+          return Err(self.invalid_data());
+        }
+      }
+      let len = core::cmp::min(output.len() - pos, self.buffer_end - self.buffer_start);
+      output[pos..pos + len].clone_from_slice(&self.buffer.slice()[self.buffer_start..self.buffer_start + len]);
+      self.buffer_start += len;
+      pos += len;
+    }
+    Ok(())
+  }
+  fn get_byte(&mut self) -> Result<u8, ErrType> {
+    let mut byte = [0u8];
+    self.get(&mut byte[..])?;
+    Ok(byte[0])
+  }
+  fn get_varint(&mut self, max: u64) -> Result<u64, ErrType> {
+    let mut value = 0u64;
+    for index in 0..MAX_VARINT_SIZE {
+      let byte = self.get_byte()?;
+      value |= u64::from(byte & 0x7f) << (7 * index);
+      if byte & 0x80 == 0 {
+        if value > max {
+          return Err(self.invalid_data());
+        }
+        return Ok(value);
+      }
+    }
+    Err(self.invalid_data())
+  }
+  // offset is where the literals of the next command start, carried across calls
+  fn get_commands(&mut self,
+                  commands: &mut [StaticCommand],
+                  offset: &mut usize,
+                  literal_size: usize)
+                  -> Result<(), ErrType> {
+    for cmd in commands.iter_mut() {
+      *cmd = match self.get_byte()? {
+        tag @ TAG_LITERAL | tag @ TAG_RANDOM_LITERAL => {
+          let len = self.get_varint(MAX_RECORD_SIZE)? as usize;
+          *offset += len;
+          interface::Command::Literal(LiteralCommand::<SliceOffset> {
+            data: SliceOffset(*offset - len, len as u32),
+            prob: FeatureFlagSliceType::<SliceOffset>::default(),
+            high_entropy: tag == TAG_RANDOM_LITERAL,
+          })
+        }
+        TAG_COPY => {
+          let num_bytes = self.get_varint(0xffffffff)? as u32;
+          let distance = self.get_varint(0xffffffff)? as u32;
+          interface::Command::Copy(CopyCommand {
+            distance: distance,
+            num_bytes: num_bytes,
+          })
+        }
+        TAG_DICT => {
+          let mut fields = [0u8; 4];
+          self.get(&mut fields[..])?;
+          interface::Command::Dict(DictCommand {
+            word_size: fields[0],
+            transform: fields[1],
+            final_size: fields[2],
+            empty: fields[3],
+            word_id: self.get_varint(0xffffffff)? as u32,
+          })
+        }
+        TAG_LTYPE => {
+          let block_type = self.get_byte()?;
+          let stride = self.get_byte()?;
+          interface::Command::BlockSwitchLiteral(LiteralBlockSwitch::new(block_type, stride))
+        }
+        TAG_CTYPE => interface::Command::BlockSwitchCommand(BlockSwitch::new(self.get_byte()?)),
+        TAG_DTYPE => interface::Command::BlockSwitchDistance(BlockSwitch::new(self.get_byte()?)),
+        TAG_PREDICTION_MODE => {
+          let literal_context_map_len = self.get_varint(MAX_RECORD_SIZE)? as usize;
+          let len = self.get_varint(MAX_RECORD_SIZE)? as usize;
+          *offset += literal_context_map_len + len;
+          interface::Command::PredictionMode(PredictionModeContextMap::<SliceOffset> {
+            literal_context_map: SliceOffset(*offset - len - literal_context_map_len, literal_context_map_len as u32),
+            predmode_speed_and_distance_context_map: SliceOffset(*offset - len, len as u32),
+          })
+        }
+        _ => return Err(self.invalid_data()),
+      };
+      if *offset > literal_size {
+        return Err(self.invalid_data());
+      }
+    }
+    Ok(())
+  }
+  fn get_metablock<AllocU8: alloc::Allocator<u8>, AllocSC: alloc::Allocator<StaticCommand>>
+    (&mut self,
+     m8: &mut AllocU8,
+     mc: &mut AllocSC,
+     metablock: &mut BrotliIRMetaBlock<AllocU8, AllocSC>)
+     -> Result<(), ErrType> {
+    let len = self.get_varint(interface::MAX_ADV_LITERAL_CONTEXT_MAP_SIZE as u64)? as usize;
+    metablock.prediction_mode.literal_context_map = m8.alloc_cell(len);
+    self.get(metablock.prediction_mode.literal_context_map.slice_mut())?;
+    let len = self.get_varint(interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE as u64)? as usize;
+    metablock.prediction_mode.predmode_speed_and_distance_context_map = m8.alloc_cell(len);
+    self.get(metablock.prediction_mode.predmode_speed_and_distance_context_map.slice_mut())?;
+    let len = self.get_varint(MAX_RECORD_SIZE)? as usize;
+    metablock.literals = m8.alloc_cell(len);
+    self.get(metablock.literals.slice_mut())?;
+    let num_commands = self.get_varint(MAX_RECORD_SIZE)? as usize;
+    let mut capacity = core::cmp::min(num_commands, INITIAL_COMMAND_CAPACITY);
+    metablock.commands = mc.alloc_cell(capacity);
+    let mut num_read = 0usize;
+    let mut offset = 0usize;
+    loop {
+      self.get_commands(&mut metablock.commands.slice_mut()[num_read..capacity], &mut offset, len)?;
+      num_read = capacity;
+      if num_read == num_commands {
+        return Ok(());
+      }
+      capacity = core::cmp::min(capacity * 2, num_commands);
+      let mut grown = mc.alloc_cell(capacity);
+      grown.slice_mut()[..num_read].clone_from_slice(&metablock.commands.slice()[..num_read]);
+      mc.free_cell(core::mem::replace(&mut metablock.commands, grown));
+    }
+  }
+  // Returns the next metablock, or None once the end of the stream is reached.
+  pub fn read_metablock<AllocU8: alloc::Allocator<u8>, AllocSC: alloc::Allocator<StaticCommand>>
+    (&mut self,
+     m8: &mut AllocU8,
+     mc: &mut AllocSC)
+     -> Result<Option<BrotliIRMetaBlock<AllocU8, AllocSC>>, ErrType> {
+    if self.finished {
+      return Ok(None);
+    }
+    if !self.header_read {
+      let mut header = [0u8; 5];
+      self.get(&mut header[..])?;
+      if header[..4] != BROTLI_IR_MAGIC[..] || header[4] != BROTLI_IR_VERSION {
+        return Err(self.invalid_data());
+      }
+      self.header_read = true;
+    }
+    match self.get_byte()? {
+      RECORD_END => {
+        self.finished = true;
+        return Ok(None);
+      }
+      RECORD_METABLOCK => {}
+      _ => return Err(self.invalid_data()),
+    }
+    let mut metablock = BrotliIRMetaBlock::<AllocU8, AllocSC> {
+      prediction_mode: PredictionModeContextMap::<AllocU8::AllocatedMemory> {
+        literal_context_map: AllocU8::AllocatedMemory::default(),
+        predmode_speed_and_distance_context_map: AllocU8::AllocatedMemory::default(),
+      },
+      literals: AllocU8::AllocatedMemory::default(),
+      commands: AllocSC::AllocatedMemory::default(),
+    };
+    match self.get_metablock(m8, mc, &mut metablock) {
+      Ok(()) => Ok(Some(metablock)),
+      Err(e) => {
+        metablock.free(m8, mc);
+        Err(e)
+      }
+    }
+  }
+  pub fn get_ref(&self) -> &R {
+    &self.input
+  }
+  pub fn into_inner(self) -> R {
+    self.input
+  }
+}
+
+#[cfg(all(test, not(feature="no-stdlib")))]
+mod test {
+  #[allow(unused_imports)]
+  use std::vec::Vec;
+  #[allow(unused_imports)]
+  use super::super::input_pair::{InputPair, InputReference};
+  #[allow(unused_imports)]
+  use super::super::interface::{BlockSwitch, Command, CopyCommand, DictCommand, FeatureFlagSliceType,
+                                LiteralBlockSwitch, LiteralCommand, PredictionModeContextMap, SliceOffset,
+                                StaticCommand};
+  #[allow(unused_imports)]
+  use super::super::super::alloc::{Allocator, HeapAlloc, SliceWrapper};
+  #[allow(unused_imports)]
+  use brotli_decompressor::{CustomRead, CustomWrite};
+
+  #[allow(unused)]
+  struct VecWriter(Vec<u8>);
+  impl CustomWrite<()> for VecWriter {
+    fn write(&mut self, data: &[u8]) -> Result<usize, ()> {
+      self.0.extend_from_slice(data);
+      Ok(data.len())
+    }
+    fn flush(&mut self) -> Result<(), ()> {
+      Ok(())
+    }
+  }
+  // hands out at most 3 bytes per read to exercise refills
+  #[allow(unused)]
+  struct SliceReader<'a>(&'a [u8]);
+  impl<'a> CustomRead<()> for SliceReader<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<usize, ()> {
+      let len = core::cmp::min(3, core::cmp::min(data.len(), self.0.len()));
+      data[..len].clone_from_slice(&self.0[..len]);
+      self.0 = &self.0[len..];
+      Ok(len)
+    }
+  }
+  #[allow(unused)]
+  fn literal(offset: usize, len: u32, high_entropy: bool) -> StaticCommand {
+    Command::Literal(LiteralCommand::<SliceOffset> {
+      data: SliceOffset(offset, len),
+      prob: FeatureFlagSliceType::<SliceOffset>::default(),
+      high_entropy: high_entropy,
+    })
+  }
+  // the command with the bytes it refers to, independent of where they are stored
+  #[allow(unused)]
+  fn describe(cmd: &Command<InputReference>) -> Vec<u8> {
+    match *cmd {
+      Command::Literal(ref lit) => format!("literal {:?} {}\n", lit.data.slice(), lit.high_entropy).into_bytes(),
+      Command::PredictionMode(ref pm) => {
+        format!("prediction {:?} {:?}\n",
+                pm.literal_context_map.slice(),
+                pm.predmode_speed_and_distance_context_map.slice()).into_bytes()
+      }
+      _ => format!("{:?}\n", cmd).into_bytes(),
+    }
+  }
+  #[allow(unused)]
+  fn read_all(data: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, ()> {
+    let mut m8 = HeapAlloc::<u8> { default_value: 0 };
+    let mut mc = HeapAlloc::<StaticCommand> { default_value: StaticCommand::default() };
+    let mut reader = super::BrotliIRReader::new(SliceReader(data), HeapAlloc::<u8> { default_value: 0 }.alloc_cell(7), || ());
+    let mut ret = Vec::new();
+    while let Some(metablock) = reader.read_metablock(&mut m8, &mut mc)? {
+      let mut text = Vec::new();
+      for index in 0..metablock.commands.slice().len() {
+        text.extend_from_slice(&describe(&metablock.command(index))[..]);
+      }
+      ret.push((metablock.prediction_mode.predmode_speed_and_distance_context_map.slice().to_vec(), text));
+      metablock.free(&mut m8, &mut mc);
+    }
+    Ok(ret)
+  }
+  #[test]
+  fn test_ir_serialize_roundtrip() {
+    let data = b"hello brotli, hello world";
+    let mb = InputPair(InputReference { data: &data[..13], orig_offset: 0 },
+                       InputReference { data: &data[13..], orig_offset: 13 });
+    let predmode = [2u8];
+    let commands = [literal(0, 6, false),
+                    Command::Copy(CopyCommand { distance: 6, num_bytes: 1 }),
+                    Command::BlockSwitchLiteral(LiteralBlockSwitch::new(1, 3)),
+                    literal(13, 6, true),
+                    Command::Dict(DictCommand { word_size: 4, transform: 9, final_size: 5, empty: 0, word_id: 300 }),
+                    Command::BlockSwitchCommand(BlockSwitch::new(2)),
+                    Command::BlockSwitchDistance(BlockSwitch::new(3)),
+                    Command::PredictionMode(PredictionModeContextMap::<SliceOffset> {
+                      literal_context_map: SliceOffset(19, 2),
+                      predmode_speed_and_distance_context_map: SliceOffset(21, 4),
+                    }),
+                    Command::Copy(CopyCommand { distance: 1 << 20, num_bytes: 70000 })];
+    let pm = PredictionModeContextMap::<InputReference> {
+      literal_context_map: InputReference { data: &[0, 1, 2], orig_offset: 0 },
+      predmode_speed_and_distance_context_map: InputReference { data: &predmode[..], orig_offset: 0 },
+    };
+    let mut writer = super::BrotliIRWriter::new(VecWriter(Vec::new()), HeapAlloc::<u8> { default_value: 0 }.alloc_cell(16), || ());
+    writer.write_metablock(&pm, &commands[..], &mb).unwrap();
+    writer.write_metablock(&pm, &commands[..2], &mb).unwrap();
+    writer.finish().unwrap();
+    let serialized = writer.into_inner().0;
+    let mut expected = Vec::new();
+    for cmd in commands.iter() {
+      expected.extend_from_slice(&describe(&super::super::interface::thaw_pair(cmd, &mb))[..]);
+    }
+    let metablocks = read_all(&serialized[..]).unwrap();
+    assert_eq!(metablocks.len(), 2);
+    assert_eq!(metablocks[0].0, predmode.to_vec());
+    assert_eq!(metablocks[0].1, expected);
+    assert!(expected.starts_with(&metablocks[1].1[..]));
+    // truncated streams and bad headers are rejected
+    for len in 0..serialized.len() {
+      assert!(read_all(&serialized[..len]).is_err());
+    }
+    // literals must not straddle the two halves of the ring buffer
+    let mut writer = super::BrotliIRWriter::new(VecWriter(Vec::new()), HeapAlloc::<u8> { default_value: 0 }.alloc_cell(16), || ());
+    assert!(writer.write_metablock(&pm, &[literal(7, 12, false)][..], &mb).is_err());
+    let mut bad_version = serialized.clone();
+    bad_version[4] = super::BROTLI_IR_VERSION + 1;
+    assert!(read_all(&bad_version[..]).is_err());
+  }
+  #[test]
+  fn test_ir_serialize_corrupt_command_count() {
+    // a metablock claiming the largest command count, followed by a single literal command
+    let mut data = super::BROTLI_IR_MAGIC.to_vec();
+    data.extend_from_slice(&[super::BROTLI_IR_VERSION, super::RECORD_METABLOCK, 0, 0, 1, b'x']);
+    data.extend_from_slice(&[0x80, 0x80, 0x80, 0x10, super::TAG_LITERAL, 1]);
+    let mut m8 = HeapAlloc::<u8> { default_value: 0 };
+    let mut mc = HeapAlloc::<StaticCommand> { default_value: StaticCommand::default() };
+    let mut reader = super::BrotliIRReader::new(SliceReader(&data[..]), m8.alloc_cell(7), || ());
+    assert!(reader.read_metablock(&mut m8, &mut mc).is_err());
+    // the reader keeps reporting the error rather than panicking
+    assert!(reader.read_metablock(&mut m8, &mut mc).is_err());
+  }
+}
@@ -0,0 +1,144 @@
+// Decompression with a cap on the output size, for input that may be hostile: a few hundred bytes
+// of brotli can describe gigabytes of output. Both helpers here hand out at most max_output bytes
+// and fail as soon as the stream would produce one more, with an io::Error of kind
+// ErrorKind::Other wrapping OutputLimitExceeded, so the caller can tell a stream that is too big
+// (see is_output_limit_exceeded) from one that is corrupt (ErrorKind::InvalidData) or truncated
+// (ErrorKind::UnexpectedEof). A stream of exactly max_output bytes decodes fine: past the limit
+// the decoder only gets room for a single byte, and any byte it writes there is the failure.
+use brotli_decompressor::{BrotliDecompressStream, BrotliResult, BrotliState, HuffmanCode};
+use brotli_decompressor::reader::Decompressor;
+use alloc::HeapAlloc;
+use core::cmp;
+use core::fmt;
+use std::error;
+use std::io::{self, Error, ErrorKind, Read};
+use std::vec::Vec;
+
+const LIMITED_DECOMPRESS_CHUNK_SIZE: usize = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputLimitExceeded {
+  pub max_output: usize,
+}
+
+impl fmt::Display for OutputLimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "decompressed output exceeds the limit of {} bytes", self.max_output)
+  }
+}
+
+impl error::Error for OutputLimitExceeded {}
+
+fn output_limit_error(max_output: usize) -> Error {
+  Error::new(ErrorKind::Other, OutputLimitExceeded { max_output: max_output })
+}
+
+pub fn is_output_limit_exceeded(e: &Error) -> bool {
+  match e.get_ref() {
+    Some(inner) => inner.is::<OutputLimitExceeded>(),
+    None => false,
+  }
+}
+
+// Decompresses a whole stream held in memory. Bytes after the end of the stream are an error, as
+// is a stream that ends early.
+pub fn decompress_to_vec_with_limit(input: &[u8], max_output: usize) -> Result<Vec<u8>, Error> {
+  let mut state = BrotliState::new(HeapAlloc::<u8> { default_value: 0 },
+                                   HeapAlloc::<u32> { default_value: 0 },
+                                   HeapAlloc::<HuffmanCode> { default_value: HuffmanCode::default() });
+  let mut output = Vec::new();
+  let mut avail_in = input.len();
+  let mut input_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let ret = loop {
+    // one byte of room past the limit, to find out whether the stream goes on
+    let room = cmp::min(LIMITED_DECOMPRESS_CHUNK_SIZE, (max_output - output.len()).saturating_add(1));
+    let start = output.len();
+    output.resize(start + room, 0);
+    let mut avail_out = room;
+    let mut output_offset = start;
+    let result = BrotliDecompressStream(&mut avail_in,
+                                        &mut input_offset,
+                                        input,
+                                        &mut avail_out,
+                                        &mut output_offset,
+                                        &mut output[..],
+                                        &mut total_out,
+                                        &mut state);
+    output.truncate(output_offset);
+    if output.len() > max_output {
+      break Err(output_limit_error(max_output));
+    }
+    match result {
+      BrotliResult::NeedsMoreOutput => continue,
+      // the decoder may stop for input with decoded bytes it has not been able to write yet
+      BrotliResult::NeedsMoreInput if avail_out == 0 => continue,
+      BrotliResult::NeedsMoreInput => break Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF")),
+      BrotliResult::ResultSuccess => {
+        if avail_in != 0 {
+          break Err(Error::new(ErrorKind::InvalidData, "Data after the end of the stream"));
+        }
+        break Ok(());
+      }
+      BrotliResult::ResultFailure => break Err(Error::new(ErrorKind::InvalidData, "Invalid Data")),
+    }
+  };
+  state.BrotliStateCleanup();
+  ret.map(|_| output)
+}
+
+// A Decompressor that fails once the stream decodes to more than max_output bytes. Every read
+// after the failure fails the same way.
+pub struct LimitedDecompressor<R: Read> {
+  decompressor: Decompressor<R>,
+  max_output: usize,
+  total_out: usize,
+  exceeded: bool,
+}
+
+impl<R: Read> LimitedDecompressor<R> {
+  pub fn new(r: R, buffer_size: usize, max_output: usize) -> Self {
+    LimitedDecompressor {
+      decompressor: Decompressor::new(r, buffer_size),
+      max_output,
+      total_out: 0,
+      exceeded: false,
+    }
+  }
+
+  pub fn get_ref(&self) -> &R {
+    self.decompressor.get_ref()
+  }
+
+  pub fn get_mut(&mut self) -> &mut R {
+    self.decompressor.get_mut()
+  }
+
+  // The number of decompressed bytes handed out so far.
+  pub fn total_out(&self) -> usize {
+    self.total_out
+  }
+}
+
+impl<R: Read> Read for LimitedDecompressor<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.exceeded {
+      return Err(output_limit_error(self.max_output));
+    }
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    if self.total_out == self.max_output {
+      let mut probe = [0u8; 1];
+      if self.decompressor.read(&mut probe[..])? == 0 {
+        return Ok(0);
+      }
+      self.exceeded = true;
+      return Err(output_limit_error(self.max_output));
+    }
+    let len = cmp::min(buf.len(), self.max_output - self.total_out);
+    let size = self.decompressor.read(&mut buf[..len])?;
+    self.total_out += size;
+    Ok(size)
+  }
+}
@@ -0,0 +1,202 @@
+// Compresses a sequence of messages (e.g. websocket frames) so that each one can be decoded
+// completely as soon as it arrives.
+//
+// With context takeover every message continues the same brotli stream: the window and the
+// hasher carry over, so later messages may reference earlier ones. compress_message encodes the
+// message with BROTLI_OPERATION_FLUSH, which ends the current metablock and then, if the stream
+// is not on a byte boundary, appends an empty metadata block (6 bits) and pads it to the next
+// byte. That padding block is the only framing a message pays beyond its own metablock headers:
+// at most 2 bytes, and nothing when the metablock already ends on a byte boundary. The first
+// message also carries the stream header (the window size, 1 to 7 bits). An empty message
+// produces no output once the stream is on a byte boundary. The stream is never finished.
+//
+// Without context takeover every message is a complete brotli stream of its own: the stream
+// header, the metablocks and the final ISLAST, ISLASTEMPTY bits, padded to a byte. Messages can
+// then be decoded in any order but cannot reference each other.
+use super::backward_references::BrotliEncoderParams;
+use super::pull::Encoder;
+use brotli_decompressor::{BrotliDecompressStream, BrotliResult, BrotliState, HuffmanCode};
+use alloc::HeapAlloc;
+use std::io::{Error, ErrorKind};
+use std::vec::Vec;
+
+const MESSAGE_BUFFER_SIZE: usize = 4096;
+// A 16 bit window (one 0 bit) and an empty metadata block, padded to the byte. The decoder stops
+// after it exactly where it stops after a whole flushed message: waiting for the next metablock
+// header, with no bits left over.
+const FLUSH_BOUNDARY_STREAM: [u8; 1] = [0x0c];
+
+pub struct MessageCompressor {
+  params: BrotliEncoderParams,
+  context_takeover: bool,
+  encoder: Encoder,
+  output: Vec<u8>,
+}
+
+impl MessageCompressor {
+  pub fn new(params: &BrotliEncoderParams, context_takeover: bool) -> Self {
+    MessageCompressor {
+      params: params.clone(),
+      context_takeover,
+      encoder: Encoder::new(params),
+      output: Vec::new(),
+    }
+  }
+
+  pub fn context_takeover(&self) -> bool {
+    self.context_takeover
+  }
+
+  // Returns the compressed message, valid until the next call. It decodes completely on its own
+  // (without context takeover) or after the messages before it (with context takeover).
+  pub fn compress_message(&mut self, message: &[u8]) -> Result<&[u8], Error> {
+    self.output.clear();
+    let mut input = message;
+    while !input.is_empty() {
+      let (consumed, output) = self.encoder.push(input)?;
+      self.output.extend_from_slice(output);
+      input = &input[consumed..];
+    }
+    if self.context_takeover {
+      loop {
+        let output = self.encoder.flush()?;
+        if output.is_empty() {
+          break;
+        }
+        self.output.extend_from_slice(output);
+      }
+    } else {
+      while !self.encoder.is_finished() {
+        self.output.extend_from_slice(self.encoder.finish()?);
+      }
+      self.encoder = Encoder::new(&self.params);
+    }
+    Ok(&self.output[..])
+  }
+}
+
+type MessageDecoderState = BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>;
+
+fn new_decoder_state() -> MessageDecoderState {
+  BrotliState::new(HeapAlloc::<u8> { default_value: 0 },
+                   HeapAlloc::<u32> { default_value: 0 },
+                   HeapAlloc::<HuffmanCode> { default_value: HuffmanCode::default() })
+}
+
+// A decoder that has read FLUSH_BOUNDARY_STREAM, to compare others against.
+fn flush_boundary_state() -> MessageDecoderState {
+  let mut state = new_decoder_state();
+  let mut avail_in = FLUSH_BOUNDARY_STREAM.len();
+  let mut input_offset: usize = 0;
+  let mut output = [0u8; 1];
+  let mut avail_out = output.len();
+  let mut output_offset: usize = 0;
+  let mut total_out: usize = 0;
+  BrotliDecompressStream(&mut avail_in,
+                         &mut input_offset,
+                         &FLUSH_BOUNDARY_STREAM[..],
+                         &mut avail_out,
+                         &mut output_offset,
+                         &mut output[..],
+                         &mut total_out,
+                         &mut state);
+  state
+}
+
+// Whether state stopped between metablocks, on a byte boundary, as it does at the end of a
+// flushed message. The decoder does not name its states, so they are compared with those of
+// boundary, which stopped there.
+fn at_flush_boundary(state: &MessageDecoderState, boundary: &MessageDecoderState) -> bool {
+  core::mem::discriminant(&state.state) == core::mem::discriminant(&boundary.state) &&
+  core::mem::discriminant(&state.substate_metablock_header) ==
+  core::mem::discriminant(&boundary.substate_metablock_header) &&
+  state.br.bit_pos_ == boundary.br.bit_pos_ && state.br.avail_in == 0 && state.buffer_length == 0
+}
+
+// Decodes the messages of a MessageCompressor with the same context takeover setting, in order.
+pub struct MessageDecompressor {
+  context_takeover: bool,
+  state: MessageDecoderState,
+  boundary: MessageDecoderState,
+  started: bool,
+  buffer: Vec<u8>,
+  output: Vec<u8>,
+}
+
+impl MessageDecompressor {
+  pub fn new(context_takeover: bool) -> Self {
+    MessageDecompressor {
+      context_takeover,
+      state: new_decoder_state(),
+      boundary: flush_boundary_state(),
+      started: false,
+      buffer: vec![0u8; MESSAGE_BUFFER_SIZE],
+      output: Vec::new(),
+    }
+  }
+
+  pub fn context_takeover(&self) -> bool {
+    self.context_takeover
+  }
+
+  // Returns the decompressed message, valid until the next call. A message that is cut short is
+  // an UnexpectedEof error: without context takeover it does not hold a whole stream, and with
+  // context takeover the decoder did not stop where a flush ends the message, between metablocks
+  // on a byte boundary. A truncated message that happens to end between two of its own
+  // metablocks on a byte boundary cannot be told apart from a whole one.
+  pub fn decompress_message(&mut self, message: &[u8]) -> Result<&[u8], Error> {
+    self.output.clear();
+    if self.context_takeover && message.is_empty() && !self.started {
+      // an empty first message leaves even the stream header for later
+      return Ok(&self.output[..]);
+    }
+    self.started = true;
+    let mut avail_in = message.len();
+    let mut input_offset: usize = 0;
+    let mut total_out: usize = 0;
+    loop {
+      let mut avail_out = self.buffer.len();
+      let mut output_offset: usize = 0;
+      let result = BrotliDecompressStream(&mut avail_in,
+                                          &mut input_offset,
+                                          message,
+                                          &mut avail_out,
+                                          &mut output_offset,
+                                          &mut self.buffer[..],
+                                          &mut total_out,
+                                          &mut self.state);
+      self.output.extend_from_slice(&self.buffer[..output_offset]);
+      match result {
+        BrotliResult::NeedsMoreOutput => continue,
+        // the decoder may stop for input with decoded bytes it has not been able to write yet
+        BrotliResult::NeedsMoreInput if avail_out == 0 => continue,
+        BrotliResult::NeedsMoreInput => {
+          if !self.context_takeover || !at_flush_boundary(&self.state, &self.boundary) {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Truncated message"));
+          }
+          break;
+        }
+        BrotliResult::ResultSuccess => {
+          if avail_in != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Data after the end of the stream"));
+          }
+          if !self.context_takeover {
+            self.state.BrotliStateCleanup();
+            self.state = new_decoder_state();
+            self.started = false;
+          }
+          break;
+        }
+        BrotliResult::ResultFailure => return Err(Error::new(ErrorKind::InvalidData, "Invalid Data")),
+      }
+    }
+    Ok(&self.output[..])
+  }
+}
+
+impl Drop for MessageDecompressor {
+  fn drop(&mut self) {
+    self.state.BrotliStateCleanup();
+    self.boundary.BrotliStateCleanup();
+  }
+}
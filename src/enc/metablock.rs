@@ -1,18 +1,21 @@
 #![allow(dead_code)]
 
 use super::vectorization::Mem256f;
-use super::backward_references::{BrotliEncoderParams};
+use super::backward_references::{BrotliEncoderParams, BrotliEncoderMode};
 use super::encode::{BROTLI_DISTANCE_ALPHABET_SIZE, BROTLI_MAX_DISTANCE_BITS, BROTLI_LARGE_MAX_DISTANCE_BITS, BROTLI_MAX_ALLOWED_DISTANCE};
-use super::constants::BROTLI_MAX_NPOSTFIX;
+use super::constants::{BROTLI_MAX_NPOSTFIX, BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS};
 use super::bit_cost::{BitsEntropy, BrotliPopulationCost};
 use super::block_split::BlockSplit;
 use super::block_splitter::BrotliSplitBlock;
-use super::brotli_bit_stream::MetaBlockSplit;
+use super::brotli_bit_stream::{BrotliPrefixCodeStoredBits, MetaBlockSplit, BROTLI_PREFIX_CODE_DEPTH_SIZE,
+                               BROTLI_PREFIX_CODE_STORAGE_SIZE, BROTLI_PREFIX_CODE_TREE_SIZE};
 use super::cluster::BrotliClusterHistograms;
 use super::cluster::HistogramPair;
-use super::command::{Command, CommandCopyLen, CommandRestoreDistanceCode, PrefixEncodeCopyDistance, BrotliDistanceParams};
-use super::entropy_encode::BrotliOptimizeHuffmanCountsForRle;
-use super::histogram::{BrotliBuildHistogramsWithContext, CostAccessors, HistogramLiteral,
+use super::command::{Command, CommandCopyLen, CommandDistanceContext, CommandRestoreDistanceCode,
+                     PrefixEncodeCopyDistance, BrotliDistanceParams};
+use super::entropy_encode::{BrotliOptimizeHuffmanCountsForRle, HuffmanTree};
+use super::histogram::{BrotliBuildHistogramsWithContext, BrotliBuildLiteralHistogramsWithContext,
+                       CostAccessors, HistogramLiteral,
                        HistogramCommand, HistogramDistance, HistogramClear, ClearHistograms,
                        ContextType, HistogramAddHistogram, HistogramAddItem, Context,
                        };
@@ -122,6 +125,104 @@ fn ComputeDistanceCost(cmds: &[Command],
     return true;
 }
 
+// Like ComputeDistanceCost, but with one histogram per distance context as the metablock
+// may be coded, and with the exact size of each stored prefix code rather than an entropy
+// estimate, which tells the layouts apart more reliably. The contexts are priced apart or
+// merged, whichever is cheaper, since clustering may fold them back into one code.
+fn ComputeDistanceContextCost(cmds: &[Command],
+                              num_commands: usize,
+                              orig_params: &BrotliDistanceParams,
+                              new_params: &BrotliDistanceParams,
+                              tree: &mut [HuffmanTree],
+                              depth: &mut [u8],
+                              bits: &mut [u16],
+                              storage: &mut [u8],
+                              cost: &mut f64) -> bool {
+  let mut histograms = [HistogramDistance::default(), HistogramDistance::default(),
+                        HistogramDistance::default(), HistogramDistance::default()];
+  // large windows widen the alphabet past what a histogram holds, and BrotliStoreMetaBlock
+  // codes the clamped alphabet with the histograms as they are, so such layouts are out
+  let alphabet_size = core::cmp::min(new_params.alphabet_size as usize,
+                                     BROTLI_NUM_HISTOGRAM_DISTANCE_SYMBOLS);
+  if alphabet_size > histograms[0].slice().len() {
+    return false;
+  }
+  let mut extra_bits: f64 = 0.0;
+  for cmd in cmds.split_at(num_commands).0 {
+    if CommandCopyLen(cmd) != 0 && cmd.cmd_prefix_ >= 128 {
+      let distance = CommandRestoreDistanceCode(cmd, orig_params);
+      if distance > new_params.max_distance as u32 {
+        return false;
+      }
+      let mut dist_prefix: u16 = 0;
+      let mut dist_extra: u32 = 0;
+      PrefixEncodeCopyDistance(distance as usize,
+                               new_params.num_direct_distance_codes as usize,
+                               new_params.distance_postfix_bits as u64,
+                               &mut dist_prefix,
+                               &mut dist_extra);
+      HistogramAddItem(&mut histograms[CommandDistanceContext(cmd) as usize], (dist_prefix & 0x3FF) as usize);
+      extra_bits += (dist_prefix >> 10) as f64;
+    }
+  }
+  let mut combined = HistogramDistance::default();
+  let mut separate_cost: f64 = 0.0;
+  for histogram in histograms.iter() {
+    if histogram.total_count_ != 0 {
+      separate_cost += BrotliPrefixCodeStoredBits(&histogram.slice()[..alphabet_size], true,
+                                                  tree, depth, bits, storage) as f64;
+      HistogramAddHistogram(&mut combined, histogram);
+    }
+  }
+  let combined_cost = BrotliPrefixCodeStoredBits(&combined.slice()[..alphabet_size], true,
+                                                 tree, depth, bits, storage) as f64;
+  *cost = extra_bits + if combined_cost < separate_cost { combined_cost } else { separate_cost };
+  true
+}
+
+// Prices the distances of cmds under every legal NPOSTFIX and NDIRECT pair and moves params
+// and cmds over to the cheapest pair; the current pair is kept unless another one is cheaper.
+fn SearchAllDistanceParams<AllocU8: alloc::Allocator<u8>,
+                           AllocU16: alloc::Allocator<u16>,
+                           AllocHT: alloc::Allocator<HuffmanTree>>(m8: &mut AllocU8,
+                                                                   m16: &mut AllocU16,
+                                                                   mht: &mut AllocHT,
+                                                                   cmds: &mut [Command],
+                                                                   num_commands: usize,
+                                                                   params: &mut BrotliEncoderParams) {
+  let orig_params = params.clone();
+  let mut new_params = params.clone();
+  let mut tree = mht.alloc_cell(BROTLI_PREFIX_CODE_TREE_SIZE);
+  let mut depth = m8.alloc_cell(BROTLI_PREFIX_CODE_DEPTH_SIZE);
+  let mut bits = m16.alloc_cell(BROTLI_PREFIX_CODE_DEPTH_SIZE);
+  let mut storage = m8.alloc_cell(BROTLI_PREFIX_CODE_STORAGE_SIZE);
+  let mut best_dist_cost: f64 = 0.0;
+  if !ComputeDistanceContextCost(cmds, num_commands,
+                                 &orig_params.dist, &orig_params.dist,
+                                 tree.slice_mut(), depth.slice_mut(), bits.slice_mut(), storage.slice_mut(),
+                                 &mut best_dist_cost) {
+    best_dist_cost = 1e99;
+  }
+  for npostfix in 0..(BROTLI_MAX_NPOSTFIX as u32 + 1) {
+    for ndirect_msb in 0..16u32 {
+      let mut dist_cost: f64 = 0.0;
+      BrotliInitDistanceParams(&mut new_params, npostfix, ndirect_msb << npostfix);
+      if ComputeDistanceContextCost(cmds, num_commands,
+                                    &orig_params.dist, &new_params.dist,
+                                    tree.slice_mut(), depth.slice_mut(), bits.slice_mut(), storage.slice_mut(),
+                                    &mut dist_cost) &&
+         dist_cost < best_dist_cost {
+        best_dist_cost = dist_cost;
+        params.dist = new_params.dist;
+      }
+    }
+  }
+  mht.free_cell(tree);
+  m8.free_cell(depth);
+  m16.free_cell(bits);
+  m8.free_cell(storage);
+  RecomputeDistancePrefixes(cmds, num_commands, &orig_params.dist, &params.dist);
+}
 
 pub fn BrotliBuildMetaBlock<AllocU8: alloc::Allocator<u8>,
                             AllocU16: alloc::Allocator<u16>,
@@ -133,7 +234,8 @@ pub fn BrotliBuildMetaBlock<AllocU8: alloc::Allocator<u8>,
                             AllocHC: alloc::Allocator<HistogramCommand>,
                             AllocHD: alloc::Allocator<HistogramDistance>,
                             AllocHP: alloc::Allocator<HistogramPair>,
-                            AllocCT: alloc::Allocator<ContextType>>
+                            AllocCT: alloc::Allocator<ContextType>,
+                            AllocHT: alloc::Allocator<HuffmanTree>>
   (m8: &mut AllocU8,
    m16: &mut AllocU16,
    m32: &mut AllocU32,
@@ -145,6 +247,7 @@ pub fn BrotliBuildMetaBlock<AllocU8: alloc::Allocator<u8>,
    mhd: &mut AllocHD,
    mhp: &mut AllocHP,
    mct: &mut AllocCT,
+   mht: &mut AllocHT,
    ringbuffer: &[u8],
    pos: usize,
    mask: usize,
@@ -159,17 +262,11 @@ pub fn BrotliBuildMetaBlock<AllocU8: alloc::Allocator<u8>,
    dst_scratch_space: &mut <HistogramDistance as CostAccessors>::i32vec,
    mb: &mut MetaBlockSplit<AllocU8, AllocU32, AllocHL, AllocHC, AllocHD>) {
 
-  static kMaxNumberOfHistograms: usize = 256usize;
-  let mut distance_histograms: AllocHD::AllocatedMemory;
-  let mut literal_histograms: AllocHL::AllocatedMemory;
-  let mut literal_context_modes: AllocCT::AllocatedMemory = AllocCT::AllocatedMemory::default();
-  let literal_histograms_size: usize;
-  let distance_histograms_size: usize;
-  let mut i: usize;
-  let mut literal_context_multiplier: usize = 1usize;
   let mut ndirect_msb:u32 = 0;
   let mut check_orig = true;
-  if !params.avoid_distance_prefix_search {
+  if !params.avoid_distance_prefix_search && params.exhaustive_distance_prefix_search {
+    SearchAllDistanceParams(m8, m16, mht, cmds, num_commands, params);
+  } else if !params.avoid_distance_prefix_search {
     let mut best_dist_cost: f64 = 1e99;
     let orig_params = params.clone();
     let mut new_params = params.clone();
@@ -233,11 +330,174 @@ pub fn BrotliBuildMetaBlock<AllocU8: alloc::Allocator<u8>,
                    &mut (*mb).literal_split,
                    &mut (*mb).command_split,
                    &mut (*mb).distance_split);
+  if (*params).disable_literal_context_modeling == 0 && !LiteralContextModeIsForced(params) {
+    ChooseLiteralContextModes(m8, m32, mf64, mhl, mhp, mct,
+                              ringbuffer,
+                              pos,
+                              mask,
+                              prev_byte,
+                              prev_byte2,
+                              cmds,
+                              num_commands,
+                              literal_context_mode,
+                              lit_scratch_space,
+                              mb);
+  }
+  BrotliBuildMetaBlockHistograms(m32, mhl, mhc, mhd, mhp, mct,
+                                 ringbuffer,
+                                 pos,
+                                 mask,
+                                 params,
+                                 prev_byte,
+                                 prev_byte2,
+                                 cmds,
+                                 num_commands,
+                                 literal_context_mode,
+                                 lit_scratch_space,
+                                 dst_scratch_space,
+                                 mb);
+}
+
+fn LiteralContextModeIsForced(params: &BrotliEncoderParams) -> bool {
+  match params.mode {
+    BrotliEncoderMode::BROTLI_FORCE_LSB_PRIOR |
+    BrotliEncoderMode::BROTLI_FORCE_MSB_PRIOR |
+    BrotliEncoderMode::BROTLI_FORCE_UTF8_PRIOR |
+    BrotliEncoderMode::BROTLI_FORCE_SIGNED_PRIOR => true,
+    _ => false,
+  }
+}
+
+// Picks the literal context mode of every literal block type of mb: the 64 context
+// histograms of the type are built and clustered under each of the four modes, and the mode
+// whose clusters have the lowest population cost wins. default_mode is kept on ties.
+fn ChooseLiteralContextModes<AllocU8: alloc::Allocator<u8>,
+                             AllocU32: alloc::Allocator<u32>,
+                             AllocF64: alloc::Allocator<super::util::floatX>,
+                             AllocHL: alloc::Allocator<HistogramLiteral>,
+                             AllocHC: alloc::Allocator<HistogramCommand>,
+                             AllocHD: alloc::Allocator<HistogramDistance>,
+                             AllocHP: alloc::Allocator<HistogramPair>,
+                             AllocCT: alloc::Allocator<ContextType>>
+  (m8: &mut AllocU8,
+   m32: &mut AllocU32,
+   mf64: &mut AllocF64,
+   mhl: &mut AllocHL,
+   mhp: &mut AllocHP,
+   mct: &mut AllocCT,
+   ringbuffer: &[u8],
+   pos: usize,
+   mask: usize,
+   prev_byte: u8,
+   prev_byte2: u8,
+   cmds: &[Command],
+   num_commands: usize,
+   default_mode: ContextType,
+   lit_scratch_space: &mut <HistogramLiteral as CostAccessors>::i32vec,
+   mb: &mut MetaBlockSplit<AllocU8, AllocU32, AllocHL, AllocHC, AllocHD>) {
+  static kMaxNumberOfHistograms: usize = 256usize;
+  static kCandidateModes: [ContextType; 4] = [ContextType::CONTEXT_LSB6,
+                                              ContextType::CONTEXT_MSB6,
+                                              ContextType::CONTEXT_UTF8,
+                                              ContextType::CONTEXT_SIGNED];
+  let num_types = (*mb).literal_split.num_types;
+  let mut best_modes = m8.alloc_cell(num_types);
+  let mut best_costs = mf64.alloc_cell(num_types);
+  let mut context_modes = mct.alloc_cell(num_types);
+  let mut clusters = mhl.alloc_cell(1 << 6);
+  let mut cluster_map = m32.alloc_cell(1 << 6);
+  for item in best_modes.slice_mut().iter_mut() {
+    *item = default_mode as u8;
+  }
+  // evaluate the default mode first so that ties keep it
+  for (index, mode) in core::iter::once(default_mode).chain(kCandidateModes.iter().cloned()).enumerate() {
+    if index != 0 && mode as u8 == default_mode as u8 {
+      continue;
+    }
+    for item in context_modes.slice_mut().iter_mut() {
+      *item = mode;
+    }
+    let mut histograms = mhl.alloc_cell(num_types << 6);
+    BrotliBuildLiteralHistogramsWithContext(cmds,
+                                            num_commands,
+                                            &(*mb).literal_split,
+                                            ringbuffer,
+                                            pos,
+                                            mask,
+                                            prev_byte,
+                                            prev_byte2,
+                                            context_modes.slice(),
+                                            histograms.slice_mut());
+    for block_type in 0..num_types {
+      let mut cost: super::util::floatX = 0.0;
+      let mut num_clusters: usize = 0;
+      BrotliClusterHistograms(m32,
+                              mhp,
+                              mhl,
+                              &histograms.slice()[(block_type << 6)..((block_type + 1) << 6)],
+                              1 << 6,
+                              kMaxNumberOfHistograms,
+                              lit_scratch_space,
+                              clusters.slice_mut(),
+                              &mut num_clusters,
+                              cluster_map.slice_mut());
+      for histogram in clusters.slice()[..num_clusters].iter() {
+        cost += BrotliPopulationCost(histogram, lit_scratch_space);
+      }
+      if index == 0 || cost < best_costs.slice()[block_type] {
+        best_costs.slice_mut()[block_type] = cost;
+        best_modes.slice_mut()[block_type] = mode as u8;
+      }
+    }
+    mhl.free_cell(histograms);
+  }
+  mct.free_cell(context_modes);
+  mhl.free_cell(clusters);
+  m32.free_cell(cluster_map);
+  mf64.free_cell(best_costs);
+  m8.free_cell(core::mem::replace(&mut (*mb).literal_context_modes, best_modes));
+}
+
+// Fills in the histograms and context maps of mb for block splits that are already set,
+// clustering the per-context histograms of each block type.
+pub fn BrotliBuildMetaBlockHistograms<AllocU8: alloc::Allocator<u8>,
+                                      AllocU32: alloc::Allocator<u32>,
+                                      AllocHL: alloc::Allocator<HistogramLiteral>,
+                                      AllocHC: alloc::Allocator<HistogramCommand>,
+                                      AllocHD: alloc::Allocator<HistogramDistance>,
+                                      AllocHP: alloc::Allocator<HistogramPair>,
+                                      AllocCT: alloc::Allocator<ContextType>>
+  (m32: &mut AllocU32,
+   mhl: &mut AllocHL,
+   mhc: &mut AllocHC,
+   mhd: &mut AllocHD,
+   mhp: &mut AllocHP,
+   mct: &mut AllocCT,
+   ringbuffer: &[u8],
+   pos: usize,
+   mask: usize,
+   params: &BrotliEncoderParams,
+   prev_byte: u8,
+   prev_byte2: u8,
+   cmds: &[Command],
+   num_commands: usize,
+   literal_context_mode: ContextType,
+   lit_scratch_space: &mut <HistogramLiteral as CostAccessors>::i32vec,
+   dst_scratch_space: &mut <HistogramDistance as CostAccessors>::i32vec,
+   mb: &mut MetaBlockSplit<AllocU8, AllocU32, AllocHL, AllocHC, AllocHD>) {
+  static kMaxNumberOfHistograms: usize = 256usize;
+  let mut distance_histograms: AllocHD::AllocatedMemory;
+  let mut literal_histograms: AllocHL::AllocatedMemory;
+  let mut literal_context_modes: AllocCT::AllocatedMemory = AllocCT::AllocatedMemory::default();
+  let literal_histograms_size: usize;
+  let distance_histograms_size: usize;
+  let mut i: usize;
+  let mut literal_context_multiplier: usize = 1usize;
   if (*params).disable_literal_context_modeling == 0 {
     literal_context_multiplier = (1i32 << 6i32) as (usize);
     literal_context_modes = mct.alloc_cell((*mb).literal_split.num_types);
-    for item in literal_context_modes.slice_mut().iter_mut() {
-      *item = literal_context_mode;
+    for (block_type, item) in literal_context_modes.slice_mut().iter_mut().enumerate() {
+      *item = (*mb).literal_context_mode(block_type, literal_context_mode);
     }
   }
   literal_histograms_size = (*mb).literal_split.num_types.wrapping_mul(literal_context_multiplier);
@@ -487,7 +747,7 @@ fn InitContextBlockSplitter<AllocU8: alloc::Allocator<u8>,
    -> ContextBlockSplitter {
   let max_num_blocks: usize = num_symbols.wrapping_div(min_block_size).wrapping_add(1usize);
   let max_num_types: usize;
-  assert!(num_contexts <= BROTLI_MAX_STATIC_CONTEXTS);
+  debug_assert!(num_contexts <= BROTLI_MAX_STATIC_CONTEXTS);
   let mut xself = ContextBlockSplitter {
     alphabet_size_: alphabet_size,
     num_contexts_: num_contexts,
@@ -1,5 +1,6 @@
 #[macro_use]
 pub mod vectorization;
+pub mod simd;
 pub mod input_pair;
 pub mod fast_log;
 pub mod command;
@@ -9,6 +10,7 @@ pub mod constants;
 pub mod entropy_encode;
 pub mod static_dict;
 pub mod static_dict_lut;
+pub mod shared_dictionary;
 pub mod dictionary_hash;
 pub mod util;
 pub mod utf8_util;
@@ -17,6 +19,7 @@ pub mod cluster;
 pub mod literal_cost;
 pub mod histogram;
 pub mod hash_to_binary_tree;
+pub mod suffix_array;
 pub mod backward_references;
 pub mod backward_references_hq;
 pub mod block_splitter;
@@ -29,10 +32,32 @@ pub mod writer;
 pub mod find_stride;
 pub mod interface;
 pub mod ir_interpret;
+pub mod ir_compile;
+pub mod ir_parse;
+pub mod ir_serialize;
 pub mod prior_eval;
 pub mod stride_eval;
 pub mod context_map_entropy;
 pub mod pdf;
+pub mod sha256;
+pub mod dcb;
+pub mod stack_profile;
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+pub mod vec_alloc;
+#[cfg(not(feature="no-stdlib"))]
+pub mod dictionary_train;
+#[cfg(not(feature="no-stdlib"))]
+pub mod analyzer;
+#[cfg(not(feature="no-stdlib"))]
+pub mod stream_decoder;
+#[cfg(not(feature="no-stdlib"))]
+pub mod pull;
+#[cfg(not(feature="no-stdlib"))]
+pub mod message;
+#[cfg(not(feature="no-stdlib"))]
+pub mod limited_decompressor;
+#[cfg(not(feature="no-stdlib"))]
+pub mod verify;
 
 mod test;
 mod weights;
@@ -42,10 +67,12 @@ pub use self::hash_to_binary_tree::ZopfliNode;
 pub use self::backward_references::BrotliEncoderParams;
 pub use self::encode::{BrotliEncoderInitParams, BrotliEncoderSetParameter};
 use self::encode::{BrotliEncoderCreateInstance, BrotliEncoderDestroyInstance,
-                   BrotliEncoderOperation,
-                   BrotliEncoderCompressStream, BrotliEncoderIsFinished};
+                   BrotliEncoderOperation, BrotliEncoderSetCustomDictionary,
+                   BrotliEncoderCompressStream, BrotliEncoderIsFinished,
+                   BrotliEncoderCompressWholeBuffer, BrotliEncoderTakeOutput};
 use self::cluster::{HistogramPair};
 pub use self::interface::StaticCommand;
+pub use self::brotli_bit_stream::{BrotliMetaBlockStats, BrotliMetaBlockStoragePath};
 use self::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance};
 use self::command::{Command};
 use self::entropy_encode::{HuffmanTree};
@@ -115,6 +142,68 @@ pub fn BrotliCompress<InputType, OutputType>(r: &mut InputType,
                             })
 }
 
+// Compresses with dictionary preloaded as if it preceded the input, so that the
+// stream can only be decompressed with the same custom dictionary.
+#[cfg(not(any(feature="no-stdlib")))]
+pub fn BrotliCompressCustomDict<InputType, OutputType>(r: &mut InputType,
+                                                       w: &mut OutputType,
+                                                       params: &BrotliEncoderParams,
+                                                       dictionary: &[u8])
+                                                       -> Result<usize, io::Error>
+  where InputType: Read,
+        OutputType: Write
+{
+  let mut input_buffer: [u8; 4096] = [0; 4096];
+  let mut output_buffer: [u8; 4096] = [0; 4096];
+  let mut nop_callback = |_data:&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                          _cmds: &mut [interface::StaticCommand],
+                          _mb: interface::InputPair,
+                          _mfv: &mut HeapAlloc<Mem256f>,
+                          _mpdf: &mut HeapAlloc<PDF>,
+                          _mc: &mut HeapAlloc<StaticCommand>|();
+  BrotliCompressCustomIoCustomDict(&mut IoReaderWrapper::<InputType>(r),
+                                   &mut IoWriterWrapper::<OutputType>(w),
+                                   &mut input_buffer[..],
+                                   &mut output_buffer[..],
+                                   params,
+                                   HeapAlloc::<u8> { default_value: 0 },
+                                   HeapAlloc::<u16> { default_value: 0 },
+                                   HeapAlloc::<i32> { default_value: 0 },
+                                   HeapAlloc::<u32> { default_value: 0 },
+                                   HeapAlloc::<u64> { default_value: 0 },
+                                   HeapAlloc::<Command> {
+                                       default_value: Command::default(),
+                                   },
+                                   HeapAlloc::<floatX> { default_value: 0.0 as floatX },
+                                   HeapAlloc::<Mem256f> { default_value: Mem256f::default() },
+                                   HeapAlloc::<PDF> { default_value: PDF::default() },
+                                   HeapAlloc::<StaticCommand> { default_value: StaticCommand::default() },
+                                   HeapAlloc::<HistogramLiteral>{
+                                       default_value: HistogramLiteral::default(),
+                                   },
+                                   HeapAlloc::<HistogramCommand>{
+                                       default_value: HistogramCommand::default(),
+                                   },
+                                   HeapAlloc::<HistogramDistance>{
+                                       default_value: HistogramDistance::default(),
+                                   },
+                                   HeapAlloc::<HistogramPair>{
+                                       default_value: HistogramPair::default(),
+                                   },
+                                   HeapAlloc::<ContextType>{
+                                       default_value: ContextType::default(),
+                                   },
+                                   HeapAlloc::<HuffmanTree>{
+                                       default_value: HuffmanTree::default(),
+                                   },
+                                   HeapAlloc::<ZopfliNode>{
+                                       default_value: ZopfliNode::default(),
+                                   },
+                                   &mut nop_callback,
+                                   dictionary,
+                                   Error::new(ErrorKind::UnexpectedEof, "Unexpected EOF"))
+}
+
 #[cfg(not(feature="no-stdlib"))]
 pub fn BrotliCompressCustomAlloc<InputType,
                                  OutputType,
@@ -213,6 +302,65 @@ pub fn BrotliCompressCustomIo<ErrType,
                               MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
                                                        &mut [interface::StaticCommand],
                                                        interface::InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand)>
+  (r: &mut InputType,
+   w: &mut OutputType,
+   input_buffer: &mut [u8],
+   output_buffer: &mut [u8],
+   params: &BrotliEncoderParams,
+   mu8: AllocU8,
+   mu16: AllocU16,
+   mi32: AllocI32,
+   mu32: AllocU32,
+   m64: AllocU64,
+   mc: AllocCommand,
+   mf64: AllocF64,
+   mfv: AllocFV,
+   mpdf: AllocPDF,
+   msc: AllocStaticCommand,
+   mhl: AllocHL,
+   mhc: AllocHC,
+   mhd: AllocHD,
+   mhp: AllocHP,
+   mct: AllocCT,
+   mht: AllocHT,
+   mzn: AllocZN,
+   metablock_callback: &mut MetablockCallback,
+   unexpected_eof_error_constant: ErrType)
+   -> Result<usize, ErrType>
+  where InputType: CustomRead<ErrType>,
+        OutputType: CustomWrite<ErrType>
+{
+  BrotliCompressCustomIoCustomDict(r, w, input_buffer, output_buffer, params,
+                                   mu8, mu16, mi32, mu32, m64, mc,
+                                   mf64, mfv, mpdf, msc, mhl, mhc, mhd, mhp, mct, mht, mzn,
+                                   metablock_callback,
+                                   &[],
+                                   unexpected_eof_error_constant)
+}
+
+pub fn BrotliCompressCustomIoCustomDict<ErrType,
+                              InputType,
+                              OutputType,
+                              AllocU8: Allocator<u8>,
+                              AllocU16: Allocator<u16>,
+                              AllocI32: Allocator<i32>,
+                              AllocU32: Allocator<u32>,
+                              AllocU64: Allocator<u64>,
+                              AllocCommand: Allocator<Command>,
+                              AllocF64: Allocator<util::floatX>,
+                              AllocFV: Allocator<Mem256f>,
+                              AllocPDF: Allocator<PDF>,
+                              AllocStaticCommand: Allocator<StaticCommand>,
+                              AllocHL: Allocator<HistogramLiteral>,
+                              AllocHC: Allocator<HistogramCommand>,
+                              AllocHD: Allocator<HistogramDistance>,
+                              AllocHP: Allocator<HistogramPair>,
+                              AllocCT: Allocator<ContextType>,
+                              AllocHT: Allocator<HuffmanTree>,
+                              AllocZN: Allocator<ZopfliNode>,
+                              MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                       &mut [interface::StaticCommand],
+                                                       interface::InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand)>
   (r: &mut InputType,
    w: &mut OutputType,
    input_buffer: &mut [u8],
@@ -236,15 +384,20 @@ pub fn BrotliCompressCustomIo<ErrType,
    mut mht: AllocHT,
    mut mzn: AllocZN,
    metablock_callback: &mut MetablockCallback,
+   dictionary: &[u8],
    unexpected_eof_error_constant: ErrType)
    -> Result<usize, ErrType>
   where InputType: CustomRead<ErrType>,
         OutputType: CustomWrite<ErrType>
 {
-  assert!(input_buffer.len() != 0);
-  assert!(output_buffer.len() != 0);
+  if input_buffer.len() == 0 || output_buffer.len() == 0 {
+    return Err(unexpected_eof_error_constant);
+  }
   let mut s_orig = BrotliEncoderCreateInstance(mu8, mu16, mi32, mu32, mc);
   s_orig.params = params.clone();
+  if dictionary.len() != 0 {
+    BrotliEncoderSetCustomDictionary(&mut s_orig, dictionary.len(), dictionary);
+  }
   let mut next_in_offset: usize = 0;  
   let mut next_out_offset: usize = 0;
   let mut total_out = Some(0usize);
@@ -270,7 +423,7 @@ pub fn BrotliCompressCustomIo<ErrType,
                       if size == 0 {
                           eof = true;
                       }
-                      available_in = size;
+                      available_in = core::cmp::min(size, input_buffer.len());
                   }
               }
           }
@@ -294,14 +447,13 @@ pub fn BrotliCompressCustomIo<ErrType,
                                                    metablock_callback);
           let fin = BrotliEncoderIsFinished(s);
           if available_out == 0 || fin != 0 {
-              let lim = output_buffer.len() - available_out;
-              assert_eq!(next_out_offset, lim);
+              let lim = next_out_offset;
               next_out_offset = 0;
               while next_out_offset < lim {
                   match w.write(&mut output_buffer[next_out_offset..lim]) {
                       Err(e) => return Err(e),
                       Ok(size) => {
-                          next_out_offset += size;
+                          next_out_offset += core::cmp::min(size, lim - next_out_offset);
                       }
                   }
               }
@@ -317,6 +469,139 @@ pub fn BrotliCompressCustomIo<ErrType,
       }
       BrotliEncoderDestroyInstance(s);
   }
-  Ok(total_out.unwrap())
+  match total_out {
+    Some(total) => Ok(total),
+    None => Err(unexpected_eof_error_constant),
+  }
+}
+
+
+// Compresses an input held entirely in memory without copying it into the ring buffer (see
+// BrotliEncoderCompressWholeBuffer), writing each block of output as soon as it is ready.
+pub fn BrotliCompressWholeBufferCustomIo<ErrType,
+                                         OutputType,
+                                         AllocU8: Allocator<u8>,
+                                         AllocU16: Allocator<u16>,
+                                         AllocI32: Allocator<i32>,
+                                         AllocU32: Allocator<u32>,
+                                         AllocU64: Allocator<u64>,
+                                         AllocCommand: Allocator<Command>,
+                                         AllocF64: Allocator<util::floatX>,
+                                         AllocFV: Allocator<Mem256f>,
+                                         AllocPDF: Allocator<PDF>,
+                                         AllocStaticCommand: Allocator<StaticCommand>,
+                                         AllocHL: Allocator<HistogramLiteral>,
+                                         AllocHC: Allocator<HistogramCommand>,
+                                         AllocHD: Allocator<HistogramDistance>,
+                                         AllocHP: Allocator<HistogramPair>,
+                                         AllocCT: Allocator<ContextType>,
+                                         AllocHT: Allocator<HuffmanTree>,
+                                         AllocZN: Allocator<ZopfliNode>,
+                                         MetablockCallback: FnMut(&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                                                                  &mut [interface::StaticCommand],
+                                                                  interface::InputPair, &mut AllocFV, &mut AllocPDF, &mut AllocStaticCommand)>
+  (input: &[u8],
+   w: &mut OutputType,
+   params: &BrotliEncoderParams,
+   mu8: AllocU8,
+   mu16: AllocU16,
+   mi32: AllocI32,
+   mu32: AllocU32,
+   mut m64: AllocU64,
+   mc: AllocCommand,
+   mut mf64: AllocF64,
+   mut mfv: AllocFV,
+   mut mpdf: AllocPDF,
+   mut msc: AllocStaticCommand,
+   mut mhl: AllocHL,
+   mut mhc: AllocHC,
+   mut mhd: AllocHD,
+   mut mhp: AllocHP,
+   mut mct: AllocCT,
+   mut mht: AllocHT,
+   mut mzn: AllocZN,
+   metablock_callback: &mut MetablockCallback,
+   invalid_input_error_constant: ErrType)
+   -> Result<usize, ErrType>
+  where OutputType: CustomWrite<ErrType>
+{
+  let mut s = BrotliEncoderCreateInstance(mu8, mu16, mi32, mu32, mc);
+  s.params = params.clone();
+  let mut next_in_offset: usize = 0;
+  let mut total_out: usize = 0;
+  let mut result = Ok(());
+  while BrotliEncoderIsFinished(&mut s) == 0 {
+    if BrotliEncoderCompressWholeBuffer(&mut s,
+                                        &mut m64,
+                                        &mut mf64, &mut mfv, &mut mpdf, &mut msc, &mut mhl, &mut mhc, &mut mhd, &mut mhp, &mut mct, &mut mht, &mut mzn,
+                                        input,
+                                        &mut next_in_offset,
+                                        metablock_callback,
+                                        &mut |_stats: &BrotliMetaBlockStats| ()) <= 0 {
+      result = Err(invalid_input_error_constant);
+      break;
+    }
+    let mut size: usize = 0;
+    let output = BrotliEncoderTakeOutput(&mut s, &mut size);
+    result = writer::write_all(w, output);
+    if result.is_err() {
+      break;
+    }
+    total_out += size;
+  }
+  BrotliEncoderDestroyInstance(&mut s);
+  result.map(|_| total_out)
 }
 
+#[cfg(not(feature="no-stdlib"))]
+pub fn BrotliCompressWholeBuffer<OutputType>(input: &[u8],
+                                             w: &mut OutputType,
+                                             params: &BrotliEncoderParams)
+                                             -> Result<usize, io::Error>
+  where OutputType: Write
+{
+  let mut nop_callback = |_data:&mut interface::PredictionModeContextMap<InputReferenceMut>,
+                          _cmds: &mut [interface::StaticCommand],
+                          _mb: interface::InputPair,
+                          _mfv: &mut HeapAlloc<Mem256f>,
+                          _mpdf: &mut HeapAlloc<PDF>,
+                          _mc: &mut HeapAlloc<StaticCommand>|();
+  BrotliCompressWholeBufferCustomIo(input,
+                                    &mut IoWriterWrapper::<OutputType>(w),
+                                    params,
+                                    HeapAlloc::<u8> { default_value: 0 },
+                                    HeapAlloc::<u16> { default_value: 0 },
+                                    HeapAlloc::<i32> { default_value: 0 },
+                                    HeapAlloc::<u32> { default_value: 0 },
+                                    HeapAlloc::<u64> { default_value: 0 },
+                                    HeapAlloc::<Command> {
+                                        default_value: Command::default(),
+                                    },
+                                    HeapAlloc::<floatX> { default_value: 0.0 as floatX },
+                                    HeapAlloc::<Mem256f> { default_value: Mem256f::default() },
+                                    HeapAlloc::<PDF> { default_value: PDF::default() },
+                                    HeapAlloc::<StaticCommand> { default_value: StaticCommand::default() },
+                                    HeapAlloc::<HistogramLiteral>{
+                                        default_value: HistogramLiteral::default(),
+                                    },
+                                    HeapAlloc::<HistogramCommand>{
+                                        default_value: HistogramCommand::default(),
+                                    },
+                                    HeapAlloc::<HistogramDistance>{
+                                        default_value: HistogramDistance::default(),
+                                    },
+                                    HeapAlloc::<HistogramPair>{
+                                        default_value: HistogramPair::default(),
+                                    },
+                                    HeapAlloc::<ContextType>{
+                                        default_value: ContextType::default(),
+                                    },
+                                    HeapAlloc::<HuffmanTree>{
+                                        default_value: HuffmanTree::default(),
+                                    },
+                                    HeapAlloc::<ZopfliNode>{
+                                        default_value: ZopfliNode::default(),
+                                    },
+                                    &mut nop_callback,
+                                    Error::new(ErrorKind::InvalidInput, "Input too large for whole buffer compression"))
+}
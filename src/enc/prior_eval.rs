@@ -46,8 +46,8 @@ pub trait Prior {
     #[inline]
     fn score_index(stride_byte: u8, selected_context: u8, actual_context: usize, high_nibble: Option<u8>) -> usize {
         let which = Self::which() as usize;
-        assert!(which < WhichPrior::NUM_PRIORS as usize);
-        assert!(actual_context < 256);
+        debug_assert!(which < WhichPrior::NUM_PRIORS as usize);
+        debug_assert!(actual_context < 256);
         if let Some(nibble) = high_nibble {
             WhichPrior::NUM_PRIORS as usize * (actual_context + 4096 + 256 * nibble as usize) + which
         } else {
@@ -253,7 +253,7 @@ pub struct CDF<'a> {
 impl<'a> CDF<'a> {
     #[inline(always)]
     pub fn cost(&self, nibble_u8:u8) -> floatX {
-        assert_eq!(self.cdf.len(), 16);
+        debug_assert_eq!(self.cdf.len(), 16);
         let nibble = nibble_u8 as usize & 0xf;
         let mut pdf = self.cdf[nibble];
         if nibble_u8 != 0 {
@@ -263,7 +263,7 @@ impl<'a> CDF<'a> {
     }
     #[inline(always)]
     pub fn update(&mut self, nibble_u8:u8, speed: (u16, u16)) {
-        assert_eq!(self.cdf.len(), 16);
+        debug_assert_eq!(self.cdf.len(), 16);
         for nib_range in (nibble_u8 as usize & 0xf) .. 16 {
             self.cdf[nib_range] += speed.0;
         }
@@ -281,7 +281,7 @@ impl<'a> CDF<'a> {
 impl<'a> From<&'a mut[u16]> for CDF<'a> {
     #[inline]
     fn from(cdf: &'a mut[u16]) -> CDF<'a> {
-        assert_eq!(cdf.len(), 16);
+        debug_assert_eq!(cdf.len(), 16);
         CDF {
             cdf:cdf,
         }
@@ -405,7 +405,7 @@ impl<'a,
        let epsilon = 6.0;
        let mut max_popularity = 0u32;
        let mut max_popularity_index = 0u8;
-       assert_eq!(WhichPrior::NUM_PRIORS as usize, 9); // workaround rust 1.8.0 compiler bug
+       debug_assert_eq!(WhichPrior::NUM_PRIORS as usize, 9); // workaround rust 1.8.0 compiler bug
        let mut popularity = [0u32; 9];
        let mut bitmask = [0u8; super::interface::NUM_MIXING_VALUES];
        for i in 0..super::interface::NUM_MIXING_VALUES {
@@ -0,0 +1,135 @@
+// A pull-model encoder: every call returns the output it produced as a slice of the encoder's
+// own storage (BrotliEncoderTakeOutput), so it can go straight to a socket or a submission
+// queue without passing through an output buffer first. The slice is valid until the next call.
+use super::backward_references::BrotliEncoderParams;
+use super::brotli_bit_stream::BrotliMetaBlockStats;
+use super::cluster::HistogramPair;
+use super::command::Command;
+use super::encode::{BrotliEncoderCreateInstance, BrotliEncoderDestroyInstance, BrotliEncoderHasMoreOutput,
+                    BrotliEncoderOperation, BrotliEncoderStateStruct, BrotliEncoderStreamState,
+                    BrotliEncoderCompressStreamWithStats, BrotliEncoderTakeOutput};
+use super::entropy_encode::HuffmanTree;
+use super::hash_to_binary_tree::ZopfliNode;
+use super::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance};
+use super::interface;
+use super::vectorization::Mem256f;
+use super::util::floatX;
+use super::{PDF, StaticCommand};
+use alloc::HeapAlloc;
+use std::io::{Error, ErrorKind};
+
+type EncoderState = BrotliEncoderStateStruct<HeapAlloc<u8>, HeapAlloc<u16>, HeapAlloc<u32>, HeapAlloc<i32>,
+                                             HeapAlloc<Command>>;
+
+pub struct Encoder {
+  state: EncoderState,
+  alloc_u64: HeapAlloc<u64>,
+  alloc_f64: HeapAlloc<floatX>,
+  alloc_fv: HeapAlloc<Mem256f>,
+  alloc_pdf: HeapAlloc<PDF>,
+  alloc_sc: HeapAlloc<StaticCommand>,
+  alloc_hl: HeapAlloc<HistogramLiteral>,
+  alloc_hc: HeapAlloc<HistogramCommand>,
+  alloc_hd: HeapAlloc<HistogramDistance>,
+  alloc_hp: HeapAlloc<HistogramPair>,
+  alloc_ct: HeapAlloc<ContextType>,
+  alloc_ht: HeapAlloc<HuffmanTree>,
+  alloc_zn: HeapAlloc<ZopfliNode>,
+}
+
+impl Encoder {
+  pub fn new(params: &BrotliEncoderParams) -> Self {
+    let mut state = BrotliEncoderCreateInstance(HeapAlloc::<u8> { default_value: 0 },
+                                                HeapAlloc::<u16> { default_value: 0 },
+                                                HeapAlloc::<i32> { default_value: 0 },
+                                                HeapAlloc::<u32> { default_value: 0 },
+                                                HeapAlloc::<Command> { default_value: Command::default() });
+    state.params = params.clone();
+    Encoder {
+      state,
+      alloc_u64: HeapAlloc::<u64> { default_value: 0 },
+      alloc_f64: HeapAlloc::<floatX> { default_value: 0.0 as floatX },
+      alloc_fv: HeapAlloc::<Mem256f> { default_value: Mem256f::default() },
+      alloc_pdf: HeapAlloc::<PDF> { default_value: PDF::default() },
+      alloc_sc: HeapAlloc::<StaticCommand> { default_value: StaticCommand::default() },
+      alloc_hl: HeapAlloc::<HistogramLiteral> { default_value: HistogramLiteral::default() },
+      alloc_hc: HeapAlloc::<HistogramCommand> { default_value: HistogramCommand::default() },
+      alloc_hd: HeapAlloc::<HistogramDistance> { default_value: HistogramDistance::default() },
+      alloc_hp: HeapAlloc::<HistogramPair> { default_value: HistogramPair::default() },
+      alloc_ct: HeapAlloc::<ContextType> { default_value: ContextType::default() },
+      alloc_ht: HeapAlloc::<HuffmanTree> { default_value: HuffmanTree::default() },
+      alloc_zn: HeapAlloc::<ZopfliNode> { default_value: ZopfliNode::default() },
+    }
+  }
+
+  // Runs the encoder with no output space, so whatever it produces stays in its storage, and
+  // takes all of it. Returns the number of input bytes consumed along with the output.
+  fn step(&mut self, op: BrotliEncoderOperation, input: &[u8]) -> Result<(usize, &[u8]), Error> {
+    let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
+                            _cmds: &mut [interface::StaticCommand],
+                            _mb: interface::InputPair, _mfv: &mut HeapAlloc<Mem256f>,
+                            _mpdf: &mut HeapAlloc<PDF>, _mc: &mut HeapAlloc<StaticCommand>| ();
+    let mut nop_stats_callback = |_stats: &BrotliMetaBlockStats| ();
+    let mut avail_in = input.len();
+    let mut input_offset: usize = 0;
+    let mut avail_out: usize = 0;
+    let mut output_offset: usize = 0;
+    let mut total_out: Option<usize> = None;
+    let ret = BrotliEncoderCompressStreamWithStats(&mut self.state,
+                                                   &mut self.alloc_u64,
+                                                   &mut self.alloc_f64,
+                                                   &mut self.alloc_fv,
+                                                   &mut self.alloc_pdf,
+                                                   &mut self.alloc_sc,
+                                                   &mut self.alloc_hl,
+                                                   &mut self.alloc_hc,
+                                                   &mut self.alloc_hd,
+                                                   &mut self.alloc_hp,
+                                                   &mut self.alloc_ct,
+                                                   &mut self.alloc_ht,
+                                                   &mut self.alloc_zn,
+                                                   op,
+                                                   &mut avail_in,
+                                                   input,
+                                                   &mut input_offset,
+                                                   &mut avail_out,
+                                                   &mut [],
+                                                   &mut output_offset,
+                                                   &mut total_out,
+                                                   &mut nop_callback,
+                                                   &mut nop_stats_callback);
+    if ret <= 0 {
+      return Err(Error::new(ErrorKind::InvalidData, "Invalid Data"));
+    }
+    let mut size: usize = 0;
+    Ok((input_offset, BrotliEncoderTakeOutput(&mut self.state, &mut size)))
+  }
+
+  // Consumes a prefix of the input (at least one byte of non-empty input) and returns its length
+  // along with any output that became ready, usually none until a whole metablock is buffered.
+  pub fn push(&mut self, input: &[u8]) -> Result<(usize, &[u8]), Error> {
+    self.step(BrotliEncoderOperation::BROTLI_OPERATION_PROCESS, input)
+  }
+
+  // Returns the next piece of the flush: call until it returns an empty slice, and only then push
+  // again. The output up to that point decodes completely.
+  pub fn flush(&mut self) -> Result<&[u8], Error> {
+    Ok(self.step(BrotliEncoderOperation::BROTLI_OPERATION_FLUSH, &[])?.1)
+  }
+
+  // Returns the next piece of the end of the stream: call until is_finished().
+  pub fn finish(&mut self) -> Result<&[u8], Error> {
+    Ok(self.step(BrotliEncoderOperation::BROTLI_OPERATION_FINISH, &[])?.1)
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.state.stream_state_ == BrotliEncoderStreamState::BROTLI_STREAM_FINISHED &&
+    BrotliEncoderHasMoreOutput(&self.state) == 0
+  }
+}
+
+impl Drop for Encoder {
+  fn drop(&mut self) {
+    BrotliEncoderDestroyInstance(&mut self.state);
+  }
+}
@@ -24,6 +24,8 @@ use std::io;
 
 #[cfg(not(feature="no-stdlib"))]
 use std::io::{Read, Error, ErrorKind};
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+use super::vec_alloc::{BoxedSlice, VecAlloc, VecAllocBundle};
 
 
 
@@ -229,6 +231,80 @@ impl<R: Read> CompressorReader<R> {
 
 
 
+// CompressorReader for the alloc feature: it reads from a CustomRead and needs no std::io.
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+type VecAllocReaderCustomIo<ErrType, R> = CompressorReaderCustomIo<ErrType,
+                                                                   R,
+                                                                   BoxedSlice<u8>,
+                                                                   VecAlloc<u8>,
+                                                                   VecAlloc<u16>,
+                                                                   VecAlloc<i32>,
+                                                                   VecAlloc<u32>,
+                                                                   VecAlloc<u64>,
+                                                                   VecAlloc<Command>,
+                                                                   VecAlloc<super::util::floatX>,
+                                                                   VecAlloc<Mem256f>,
+                                                                   VecAlloc<PDF>,
+                                                                   VecAlloc<StaticCommand>,
+                                                                   VecAlloc<HistogramLiteral>,
+                                                                   VecAlloc<HistogramCommand>,
+                                                                   VecAlloc<HistogramDistance>,
+                                                                   VecAlloc<HistogramPair>,
+                                                                   VecAlloc<ContextType>,
+                                                                   VecAlloc<HuffmanTree>,
+                                                                   VecAlloc<ZopfliNode>>;
+
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+pub struct CompressorReaderVecAlloc<ErrType, R: CustomRead<ErrType>>(VecAllocReaderCustomIo<ErrType, R>);
+
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+impl<ErrType, R: CustomRead<ErrType>> CompressorReaderVecAlloc<ErrType, R> {
+  pub fn new(r: R, buffer_size: usize, q: u32, lgwin: u32, invalid_data_error: ErrType) -> Self {
+    let mut m = VecAllocBundle::default();
+    let buffer = m.alloc_u8.alloc_cell(if buffer_size == 0 {4096} else {buffer_size});
+    CompressorReaderVecAlloc::<ErrType, R>(CompressorReaderCustomIo::new(r,
+                                                                         buffer,
+                                                                         m.alloc_u8,
+                                                                         m.alloc_u16,
+                                                                         m.alloc_i32,
+                                                                         m.alloc_u32,
+                                                                         m.alloc_u64,
+                                                                         m.alloc_c,
+                                                                         m.alloc_f64,
+                                                                         m.alloc_fv,
+                                                                         m.alloc_pdf,
+                                                                         m.alloc_sc,
+                                                                         m.alloc_hl,
+                                                                         m.alloc_hc,
+                                                                         m.alloc_hd,
+                                                                         m.alloc_hp,
+                                                                         m.alloc_ct,
+                                                                         m.alloc_ht,
+                                                                         m.alloc_zn,
+                                                                         invalid_data_error,
+                                                                         q,
+                                                                         lgwin))
+  }
+
+  pub fn with_params(r: R, buffer_size: usize, params: &BrotliEncoderParams, invalid_data_error: ErrType) -> Self {
+    let mut reader = Self::new(r, buffer_size, params.quality as u32, params.lgwin as u32, invalid_data_error);
+    reader.0.state.params = params.clone();
+    reader
+  }
+
+  pub fn get_ref(&self) -> &R {
+    self.0.get_ref()
+  }
+}
+
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+impl<ErrType, R: CustomRead<ErrType>> CustomRead<ErrType> for CompressorReaderVecAlloc<ErrType, R> {
+  fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrType> {
+    self.0.read(buf)
+  }
+}
+
+
 #[cfg(not(feature="no-stdlib"))]
 impl<R: Read> Read for CompressorReader<R> {
   fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
@@ -446,7 +522,7 @@ CompressorReaderCustomIo<ErrType, R, BufferType, AllocU8, AllocU16, AllocI32, Al
                         self.input_eof = true;
                     }else {
                         needs_input = false;
-                        self.input_len += size;
+                        self.input_len = core::cmp::min(self.input_len + size, self.input_buffer.slice_mut().len());
                         avail_in = self.input_len - self.input_offset;
                     },
                 }
@@ -490,7 +566,11 @@ CompressorReaderCustomIo<ErrType, R, BufferType, AllocU8, AllocU16, AllocI32, Al
             }
           }
           if ret <= 0 {
-              return Err(self.error_if_invalid_data.take().unwrap());
+              // the error is only handed out once; later reads see the end of the stream
+              return match self.error_if_invalid_data.take() {
+                Some(e) => Err(e),
+                None => Ok(0),
+              };
           }
           let fin = BrotliEncoderIsFinished(&mut self.state);
           if fin != 0 {
@@ -0,0 +1,161 @@
+// SHA-256 (FIPS 180-4), used to identify the dictionary of a dictionary-compressed stream.
+use core;
+
+static kSha256RoundConstants: [u32; 64] = [
+  0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+  0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+  0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+  0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+  0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+  0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+  0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+  0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+static kSha256InitialState: [u32; 8] = [
+  0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+pub const SHA256_DIGEST_SIZE: usize = 32;
+
+#[derive(Clone)]
+pub struct Sha256 {
+  state: [u32; 8],
+  // bytes of the current block that have been received
+  block: [u8; 64],
+  block_len: usize,
+  total_len: u64,
+}
+
+impl Default for Sha256 {
+  fn default() -> Self {
+    Sha256 {
+      state: kSha256InitialState,
+      block: [0; 64],
+      block_len: 0,
+      total_len: 0,
+    }
+  }
+}
+
+fn Sha256Compress(state: &mut [u32; 8], block: &[u8]) {
+  let mut w = [0u32; 64];
+  for (i, word) in block.chunks(4).enumerate() {
+    w[i] = ((word[0] as u32) << 24) | ((word[1] as u32) << 16) | ((word[2] as u32) << 8) |
+           (word[3] as u32);
+  }
+  for i in 16..64 {
+    let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+    let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+    w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+  }
+  let mut v = *state;
+  for i in 0..64 {
+    let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+    let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+    let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(kSha256RoundConstants[i]).wrapping_add(w[i]);
+    let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+    let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+    let t2 = s0.wrapping_add(maj);
+    v[7] = v[6];
+    v[6] = v[5];
+    v[5] = v[4];
+    v[4] = v[3].wrapping_add(t1);
+    v[3] = v[2];
+    v[2] = v[1];
+    v[1] = v[0];
+    v[0] = t1.wrapping_add(t2);
+  }
+  for (item, value) in state.iter_mut().zip(v.iter()) {
+    *item = item.wrapping_add(*value);
+  }
+}
+
+impl Sha256 {
+  pub fn new() -> Self {
+    Sha256::default()
+  }
+  pub fn update(&mut self, mut data: &[u8]) {
+    self.total_len = self.total_len.wrapping_add(data.len() as u64);
+    if self.block_len != 0 {
+      let fill = core::cmp::min(64 - self.block_len, data.len());
+      self.block[self.block_len..self.block_len + fill].clone_from_slice(&data[..fill]);
+      self.block_len += fill;
+      data = &data[fill..];
+      if self.block_len < 64 {
+        return;
+      }
+      Sha256Compress(&mut self.state, &self.block[..]);
+      self.block_len = 0;
+    }
+    while data.len() >= 64 {
+      Sha256Compress(&mut self.state, &data[..64]);
+      data = &data[64..];
+    }
+    self.block[..data.len()].clone_from_slice(data);
+    self.block_len = data.len();
+  }
+  pub fn finalize(mut self) -> [u8; SHA256_DIGEST_SIZE] {
+    let bit_len = self.total_len.wrapping_mul(8);
+    // a one bit, zeros up to 56 bytes into a block, then the message length in bits
+    let mut padding = [0u8; 72];
+    padding[0] = 0x80;
+    let pad_len = if self.block_len < 56 { 56 - self.block_len } else { 120 - self.block_len };
+    for i in 0..8 {
+      padding[pad_len + i] = (bit_len >> (56 - 8 * i)) as u8;
+    }
+    let total_len = self.total_len;
+    self.update(&padding[..pad_len + 8]);
+    self.total_len = total_len;
+    let mut digest = [0u8; SHA256_DIGEST_SIZE];
+    for (i, word) in self.state.iter().enumerate() {
+      digest[4 * i] = (*word >> 24) as u8;
+      digest[4 * i + 1] = (*word >> 16) as u8;
+      digest[4 * i + 2] = (*word >> 8) as u8;
+      digest[4 * i + 3] = *word as u8;
+    }
+    digest
+  }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; SHA256_DIGEST_SIZE] {
+  let mut hasher = Sha256::new();
+  hasher.update(data);
+  hasher.finalize()
+}
+
+mod test {
+  #[allow(unused)]
+  fn hex(digest: &[u8]) -> [u8; 64] {
+    let mut out = [0u8; 64];
+    for (i, byte) in digest.iter().enumerate() {
+      out[2 * i] = b"0123456789abcdef"[(*byte >> 4) as usize];
+      out[2 * i + 1] = b"0123456789abcdef"[(*byte & 0xf) as usize];
+    }
+    out
+  }
+  #[test]
+  fn test_sha256_vectors() {
+    assert_eq!(&hex(&super::sha256(b""))[..],
+               &b"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"[..]);
+    assert_eq!(&hex(&super::sha256(b"abc"))[..],
+               &b"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"[..]);
+    assert_eq!(&hex(&super::sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"))[..],
+               &b"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"[..]);
+  }
+  #[test]
+  fn test_sha256_incremental() {
+    let data = [b'a'; 1000];
+    let mut hasher = super::Sha256::new();
+    for chunk in data.chunks(7) {
+      hasher.update(chunk);
+    }
+    assert_eq!(hasher.finalize(), super::sha256(&data[..]));
+    let mut million = super::Sha256::new();
+    for _ in 0..1000 {
+      million.update(&data[..]);
+    }
+    assert_eq!(&hex(&million.finalize())[..],
+               &b"cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"[..]);
+  }
+}
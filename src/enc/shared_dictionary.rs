@@ -0,0 +1,822 @@
+#![allow(dead_code)]
+use super::static_dict::{BrotliDictionary, BrotliDictionaryHash14};
+use super::super::dictionary::{kBrotliDictionary, kBrotliDictionarySizeBitsByLength,
+                               kBrotliDictionaryOffsetsByLength};
+use super::super::transform::{TransformDictionaryWord, kNumTransforms, kTransforms};
+use super::super::alloc::{SliceWrapper, SliceWrapperMut, Allocator};
+use core;
+
+// Shared dictionaries in the serialized format of the Shared Brotli draft
+// (draft-vandevenne-shared-brotli-format): an LZ77 prefix, optional replacement
+// word lists and transform lists, and how they are assigned to literal contexts.
+
+pub const BROTLI_SHARED_DICTIONARY_MAGIC: [u8; 2] = [0x91, 0x00];
+// word lengths 0 to 31 each have a size_bits entry; lengths below 4 are never used
+pub const BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS: usize = 32;
+pub const BROTLI_SHARED_DICTIONARY_MIN_WORD_LENGTH: usize = 4;
+pub const BROTLI_SHARED_DICTIONARY_MAX_LISTS: usize = 64;
+pub const BROTLI_SHARED_DICTIONARY_NUM_CONTEXTS: usize = 64;
+const BROTLI_MAX_PREFIX_SIZE: u32 = 1073741823;
+pub const BROTLI_TRANSFORM_IDENTITY: u8 = 0;
+pub const BROTLI_TRANSFORM_OMIT_LAST_9: u8 = 9;
+pub const BROTLI_TRANSFORM_UPPERCASE_FIRST: u8 = 10;
+pub const BROTLI_TRANSFORM_UPPERCASE_ALL: u8 = 11;
+pub const BROTLI_TRANSFORM_OMIT_FIRST_1: u8 = 12;
+pub const BROTLI_TRANSFORM_OMIT_FIRST_9: u8 = 20;
+pub const BROTLI_TRANSFORM_SHIFT_FIRST: u8 = 21;
+pub const BROTLI_TRANSFORM_SHIFT_ALL: u8 = 22;
+// the u16 hash table entries hold a 5 bit length and the low 11 bits of the word index
+const BROTLI_MAX_HASHED_WORD_INDEX: usize = 1 << 11;
+// a string longer than any transform output, made of bytes that no built-in prefix or
+// suffix contains and that uppercasing leaves alone
+const BUILTIN_TRANSFORM_PROBE: [u8; 10] = [0x80; 10];
+
+#[derive(Clone, Copy)]
+pub struct BrotliSharedWordList<'a> {
+  pub size_bits_by_length: [u8; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+  pub offsets_by_length: [u32; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+  pub data: &'a [u8],
+}
+
+#[derive(Clone, Copy)]
+pub struct BrotliSharedTransforms<'a> {
+  // length prefixed prefix and suffix strings, the last one empty
+  pub prefix_suffix: &'a [u8],
+  // offset of each string in prefix_suffix
+  pub prefix_suffix_map: [u16; 256],
+  pub num_prefix_suffix: usize,
+  // (prefix_id, type, suffix_id) for each transform
+  pub triplets: &'a [u8],
+  // two bytes per transform, only present when a shift transform is used
+  pub params: &'a [u8],
+}
+
+impl<'a> BrotliSharedTransforms<'a> {
+  pub fn num_transforms(&self) -> usize {
+    self.triplets.len() / 3
+  }
+  pub fn transform_type(&self, transform_id: usize) -> u8 {
+    self.triplets[transform_id * 3 + 1]
+  }
+  pub fn prefix(&self, transform_id: usize) -> &'a [u8] {
+    self.string(self.triplets[transform_id * 3])
+  }
+  pub fn suffix(&self, transform_id: usize) -> &'a [u8] {
+    self.string(self.triplets[transform_id * 3 + 2])
+  }
+  // the signed code point offset of a shift transform
+  pub fn shift(&self, transform_id: usize) -> i32 {
+    if self.params.is_empty() {
+      return 0;
+    }
+    let param = self.params[transform_id * 2] as i32 | ((self.params[transform_id * 2 + 1] as i32) << 8);
+    (param & 0x7fff) - (param & 0x8000)
+  }
+  fn string(&self, string_id: u8) -> &'a [u8] {
+    let offset = self.prefix_suffix_map[string_id as usize] as usize;
+    let len = self.prefix_suffix[offset] as usize;
+    &self.prefix_suffix[offset + 1..offset + 1 + len]
+  }
+  fn is_plain(&self, transform_id: usize, transform_type: u8) -> bool {
+    self.transform_type(transform_id) == transform_type && self.prefix(transform_id).is_empty() &&
+    self.suffix(transform_id).is_empty()
+  }
+}
+
+// Uppercases the character at the front of p the way the decoder does, returning its length.
+fn ToUpperCase(p: &mut [u8]) -> usize {
+  if p[0] < 0xc0 {
+    if p[0] >= b'a' && p[0] <= b'z' {
+      p[0] ^= 32;
+    }
+    return 1;
+  }
+  if p[0] < 0xe0 {
+    if p.len() > 1 {
+      p[1] ^= 32;
+    }
+    return 2;
+  }
+  if p.len() > 2 {
+    p[2] ^= 5;
+  }
+  3
+}
+
+// Adds delta to the code point of the UTF-8 character at the front of p, keeping its
+// length, and returns the number of bytes it covers. A truncated character is left alone.
+pub fn ShiftCodePoint(p: &mut [u8], delta: i32) -> usize {
+  let delta = delta as u32;
+  if p[0] < 0x80 {
+    p[0] = (p[0] as u32).wrapping_add(delta) as u8 & 0x7f;
+    return 1;
+  }
+  if p[0] < 0xc0 {
+    return 1;
+  }
+  if p[0] < 0xe0 {
+    if p.len() < 2 {
+      return 1;
+    }
+    let scalar = ((p[1] as u32 & 0x3f) | ((p[0] as u32 & 0x1f) << 6)).wrapping_add(delta);
+    p[0] = 0xc0 | ((scalar >> 6) & 0x1f) as u8;
+    p[1] = (p[1] & 0xc0) | (scalar & 0x3f) as u8;
+    return 2;
+  }
+  if p[0] < 0xf0 {
+    if p.len() < 3 {
+      return p.len();
+    }
+    let scalar = ((p[2] as u32 & 0x3f) | ((p[1] as u32 & 0x3f) << 6) | ((p[0] as u32 & 0x0f) << 12))
+      .wrapping_add(delta);
+    p[0] = 0xe0 | ((scalar >> 12) & 0x0f) as u8;
+    p[1] = (p[1] & 0xc0) | ((scalar >> 6) & 0x3f) as u8;
+    p[2] = (p[2] & 0xc0) | (scalar & 0x3f) as u8;
+    return 3;
+  }
+  if p[0] < 0xf8 {
+    if p.len() < 4 {
+      return p.len();
+    }
+    let scalar = ((p[3] as u32 & 0x3f) | ((p[2] as u32 & 0x3f) << 6) | ((p[1] as u32 & 0x3f) << 12) |
+                  ((p[0] as u32 & 0x07) << 18))
+      .wrapping_add(delta);
+    p[0] = 0xf0 | ((scalar >> 18) & 0x07) as u8;
+    p[1] = (p[1] & 0xc0) | ((scalar >> 12) & 0x3f) as u8;
+    p[2] = (p[2] & 0xc0) | ((scalar >> 6) & 0x3f) as u8;
+    p[3] = (p[3] & 0xc0) | (scalar & 0x3f) as u8;
+    return 4;
+  }
+  1
+}
+
+// Writes the prefix, the transformed word and the suffix of a transform to dst, which must
+// have room for them, and returns their length. This is TransformDictionaryWord for the
+// transform lists of shared dictionaries, which add the shift transforms.
+pub fn TransformSharedDictionaryWord(transforms: &BrotliSharedTransforms,
+                                     dst: &mut [u8],
+                                     word: &[u8],
+                                     transform_id: usize)
+                                     -> usize {
+  let prefix = transforms.prefix(transform_id);
+  let suffix = transforms.suffix(transform_id);
+  let transform_type = transforms.transform_type(transform_id);
+  let word = if transform_type <= BROTLI_TRANSFORM_OMIT_LAST_9 {
+    &word[..word.len() - core::cmp::min(transform_type as usize, word.len())]
+  } else if transform_type >= BROTLI_TRANSFORM_OMIT_FIRST_1 && transform_type <= BROTLI_TRANSFORM_OMIT_FIRST_9 {
+    let skip = (transform_type - BROTLI_TRANSFORM_OMIT_FIRST_1) as usize + 1;
+    &word[core::cmp::min(skip, word.len())..]
+  } else {
+    word
+  };
+  dst[..prefix.len()].clone_from_slice(prefix);
+  let start = prefix.len();
+  let end = start + word.len();
+  dst[start..end].clone_from_slice(word);
+  if start != end {
+    let body = &mut dst[start..end];
+    let mut pos = 0usize;
+    match transform_type {
+      BROTLI_TRANSFORM_UPPERCASE_FIRST => {
+        ToUpperCase(body);
+      }
+      BROTLI_TRANSFORM_UPPERCASE_ALL => {
+        while pos < body.len() {
+          pos += ToUpperCase(&mut body[pos..]);
+        }
+      }
+      BROTLI_TRANSFORM_SHIFT_FIRST => {
+        ShiftCodePoint(body, transforms.shift(transform_id));
+      }
+      BROTLI_TRANSFORM_SHIFT_ALL => {
+        let delta = transforms.shift(transform_id);
+        while pos < body.len() {
+          pos += ShiftCodePoint(&mut body[pos..], delta);
+        }
+      }
+      _ => {}
+    }
+  }
+  dst[end..end + suffix.len()].clone_from_slice(suffix);
+  end + suffix.len()
+}
+
+// The parts of a serialized shared dictionary that the encoder uses. The word list and
+// transforms are the ones of the first dictionary; None stands for the built-in ones.
+#[derive(Clone, Copy)]
+pub struct BrotliSharedDictionary<'a> {
+  pub prefix: &'a [u8],
+  pub words: Option<BrotliSharedWordList<'a>>,
+  pub transforms: Option<BrotliSharedTransforms<'a>>,
+  // the decoder picks among several dictionaries by literal context
+  pub context_based: bool,
+}
+
+fn ReadVarint32(encoded: &[u8], pos: &mut usize) -> Option<u32> {
+  let mut result: u32 = 0;
+  let mut shift = 0;
+  loop {
+    if *pos >= encoded.len() || shift >= 32 {
+      return None;
+    }
+    let byte = encoded[*pos];
+    *pos += 1;
+    if shift == 28 && byte > 0x0f {
+      return None;
+    }
+    result |= ((byte & 0x7f) as u32) << shift;
+    if byte & 0x80 == 0 {
+      return Some(result);
+    }
+    shift += 7;
+  }
+}
+
+fn ReadUint8(encoded: &[u8], pos: &mut usize) -> Option<u8> {
+  if *pos >= encoded.len() {
+    return None;
+  }
+  *pos += 1;
+  Some(encoded[*pos - 1])
+}
+
+fn ReadUint16(encoded: &[u8], pos: &mut usize) -> Option<u16> {
+  if *pos + 2 > encoded.len() {
+    return None;
+  }
+  *pos += 2;
+  Some(encoded[*pos - 2] as u16 | ((encoded[*pos - 1] as u16) << 8))
+}
+
+fn ParseWordList<'a>(encoded: &'a [u8], pos: &mut usize) -> Option<BrotliSharedWordList<'a>> {
+  let num_lengths = BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS - BROTLI_SHARED_DICTIONARY_MIN_WORD_LENGTH;
+  if *pos + num_lengths > encoded.len() {
+    return None;
+  }
+  let mut words = BrotliSharedWordList {
+    size_bits_by_length: [0; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+    offsets_by_length: [0; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+    data: &[],
+  };
+  words.size_bits_by_length[BROTLI_SHARED_DICTIONARY_MIN_WORD_LENGTH..]
+    .clone_from_slice(&encoded[*pos..*pos + num_lengths]);
+  *pos += num_lengths;
+  // a length with size_bits 0 has no words
+  let mut offset: usize = 0;
+  for len in BROTLI_SHARED_DICTIONARY_MIN_WORD_LENGTH..BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS {
+    let size_bits = words.size_bits_by_length[len];
+    if size_bits > 15 {
+      return None;
+    }
+    words.offsets_by_length[len] = offset as u32;
+    if size_bits != 0 {
+      offset += len << size_bits;
+    }
+  }
+  if *pos + offset > encoded.len() {
+    return None;
+  }
+  words.data = &encoded[*pos..*pos + offset];
+  *pos += offset;
+  Some(words)
+}
+
+fn ParseTransformsList<'a>(encoded: &'a [u8], pos: &mut usize) -> Option<BrotliSharedTransforms<'a>> {
+  let prefix_suffix_size = match ReadUint16(encoded, pos) {
+    Some(size) => size as usize,
+    None => return None,
+  };
+  // there is always room for the terminating empty string
+  if prefix_suffix_size == 0 || *pos + prefix_suffix_size > encoded.len() {
+    return None;
+  }
+  let mut transforms = BrotliSharedTransforms {
+    prefix_suffix: &encoded[*pos..*pos + prefix_suffix_size],
+    prefix_suffix_map: [0; 256],
+    num_prefix_suffix: 0,
+    triplets: &[],
+    params: &[],
+  };
+  let mut offset: usize = 0;
+  loop {
+    if transforms.num_prefix_suffix == transforms.prefix_suffix_map.len() {
+      return None;
+    }
+    let len = transforms.prefix_suffix[offset] as usize;
+    transforms.prefix_suffix_map[transforms.num_prefix_suffix] = offset as u16;
+    transforms.num_prefix_suffix += 1;
+    offset += 1 + len;
+    if len == 0 {
+      if offset != prefix_suffix_size {
+        return None;
+      }
+      break;
+    }
+    if offset >= prefix_suffix_size {
+      return None;
+    }
+  }
+  *pos += prefix_suffix_size;
+  let num_transforms = match ReadUint8(encoded, pos) {
+    Some(num) => num as usize,
+    None => return None,
+  };
+  if *pos + num_transforms * 3 > encoded.len() {
+    return None;
+  }
+  transforms.triplets = &encoded[*pos..*pos + num_transforms * 3];
+  *pos += num_transforms * 3;
+  let mut has_params = false;
+  for triplet in transforms.triplets.chunks(3) {
+    if triplet[0] as usize >= transforms.num_prefix_suffix ||
+       triplet[2] as usize >= transforms.num_prefix_suffix ||
+       triplet[1] > BROTLI_TRANSFORM_SHIFT_ALL {
+      return None;
+    }
+    if triplet[1] == BROTLI_TRANSFORM_SHIFT_FIRST || triplet[1] == BROTLI_TRANSFORM_SHIFT_ALL {
+      has_params = true;
+    }
+  }
+  if has_params {
+    if *pos + num_transforms * 2 > encoded.len() {
+      return None;
+    }
+    transforms.params = &encoded[*pos..*pos + num_transforms * 2];
+    *pos += num_transforms * 2;
+  }
+  Some(transforms)
+}
+
+// Parses a serialized shared dictionary, returning None if it is malformed.
+pub fn BrotliParseSharedDictionary<'a>(encoded: &'a [u8]) -> Option<BrotliSharedDictionary<'a>> {
+  if encoded.len() < 2 || encoded[..2] != BROTLI_SHARED_DICTIONARY_MAGIC[..] {
+    return None;
+  }
+  let mut pos: usize = 2;
+  let mut dict = BrotliSharedDictionary {
+    prefix: &[],
+    words: None,
+    transforms: None,
+    context_based: false,
+  };
+  let prefix_size = match ReadVarint32(encoded, &mut pos) {
+    Some(size) => size,
+    None => return None,
+  };
+  if prefix_size > BROTLI_MAX_PREFIX_SIZE || pos + prefix_size as usize > encoded.len() {
+    return None;
+  }
+  dict.prefix = &encoded[pos..pos + prefix_size as usize];
+  pos += prefix_size as usize;
+
+  let num_word_lists = match ReadUint8(encoded, &mut pos) {
+    Some(num) => num as usize,
+    None => return None,
+  };
+  if num_word_lists > BROTLI_SHARED_DICTIONARY_MAX_LISTS {
+    return None;
+  }
+  // every list is validated here and parsed again if the first dictionary selects it
+  let mut word_list_pos = [0usize; BROTLI_SHARED_DICTIONARY_MAX_LISTS];
+  for index in 0..num_word_lists {
+    word_list_pos[index] = pos;
+    if ParseWordList(encoded, &mut pos).is_none() {
+      return None;
+    }
+  }
+  let num_transform_lists = match ReadUint8(encoded, &mut pos) {
+    Some(num) => num as usize,
+    None => return None,
+  };
+  if num_transform_lists > BROTLI_SHARED_DICTIONARY_MAX_LISTS {
+    return None;
+  }
+  let mut transform_list_pos = [0usize; BROTLI_SHARED_DICTIONARY_MAX_LISTS];
+  for index in 0..num_transform_lists {
+    transform_list_pos[index] = pos;
+    if ParseTransformsList(encoded, &mut pos).is_none() {
+      return None;
+    }
+  }
+
+  // each dictionary pairs a word list with a transform list; an index one past the
+  // last list selects the built-in one
+  let num_dictionaries = match ReadUint8(encoded, &mut pos) {
+    Some(num) => num as usize,
+    None => return None,
+  };
+  if num_dictionaries == 0 || num_dictionaries > BROTLI_SHARED_DICTIONARY_MAX_LISTS {
+    return None;
+  }
+  for index in 0..num_dictionaries {
+    if pos + 2 > encoded.len() {
+      return None;
+    }
+    let words_index = encoded[pos] as usize;
+    let transforms_index = encoded[pos + 1] as usize;
+    pos += 2;
+    if words_index > num_word_lists || transforms_index > num_transform_lists {
+      return None;
+    }
+    if index == 0 {
+      if words_index != num_word_lists {
+        dict.words = ParseWordList(encoded, &mut word_list_pos[words_index]);
+      }
+      if transforms_index != num_transform_lists {
+        dict.transforms = ParseTransformsList(encoded, &mut transform_list_pos[transforms_index]);
+      }
+    }
+  }
+
+  dict.context_based = match ReadUint8(encoded, &mut pos) {
+    Some(0) => false,
+    Some(1) => true,
+    _ => return None,
+  };
+  if dict.context_based {
+    if pos + BROTLI_SHARED_DICTIONARY_NUM_CONTEXTS > encoded.len() {
+      return None;
+    }
+    for context in 0..BROTLI_SHARED_DICTIONARY_NUM_CONTEXTS {
+      if encoded[pos + context] as usize >= num_dictionaries {
+        return None;
+      }
+    }
+    pos += BROTLI_SHARED_DICTIONARY_NUM_CONTEXTS;
+  }
+  if pos != encoded.len() {
+    return None;
+  }
+  Some(dict)
+}
+
+// The word list and transforms of a shared dictionary, copied into encoder memory
+// together with the hash table that the dictionary search needs.
+pub struct SharedDictionaryWords<AllocU8: Allocator<u8>, AllocU16: Allocator<u16>> {
+  size_bits_by_length: [u8; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+  offsets_by_length: [u32; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+  data: AllocU8::AllocatedMemory,
+  hash_table: AllocU16::AllocatedMemory,
+  // the word index bits past the 11 that fit in hash_table, when a length has more words
+  hash_table_high: AllocU8::AllocatedMemory,
+  // the prefix and suffix strings, the triplets and the params of the transforms, laid
+  // out as in a serialized transform list
+  transform_data: AllocU8::AllocatedMemory,
+  prefix_suffix_map: [u16; 256],
+  num_prefix_suffix: usize,
+  prefix_suffix_size: usize,
+  num_transforms: usize,
+  cutoff_transforms: [u8; 10],
+  num_cutoff_transforms: usize,
+}
+
+impl<AllocU8: Allocator<u8>, AllocU16: Allocator<u16>> Default for SharedDictionaryWords<AllocU8, AllocU16> {
+  fn default() -> Self {
+    SharedDictionaryWords::<AllocU8, AllocU16> {
+      size_bits_by_length: [0; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+      offsets_by_length: [0; BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+      data: AllocU8::AllocatedMemory::default(),
+      hash_table: AllocU16::AllocatedMemory::default(),
+      hash_table_high: AllocU8::AllocatedMemory::default(),
+      transform_data: AllocU8::AllocatedMemory::default(),
+      prefix_suffix_map: [0; 256],
+      num_prefix_suffix: 0,
+      prefix_suffix_size: 0,
+      num_transforms: 0,
+      cutoff_transforms: [0; 10],
+      num_cutoff_transforms: 0,
+    }
+  }
+}
+
+impl<AllocU8: Allocator<u8>, AllocU16: Allocator<u16>> SharedDictionaryWords<AllocU8, AllocU16> {
+  // The dictionary to search in place of the built-in one, if a word list or transforms were set.
+  pub fn dictionary<'a>(&'a self) -> Option<BrotliDictionary<'a>> {
+    if self.hash_table.slice().len() == 0 {
+      return None;
+    }
+    Some(BrotliDictionary {
+      size_bits_by_length: &self.size_bits_by_length[..],
+      offsets_by_length: &self.offsets_by_length[..],
+      data: self.data.slice(),
+      hash_table: self.hash_table.slice(),
+      hash_table_high: self.hash_table_high.slice(),
+      cutoff_transforms: &self.cutoff_transforms[..self.num_cutoff_transforms],
+      transforms: Some(self.transforms()),
+    })
+  }
+  fn transforms<'a>(&'a self) -> BrotliSharedTransforms<'a> {
+    let transform_data = self.transform_data.slice();
+    let triplets_end = self.prefix_suffix_size + self.num_transforms * 3;
+    BrotliSharedTransforms {
+      prefix_suffix: &transform_data[..self.prefix_suffix_size],
+      prefix_suffix_map: self.prefix_suffix_map,
+      num_prefix_suffix: self.num_prefix_suffix,
+      triplets: &transform_data[self.prefix_suffix_size..triplets_end],
+      params: &transform_data[triplets_end..],
+    }
+  }
+  pub fn free(&mut self, m8: &mut AllocU8, m16: &mut AllocU16) {
+    m8.free_cell(core::mem::replace(&mut self.data, AllocU8::AllocatedMemory::default()));
+    m16.free_cell(core::mem::replace(&mut self.hash_table, AllocU16::AllocatedMemory::default()));
+    m8.free_cell(core::mem::replace(&mut self.hash_table_high, AllocU8::AllocatedMemory::default()));
+    m8.free_cell(core::mem::replace(&mut self.transform_data, AllocU8::AllocatedMemory::default()));
+    self.num_transforms = 0;
+    self.num_cutoff_transforms = 0;
+  }
+}
+
+// Finds, for each number of bytes omitted from the end of a word, the first transform
+// that does only that. The hasher based dictionary search only tries these, as it does
+// for the built-in dictionary.
+fn ComputeCutoffTransforms(transforms: &BrotliSharedTransforms, cutoff_transforms: &mut [u8; 10]) -> usize {
+  for cut in 0..cutoff_transforms.len() {
+    let transform_type = if cut == 0 { BROTLI_TRANSFORM_IDENTITY } else { cut as u8 };
+    match (0..core::cmp::min(transforms.num_transforms(), 256))
+      .find(|transform_id| transforms.is_plain(*transform_id, transform_type)) {
+      Some(transform_id) => cutoff_transforms[cut] = transform_id as u8,
+      None => return cut,
+    }
+  }
+  cutoff_transforms.len()
+}
+
+// Keeps the two longest words per hash bucket, the earlier word on ties. hash_table_high
+// must be set up when a length has more than 2048 words.
+fn BuildWordHashTable(size_bits_by_length: &[u8],
+                      offsets_by_length: &[u32],
+                      data: &[u8],
+                      hash_table: &mut [u16],
+                      hash_table_high: &mut [u8]) {
+  for item in hash_table.iter_mut() {
+    *item = 0;
+  }
+  for item in hash_table_high.iter_mut() {
+    *item = 0;
+  }
+  for len in BROTLI_SHARED_DICTIONARY_MIN_WORD_LENGTH..size_bits_by_length.len() {
+    if size_bits_by_length[len] == 0 {
+      continue;
+    }
+    for index in 0..(1usize << size_bits_by_length[len]) {
+      let offset = offsets_by_length[len] as usize + len * index;
+      let key = (BrotliDictionaryHash14(&data[offset..offset + len]) << 1) as usize;
+      let item = len | (index << 5);
+      let slot = if hash_table[key] == 0 || (hash_table[key] & 0x1f) < len as u16 {
+        hash_table[key + 1] = hash_table[key];
+        if !hash_table_high.is_empty() {
+          hash_table_high[key + 1] = hash_table_high[key];
+        }
+        key
+      } else if hash_table[key + 1] == 0 || (hash_table[key + 1] & 0x1f) < len as u16 {
+        key + 1
+      } else {
+        continue;
+      };
+      hash_table[slot] = item as u16;
+      if !hash_table_high.is_empty() {
+        hash_table_high[slot] = (item >> 16) as u8;
+      }
+    }
+  }
+}
+
+// The prefix and suffix of a built-in transform: the bytes around the probe word in its
+// output, which out holds. Returns the prefix length, then where the suffix starts and ends.
+fn BuiltinTransformAffixes(transform_id: usize, out: &mut [u8; 64]) -> (usize, usize, usize) {
+  let len = TransformDictionaryWord(&mut out[..],
+                                    &BUILTIN_TRANSFORM_PROBE[..],
+                                    BUILTIN_TRANSFORM_PROBE.len() as i32,
+                                    transform_id as i32) as usize;
+  let word_start = out[..len].iter().position(|byte| *byte == BUILTIN_TRANSFORM_PROBE[0]).unwrap_or(len);
+  let word_end = out[..len].iter().rposition(|byte| *byte == BUILTIN_TRANSFORM_PROBE[0]).map_or(len, |pos| pos + 1);
+  (word_start, word_end, len)
+}
+
+// Copies a parsed transform list into transform_data, or lays out the built-in transforms
+// the same way (each with its own prefix and suffix string) when there is none.
+fn InitializeSharedTransforms<AllocU8: Allocator<u8>, AllocU16: Allocator<u16>>(
+  m8: &mut AllocU8,
+  ret: &mut SharedDictionaryWords<AllocU8, AllocU16>,
+  transforms: Option<&BrotliSharedTransforms>,
+) {
+  if let Some(transforms) = transforms {
+    let triplets_end = transforms.prefix_suffix.len() + transforms.triplets.len();
+    ret.transform_data = m8.alloc_cell(triplets_end + transforms.params.len());
+    let transform_data = ret.transform_data.slice_mut();
+    transform_data[..transforms.prefix_suffix.len()].clone_from_slice(transforms.prefix_suffix);
+    transform_data[transforms.prefix_suffix.len()..triplets_end].clone_from_slice(transforms.triplets);
+    transform_data[triplets_end..].clone_from_slice(transforms.params);
+    ret.prefix_suffix_map = transforms.prefix_suffix_map;
+    ret.num_prefix_suffix = transforms.num_prefix_suffix;
+    ret.prefix_suffix_size = transforms.prefix_suffix.len();
+    ret.num_transforms = transforms.num_transforms();
+    return;
+  }
+  let num_transforms = kNumTransforms as usize;
+  let mut out = [0u8; 64];
+  let mut prefix_suffix_size = 1usize;
+  for transform_id in 0..num_transforms {
+    let (prefix_len, suffix_start, len) = BuiltinTransformAffixes(transform_id, &mut out);
+    prefix_suffix_size += 2 + prefix_len + len - suffix_start;
+  }
+  ret.transform_data = m8.alloc_cell(prefix_suffix_size + num_transforms * 3);
+  let transform_data = ret.transform_data.slice_mut();
+  let empty_string_id = (num_transforms * 2) as u8;
+  let mut offset = 0usize;
+  for transform_id in 0..num_transforms {
+    let (prefix_len, suffix_start, len) = BuiltinTransformAffixes(transform_id, &mut out);
+    let strings = [(0, prefix_len), (suffix_start, len)];
+    for (index, &(start, end)) in strings.iter().enumerate() {
+      let string_id = transform_id * 2 + index;
+      ret.prefix_suffix_map[string_id] = offset as u16;
+      transform_data[offset] = (end - start) as u8;
+      transform_data[offset + 1..offset + 1 + end - start].clone_from_slice(&out[start..end]);
+      offset += 1 + end - start;
+      transform_data[prefix_suffix_size + transform_id * 3 + index * 2] =
+        if start == end { empty_string_id } else { string_id as u8 };
+    }
+    transform_data[prefix_suffix_size + transform_id * 3 + 1] = kTransforms[transform_id].transform;
+  }
+  ret.prefix_suffix_map[empty_string_id as usize] = offset as u16;
+  transform_data[offset] = 0;
+  ret.num_prefix_suffix = empty_string_id as usize + 1;
+  ret.prefix_suffix_size = prefix_suffix_size;
+  ret.num_transforms = num_transforms;
+}
+
+// Prepares the word list and transforms of a parsed shared dictionary for the encoder.
+// Returns an empty word list when the dictionary only replaces the LZ77 prefix.
+pub fn InitializeSharedDictionaryWords<AllocU8: Allocator<u8>, AllocU16: Allocator<u16>>(
+  m8: &mut AllocU8,
+  m16: &mut AllocU16,
+  dict: &BrotliSharedDictionary,
+) -> SharedDictionaryWords<AllocU8, AllocU16> {
+  let mut ret = SharedDictionaryWords::<AllocU8, AllocU16>::default();
+  if dict.words.is_none() && dict.transforms.is_none() {
+    return ret;
+  }
+  let data: &[u8] = match dict.words {
+    Some(ref words) => {
+      ret.size_bits_by_length = words.size_bits_by_length;
+      ret.offsets_by_length = words.offsets_by_length;
+      words.data
+    }
+    None => {
+      let num_lengths = kBrotliDictionarySizeBitsByLength.len();
+      ret.size_bits_by_length[..num_lengths].clone_from_slice(&kBrotliDictionarySizeBitsByLength[..]);
+      ret.offsets_by_length[..num_lengths].clone_from_slice(&kBrotliDictionaryOffsetsByLength[..]);
+      &kBrotliDictionary[..]
+    }
+  };
+  ret.data = m8.alloc_cell(data.len());
+  ret.data.slice_mut().clone_from_slice(data);
+  ret.hash_table = m16.alloc_cell(1 << 15);
+  if ret.size_bits_by_length.iter().any(|size_bits| (1usize << *size_bits) > BROTLI_MAX_HASHED_WORD_INDEX) {
+    ret.hash_table_high = m8.alloc_cell(1 << 15);
+  }
+  BuildWordHashTable(&ret.size_bits_by_length[..],
+                     &ret.offsets_by_length[..],
+                     data,
+                     ret.hash_table.slice_mut(),
+                     ret.hash_table_high.slice_mut());
+  InitializeSharedTransforms(m8, &mut ret, dict.transforms.as_ref());
+  let mut cutoff_transforms = [0u8; 10];
+  ret.num_cutoff_transforms = ComputeCutoffTransforms(&ret.transforms(), &mut cutoff_transforms);
+  ret.cutoff_transforms = cutoff_transforms;
+  ret
+}
+
+mod test {
+  #[test]
+  fn test_parse_prefix_only() {
+    let encoded = [0x91, 0x00, 0x03, b'a', b'b', b'c', 0, 0, 1, 0, 0, 0];
+    let dict = super::BrotliParseSharedDictionary(&encoded[..]).unwrap();
+    assert_eq!(dict.prefix, &b"abc"[..]);
+    assert!(dict.words.is_none());
+    assert!(dict.transforms.is_none());
+    assert!(!dict.context_based);
+    // bad magic, truncated, trailing bytes, dictionary index past the built-in one
+    assert!(super::BrotliParseSharedDictionary(&encoded[1..]).is_none());
+    assert!(super::BrotliParseSharedDictionary(&encoded[..encoded.len() - 1]).is_none());
+    let mut trailing = [0u8; 13];
+    trailing[..12].clone_from_slice(&encoded[..]);
+    assert!(super::BrotliParseSharedDictionary(&trailing[..]).is_none());
+    let mut bad_index = encoded;
+    bad_index[9] = 1;
+    assert!(super::BrotliParseSharedDictionary(&bad_index[..]).is_none());
+  }
+
+  #[test]
+  fn test_parse_words_and_transforms() {
+    let mut encoded = [0u8; 2 + 1 + 1 + 28 + 8 + 1 + 2 + 3 + 1 + 9 + 1 + 2 + 1];
+    let mut pos = 0;
+    {
+      let mut push = |bytes: &[u8]| {
+        encoded[pos..pos + bytes.len()].clone_from_slice(bytes);
+        pos += bytes.len();
+      };
+      push(&[0x91, 0x00, 0x00, 1]);
+      // two words of length 4
+      let mut size_bits = [0u8; 28];
+      size_bits[0] = 1;
+      push(&size_bits[..]);
+      push(b"sendrecv");
+      // strings " " and "", then identity, " " + identity and omit-last-1
+      push(&[1, 3, 0, 1, b' ', 0, 3, 1, 0, 1, 0, 0, 1, 1, 1, 1]);
+      push(&[1, 0, 0, 0]);
+    }
+    assert_eq!(pos, encoded.len());
+    let dict = super::BrotliParseSharedDictionary(&encoded[..]).unwrap();
+    let words = dict.words.unwrap();
+    assert_eq!(words.size_bits_by_length[4], 1);
+    assert_eq!(words.data, &b"sendrecv"[..]);
+    let transforms = dict.transforms.unwrap();
+    assert_eq!(transforms.num_transforms(), 3);
+    let mut cutoff = [0u8; 10];
+    assert_eq!(super::ComputeCutoffTransforms(&transforms, &mut cutoff), 2);
+    assert_eq!(&cutoff[..2], &[0, 2]);
+  }
+
+  #[cfg(not(feature="no-stdlib"))]
+  #[test]
+  fn test_builtin_transform_layout() {
+    use super::super::super::alloc::HeapAlloc;
+    use super::super::static_dict::kOmitLastNTransforms;
+    let mut m8 = HeapAlloc::<u8> { default_value: 0 };
+    let mut m16 = HeapAlloc::<u16> { default_value: 0 };
+    // two words of their own, with the built-in transforms laid out like a transform list
+    let mut size_bits_by_length = [0u8; super::BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS];
+    size_bits_by_length[4] = 1;
+    let dict = super::BrotliSharedDictionary {
+      prefix: &[],
+      words: Some(super::BrotliSharedWordList {
+        size_bits_by_length: size_bits_by_length,
+        offsets_by_length: [0; super::BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+        data: &b"sendrecv"[..],
+      }),
+      transforms: None,
+      context_based: false,
+    };
+    let mut words = super::InitializeSharedDictionaryWords(&mut m8, &mut m16, &dict);
+    {
+      let dictionary = words.dictionary().unwrap();
+      assert_eq!(dictionary.cutoff_transforms, &kOmitLastNTransforms[..]);
+      let transforms = dictionary.transforms.unwrap();
+      assert_eq!(transforms.num_transforms(), super::kNumTransforms as usize);
+      let mut expected = [0u8; 64];
+      let mut actual = [0u8; 64];
+      for word in [&b"time"[..], &b"\xc3\xa9t\xc3\xa9s"[..], &b"whatever"[..]].iter() {
+        for transform_id in 0..transforms.num_transforms() {
+          let expected_len = super::TransformDictionaryWord(&mut expected[..], word, word.len() as i32,
+                                                           transform_id as i32) as usize;
+          let actual_len = super::TransformSharedDictionaryWord(&transforms, &mut actual[..], word, transform_id);
+          assert_eq!(&actual[..actual_len], &expected[..expected_len]);
+        }
+      }
+    }
+    words.free(&mut m8, &mut m16);
+  }
+
+  #[cfg(not(feature="no-stdlib"))]
+  #[test]
+  fn test_word_hash_table_past_2048_words() {
+    use super::super::super::alloc::HeapAlloc;
+    use super::super::static_dict::BrotliDictionaryHash14;
+    let mut m8 = HeapAlloc::<u8> { default_value: 0 };
+    let mut m16 = HeapAlloc::<u16> { default_value: 0 };
+    let mut data = [0u8; 6 << 13];
+    let mut seed = 1u32;
+    for byte in data.iter_mut() {
+      seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+      *byte = b'a' + ((seed >> 16) % 26) as u8;
+    }
+    let mut size_bits_by_length = [0u8; super::BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS];
+    size_bits_by_length[6] = 13;
+    let dict = super::BrotliSharedDictionary {
+      prefix: &[],
+      words: Some(super::BrotliSharedWordList {
+        size_bits_by_length: size_bits_by_length,
+        offsets_by_length: [0; super::BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS],
+        data: &data[..],
+      }),
+      transforms: None,
+      context_based: false,
+    };
+    let mut words = super::InitializeSharedDictionaryWords(&mut m8, &mut m16, &dict);
+    {
+      let dictionary = words.dictionary().unwrap();
+      let mut num_found = 0;
+      for index in 0..(1usize << 13) {
+        let word = &data[index * 6..index * 6 + 6];
+        let key = (BrotliDictionaryHash14(word) << 1) as usize;
+        if dictionary.hash_item(key) == (6 | (index << 5)) || dictionary.hash_item(key + 1) == (6 | (index << 5)) {
+          num_found += 1;
+        }
+      }
+      // all but the words that lost their bucket to two others
+      assert!(num_found > 7000, "{}", num_found);
+    }
+    words.free(&mut m8, &mut m16);
+  }
+}
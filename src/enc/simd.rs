@@ -0,0 +1,609 @@
+// SSE2/AVX2 versions of the hottest encoder loops, enabled by the "simd" feature.
+// The instruction set is picked at runtime, which needs std, so no-stdlib builds and
+// other architectures always take the scalar path.
+// Every kernel performs the same per-lane operations in the same order as the scalar code
+// it stands in for, so the compressed output is bit-identical with or without the feature.
+// Each entry point reports whether it did the work; callers fall back to scalar otherwise.
+use super::vectorization::{Mem256f, Mem256i};
+use super::util::floatX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+  Scalar,
+  Sse2,
+  Avx2,
+}
+
+#[cfg(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64")))]
+static SIMD_LEVEL_LIMIT: core::sync::atomic::AtomicUsize =
+  core::sync::atomic::AtomicUsize::new(SimdLevel::Avx2 as usize);
+
+// Keeps the encoder from picking anything above level, to benchmark or compare the kernels.
+// The output does not depend on it.
+#[cfg(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64")))]
+pub fn BrotliSetSimdLevelLimit(level: SimdLevel) {
+  SIMD_LEVEL_LIMIT.store(level as usize, core::sync::atomic::Ordering::Relaxed);
+}
+
+#[cfg(not(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64"))))]
+pub fn BrotliSetSimdLevelLimit(_level: SimdLevel) {
+}
+
+// The best level this machine supports, regardless of the limit.
+#[cfg(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64")))]
+#[inline(always)]
+pub fn BrotliDetectSimdLevel() -> SimdLevel {
+  if is_x86_feature_detected!("avx2") {
+    SimdLevel::Avx2
+  } else if is_x86_feature_detected!("sse2") {
+    SimdLevel::Sse2
+  } else {
+    SimdLevel::Scalar
+  }
+}
+
+#[cfg(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64")))]
+#[inline(always)]
+pub fn BrotliSimdLevel() -> SimdLevel {
+  let detected = BrotliDetectSimdLevel();
+  match SIMD_LEVEL_LIMIT.load(core::sync::atomic::Ordering::Relaxed) {
+    limit if limit >= detected as usize => detected,
+    limit if limit >= SimdLevel::Sse2 as usize => SimdLevel::Sse2,
+    _ => SimdLevel::Scalar,
+  }
+}
+
+#[cfg(not(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64"))))]
+#[inline(always)]
+pub fn BrotliDetectSimdLevel() -> SimdLevel {
+  SimdLevel::Scalar
+}
+
+#[cfg(not(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64"))))]
+#[inline(always)]
+pub fn BrotliSimdLevel() -> SimdLevel {
+  SimdLevel::Scalar
+}
+
+// Number of leading bytes on which s1 and s2 agree, looking at no more than limit bytes.
+#[inline(always)]
+pub fn FindMatchLengthWithLimit(s1: &[u8], s2: &[u8], limit: usize) -> Option<usize> {
+  match BrotliSimdLevel() {
+    SimdLevel::Scalar => None,
+    level => Some(x86::FindMatchLengthWithLimit(level, s1, s2, limit)),
+  }
+}
+
+// cost[i].0[k] += insert_cost[8 * i + k] for every lane of every vector in cost.
+#[inline(always)]
+pub fn AddInsertCosts(cost: &mut [Mem256f], insert_cost: &[floatX]) -> bool {
+  match BrotliSimdLevel() {
+    SimdLevel::Scalar => false,
+    level => x86::AddInsertCosts(level, cost, insert_cost),
+  }
+}
+
+// Lowers every cost by min_cost and caps it at block_switch_cost, setting the
+// switch_signal bit of each lane that reached the cap.
+#[inline(always)]
+pub fn UpdateCostAndSignal(cost: &mut [Mem256f],
+                           min_cost: floatX,
+                           block_switch_cost: floatX,
+                           switch_signal: &mut [u8]) -> bool {
+  match BrotliSimdLevel() {
+    SimdLevel::Scalar => false,
+    level => x86::UpdateCostAndSignal(level, cost, min_cost, block_switch_cost, switch_signal),
+  }
+}
+
+// Adds the number of occurrences of each symbol to histogram.
+#[inline(always)]
+pub fn HistogramAddVector<IntegerType: Sized + Clone>(histogram: &mut [u32],
+                                                      p: &[IntegerType]) -> bool
+  where u64: core::convert::From<IntegerType>
+{
+  match BrotliSimdLevel() {
+    SimdLevel::Scalar => false,
+    level => x86::HistogramAddVector(level, histogram, p),
+  }
+}
+
+// For each lane: log2p = log2total - log2_count and bits = count * log2p.
+#[inline(always)]
+pub fn EntropyTerms(counts: &Mem256i,
+                    log2_counts: &[floatX; 8],
+                    log2total: floatX,
+                    log2p: &mut [floatX; 8],
+                    bits: &mut [floatX; 8]) -> bool {
+  match BrotliSimdLevel() {
+    SimdLevel::Scalar => false,
+    level => x86::EntropyTerms(level, counts, log2_counts, log2total, log2p, bits),
+  }
+}
+
+#[cfg(not(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64"))))]
+mod x86 {
+  // unreachable: BrotliSimdLevel() is always Scalar here
+  use super::{SimdLevel, Mem256f, Mem256i, floatX};
+  pub fn FindMatchLengthWithLimit(_level: SimdLevel, _s1: &[u8], _s2: &[u8], _limit: usize) -> usize {
+    0
+  }
+  pub fn AddInsertCosts(_level: SimdLevel, _cost: &mut [Mem256f], _insert_cost: &[floatX]) -> bool {
+    false
+  }
+  pub fn UpdateCostAndSignal(_level: SimdLevel, _cost: &mut [Mem256f], _min_cost: floatX,
+                             _block_switch_cost: floatX, _switch_signal: &mut [u8]) -> bool {
+    false
+  }
+  pub fn HistogramAddVector<IntegerType: Sized + Clone>(_level: SimdLevel, _histogram: &mut [u32],
+                                                        _p: &[IntegerType]) -> bool {
+    false
+  }
+  pub fn EntropyTerms(_level: SimdLevel, _counts: &Mem256i, _log2_counts: &[floatX; 8],
+                      _log2total: floatX, _log2p: &mut [floatX; 8], _bits: &mut [floatX; 8]) -> bool {
+    false
+  }
+}
+
+#[cfg(all(feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64")))]
+mod x86 {
+  #[cfg(target_arch="x86")]
+  use core::arch::x86::*;
+  #[cfg(target_arch="x86_64")]
+  use core::arch::x86_64::*;
+  use core;
+  use super::{SimdLevel, Mem256f, Mem256i, floatX};
+
+  // largest alphabet handled by HistogramAddVector (the command alphabet)
+  const MAX_HISTOGRAM_SYMBOLS: usize = 704;
+  // number of interleaved partial histograms, to break store-to-load dependencies
+  const NUM_PARTIAL_HISTOGRAMS: usize = 4;
+
+  pub fn FindMatchLengthWithLimit(level: SimdLevel, s1: &[u8], s2: &[u8], limit: usize) -> usize {
+    // the vector loop only ever loads from inside both slices; any remainder goes
+    // through the indexed tail below, which panics on short input just like the scalar code
+    let in_bounds = core::cmp::min(limit, core::cmp::min(s1.len(), s2.len()));
+    // SAFETY: level came from BrotliSimdLevel, so the CPU has the instructions, and the kernels
+    // load no further than in_bounds, which fits in both slices.
+    let mut matched = unsafe {
+      if level == SimdLevel::Avx2 {
+        match_avx2(s1, s2, in_bounds)
+      } else {
+        match_sse2(s1, s2, in_bounds)
+      }
+    };
+    if matched < in_bounds && s1[matched] != s2[matched] {
+      return matched;
+    }
+    while matched < limit {
+      if s1[matched] != s2[matched] {
+        return matched;
+      }
+      matched += 1;
+    }
+    matched
+  }
+
+  // Returns the index of the first mismatch, or the number of whole 16-byte blocks compared.
+  #[target_feature(enable = "sse2")]
+  unsafe fn match_sse2(s1: &[u8], s2: &[u8], limit: usize) -> usize {
+    let mut matched = 0usize;
+    // SAFETY: the caller passes a limit no longer than either slice, so each 16-byte load
+    // ends at or before limit.
+    while matched + 16 <= limit {
+      let a = _mm_loadu_si128(s1.as_ptr().offset(matched as isize) as *const __m128i);
+      let b = _mm_loadu_si128(s2.as_ptr().offset(matched as isize) as *const __m128i);
+      let mask = _mm_movemask_epi8(_mm_cmpeq_epi8(a, b)) as u32;
+      if mask != 0xffff {
+        return matched + (!mask).trailing_zeros() as usize;
+      }
+      matched += 16;
+    }
+    matched
+  }
+
+  #[target_feature(enable = "avx2")]
+  unsafe fn match_avx2(s1: &[u8], s2: &[u8], limit: usize) -> usize {
+    let mut matched = 0usize;
+    // SAFETY: as in match_sse2, each 32-byte load ends at or before limit.
+    while matched + 32 <= limit {
+      let a = _mm256_loadu_si256(s1.as_ptr().offset(matched as isize) as *const __m256i);
+      let b = _mm256_loadu_si256(s2.as_ptr().offset(matched as isize) as *const __m256i);
+      let mask = _mm256_movemask_epi8(_mm256_cmpeq_epi8(a, b)) as u32;
+      if mask != 0xffffffff {
+        return matched + (!mask).trailing_zeros() as usize;
+      }
+      matched += 32;
+    }
+    matched + match_sse2(&s1[matched..], &s2[matched..], limit - matched)
+  }
+
+  pub fn HistogramAddVector<IntegerType: Sized + Clone>(level: SimdLevel,
+                                                        histogram: &mut [u32],
+                                                        p: &[IntegerType]) -> bool
+    where u64: core::convert::From<IntegerType>
+  {
+    let n = histogram.len();
+    if n > MAX_HISTOGRAM_SYMBOLS || p.len() < NUM_PARTIAL_HISTOGRAMS * n {
+      // zeroing the partial histograms would cost more than it saves
+      return false;
+    }
+    let mut partial = [[0u32; MAX_HISTOGRAM_SYMBOLS]; NUM_PARTIAL_HISTOGRAMS];
+    for chunk in p.chunks(NUM_PARTIAL_HISTOGRAMS) {
+      for (index, item) in chunk.iter().enumerate() {
+        let symbol = u64::from(item.clone()) as usize;
+        partial[index][symbol] = partial[index][symbol].wrapping_add(1);
+      }
+    }
+    // SAFETY: level came from BrotliSimdLevel, so the CPU has the instructions; n is at most
+    // MAX_HISTOGRAM_SYMBOLS, so every partial histogram is at least as long as histogram.
+    unsafe {
+      if level == SimdLevel::Avx2 {
+        sum_partial_avx2(histogram, &partial);
+      } else {
+        sum_partial_sse2(histogram, &partial);
+      }
+    }
+    true
+  }
+
+  #[target_feature(enable = "sse2")]
+  unsafe fn sum_partial_sse2(histogram: &mut [u32],
+                             partial: &[[u32; MAX_HISTOGRAM_SYMBOLS]; NUM_PARTIAL_HISTOGRAMS]) {
+    let n = histogram.len();
+    let mut i = 0usize;
+    // SAFETY: lanes i to i + 3 are below n, the length of histogram and at most that of each
+    // partial histogram.
+    while i + 4 <= n {
+      let mut acc = _mm_loadu_si128(histogram.as_ptr().offset(i as isize) as *const __m128i);
+      for sub in partial.iter() {
+        acc = _mm_add_epi32(acc, _mm_loadu_si128(sub.as_ptr().offset(i as isize) as *const __m128i));
+      }
+      _mm_storeu_si128(histogram.as_mut_ptr().offset(i as isize) as *mut __m128i, acc);
+      i += 4;
+    }
+    while i < n {
+      for sub in partial.iter() {
+        histogram[i] = histogram[i].wrapping_add(sub[i]);
+      }
+      i += 1;
+    }
+  }
+
+  #[target_feature(enable = "avx2")]
+  unsafe fn sum_partial_avx2(histogram: &mut [u32],
+                             partial: &[[u32; MAX_HISTOGRAM_SYMBOLS]; NUM_PARTIAL_HISTOGRAMS]) {
+    let n = histogram.len();
+    let mut i = 0usize;
+    // SAFETY: lanes i to i + 7 are below n, as in sum_partial_sse2.
+    while i + 8 <= n {
+      let mut acc = _mm256_loadu_si256(histogram.as_ptr().offset(i as isize) as *const __m256i);
+      for sub in partial.iter() {
+        acc = _mm256_add_epi32(acc,
+                               _mm256_loadu_si256(sub.as_ptr().offset(i as isize) as *const __m256i));
+      }
+      _mm256_storeu_si256(histogram.as_mut_ptr().offset(i as isize) as *mut __m256i, acc);
+      i += 8;
+    }
+    while i < n {
+      for sub in partial.iter() {
+        histogram[i] = histogram[i].wrapping_add(sub[i]);
+      }
+      i += 1;
+    }
+  }
+
+  #[cfg(not(feature="float64"))]
+  pub fn AddInsertCosts(level: SimdLevel, cost: &mut [Mem256f], insert_cost: &[floatX]) -> bool {
+    if insert_cost.len() < cost.len() * 8 {
+      return false;
+    }
+    // SAFETY: level came from BrotliSimdLevel, so the CPU has the instructions, and insert_cost
+    // holds the 8 lanes read for every vector of cost.
+    unsafe {
+      if level == SimdLevel::Avx2 {
+        add_insert_costs_avx(cost, insert_cost);
+      } else {
+        add_insert_costs_sse(cost, insert_cost);
+      }
+    }
+    true
+  }
+
+  #[cfg(not(feature="float64"))]
+  #[target_feature(enable = "sse2")]
+  unsafe fn add_insert_costs_sse(cost: &mut [Mem256f], insert_cost: &[floatX]) {
+    // SAFETY: dst is the 8 lanes of one vector and src the 8 matching lanes of insert_cost,
+    // which AddInsertCosts checked is long enough.
+    for (index, cost_it) in cost.iter_mut().enumerate() {
+      let src = insert_cost.as_ptr().offset((index << 3) as isize);
+      let dst = cost_it.0.as_mut_ptr();
+      _mm_storeu_ps(dst, _mm_add_ps(_mm_loadu_ps(dst), _mm_loadu_ps(src)));
+      _mm_storeu_ps(dst.offset(4), _mm_add_ps(_mm_loadu_ps(dst.offset(4)), _mm_loadu_ps(src.offset(4))));
+    }
+  }
+
+  #[cfg(not(feature="float64"))]
+  #[target_feature(enable = "avx2")]
+  unsafe fn add_insert_costs_avx(cost: &mut [Mem256f], insert_cost: &[floatX]) {
+    // SAFETY: as in add_insert_costs_sse.
+    for (index, cost_it) in cost.iter_mut().enumerate() {
+      let src = insert_cost.as_ptr().offset((index << 3) as isize);
+      let dst = cost_it.0.as_mut_ptr();
+      _mm256_storeu_ps(dst, _mm256_add_ps(_mm256_loadu_ps(dst), _mm256_loadu_ps(src)));
+    }
+  }
+
+  #[cfg(not(feature="float64"))]
+  pub fn UpdateCostAndSignal(level: SimdLevel,
+                             cost: &mut [Mem256f],
+                             min_cost: floatX,
+                             block_switch_cost: floatX,
+                             switch_signal: &mut [u8]) -> bool {
+    // SAFETY: level came from BrotliSimdLevel, so the CPU has the instructions; the kernels
+    // stop at the shorter of cost and switch_signal.
+    unsafe {
+      if level == SimdLevel::Avx2 {
+        update_cost_and_signal_avx(cost, min_cost, block_switch_cost, switch_signal);
+      } else {
+        update_cost_and_signal_sse(cost, min_cost, block_switch_cost, switch_signal);
+      }
+    }
+    true
+  }
+
+  // _mm_min_ps(a, b) is (a < b ? a : b) per lane, exactly like min256!
+  #[cfg(not(feature="float64"))]
+  #[target_feature(enable = "sse2")]
+  unsafe fn update_cost_and_signal_sse(cost: &mut [Mem256f],
+                                       min_cost: floatX,
+                                       block_switch_cost: floatX,
+                                       switch_signal: &mut [u8]) {
+    let ymm_min_cost = _mm_set1_ps(min_cost);
+    let ymm_block_switch_cost = _mm_set1_ps(block_switch_cost);
+    // SAFETY: ptr points at the 8 lanes of one vector of cost.
+    for (cost_it, signal) in cost.iter_mut().zip(switch_signal.iter_mut()) {
+      let ptr = cost_it.0.as_mut_ptr();
+      let lo = _mm_sub_ps(_mm_loadu_ps(ptr), ymm_min_cost);
+      let hi = _mm_sub_ps(_mm_loadu_ps(ptr.offset(4)), ymm_min_cost);
+      let bits = _mm_movemask_ps(_mm_cmpge_ps(lo, ymm_block_switch_cost)) |
+                 (_mm_movemask_ps(_mm_cmpge_ps(hi, ymm_block_switch_cost)) << 4);
+      *signal |= bits as u8;
+      _mm_storeu_ps(ptr, _mm_min_ps(lo, ymm_block_switch_cost));
+      _mm_storeu_ps(ptr.offset(4), _mm_min_ps(hi, ymm_block_switch_cost));
+    }
+  }
+
+  #[cfg(not(feature="float64"))]
+  #[target_feature(enable = "avx2")]
+  unsafe fn update_cost_and_signal_avx(cost: &mut [Mem256f],
+                                       min_cost: floatX,
+                                       block_switch_cost: floatX,
+                                       switch_signal: &mut [u8]) {
+    let ymm_min_cost = _mm256_set1_ps(min_cost);
+    let ymm_block_switch_cost = _mm256_set1_ps(block_switch_cost);
+    // SAFETY: ptr points at the 8 lanes of one vector of cost.
+    for (cost_it, signal) in cost.iter_mut().zip(switch_signal.iter_mut()) {
+      let ptr = cost_it.0.as_mut_ptr();
+      let costk_minus_min_cost = _mm256_sub_ps(_mm256_loadu_ps(ptr), ymm_min_cost);
+      let cmpge = _mm256_cmp_ps(costk_minus_min_cost, ymm_block_switch_cost, _CMP_GE_OQ);
+      *signal |= _mm256_movemask_ps(cmpge) as u8;
+      _mm256_storeu_ps(ptr, _mm256_min_ps(costk_minus_min_cost, ymm_block_switch_cost));
+    }
+  }
+
+  #[cfg(not(feature="float64"))]
+  pub fn EntropyTerms(level: SimdLevel,
+                      counts: &Mem256i,
+                      log2_counts: &[floatX; 8],
+                      log2total: floatX,
+                      log2p: &mut [floatX; 8],
+                      bits: &mut [floatX; 8]) -> bool {
+    // SAFETY: level came from BrotliSimdLevel, so the CPU has the instructions; every argument
+    // has exactly 8 lanes.
+    unsafe {
+      if level == SimdLevel::Avx2 {
+        entropy_terms_avx(counts, log2_counts, log2total, log2p, bits);
+      } else {
+        entropy_terms_sse(counts, log2_counts, log2total, log2p, bits);
+      }
+    }
+    true
+  }
+
+  #[cfg(not(feature="float64"))]
+  #[target_feature(enable = "sse2")]
+  unsafe fn entropy_terms_sse(counts: &Mem256i,
+                              log2_counts: &[floatX; 8],
+                              log2total: floatX,
+                              log2p: &mut [floatX; 8],
+                              bits: &mut [floatX; 8]) {
+    let total = _mm_set1_ps(log2total);
+    // SAFETY: each half reads and writes lanes offset to offset + 3 of 8-lane arrays.
+    for half in 0..2 {
+      let offset = (half * 4) as isize;
+      let count = _mm_cvtepi32_ps(_mm_loadu_si128(counts.0.as_ptr().offset(offset) as *const __m128i));
+      let lp = _mm_sub_ps(total, _mm_loadu_ps(log2_counts.as_ptr().offset(offset)));
+      _mm_storeu_ps(log2p.as_mut_ptr().offset(offset), lp);
+      _mm_storeu_ps(bits.as_mut_ptr().offset(offset), _mm_mul_ps(count, lp));
+    }
+  }
+
+  #[cfg(not(feature="float64"))]
+  #[target_feature(enable = "avx2")]
+  unsafe fn entropy_terms_avx(counts: &Mem256i,
+                              log2_counts: &[floatX; 8],
+                              log2total: floatX,
+                              log2p: &mut [floatX; 8],
+                              bits: &mut [floatX; 8]) {
+    // SAFETY: every load and store covers exactly the 8 lanes of its array.
+    let count = _mm256_cvtepi32_ps(_mm256_loadu_si256(counts.0.as_ptr() as *const __m256i));
+    let lp = _mm256_sub_ps(_mm256_set1_ps(log2total), _mm256_loadu_ps(log2_counts.as_ptr()));
+    _mm256_storeu_ps(log2p.as_mut_ptr(), lp);
+    _mm256_storeu_ps(bits.as_mut_ptr(), _mm256_mul_ps(count, lp));
+  }
+
+  // the floating point kernels are only written for single precision
+  #[cfg(feature="float64")]
+  pub fn AddInsertCosts(_level: SimdLevel, _cost: &mut [Mem256f], _insert_cost: &[floatX]) -> bool {
+    false
+  }
+  #[cfg(feature="float64")]
+  pub fn UpdateCostAndSignal(_level: SimdLevel, _cost: &mut [Mem256f], _min_cost: floatX,
+                             _block_switch_cost: floatX, _switch_signal: &mut [u8]) -> bool {
+    false
+  }
+  #[cfg(feature="float64")]
+  pub fn EntropyTerms(_level: SimdLevel, _counts: &Mem256i, _log2_counts: &[floatX; 8],
+                      _log2total: floatX, _log2p: &mut [floatX; 8], _bits: &mut [floatX; 8]) -> bool {
+    false
+  }
+}
+
+#[cfg(all(test, feature="simd", not(feature="no-stdlib"), any(target_arch="x86", target_arch="x86_64")))]
+mod test {
+  use super::{x86, BrotliDetectSimdLevel, SimdLevel};
+  use super::super::vectorization::{Mem256f, Mem256i};
+  use super::super::util::{floatX, FastLog2u16};
+
+  // every level this machine can run, so both the SSE2 and the AVX2 kernels get checked
+  fn levels() -> [Option<SimdLevel>; 2] {
+    let detected = BrotliDetectSimdLevel();
+    [if detected >= SimdLevel::Sse2 { Some(SimdLevel::Sse2) } else { None },
+     if detected >= SimdLevel::Avx2 { Some(SimdLevel::Avx2) } else { None }]
+  }
+
+  fn pseudo_random(state: &mut u32) -> u32 {
+    *state = state.wrapping_mul(1103515245).wrapping_add(12345);
+    *state >> 8
+  }
+
+  fn byte_loop_match_length(s1: &[u8], s2: &[u8], limit: usize) -> usize {
+    let mut matched = 0usize;
+    while matched < limit && s1[matched] == s2[matched] {
+      matched += 1;
+    }
+    matched
+  }
+
+  #[test]
+  fn test_match_length_matches_scalar() {
+    let mut a = [0u8; 4096];
+    let mut seed = 7u32;
+    for item in a.iter_mut() {
+      *item = pseudo_random(&mut seed) as u8;
+    }
+    for mismatch in [0usize, 1, 7, 15, 16, 17, 31, 32, 33, 63, 100, 1000, 4095, 4096].iter() {
+      let mut b = a;
+      if *mismatch < b.len() {
+        b[*mismatch] ^= 0x40;
+      }
+      for limit in [0usize, 3, 16, 31, 32, 48, 200, 4096].iter() {
+        let expected = byte_loop_match_length(&a[..], &b[..], *limit);
+        for level in levels().iter().filter_map(|x| *x) {
+          assert_eq!(x86::FindMatchLengthWithLimit(level, &a[..], &b[..], *limit), expected);
+        }
+      }
+    }
+    // unaligned starts, a few mismatches each and limits past the shorter slice's vector blocks
+    for _ in 0..1000 {
+      let start = pseudo_random(&mut seed) as usize % 64;
+      let mut b = a;
+      for _ in 0..(pseudo_random(&mut seed) % 4) {
+        let index = pseudo_random(&mut seed) as usize % b.len();
+        b[index] = b[index].wrapping_add(1);
+      }
+      let limit = pseudo_random(&mut seed) as usize % (a.len() - start + 1);
+      let expected = byte_loop_match_length(&a[start..], &b[start..], limit);
+      for level in levels().iter().filter_map(|x| *x) {
+        assert_eq!(x86::FindMatchLengthWithLimit(level, &a[start..], &b[start..], limit), expected);
+      }
+    }
+  }
+
+  #[test]
+  fn test_cost_update_matches_scalar() {
+    for level in levels().iter().filter_map(|x| *x) {
+      let mut seed = 99u32;
+      let mut cost = [Mem256f::default(), Mem256f::default(), Mem256f::default()];
+      let mut insert_cost = [0.0 as floatX; 24];
+      for (index, item) in insert_cost.iter_mut().enumerate() {
+        *item = (pseudo_random(&mut seed) % 1000) as floatX / 7.0 as floatX;
+        cost[index >> 3].0[index & 7] = (pseudo_random(&mut seed) % 1000) as floatX / 3.0 as floatX;
+      }
+      let mut expected = cost.clone();
+      for (index, item) in insert_cost.iter().enumerate() {
+        expected[index >> 3].0[index & 7] += *item;
+      }
+      if x86::AddInsertCosts(level, &mut cost[..], &insert_cost[..]) {
+        for (a, b) in cost.iter().zip(expected.iter()) {
+          for k in 0..8 {
+            assert_eq!(a.0[k].to_bits(), b.0[k].to_bits());
+          }
+        }
+      }
+      let min_cost = 17.25 as floatX;
+      let block_switch_cost = 150.5 as floatX;
+      let mut expected_signal = [0u8; 3];
+      let mut expected = cost.clone();
+      for k in 0..24 {
+        let lane = &mut expected[k >> 3].0[k & 7];
+        *lane -= min_cost;
+        if *lane >= block_switch_cost {
+          expected_signal[k >> 3] |= 1 << (k & 7);
+        }
+        if !(*lane < block_switch_cost) {
+          *lane = block_switch_cost;
+        }
+      }
+      let mut signal = [0u8; 3];
+      if x86::UpdateCostAndSignal(level, &mut cost[..], min_cost, block_switch_cost, &mut signal[..]) {
+        assert_eq!(signal, expected_signal);
+        for (a, b) in cost.iter().zip(expected.iter()) {
+          for k in 0..8 {
+            assert_eq!(a.0[k].to_bits(), b.0[k].to_bits());
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_histogram_matches_scalar() {
+    let mut seed = 3u32;
+    let mut data = [0u16; 5000];
+    for item in data.iter_mut() {
+      *item = (pseudo_random(&mut seed) % 704) as u16;
+    }
+    let mut expected = [1u32; 704];
+    for item in data.iter() {
+      expected[*item as usize] += 1;
+    }
+    for level in levels().iter().filter_map(|x| *x) {
+      let mut histogram = [1u32; 704];
+      assert!(x86::HistogramAddVector(level, &mut histogram[..], &data[..]));
+      assert_eq!(&histogram[..], &expected[..]);
+    }
+  }
+
+  #[test]
+  fn test_entropy_terms_match_scalar() {
+    let counts = Mem256i([1, 2, 3, 100, 4095, 65535, 7, 12345]);
+    let log2total = 17.5 as floatX;
+    let mut log2_counts = [0.0 as floatX; 8];
+    for k in 0..8 {
+      log2_counts[k] = FastLog2u16(counts.0[k] as u16);
+    }
+    for level in levels().iter().filter_map(|x| *x) {
+      let mut log2p = [0.0 as floatX; 8];
+      let mut bits = [0.0 as floatX; 8];
+      if x86::EntropyTerms(level, &counts, &log2_counts, log2total, &mut log2p, &mut bits) {
+        for k in 0..8 {
+          let expected_log2p = log2total - FastLog2u16(counts.0[k] as u16);
+          assert_eq!(log2p[k].to_bits(), expected_log2p.to_bits());
+          assert_eq!(bits[k].to_bits(), (counts.0[k] as floatX * expected_log2p).to_bits());
+        }
+      }
+    }
+  }
+}
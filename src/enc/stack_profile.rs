@@ -0,0 +1,380 @@
+// A compressor that runs entirely out of fixed size pools on the stack, for targets without a
+// heap. Every allocation the encoder makes at qualities 0 to 4 with a window of at most
+// BROTLI_STACK_PROFILE_MAX_LGWIN bits and an input block of at most
+// BROTLI_STACK_PROFILE_MAX_LGBLOCK bits is served from one of the pools below. The pool sizes are
+// derived from those bounds and the buffer size, one allocation site of the encoder at a time, so
+// they hold the most the encoder can have live at once for any input, and the memory the
+// compressor needs is known at compile time.
+//
+// Qualities 0 and 1 compress each input buffer on its own and only need the small pools of
+// fast_pools: BROTLI_STACK_PROFILE_FAST_MEMORY_SIZE bytes. Qualities 2 to 4 keep the window in a
+// ring buffer, hash it into tables of a fixed 256KB or 512KB and split metablocks of up to 128KB,
+// and need the pools of ring_buffer_pools: BROTLI_STACK_PROFILE_MEMORY_SIZE bytes. Each set of
+// pools only takes up the stack while a compression at its qualities runs.
+use core;
+use core::ops;
+use super::backward_references::BrotliEncoderParams;
+use super::cluster::HistogramPair;
+use super::command::Command;
+use super::encode::BrotliEncoderInitParams;
+use super::entropy_encode::HuffmanTree;
+use super::hash_to_binary_tree::ZopfliNode;
+use super::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance};
+use super::interface;
+use super::pdf::PDF;
+use super::util::floatX;
+use super::vectorization::Mem256f;
+use super::{BrotliCompressCustomIo, StaticCommand};
+use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator, bzero};
+use brotli_decompressor::{CustomRead, CustomWrite};
+
+declare_stack_allocator_struct!(StackProfilePool, 128, stack);
+
+pub const BROTLI_STACK_PROFILE_MAX_QUALITY: i32 = 4;
+pub const BROTLI_STACK_PROFILE_MAX_LGWIN: i32 = 16;
+// the largest input block of quality 4, which reads 1 << 16 bytes at a time unless asked for more
+pub const BROTLI_STACK_PROFILE_MAX_LGBLOCK: i32 = 16;
+// the size of the input and of the output buffer, each
+pub const BROTLI_STACK_PROFILE_BUFFER_SIZE: usize = 4096;
+
+// the ring buffer has one bit more than the larger of lgwin and lgblock, and lgblock is 14 at
+// qualities 2 and 3 and at least 16 at quality 4, so it never exceeds MAX_LGWIN
+const MAX_RING_BUFFER_BITS: usize = 1 + BROTLI_STACK_PROFILE_MAX_LGBLOCK as usize;
+// a metablock is at most as long as the ring buffer
+const MAX_METABLOCK_SIZE: usize = 1 << MAX_RING_BUFFER_BITS;
+
+// Size classes are the powers of two up to the largest metablock, plus one for the large cells.
+const SIZE_CLASSES: usize = MAX_RING_BUFFER_BITS + 2;
+const LARGE_SIZE_CLASS: usize = SIZE_CLASSES - 1;
+// the most cells of one class the encoder has live at once: at qualities 2 to 4 the first ring
+// buffer cell, the output storage and the literal, command and distance block types or symbol
+// depths of a metablock can share a u8 class
+const CELLS_PER_SIZE_CLASS: usize = 5;
+
+// Rounds every allocation of up to largest_small_cell elements up to a power of two and every
+// larger one up to large_cell elements, and keeps freed cells for the next request of the same
+// class. The pools never merge freed cells, so handing them back would split the pool into ever
+// smaller pieces as the encoder frees and reallocates its per metablock buffers; instead each
+// class only takes as many cells from the pool as the encoder ever has live in it at once. A
+// request the pool has no room for gets an empty cell instead of the StackAllocator's panic, and
+// the encoder fails the stream when its ring buffer, hasher, command buffer, output storage or
+// hash table comes back short. The pool sizes rule that out for the parameters
+// BrotliCompressStackProfile accepts.
+pub struct StackProfileAllocator<'a, T: 'a> {
+  pool: StackAllocator<'a, T, StackProfilePool<'a, T>>,
+  // elements at the end of the pool that no cell has been cut from yet
+  unused: usize,
+  largest_small_cell: usize,
+  large_cell: usize,
+  free_cells: [[AllocatedStackMemory<'a, T>; CELLS_PER_SIZE_CLASS]; SIZE_CLASSES],
+}
+
+impl<'a, T: 'a + Default> StackProfileAllocator<'a, T> {
+  fn new(pool: &'a mut [T], largest_small_cell: usize, large_cell: usize) -> Self {
+    StackProfileAllocator {
+      unused: pool.len(),
+      pool: StackProfilePool::<T>::new_allocator(pool, bzero),
+      largest_small_cell,
+      large_cell,
+      free_cells: Default::default(),
+    }
+  }
+}
+
+impl<'a, T: 'a + Default> Allocator<T> for StackProfileAllocator<'a, T> {
+  type AllocatedMemory = AllocatedStackMemory<'a, T>;
+  fn alloc_cell(&mut self, len: usize) -> AllocatedStackMemory<'a, T> {
+    let (size_class, cell_len) = if len == 0 || len > self.large_cell {
+      return AllocatedStackMemory::<'a, T>::default();
+    } else if len <= self.largest_small_cell {
+      let cell_len = len.next_power_of_two();
+      (cell_len.trailing_zeros() as usize, cell_len)
+    } else {
+      (LARGE_SIZE_CLASS, self.large_cell)
+    };
+    for cell in self.free_cells[size_class].iter_mut() {
+      if !cell.slice().is_empty() {
+        let mut ret = core::mem::take(cell);
+        bzero(ret.slice_mut());
+        return ret;
+      }
+    }
+    if cell_len > self.unused {
+      return AllocatedStackMemory::<'a, T>::default();
+    }
+    // cut from the end of the pool, which comes back zeroed and exactly cell_len long
+    self.unused -= cell_len;
+    self.pool.alloc_cell(cell_len)
+  }
+  fn free_cell(&mut self, cell: AllocatedStackMemory<'a, T>) {
+    let len = cell.slice().len();
+    if len == 0 {
+      return;
+    }
+    let size_class = if len > self.largest_small_cell {
+      LARGE_SIZE_CLASS
+    } else {
+      len.trailing_zeros() as usize
+    };
+    for slot in self.free_cells[size_class].iter_mut() {
+      if slot.slice().is_empty() {
+        *slot = cell;
+        return;
+      }
+    }
+  }
+}
+
+// Pool sizes, in elements, with the largest small cell and the large cell of each pool. A site
+// that holds one cell at a time whose size varies between calls may leave a cell behind in every
+// class up to its largest, so it needs up to twice its largest power of two.
+
+// Qualities 0 and 1 compress each input buffer on its own, so every size here follows from the
+// buffer size.
+mod fast_pools {
+  use super::BROTLI_STACK_PROFILE_BUFFER_SIZE;
+  const BLOCK_SIZE: usize = BROTLI_STACK_PROFILE_BUFFER_SIZE;
+  // the storage for a block that does not fit in what is left of the output buffer, reallocated
+  // as blocks grow: 2 * block + 503 bytes, plus at quality 1 a literal buffer of one block
+  pub const U8_LARGE_CELL: usize = 2 * BLOCK_SIZE + 503;
+  pub const U8_LARGEST_SMALL_CELL: usize = BLOCK_SIZE;
+  pub const U8_POOL_SIZE: usize = U8_LARGE_CELL + 2 * BLOCK_SIZE + 2 * BLOCK_SIZE;
+  pub const U16_LARGE_CELL: usize = 0;
+  pub const U16_LARGEST_SMALL_CELL: usize = 0;
+  pub const U16_POOL_SIZE: usize = 0;
+  // the hash table, reallocated as blocks grow: one entry per byte, doubled at quality 0
+  pub const I32_LARGE_CELL: usize = 2 * BLOCK_SIZE;
+  pub const I32_LARGEST_SMALL_CELL: usize = 2 * BLOCK_SIZE;
+  pub const I32_POOL_SIZE: usize = 2 * I32_LARGE_CELL;
+  // the command buffer of quality 1, of one block
+  pub const U32_LARGE_CELL: usize = BLOCK_SIZE;
+  pub const U32_LARGEST_SMALL_CELL: usize = BLOCK_SIZE;
+  pub const U32_POOL_SIZE: usize = 2 * BLOCK_SIZE;
+  pub const COMMAND_LARGE_CELL: usize = 0;
+  pub const COMMAND_LARGEST_SMALL_CELL: usize = 0;
+  pub const COMMAND_POOL_SIZE: usize = 0;
+  pub const HISTOGRAM_LITERAL_LARGE_CELL: usize = 0;
+  pub const HISTOGRAM_LITERAL_POOL_SIZE: usize = 0;
+  pub const HISTOGRAM_COMMAND_LARGE_CELL: usize = 0;
+  pub const HISTOGRAM_COMMAND_POOL_SIZE: usize = 0;
+  pub const HISTOGRAM_DISTANCE_LARGE_CELL: usize = 0;
+  pub const HISTOGRAM_DISTANCE_POOL_SIZE: usize = 0;
+  // one literal tree at a time, of up to 2 * 256 + 1 nodes
+  pub const HUFFMAN_TREE_LARGE_CELL: usize = 2 * 256 + 1;
+  pub const HUFFMAN_TREE_LARGEST_SMALL_CELL: usize = 256;
+  pub const HUFFMAN_TREE_POOL_SIZE: usize = HUFFMAN_TREE_LARGE_CELL + 2 * HUFFMAN_TREE_LARGEST_SMALL_CELL;
+}
+
+// Qualities 2 to 4 copy the input into the ring buffer and compress it a block of 1 << lgblock
+// bytes at a time; no block ends early, since the profile never flushes.
+mod ring_buffer_pools {
+  use super::{BROTLI_STACK_PROFILE_BUFFER_SIZE, BROTLI_STACK_PROFILE_MAX_LGBLOCK, MAX_METABLOCK_SIZE};
+  const LGBLOCK: usize = BROTLI_STACK_PROFILE_MAX_LGBLOCK as usize;
+  // The command buffer of quality 4 grows to the commands of the metablock so far, which end it
+  // at 1/8 of the metablock size, plus bytes / 2 + 1 for the block and bytes / 4 + 16 of slack.
+  // Its first block sets it to that, and no later block can need more.
+  const COMMANDS: usize = (3 << (LGBLOCK - 2)) + 17;
+  // The greedy block splitter of quality 4 starts a block type every 512 literals, 1024 commands
+  // and 512 distances at most, and the format allows 256 types.
+  const LITERAL_BLOCK_TYPES: usize = MAX_METABLOCK_SIZE / 512 + 1;
+  const COMMAND_BLOCK_TYPES: usize = COMMANDS / 1024 + 1;
+  const DISTANCE_BLOCK_TYPES: usize = COMMANDS / 512 + 1;
+  // the sums over the classes of the block type and block length arrays, which the splitter
+  // allocates anew for every metablock: less than 1024, 128 and 256 elements
+  const BLOCK_SPLIT_CLASSES: usize = 2 * (MAX_METABLOCK_SIZE >> 8) + 2 * (1 << (LGBLOCK - 10)) +
+                                     2 * (1 << (LGBLOCK - 9));
+  // the same for the symbol depths and codes of every histogram of a metablock: up to 256 types
+  // of 256 literals, COMMAND_BLOCK_TYPES < 1 << (LGBLOCK - 10) of 704 < 1024 commands and
+  // DISTANCE_BLOCK_TYPES < 1 << (LGBLOCK - 9) of at most 124 < 128 distance codes
+  const SYMBOL_TABLE_CLASSES: usize = 2 * 256 * 256 + 2 * (1 << LGBLOCK) + 2 * (1 << (LGBLOCK - 2));
+  // The ring buffer (1 << 17 bytes, its tail of 1 << lgblock and 9 bytes of slack) and the
+  // storage for a metablock (2 * 128KB + 503 bytes) are the large cells. The storage grows with
+  // the metablocks and may leave a cell in every class up to 128KB, and the ring buffer starts
+  // out as a cell of the first input buffer before it takes its full size.
+  pub const U8_LARGE_CELL: usize = 2 * MAX_METABLOCK_SIZE + 503;
+  pub const U8_LARGEST_SMALL_CELL: usize = MAX_METABLOCK_SIZE;
+  pub const U8_POOL_SIZE: usize = 2 * U8_LARGE_CELL + 2 * MAX_METABLOCK_SIZE + 2 * BROTLI_STACK_PROFILE_BUFFER_SIZE +
+                                  BLOCK_SPLIT_CLASSES + SYMBOL_TABLE_CLASSES;
+  // the symbol codes of quality 4, which go with the depths
+  pub const U16_LARGE_CELL: usize = 256 * 256;
+  pub const U16_LARGEST_SMALL_CELL: usize = 256 * 256;
+  pub const U16_POOL_SIZE: usize = SYMBOL_TABLE_CLASSES;
+  pub const I32_LARGE_CELL: usize = 0;
+  pub const I32_LARGEST_SMALL_CELL: usize = 0;
+  pub const I32_POOL_SIZE: usize = 0;
+  // the hasher of quality 4 is the largest, at (1 << 17) + 8 buckets against (1 << 16) + 9 and
+  // (1 << 16) + 10 at qualities 2 and 3; the encoder only sees one input buffer at a time, so its
+  // size hint stays far below the 1MB that would pick a larger one
+  pub const U32_LARGE_CELL: usize = (1 << 17) + 8;
+  pub const U32_LARGEST_SMALL_CELL: usize = MAX_METABLOCK_SIZE >> 8;
+  pub const U32_POOL_SIZE: usize = U32_LARGE_CELL + BLOCK_SPLIT_CLASSES;
+  // Qualities 2 and 3 start with 3 << 12 + 17 commands and end metablocks at 0x2fff literals and
+  // commands, which lets the buffer grow twice to less than 1 << 15, with the old buffer live
+  // while it is copied to the new one. That is more than the one buffer of quality 4.
+  pub const COMMAND_LARGE_CELL: usize = 1 << LGBLOCK;
+  pub const COMMAND_LARGEST_SMALL_CELL: usize = 1 << LGBLOCK;
+  pub const COMMAND_POOL_SIZE: usize = (1 << 14) + 2 * (1 << 15);
+  // one histogram per block type at most, allocated anew for every metablock
+  pub const HISTOGRAM_LITERAL_LARGE_CELL: usize = LITERAL_BLOCK_TYPES;
+  pub const HISTOGRAM_LITERAL_POOL_SIZE: usize = LITERAL_BLOCK_TYPES;
+  pub const HISTOGRAM_COMMAND_LARGE_CELL: usize = COMMAND_BLOCK_TYPES;
+  pub const HISTOGRAM_COMMAND_POOL_SIZE: usize = COMMAND_BLOCK_TYPES;
+  pub const HISTOGRAM_DISTANCE_LARGE_CELL: usize = DISTANCE_BLOCK_TYPES;
+  pub const HISTOGRAM_DISTANCE_POOL_SIZE: usize = DISTANCE_BLOCK_TYPES;
+  // one tree at a time, for up to the 704 command symbols
+  pub const HUFFMAN_TREE_LARGE_CELL: usize = 2 * 704 + 1;
+  pub const HUFFMAN_TREE_LARGEST_SMALL_CELL: usize = 0;
+  pub const HUFFMAN_TREE_POOL_SIZE: usize = HUFFMAN_TREE_LARGE_CELL;
+}
+
+// the histograms are not Copy, so their pools are filled from constants
+const EMPTY_HISTOGRAM_LITERAL: HistogramLiteral = HistogramLiteral {
+  data_: [0; 256],
+  total_count_: 0,
+  bit_cost_: 3.402e+38 as floatX,
+};
+const EMPTY_HISTOGRAM_COMMAND: HistogramCommand = HistogramCommand {
+  data_: [0; 704],
+  total_count_: 0,
+  bit_cost_: 3.402e+38 as floatX,
+};
+const EMPTY_HISTOGRAM_DISTANCE: HistogramDistance = HistogramDistance {
+  data_: [0; 544],
+  total_count_: 0,
+  bit_cost_: 3.402e+38 as floatX,
+};
+
+macro_rules! stack_profile_memory_size {
+  ($pools: ident) => {
+    $pools::U8_POOL_SIZE + $pools::U16_POOL_SIZE * 2 + ($pools::U32_POOL_SIZE + $pools::I32_POOL_SIZE) * 4 +
+    $pools::COMMAND_POOL_SIZE * core::mem::size_of::<Command>() +
+    $pools::HISTOGRAM_LITERAL_POOL_SIZE * core::mem::size_of::<HistogramLiteral>() +
+    $pools::HISTOGRAM_COMMAND_POOL_SIZE * core::mem::size_of::<HistogramCommand>() +
+    $pools::HISTOGRAM_DISTANCE_POOL_SIZE * core::mem::size_of::<HistogramDistance>() +
+    $pools::HUFFMAN_TREE_POOL_SIZE * core::mem::size_of::<HuffmanTree>()
+  };
+}
+
+// the pools of qualities 0 and 1
+pub const BROTLI_STACK_PROFILE_FAST_MEMORY_SIZE: usize = stack_profile_memory_size!(fast_pools);
+// the pools of qualities 2 to 4, the most any quality of the profile needs
+pub const BROTLI_STACK_PROFILE_MEMORY_SIZE: usize = stack_profile_memory_size!(ring_buffer_pools);
+
+// Compresses r into w with the quality, window size, block size and mode of params; the other
+// parameters keep their defaults. Parameters outside the profile (a quality above 4, a window
+// above 16 bits, a block above 16 bits or a large window) are rejected with
+// invalid_params_error_constant before anything is allocated.
+pub fn BrotliCompressStackProfile<ErrType, InputType, OutputType>(r: &mut InputType,
+                                                                  w: &mut OutputType,
+                                                                  params: &BrotliEncoderParams,
+                                                                  unexpected_eof_error_constant: ErrType,
+                                                                  invalid_params_error_constant: ErrType)
+                                                                  -> Result<usize, ErrType>
+  where InputType: CustomRead<ErrType>,
+        OutputType: CustomWrite<ErrType>
+{
+  if params.quality < 0 || params.quality > BROTLI_STACK_PROFILE_MAX_QUALITY ||
+     params.lgwin > BROTLI_STACK_PROFILE_MAX_LGWIN || params.lgblock > BROTLI_STACK_PROFILE_MAX_LGBLOCK ||
+     params.large_window {
+    return Err(invalid_params_error_constant);
+  }
+  let mut profile_params = BrotliEncoderInitParams();
+  profile_params.quality = params.quality;
+  profile_params.lgwin = params.lgwin;
+  profile_params.lgblock = params.lgblock;
+  profile_params.mode = params.mode;
+  if params.quality <= 1 {
+    BrotliCompressWithFastPools(r, w, &profile_params, unexpected_eof_error_constant)
+  } else {
+    BrotliCompressWithRingBufferPools(r, w, &profile_params, unexpected_eof_error_constant)
+  }
+}
+
+// Each set of pools gets a function of its own, kept out of line so that the pools only take up
+// the stack once the parameters are known to fit.
+macro_rules! define_stack_profile_compressor {
+  ($name: ident, $pools: ident) => {
+    #[inline(never)]
+    fn $name<ErrType, InputType, OutputType>(r: &mut InputType,
+                                             w: &mut OutputType,
+                                             params: &BrotliEncoderParams,
+                                             unexpected_eof_error_constant: ErrType)
+                                             -> Result<usize, ErrType>
+      where InputType: CustomRead<ErrType>,
+            OutputType: CustomWrite<ErrType>
+    {
+      let mut input_buffer = [0u8; BROTLI_STACK_PROFILE_BUFFER_SIZE];
+      let mut output_buffer = [0u8; BROTLI_STACK_PROFILE_BUFFER_SIZE];
+      let mut u8_pool = define_allocator_memory_pool!(128, u8, [0; $pools::U8_POOL_SIZE], stack);
+      let mut u16_pool = define_allocator_memory_pool!(128, u16, [0; $pools::U16_POOL_SIZE], stack);
+      let mut i32_pool = define_allocator_memory_pool!(128, i32, [0; $pools::I32_POOL_SIZE], stack);
+      let mut u32_pool = define_allocator_memory_pool!(128, u32, [0; $pools::U32_POOL_SIZE], stack);
+      let mut command_pool = define_allocator_memory_pool!(128, Command,
+                                                           [Command::default(); $pools::COMMAND_POOL_SIZE], stack);
+      let mut hl_pool = define_allocator_memory_pool!(128, HistogramLiteral,
+                                                      [EMPTY_HISTOGRAM_LITERAL; $pools::HISTOGRAM_LITERAL_POOL_SIZE],
+                                                      stack);
+      let mut hc_pool = define_allocator_memory_pool!(128, HistogramCommand,
+                                                      [EMPTY_HISTOGRAM_COMMAND; $pools::HISTOGRAM_COMMAND_POOL_SIZE],
+                                                      stack);
+      let mut hd_pool = define_allocator_memory_pool!(128, HistogramDistance,
+                                                      [EMPTY_HISTOGRAM_DISTANCE; $pools::HISTOGRAM_DISTANCE_POOL_SIZE],
+                                                      stack);
+      let mut ht_pool = define_allocator_memory_pool!(128, HuffmanTree,
+                                                      [HuffmanTree::default(); $pools::HUFFMAN_TREE_POOL_SIZE], stack);
+      // only qualities above 4 allocate these
+      let mut u64_pool: [u64; 0] = [];
+      let mut f64_pool: [floatX; 0] = [];
+      let mut fv_pool: [Mem256f; 0] = [];
+      let mut pdf_pool: [PDF; 0] = [];
+      let mut sc_pool: [StaticCommand; 0] = [];
+      let mut hp_pool: [HistogramPair; 0] = [];
+      let mut ct_pool: [ContextType; 0] = [];
+      let mut zn_pool: [ZopfliNode; 0] = [];
+      let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
+                              _cmds: &mut [interface::StaticCommand],
+                              _mb: interface::InputPair,
+                              _mfv: &mut StackProfileAllocator<Mem256f>,
+                              _mpdf: &mut StackProfileAllocator<PDF>,
+                              _mc: &mut StackProfileAllocator<StaticCommand>| ();
+      BrotliCompressCustomIo(r,
+                             w,
+                             &mut input_buffer[..],
+                             &mut output_buffer[..],
+                             params,
+                             StackProfileAllocator::<u8>::new(&mut u8_pool, $pools::U8_LARGEST_SMALL_CELL,
+                                                              $pools::U8_LARGE_CELL),
+                             StackProfileAllocator::<u16>::new(&mut u16_pool, $pools::U16_LARGEST_SMALL_CELL,
+                                                               $pools::U16_LARGE_CELL),
+                             StackProfileAllocator::<i32>::new(&mut i32_pool, $pools::I32_LARGEST_SMALL_CELL,
+                                                               $pools::I32_LARGE_CELL),
+                             StackProfileAllocator::<u32>::new(&mut u32_pool, $pools::U32_LARGEST_SMALL_CELL,
+                                                               $pools::U32_LARGE_CELL),
+                             StackProfileAllocator::<u64>::new(&mut u64_pool, 0, 0),
+                             StackProfileAllocator::<Command>::new(&mut command_pool,
+                                                                   $pools::COMMAND_LARGEST_SMALL_CELL,
+                                                                   $pools::COMMAND_LARGE_CELL),
+                             StackProfileAllocator::<floatX>::new(&mut f64_pool, 0, 0),
+                             StackProfileAllocator::<Mem256f>::new(&mut fv_pool, 0, 0),
+                             StackProfileAllocator::<PDF>::new(&mut pdf_pool, 0, 0),
+                             StackProfileAllocator::<StaticCommand>::new(&mut sc_pool, 0, 0),
+                             StackProfileAllocator::<HistogramLiteral>::new(&mut hl_pool, 0,
+                                                                            $pools::HISTOGRAM_LITERAL_LARGE_CELL),
+                             StackProfileAllocator::<HistogramCommand>::new(&mut hc_pool, 0,
+                                                                            $pools::HISTOGRAM_COMMAND_LARGE_CELL),
+                             StackProfileAllocator::<HistogramDistance>::new(&mut hd_pool, 0,
+                                                                             $pools::HISTOGRAM_DISTANCE_LARGE_CELL),
+                             StackProfileAllocator::<HistogramPair>::new(&mut hp_pool, 0, 0),
+                             StackProfileAllocator::<ContextType>::new(&mut ct_pool, 0, 0),
+                             StackProfileAllocator::<HuffmanTree>::new(&mut ht_pool,
+                                                                       $pools::HUFFMAN_TREE_LARGEST_SMALL_CELL,
+                                                                       $pools::HUFFMAN_TREE_LARGE_CELL),
+                             StackProfileAllocator::<ZopfliNode>::new(&mut zn_pool, 0, 0),
+                             &mut nop_callback,
+                             unexpected_eof_error_constant)
+    }
+  };
+}
+
+define_stack_profile_compressor!(BrotliCompressWithFastPools, fast_pools);
+define_stack_profile_compressor!(BrotliCompressWithRingBufferPools, ring_buffer_pools);
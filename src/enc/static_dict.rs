@@ -2,13 +2,21 @@ pub const kNumDistanceCacheEntries: usize = 4;
 
 use super::static_dict_lut::{kDictHashMul32, kDictNumBits, kStaticDictionaryBuckets,
                              kStaticDictionaryWords, DictWord};
+use super::backward_references::{BrotliHasherParams, kHashMul32};
+use super::dictionary_hash::kStaticDictionaryHash;
+use super::super::transform::kTransforms;
+use super::shared_dictionary::{BrotliSharedTransforms, ShiftCodePoint, TransformSharedDictionaryWord,
+                               BROTLI_TRANSFORM_IDENTITY, BROTLI_TRANSFORM_OMIT_LAST_9,
+                               BROTLI_TRANSFORM_UPPERCASE_FIRST, BROTLI_TRANSFORM_UPPERCASE_ALL,
+                               BROTLI_TRANSFORM_OMIT_FIRST_1, BROTLI_TRANSFORM_OMIT_FIRST_9,
+                               BROTLI_TRANSFORM_SHIFT_FIRST, BROTLI_TRANSFORM_SHIFT_ALL,
+                               BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS};
 use super::super::dictionary::{kBrotliDictionary, kBrotliDictionarySizeBitsByLength,
                                kBrotliDictionaryOffsetsByLength};
 #[allow(unused)]
 static kUppercaseFirst: u8 = 10i32 as (u8);
 
-#[allow(unused)]
-static kOmitLastNTransforms: [u8; 10] = [0i32 as (u8),
+pub static kOmitLastNTransforms: [u8; 10] = [0i32 as (u8),
                                          12i32 as (u8),
                                          27i32 as (u8),
                                          23i32 as (u8),
@@ -19,20 +27,112 @@ static kOmitLastNTransforms: [u8; 10] = [0i32 as (u8),
                                          59i32 as (u8),
                                          64i32 as (u8)];
 
-pub struct BrotliDictionary {
-  pub size_bits_by_length: &'static [u8; 25],
-  pub offsets_by_length: &'static [u32; 25],
-  pub data: &'static [u8; 122784],
+// Classes of static dictionary transforms that the dictionary search may be restricted to.
+// A transform belongs to the class of its operation plus the affix class when it adds a prefix or suffix.
+pub const BROTLI_DICTIONARY_TRANSFORM_IDENTITY: u32 = 1;
+pub const BROTLI_DICTIONARY_TRANSFORM_OMIT_LAST: u32 = 2;
+pub const BROTLI_DICTIONARY_TRANSFORM_OMIT_FIRST: u32 = 4;
+pub const BROTLI_DICTIONARY_TRANSFORM_UPPERCASE: u32 = 8;
+pub const BROTLI_DICTIONARY_TRANSFORM_AFFIX: u32 = 16;
+pub const BROTLI_DICTIONARY_TRANSFORM_ALL: u32 = 31;
+
+// The class of a transform type of the shared dictionary format, whose types 0 to 20 are the
+// built-in ones. The shift transforms change code points like uppercasing does.
+fn TransformTypeClass(transform_type: u8) -> u32 {
+  if transform_type == BROTLI_TRANSFORM_IDENTITY {
+    BROTLI_DICTIONARY_TRANSFORM_IDENTITY
+  } else if transform_type <= BROTLI_TRANSFORM_OMIT_LAST_9 {
+    BROTLI_DICTIONARY_TRANSFORM_OMIT_LAST
+  } else if transform_type >= BROTLI_TRANSFORM_OMIT_FIRST_1 && transform_type <= BROTLI_TRANSFORM_OMIT_FIRST_9 {
+    BROTLI_DICTIONARY_TRANSFORM_OMIT_FIRST
+  } else {
+    BROTLI_DICTIONARY_TRANSFORM_UPPERCASE
+  }
+}
+
+// The classes of a transform of the dictionary; a transform id past its list is in all of them.
+pub fn BrotliDictionaryTransformClasses(dictionary: &BrotliDictionary, transform_id: usize) -> u32 {
+  let (transform_type, has_affix) = match (*dictionary).transforms {
+    Some(ref transforms) => {
+      if transform_id >= transforms.num_transforms() {
+        return BROTLI_DICTIONARY_TRANSFORM_ALL;
+      }
+      (transforms.transform_type(transform_id),
+       !transforms.prefix(transform_id).is_empty() || !transforms.suffix(transform_id).is_empty())
+    }
+    None => {
+      if transform_id >= kTransforms.len() {
+        return BROTLI_DICTIONARY_TRANSFORM_ALL;
+      }
+      let transform = &kTransforms[transform_id];
+      (transform.transform, transform.prefix_id != 0 || transform.suffix_id != 0)
+    }
+  };
+  if has_affix {
+    TransformTypeClass(transform_type) | BROTLI_DICTIONARY_TRANSFORM_AFFIX
+  } else {
+    TransformTypeClass(transform_type)
+  }
+}
+
+// Whether the hasher params let the dictionary search emit this word length and transform.
+#[inline(always)]
+pub fn BrotliDictionaryTransformAllowed(params: &BrotliHasherParams,
+                                        dictionary: &BrotliDictionary,
+                                        word_len: usize,
+                                        transform_id: usize)
+                                        -> bool {
+  if word_len < params.static_dictionary_min_word_len as usize {
+    return false;
+  }
+  params.static_dictionary_transforms == BROTLI_DICTIONARY_TRANSFORM_ALL ||
+  BrotliDictionaryTransformClasses(dictionary, transform_id) & !params.static_dictionary_transforms == 0
+}
+
+// A word list the encoder may reference past the end of the window: the built-in one,
+// or the word list of a shared dictionary (see shared_dictionary.rs).
+#[derive(Clone, Copy)]
+pub struct BrotliDictionary<'a> {
+  // log2 of the number of words of each length, indexed by length
+  pub size_bits_by_length: &'a [u8],
+  pub offsets_by_length: &'a [u32],
+  pub data: &'a [u8],
+  // two slots per 14 bit hash of the first four bytes of a word, each holding len | (index << 5)
+  pub hash_table: &'a [u16],
+  // bits 16 and up of each hash_table slot, for word lists with more than 2048 words of a
+  // length; empty otherwise
+  pub hash_table_high: &'a [u8],
+  // cutoff_transforms[n] is the transform that omits the last n bytes of a word and adds no affixes
+  pub cutoff_transforms: &'a [u8],
+  // the transforms of a shared dictionary; None for the built-in word list and transforms,
+  // which have precomputed match tables
+  pub transforms: Option<BrotliSharedTransforms<'a>>,
+}
+
+impl<'a> BrotliDictionary<'a> {
+  // the len | (index << 5) item in a slot of the hash table, 0 if it is empty
+  #[inline(always)]
+  pub fn hash_item(&self, slot: usize) -> usize {
+    if self.hash_table_high.is_empty() {
+      self.hash_table[slot] as usize
+    } else {
+      self.hash_table[slot] as usize | ((self.hash_table_high[slot] as usize) << 16)
+    }
+  }
 }
 
-pub static kBrotliEncDictionary: BrotliDictionary = BrotliDictionary {
+pub static kBrotliEncDictionary: BrotliDictionary<'static> = BrotliDictionary {
   size_bits_by_length: &kBrotliDictionarySizeBitsByLength,
   offsets_by_length: &kBrotliDictionaryOffsetsByLength,
   data: &kBrotliDictionary,
+  hash_table: &kStaticDictionaryHash,
+  hash_table_high: &[],
+  cutoff_transforms: &kOmitLastNTransforms,
+  transforms: None,
 };
 
 #[inline(always)]
-pub fn BrotliGetDictionary() -> &'static BrotliDictionary {
+pub fn BrotliGetDictionary() -> &'static BrotliDictionary<'static> {
   return &kBrotliEncDictionary;
 }
 #[inline(always)]
@@ -41,6 +141,12 @@ pub fn BROTLI_UNALIGNED_LOAD32(sl: &[u8]) -> u32 {
   p[..].clone_from_slice(&sl.split_at(4).0);
   return (p[0] as u32) | ((p[1] as u32) << 8) | ((p[2] as u32) << 16) | ((p[3] as u32) << 24);
 }
+// Hash of the first four bytes of a word, used to index BrotliDictionary::hash_table.
+#[inline(always)]
+pub fn BrotliDictionaryHash14(data: &[u8]) -> u32 {
+  let h: u32 = BROTLI_UNALIGNED_LOAD32(data).wrapping_mul(kHashMul32);
+  h >> 32i32 - 14i32
+}
 #[inline(always)]
 pub fn Hash(data: &[u8]) -> u32 {
   let h: u32 = BROTLI_UNALIGNED_LOAD32(data).wrapping_mul(kDictHashMul32);
@@ -129,6 +235,9 @@ pub fn SlowFindMatchLengthWithLimit(s1: &[u8], s2: &[u8], limit: usize) -> usize
 }
 #[inline] 
 pub fn FindMatchLengthWithLimit(mut s1: &[u8], mut s2: &[u8], mut limit: usize) -> usize {
+  if let Some(matched) = super::simd::FindMatchLengthWithLimit(s1, s2, limit) {
+    return matched;
+  }
   let mut matched: usize = 0usize;
   let mut s1_as_64 : u64;
   let mut s2_as_64 : u64;
@@ -158,8 +267,8 @@ pub fn FindMatchLengthWithLimit(mut s1: &[u8], mut s2: &[u8], mut limit: usize)
           sub_match8!(s1, s2, limit, matched, s1_as_64, s2_as_64);
       }
   }
-  assert!(s1.len() >= (limit & 7usize));
-  assert!(s2.len() >= (limit & 7usize));
+  debug_assert!(s1.len() >= (limit & 7usize));
+  debug_assert!(s2.len() >= (limit & 7usize));
   for index in 0..(limit & 7usize) {
     if s1[index] != s2[index] {
       return matched + index;
@@ -178,6 +287,30 @@ mod test {
         output[matchfor] = output[matchfor].wrapping_add((matchfor as u8 % 253u8).wrapping_add(1));
     }
     #[test]
+    fn test_dictionary_transform_allowed() {
+        let mut params = super::super::encode::BrotliEncoderInitParams().hasher;
+        let dictionary = &super::kBrotliEncDictionary;
+        // transform 0 is the bare word, 1 appends a space, 9 uppercases the first letter
+        assert_eq!(super::BrotliDictionaryTransformClasses(dictionary, 0),
+                   super::BROTLI_DICTIONARY_TRANSFORM_IDENTITY);
+        assert_eq!(super::BrotliDictionaryTransformClasses(dictionary, 1),
+                   super::BROTLI_DICTIONARY_TRANSFORM_IDENTITY | super::BROTLI_DICTIONARY_TRANSFORM_AFFIX);
+        assert_eq!(super::BrotliDictionaryTransformClasses(dictionary, 9),
+                   super::BROTLI_DICTIONARY_TRANSFORM_UPPERCASE);
+        for transform_id in 0..121 {
+            assert!(super::BrotliDictionaryTransformAllowed(&params, dictionary, 4, transform_id));
+        }
+        params.static_dictionary_transforms = super::BROTLI_DICTIONARY_TRANSFORM_IDENTITY;
+        assert!(super::BrotliDictionaryTransformAllowed(&params, dictionary, 4, 0));
+        assert!(!super::BrotliDictionaryTransformAllowed(&params, dictionary, 4, 1));
+        assert!(!super::BrotliDictionaryTransformAllowed(&params, dictionary, 4, 9));
+        // there are only 121 built-in transforms
+        assert!(!super::BrotliDictionaryTransformAllowed(&params, dictionary, 4, 200));
+        params.static_dictionary_min_word_len = 5;
+        assert!(!super::BrotliDictionaryTransformAllowed(&params, dictionary, 4, 0));
+        assert!(super::BrotliDictionaryTransformAllowed(&params, dictionary, 5, 0));
+    }
+    #[test]
     fn test_find_match_length() {
         let mut a = [91u8;600000];
         let mut b = [0u8;600000];
@@ -289,10 +422,23 @@ fn brotli_min_uint32_t(a: u32, b: u32) -> u32 {
   if a < b { a } else { b }
 }
 
-#[allow(unused)]
-fn AddMatch(distance: usize, len: usize, len_code: usize, mut matches: &mut [u32]) {
+// The match table of one dictionary lookup, which only takes the word lengths and transforms
+// that the hasher params allow, so that a ruled out transform never hides an allowed one.
+struct DictionaryMatches<'a> {
+  dictionary: &'a BrotliDictionary<'a>,
+  params: &'a BrotliHasherParams,
+  matches: &'a mut [u32],
+  has_found_match: bool,
+}
+
+fn AddMatch(distance: usize, len: usize, len_code: usize, matches: &mut DictionaryMatches) {
+  let transform_id: usize = distance >> (*matches.dictionary).size_bits_by_length[len_code];
+  if !BrotliDictionaryTransformAllowed(matches.params, matches.dictionary, len_code, transform_id) {
+    return;
+  }
   let match_: u32 = (distance << 5i32).wrapping_add(len_code) as (u32);
-  matches[len as (usize)] = brotli_min_uint32_t(matches[len as (usize)], match_);
+  matches.matches[len as (usize)] = brotli_min_uint32_t(matches.matches[len as (usize)], match_);
+  matches.has_found_match = true;
 }
 
 #[allow(unused)]
@@ -319,13 +465,137 @@ fn brotli_max_size_t(a: usize, b: usize) -> usize {
   if a > b { a } else { b }
 }
 
-#[allow(unused)]
+// The longest transformed word that the match table of one lookup can hold.
+const MAX_CUSTOM_DICTIONARY_MATCH_LEN: usize = 37;
+
+// The four bytes a word would start with to be turned into key by a transform: key itself,
+// and for the transforms that change code points, key with that change undone (where it
+// can be from key alone). Returns how many of the two candidates are set.
+fn CustomDictionaryKeys(transforms: &BrotliSharedTransforms,
+                        transform_id: usize,
+                        key: &[u8],
+                        keys: &mut [[u8; 4]; 2])
+                        -> usize {
+  keys[0].clone_from_slice(&key[..4]);
+  keys[1].clone_from_slice(&key[..4]);
+  let delta = -transforms.shift(transform_id);
+  match transforms.transform_type(transform_id) {
+    BROTLI_TRANSFORM_UPPERCASE_FIRST => keys[1][0] = keys[1][0].to_ascii_lowercase(),
+    BROTLI_TRANSFORM_UPPERCASE_ALL => keys[1].make_ascii_lowercase(),
+    BROTLI_TRANSFORM_SHIFT_FIRST => {
+      ShiftCodePoint(&mut keys[1][..], delta);
+    }
+    BROTLI_TRANSFORM_SHIFT_ALL => {
+      let mut pos = 0usize;
+      while pos < 4 {
+        pos += ShiftCodePoint(&mut keys[1][pos..], delta);
+      }
+    }
+    _ => {}
+  }
+  if keys[1] == keys[0] { 1 } else { 2 }
+}
+
+// Match finder for word lists without precomputed match tables. For each transform whose
+// prefix the data starts with, the words are looked up by the next four bytes (undoing an
+// uppercase or shift transform) and reported when the data starts with the whole output of
+// the transform. Transforms that omit the first bytes of a word can not be looked up this
+// way and are not searched.
+fn FindAllCustomDictionaryMatches(dictionary: &BrotliDictionary,
+                                  transforms: &BrotliSharedTransforms,
+                                  data: &[u8],
+                                  min_length: usize,
+                                  max_length: usize,
+                                  matches: &mut DictionaryMatches)
+                                  -> i32 {
+  let mut has_found_match: i32 = 0i32;
+  let max_length = core::cmp::min(core::cmp::min(max_length, data.len()), MAX_CUSTOM_DICTIONARY_MATCH_LEN);
+  let mut keys = [[0u8; 4]; 2];
+  let mut transformed = [0u8; 2 * 255 + BROTLI_SHARED_DICTIONARY_NUM_WORD_LENGTHS];
+  for transform_id in 0..transforms.num_transforms() {
+    let transform_type = transforms.transform_type(transform_id);
+    if transform_type >= BROTLI_TRANSFORM_OMIT_FIRST_1 && transform_type <= BROTLI_TRANSFORM_OMIT_FIRST_9 {
+      continue;
+    }
+    let prefix = transforms.prefix(transform_id);
+    let affix_len = prefix.len() + transforms.suffix(transform_id).len();
+    let omitted = if transform_type <= BROTLI_TRANSFORM_OMIT_LAST_9 {
+      transform_type as usize
+    } else {
+      0
+    };
+    if prefix.len() + 4 > max_length || data[..prefix.len()] != *prefix {
+      continue;
+    }
+    let num_keys = CustomDictionaryKeys(transforms, transform_id, &data[prefix.len()..], &mut keys);
+    for key_bytes in keys[..num_keys].iter() {
+      let key: usize = (BrotliDictionaryHash14(&key_bytes[..]) << 1i32) as (usize);
+      for slot in 0..2 {
+        let item: usize = (*dictionary).hash_item(key + slot);
+        if item == 0 {
+          continue;
+        }
+        let l: usize = item & 0x1f;
+        let id: usize = item >> 5i32;
+        if l <= omitted {
+          continue;
+        }
+        let len = l - omitted + affix_len;
+        if len < min_length || len > max_length {
+          continue;
+        }
+        let offset: usize = (*dictionary).offsets_by_length[l] as usize + l * id;
+        TransformSharedDictionaryWord(transforms,
+                                      &mut transformed[..],
+                                      &(*dictionary).data[offset..offset + l],
+                                      transform_id);
+        if transformed[..len] == data[..len] {
+          AddMatch(id.wrapping_add(transform_id << (*dictionary).size_bits_by_length[l]),
+                   len,
+                   l,
+                   matches);
+          has_found_match = 1i32;
+        }
+      }
+    }
+  }
+  has_found_match
+}
+
+// Fills matches[len] with the smallest (distance << 5 | word length) of a dictionary word and
+// transform that matches the first len bytes of data, among the ones params allows, and
+// returns whether there were any.
 pub fn BrotliFindAllStaticDictionaryMatches(dictionary: &BrotliDictionary,
-                                        data: &[u8],
-                                        min_length: usize,
-                                        max_length: usize,
-                                        mut matches: &mut [u32])
-                                        -> i32 {
+                                            params: &BrotliHasherParams,
+                                            data: &[u8],
+                                            min_length: usize,
+                                            max_length: usize,
+                                            matches: &mut [u32])
+                                            -> i32 {
+  if params.disable_static_dictionary {
+    return 0i32;
+  }
+  let mut allowed_matches = DictionaryMatches {
+    dictionary: dictionary,
+    params: params,
+    matches: matches,
+    has_found_match: false,
+  };
+  let has_found_match = match (*dictionary).transforms {
+    Some(ref transforms) => {
+      FindAllCustomDictionaryMatches(dictionary, transforms, data, min_length, max_length, &mut allowed_matches)
+    }
+    None => FindAllBuiltinDictionaryMatches(dictionary, data, min_length, max_length, &mut allowed_matches),
+  };
+  (has_found_match != 0 && allowed_matches.has_found_match) as i32
+}
+
+fn FindAllBuiltinDictionaryMatches(dictionary: &BrotliDictionary,
+                                   data: &[u8],
+                                   min_length: usize,
+                                   max_length: usize,
+                                   matches: &mut DictionaryMatches)
+                                   -> i32 {
   let mut has_found_match: i32 = 0i32;
   {
     let mut offset: usize = kStaticDictionaryBuckets[Hash(data) as (usize)] as (usize);
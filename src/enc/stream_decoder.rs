@@ -0,0 +1,795 @@
+// Walks a brotli stream metablock by metablock, filling in a BrotliStreamReport. The data is
+// decoded into a window-sized ring buffer (literal contexts need the previous bytes) and is
+// appended to output when one is given. The bit reader mirrors BrotliWriteBits and the prefix
+// code readers mirror the writers in brotli_bit_stream.rs.
+//
+// This decoder exists for the streams the decompressor can not read: those compressed with a
+// shared dictionary. BrotliAnalyzeStream runs it without an output.
+use core;
+use std::vec::Vec;
+use super::brotli_bit_stream::{Context, kBlockLengthPrefixCode};
+use super::constants::{kCopyBase, kCopyExtra, kInsBase, kInsExtra, BROTLI_NUM_BLOCK_LEN_SYMBOLS,
+                       BROTLI_NUM_COMMAND_SYMBOLS, BROTLI_NUM_LITERAL_SYMBOLS};
+use super::encode::BROTLI_LARGE_MAX_DISTANCE_BITS;
+use super::histogram::ContextType;
+use super::shared_dictionary::{BrotliSharedDictionary, BrotliSharedTransforms, TransformSharedDictionaryWord};
+use super::super::dictionary::{kBrotliDictionary, kBrotliDictionarySizeBitsByLength,
+                               kBrotliDictionaryOffsetsByLength};
+use super::super::transform::{TransformDictionaryWord, kNumTransforms};
+use super::analyzer::{BrotliAnalyzeError, BrotliBlockCategoryReport, BrotliMetaBlockReport,
+                      BrotliMetaBlockType, BrotliStreamReport};
+
+const BROTLI_MAX_DISTANCE_BITS: u32 = 24;
+const NUM_DISTANCE_SHORT_CODES: u32 = 16;
+const CODE_LENGTH_CODES: usize = 18;
+const MAX_CODE_LENGTH: usize = 15;
+static kCodeLengthCodeOrder: [usize; CODE_LENGTH_CODES] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13,
+                                                           14, 15];
+// the code length code lengths are themselves stored with a fixed prefix code, looked up
+// here by the next 4 bits
+static kCodeLengthPrefixLength: [u32; 16] = [2, 2, 2, 3, 2, 2, 2, 4, 2, 2, 2, 3, 2, 2, 2, 4];
+static kCodeLengthPrefixValue: [u8; 16] = [0, 4, 3, 2, 0, 4, 3, 1, 0, 4, 3, 2, 0, 4, 3, 5];
+
+struct BitReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+  fn read(&mut self, nbits: u32) -> Result<u64, BrotliAnalyzeError> {
+    if nbits > 64 {
+      return Err(BrotliAnalyzeError::InvalidMetaBlockHeader(self.pos));
+    }
+    if self.pos + nbits as usize > self.data.len() << 3 {
+      return Err(BrotliAnalyzeError::Truncated(self.pos));
+    }
+    let mut value = 0u64;
+    for i in 0..nbits {
+      let bit = (self.data[self.pos >> 3] >> (self.pos & 7)) & 1;
+      value |= u64::from(bit) << i;
+      self.pos += 1;
+    }
+    Ok(value)
+  }
+  // Like read, but past the end of the data reads zeros and leaves the position alone.
+  fn peek(&self, nbits: u32) -> u32 {
+    let mut value = 0u32;
+    for i in 0..nbits as usize {
+      let pos = self.pos + i;
+      if pos < self.data.len() << 3 {
+        value |= u32::from((self.data[pos >> 3] >> (pos & 7)) & 1) << i;
+      }
+    }
+    value
+  }
+  fn skip(&mut self, nbits: usize) -> Result<(), BrotliAnalyzeError> {
+    if nbits > (self.data.len() << 3) - self.pos {
+      return Err(BrotliAnalyzeError::Truncated(self.pos));
+    }
+    self.pos += nbits;
+    Ok(())
+  }
+  fn align(&mut self) -> Result<(), BrotliAnalyzeError> {
+    let padding = (8 - (self.pos & 7)) & 7;
+    self.skip(padding)
+  }
+}
+
+// A canonical prefix code, decoded one bit at a time.
+struct PrefixCode {
+  counts: [u16; MAX_CODE_LENGTH + 1],
+  // symbols ordered by code length, then value
+  symbols: Vec<u16>,
+}
+
+impl PrefixCode {
+  fn from_lengths(lengths: &[u8]) -> PrefixCode {
+    let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+    let mut symbols = Vec::new();
+    for len in 1..MAX_CODE_LENGTH + 1 {
+      for (symbol, symbol_len) in lengths.iter().enumerate() {
+        if *symbol_len as usize == len {
+          counts[len] += 1;
+          symbols.push(symbol as u16);
+        }
+      }
+    }
+    PrefixCode {
+      counts: counts,
+      symbols: symbols,
+    }
+  }
+  fn read_symbol(&self, br: &mut BitReader) -> Result<u16, BrotliAnalyzeError> {
+    // a code with a single symbol takes no bits
+    if self.symbols.len() == 1 {
+      return Ok(self.symbols[0]);
+    }
+    let start = br.pos;
+    let mut code = 0i32;
+    let mut first = 0i32;
+    let mut index = 0i32;
+    for len in 1..MAX_CODE_LENGTH + 1 {
+      code |= br.read(1)? as i32;
+      let count = i32::from(self.counts[len]);
+      if code - first < count {
+        return Ok(self.symbols[(index + code - first) as usize]);
+      }
+      index += count;
+      first = (first + count) << 1;
+      code <<= 1;
+    }
+    Err(BrotliAnalyzeError::InvalidPrefixCode(start))
+  }
+}
+
+fn AlphabetBits(alphabet_size: usize) -> u32 {
+  let mut bits = 0u32;
+  while (1usize << bits) < alphabet_size {
+    bits += 1;
+  }
+  bits
+}
+
+fn ReadSimplePrefixCode(br: &mut BitReader, alphabet_size: usize) -> Result<PrefixCode, BrotliAnalyzeError> {
+  let start = br.pos;
+  let num_symbols = br.read(2)? as usize + 1;
+  let mut symbols = [0usize; 4];
+  for i in 0..num_symbols {
+    symbols[i] = br.read(AlphabetBits(alphabet_size))? as usize;
+    if symbols[i] >= alphabet_size || symbols[..i].contains(&symbols[i]) {
+      return Err(BrotliAnalyzeError::InvalidPrefixCode(start));
+    }
+  }
+  let code_lengths: [u8; 4] = match num_symbols {
+    1 => [0, 0, 0, 0],
+    2 => [1, 1, 0, 0],
+    3 => [1, 2, 2, 0],
+    _ => {
+      if br.read(1)? == 0 {
+        [2, 2, 2, 2]
+      } else {
+        [1, 2, 3, 3]
+      }
+    }
+  };
+  if num_symbols == 1 {
+    return Ok(PrefixCode {
+      counts: [0; MAX_CODE_LENGTH + 1],
+      symbols: vec![symbols[0] as u16],
+    });
+  }
+  let mut lengths = vec![0u8; alphabet_size];
+  for i in 0..num_symbols {
+    lengths[symbols[i]] = code_lengths[i];
+  }
+  Ok(PrefixCode::from_lengths(&lengths[..]))
+}
+
+fn ReadComplexPrefixCode(br: &mut BitReader,
+                         alphabet_size: usize,
+                         skip: usize)
+                         -> Result<PrefixCode, BrotliAnalyzeError> {
+  let start = br.pos;
+  let mut code_length_code_lengths = [0u8; CODE_LENGTH_CODES];
+  let mut space = 32i32;
+  let mut num_codes = 0usize;
+  for i in skip..CODE_LENGTH_CODES {
+    let index = br.peek(4) as usize;
+    br.skip(kCodeLengthPrefixLength[index] as usize)?;
+    let len = kCodeLengthPrefixValue[index];
+    code_length_code_lengths[kCodeLengthCodeOrder[i]] = len;
+    if len != 0 {
+      space -= 32 >> len;
+      num_codes += 1;
+      if space <= 0 {
+        break;
+      }
+    }
+  }
+  if !(num_codes == 1 || space == 0) {
+    return Err(BrotliAnalyzeError::InvalidPrefixCode(start));
+  }
+  let code_length_code = PrefixCode::from_lengths(&code_length_code_lengths[..]);
+  let mut lengths = vec![0u8; alphabet_size];
+  let mut symbol = 0usize;
+  let mut prev_code_len = 8u8;
+  let mut repeat = 0usize;
+  let mut repeat_code_len = 0u8;
+  let mut space = 32768i32;
+  while symbol < alphabet_size && space > 0 {
+    let code_len = code_length_code.read_symbol(br)? as u8;
+    if code_len < 16 {
+      repeat = 0;
+      lengths[symbol] = code_len;
+      symbol += 1;
+      if code_len != 0 {
+        prev_code_len = code_len;
+        space -= 32768 >> code_len;
+      }
+      continue;
+    }
+    let extra_bits = if code_len == 16 { 2 } else { 3 };
+    let new_len = if code_len == 16 { prev_code_len } else { 0 };
+    if repeat_code_len != new_len {
+      repeat = 0;
+      repeat_code_len = new_len;
+    }
+    let old_repeat = repeat;
+    if repeat > 0 {
+      repeat = (repeat - 2) << extra_bits;
+    }
+    repeat += br.read(extra_bits)? as usize + 3;
+    let repeat_delta = repeat - old_repeat;
+    if symbol + repeat_delta > alphabet_size {
+      return Err(BrotliAnalyzeError::InvalidPrefixCode(start));
+    }
+    for length in lengths[symbol..symbol + repeat_delta].iter_mut() {
+      *length = repeat_code_len;
+    }
+    symbol += repeat_delta;
+    if repeat_code_len != 0 {
+      space -= (repeat_delta << (15 - repeat_code_len)) as i32;
+    }
+  }
+  if space != 0 {
+    return Err(BrotliAnalyzeError::InvalidPrefixCode(start));
+  }
+  Ok(PrefixCode::from_lengths(&lengths[..]))
+}
+
+fn ReadPrefixCode(br: &mut BitReader, alphabet_size: usize) -> Result<PrefixCode, BrotliAnalyzeError> {
+  let skip = br.read(2)? as usize;
+  if skip == 1 {
+    ReadSimplePrefixCode(br, alphabet_size)
+  } else {
+    ReadComplexPrefixCode(br, alphabet_size, skip)
+  }
+}
+
+fn ReadVarLenUint8(br: &mut BitReader) -> Result<usize, BrotliAnalyzeError> {
+  if br.read(1)? == 0 {
+    return Ok(0);
+  }
+  let nbits = br.read(3)? as u32;
+  if nbits == 0 {
+    return Ok(1);
+  }
+  Ok((1usize << nbits) + br.read(nbits)? as usize)
+}
+
+fn ReadBlockCount(br: &mut BitReader, code: &PrefixCode) -> Result<usize, BrotliAnalyzeError> {
+  let range = &kBlockLengthPrefixCode[code.read_symbol(br)? as usize];
+  Ok(range.offset as usize + br.read(range.nbits)? as usize)
+}
+
+fn ReadContextMap(br: &mut BitReader,
+                  size: usize,
+                  num_trees: usize)
+                  -> Result<Vec<u8>, BrotliAnalyzeError> {
+  let start = br.pos;
+  let mut context_map = vec![0u8; size];
+  if num_trees < 2 {
+    return Ok(context_map);
+  }
+  let max_run_length_prefix = if br.read(1)? != 0 {
+    br.read(4)? as usize + 1
+  } else {
+    0
+  };
+  let code = ReadPrefixCode(br, num_trees + max_run_length_prefix)?;
+  let mut i = 0usize;
+  while i < size {
+    let symbol = code.read_symbol(br)? as usize;
+    if symbol == 0 {
+      i += 1;
+    } else if symbol <= max_run_length_prefix {
+      let run = (1usize << symbol) + br.read(symbol as u32)? as usize;
+      if i + run > size {
+        return Err(BrotliAnalyzeError::InvalidContextMap(start));
+      }
+      i += run;
+    } else {
+      context_map[i] = (symbol - max_run_length_prefix) as u8;
+      i += 1;
+    }
+  }
+  if br.read(1)? != 0 {
+    // inverse move-to-front transform
+    let mut mtf = [0u8; 256];
+    for (index, value) in mtf.iter_mut().enumerate() {
+      *value = index as u8;
+    }
+    for value in context_map.iter_mut() {
+      let index = *value as usize;
+      let symbol = mtf[index];
+      for j in (0..index).rev() {
+        mtf[j + 1] = mtf[j];
+      }
+      mtf[0] = symbol;
+      *value = symbol;
+    }
+  }
+  Ok(context_map)
+}
+
+struct BlockState {
+  num_types: usize,
+  type_code: Option<PrefixCode>,
+  count_code: Option<PrefixCode>,
+  block_type: usize,
+  prev_block_type: usize,
+  remaining: usize,
+}
+
+impl BlockState {
+  fn read(br: &mut BitReader, report: &mut BrotliBlockCategoryReport) -> Result<BlockState, BrotliAnalyzeError> {
+    let start = br.pos;
+    let num_types = ReadVarLenUint8(br)? + 1;
+    let mut state = BlockState {
+      num_types: num_types,
+      type_code: None,
+      count_code: None,
+      block_type: 0,
+      prev_block_type: 1,
+      remaining: 1 << 24,
+    };
+    if num_types >= 2 {
+      state.type_code = Some(ReadPrefixCode(br, num_types + 2)?);
+      let count_code = ReadPrefixCode(br, BROTLI_NUM_BLOCK_LEN_SYMBOLS)?;
+      state.remaining = ReadBlockCount(br, &count_code)?;
+      state.count_code = Some(count_code);
+    }
+    report.num_block_types = num_types;
+    report.num_blocks = 1;
+    report.block_split_header_bits = br.pos - start;
+    Ok(state)
+  }
+  // Counts down one symbol of the current block, switching blocks first if it is used up.
+  fn next(&mut self, br: &mut BitReader, report: &mut BrotliBlockCategoryReport) -> Result<(), BrotliAnalyzeError> {
+    if self.remaining == 0 {
+      let (block_type, remaining) = match (&self.type_code, &self.count_code) {
+        (&Some(ref type_code), &Some(ref count_code)) => {
+          let block_type = match type_code.read_symbol(br)? as usize {
+            0 => self.prev_block_type,
+            1 => self.block_type + 1,
+            code => code - 2,
+          };
+          (block_type % self.num_types, ReadBlockCount(br, count_code)?)
+        }
+        _ => (0, 1 << 24),
+      };
+      self.prev_block_type = self.block_type;
+      self.block_type = block_type;
+      self.remaining = remaining;
+      report.num_blocks += 1;
+    }
+    self.remaining -= 1;
+    Ok(())
+  }
+}
+
+fn ReadPrefixCodes(br: &mut BitReader,
+                   count: usize,
+                   alphabet_size: usize,
+                   report: &mut BrotliBlockCategoryReport)
+                   -> Result<Vec<PrefixCode>, BrotliAnalyzeError> {
+  let start = br.pos;
+  let mut codes = Vec::with_capacity(count);
+  for _ in 0..count {
+    let code = ReadPrefixCode(br, alphabet_size)?;
+    report.num_used_symbols += code.symbols.len();
+    codes.push(code);
+  }
+  report.num_prefix_codes = count;
+  report.num_symbols = alphabet_size;
+  report.prefix_code_bits = br.pos - start;
+  Ok(codes)
+}
+
+struct Window<'a> {
+  // grows up to window_size as the output does, so a large window header alone allocates little
+  ring: Vec<u8>,
+  window_size: usize,
+  pos: usize,
+  max_backward: usize,
+  output: Option<&'a mut Vec<u8>>,
+}
+
+impl<'a> Window<'a> {
+  fn push(&mut self, byte: u8) {
+    if self.pos == self.ring.len() && self.ring.len() < self.window_size {
+      // nothing has wrapped around yet, so every byte keeps its place
+      let ring_size = self.ring.len() << 1;
+      self.ring.resize(ring_size, 0);
+    }
+    let mask = self.ring.len() - 1;
+    self.ring[self.pos & mask] = byte;
+    self.pos += 1;
+    if let Some(ref mut output) = self.output {
+      output.push(byte);
+    }
+  }
+  fn back(&self, distance: usize) -> u8 {
+    if distance > self.pos {
+      return 0;
+    }
+    self.ring[(self.pos - distance) & (self.ring.len() - 1)]
+  }
+}
+
+fn NewMetaBlockReport(kind: BrotliMetaBlockType, is_last: bool, start_bit: usize) -> BrotliMetaBlockReport {
+  BrotliMetaBlockReport {
+    kind: kind,
+    is_last: is_last,
+    start_bit: start_bit,
+    length: 0,
+    npostfix: 0,
+    ndirect: 0,
+    context_modes: Vec::new(),
+    literal: BrotliBlockCategoryReport::default(),
+    command: BrotliBlockCategoryReport::default(),
+    distance: BrotliBlockCategoryReport::default(),
+    num_commands: 0,
+    num_literals: 0,
+    num_dictionary_words: 0,
+    header_bits: 0,
+    literal_bits: 0,
+    command_bits: 0,
+    distance_bits: 0,
+    block_switch_bits: 0,
+  }
+}
+
+// The compound dictionary, word list and transforms that references past the window are
+// resolved with.
+struct StreamDictionary<'a> {
+  // the LZ77 prefix of a shared dictionary, which distances reach right past the window
+  prefix: &'a [u8],
+  size_bits_by_length: &'a [u8],
+  offsets_by_length: &'a [u32],
+  data: &'a [u8],
+  // None for the built-in transforms
+  transforms: Option<BrotliSharedTransforms<'a>>,
+}
+
+impl<'a> StreamDictionary<'a> {
+  fn new(shared: &'a BrotliSharedDictionary<'a>) -> Self {
+    let mut dictionary = StreamDictionary {
+      prefix: shared.prefix,
+      size_bits_by_length: &kBrotliDictionarySizeBitsByLength[..],
+      offsets_by_length: &kBrotliDictionaryOffsetsByLength[..],
+      data: &kBrotliDictionary[..],
+      transforms: shared.transforms,
+    };
+    if let Some(ref words) = shared.words {
+      dictionary.size_bits_by_length = &words.size_bits_by_length[..];
+      dictionary.offsets_by_length = &words.offsets_by_length[..];
+      dictionary.data = words.data;
+    }
+    dictionary
+  }
+  // Writes the transformed word to out and returns its length, or None if there is no such
+  // word or transform.
+  fn word(&self, word_size: usize, offset: usize, out: &mut [u8]) -> Option<usize> {
+    let ndbits = match self.size_bits_by_length.get(word_size) {
+      Some(ndbits) if *ndbits != 0 => *ndbits as usize,
+      _ => return None,
+    };
+    let word_id = offset & ((1 << ndbits) - 1);
+    let transform = offset >> ndbits;
+    let word_index = word_id * word_size + self.offsets_by_length[word_size] as usize;
+    let word = &self.data[word_index..word_index + word_size];
+    match self.transforms {
+      Some(ref transforms) => {
+        if transform >= transforms.num_transforms() {
+          return None;
+        }
+        Some(TransformSharedDictionaryWord(transforms, out, word, transform))
+      }
+      None => {
+        if transform >= kNumTransforms as usize {
+          return None;
+        }
+        Some(TransformDictionaryWord(out, word, word_size as i32, transform as i32) as usize)
+      }
+    }
+  }
+}
+
+fn ReadCompressedMetaBlock(br: &mut BitReader,
+                           window: &mut Window,
+                           dictionary: &StreamDictionary,
+                           dist_cache: &mut [usize; 4],
+                           large_window: bool,
+                           report: &mut BrotliMetaBlockReport)
+                           -> Result<(), BrotliAnalyzeError> {
+  let mut literal_blocks = BlockState::read(br, &mut report.literal)?;
+  let mut command_blocks = BlockState::read(br, &mut report.command)?;
+  let mut distance_blocks = BlockState::read(br, &mut report.distance)?;
+  report.npostfix = br.read(2)? as u32;
+  report.ndirect = (br.read(4)? as u32) << report.npostfix;
+  for _ in 0..literal_blocks.num_types {
+    report.context_modes.push(match br.read(2)? {
+      0 => ContextType::CONTEXT_LSB6,
+      1 => ContextType::CONTEXT_MSB6,
+      2 => ContextType::CONTEXT_UTF8,
+      _ => ContextType::CONTEXT_SIGNED,
+    });
+  }
+  let start = br.pos;
+  let num_literal_trees = ReadVarLenUint8(br)? + 1;
+  report.literal.context_map = ReadContextMap(br, literal_blocks.num_types << 6, num_literal_trees)?;
+  report.literal.context_map_bits = br.pos - start;
+  let start = br.pos;
+  let num_distance_trees = ReadVarLenUint8(br)? + 1;
+  report.distance.context_map = ReadContextMap(br, distance_blocks.num_types << 2, num_distance_trees)?;
+  report.distance.context_map_bits = br.pos - start;
+  let max_distance_bits = if large_window {
+    BROTLI_LARGE_MAX_DISTANCE_BITS
+  } else {
+    BROTLI_MAX_DISTANCE_BITS
+  };
+  let distance_alphabet_size = (NUM_DISTANCE_SHORT_CODES + report.ndirect +
+                                (max_distance_bits << (report.npostfix + 1))) as usize;
+  let literal_codes = ReadPrefixCodes(br, num_literal_trees, BROTLI_NUM_LITERAL_SYMBOLS, &mut report.literal)?;
+  let command_codes = ReadPrefixCodes(br, command_blocks.num_types, BROTLI_NUM_COMMAND_SYMBOLS, &mut report.command)?;
+  let distance_codes = ReadPrefixCodes(br, num_distance_trees, distance_alphabet_size, &mut report.distance)?;
+  report.header_bits = br.pos - report.start_bit;
+
+  let end = window.pos + report.length;
+  while window.pos < end {
+    let start = br.pos;
+    command_blocks.next(br, &mut report.command)?;
+    report.block_switch_bits += br.pos - start;
+    let start = br.pos;
+    let command_code = command_codes[command_blocks.block_type].read_symbol(br)? as usize;
+    // the 704 insert-and-copy codes come in 11 cells of 8x8 insert and copy length codes
+    let (insert_base, copy_base) = match command_code >> 6 {
+      0 | 2 => (0, 0),
+      1 | 3 => (0, 8),
+      4 => (8, 0),
+      5 => (8, 8),
+      6 => (0, 16),
+      7 => (16, 0),
+      8 => (8, 16),
+      9 => (16, 8),
+      _ => (16, 16),
+    };
+    let insert_code = insert_base + ((command_code >> 3) & 7);
+    let copy_code = copy_base + (command_code & 7);
+    let insert_len = kInsBase[insert_code] as usize + br.read(kInsExtra[insert_code])? as usize;
+    let copy_len = kCopyBase[copy_code] as usize + br.read(kCopyExtra[copy_code])? as usize;
+    report.command_bits += br.pos - start;
+    report.num_commands += 1;
+    if window.pos + insert_len > end {
+      return Err(BrotliAnalyzeError::MetaBlockOverrun(start));
+    }
+    for _ in 0..insert_len {
+      let start = br.pos;
+      literal_blocks.next(br, &mut report.literal)?;
+      report.block_switch_bits += br.pos - start;
+      let start = br.pos;
+      let context = Context(window.back(1), window.back(2), report.context_modes[literal_blocks.block_type]);
+      let tree = report.literal.context_map[(literal_blocks.block_type << 6) + context as usize] as usize;
+      let literal = literal_codes[tree].read_symbol(br)? as u8;
+      report.literal_bits += br.pos - start;
+      window.push(literal);
+    }
+    report.num_literals += insert_len;
+    if window.pos == end {
+      // the copy length of the last command of a metablock is ignored
+      break;
+    }
+    let start = br.pos;
+    let (distance, push) = if command_code < 128 {
+      (dist_cache[0], false)
+    } else {
+      distance_blocks.next(br, &mut report.distance)?;
+      report.block_switch_bits += br.pos - start;
+      let start = br.pos;
+      let context = if copy_len > 4 { 3 } else { copy_len - 2 };
+      let tree = report.distance.context_map[(distance_blocks.block_type << 2) + context] as usize;
+      let distance_code = distance_codes[tree].read_symbol(br)? as u32;
+      let distance = if distance_code < NUM_DISTANCE_SHORT_CODES {
+        let (index, delta) = if distance_code < 4 {
+          (distance_code as usize, 0i64)
+        } else if distance_code < 10 {
+          (0, [-1i64, 1, -2, 2, -3, 3][distance_code as usize - 4])
+        } else {
+          (1, [-1i64, 1, -2, 2, -3, 3][distance_code as usize - 10])
+        };
+        dist_cache[index] as i64 + delta
+      } else if distance_code < NUM_DISTANCE_SHORT_CODES + report.ndirect {
+        i64::from(distance_code - NUM_DISTANCE_SHORT_CODES + 1)
+      } else {
+        let code = distance_code - NUM_DISTANCE_SHORT_CODES - report.ndirect;
+        let postfix_mask = (1u32 << report.npostfix) - 1;
+        let num_extra_bits = 1 + (code >> (report.npostfix + 1));
+        let high = (code >> report.npostfix) & 1;
+        let offset = ((2 + u64::from(high)) << num_extra_bits) - 4;
+        let extra = br.read(num_extra_bits)?;
+        (((offset + extra) << report.npostfix) + u64::from(code & postfix_mask) +
+         u64::from(report.ndirect) + 1) as i64
+      };
+      report.distance_bits += br.pos - start;
+      if distance <= 0 {
+        return Err(BrotliAnalyzeError::InvalidDistance(start));
+      }
+      (distance as usize, distance_code != 0)
+    };
+    let max_distance = core::cmp::min(window.pos, window.max_backward);
+    let address = distance.wrapping_sub(max_distance + 1);
+    if distance > max_distance && address < dictionary.prefix.len() {
+      // a compound dictionary copy, counted back from the end of the prefix, which it must not
+      // run past
+      let offset = dictionary.prefix.len() - 1 - address;
+      if offset + copy_len > dictionary.prefix.len() {
+        return Err(BrotliAnalyzeError::InvalidDistance(start));
+      }
+      if window.pos + copy_len > end {
+        return Err(BrotliAnalyzeError::MetaBlockOverrun(start));
+      }
+      if push {
+        dist_cache[3] = dist_cache[2];
+        dist_cache[2] = dist_cache[1];
+        dist_cache[1] = dist_cache[0];
+        dist_cache[0] = distance;
+      }
+      for byte in dictionary.prefix[offset..offset + copy_len].iter() {
+        window.push(*byte);
+      }
+    } else if distance > max_distance {
+      // a static dictionary reference past the compound dictionary: prefix, word and suffix
+      let mut transformed_word = [0u8; 2 * 255 + 32];
+      let final_size = match dictionary.word(copy_len, address - dictionary.prefix.len(), &mut transformed_word[..]) {
+        Some(final_size) => final_size,
+        None => return Err(BrotliAnalyzeError::InvalidDictionaryWord(start)),
+      };
+      if window.pos + final_size > end {
+        return Err(BrotliAnalyzeError::MetaBlockOverrun(start));
+      }
+      for byte in transformed_word[..final_size].iter() {
+        window.push(*byte);
+      }
+      report.num_dictionary_words += 1;
+    } else {
+      if window.pos + copy_len > end {
+        return Err(BrotliAnalyzeError::MetaBlockOverrun(start));
+      }
+      if push {
+        dist_cache[3] = dist_cache[2];
+        dist_cache[2] = dist_cache[1];
+        dist_cache[1] = dist_cache[0];
+        dist_cache[0] = distance;
+      }
+      for _ in 0..copy_len {
+        let byte = window.back(distance);
+        window.push(byte);
+      }
+    }
+  }
+  Ok(())
+}
+
+fn ReadWindowBits(br: &mut BitReader) -> Result<(u32, bool), BrotliAnalyzeError> {
+  if br.read(1)? == 0 {
+    return Ok((16, false));
+  }
+  let n = br.read(3)? as u32;
+  if n != 0 {
+    return Ok((17 + n, false));
+  }
+  let m = br.read(3)? as u32;
+  if m == 1 {
+    // large window: a reserved bit, then 6 bits of window size
+    if br.read(1)? != 0 {
+      return Err(BrotliAnalyzeError::InvalidWindowBits(0));
+    }
+    let window_bits = br.read(6)? as u32;
+    if window_bits < 10 || window_bits > 30 {
+      return Err(BrotliAnalyzeError::InvalidWindowBits(0));
+    }
+    return Ok((window_bits, true));
+  }
+  Ok((if m == 0 { 17 } else { 8 + m }, false))
+}
+
+// Decodes a whole stream that was compressed with a shared dictionary (see
+// BrotliEncoderSetSharedDictionary), which the decompressor does not support, appending
+// the output to output. The prefix is a compound dictionary as the Shared Brotli draft
+// defines it. Context dependent dictionaries are not supported.
+pub fn BrotliDecodeWithSharedDictionary(data: &[u8],
+                                        dictionary: &BrotliSharedDictionary,
+                                        output: &mut Vec<u8>)
+                                        -> Result<BrotliStreamReport, BrotliAnalyzeError> {
+  BrotliDecodeStream(data, dictionary, Some(output))
+}
+
+pub fn BrotliDecodeStream(data: &[u8],
+                          shared: &BrotliSharedDictionary,
+                          output: Option<&mut Vec<u8>>)
+                          -> Result<BrotliStreamReport, BrotliAnalyzeError> {
+  let mut br = BitReader {
+    data: data,
+    pos: 0,
+  };
+  let (window_bits, large_window) = ReadWindowBits(&mut br)?;
+  let dictionary = StreamDictionary::new(shared);
+  let mut window = Window {
+    ring: vec![0u8; 1 << core::cmp::min(window_bits, 16)],
+    window_size: 1 << window_bits,
+    pos: 0,
+    max_backward: (1 << window_bits) - 16,
+    output: output,
+  };
+  let mut dist_cache = [4usize, 11, 15, 16];
+  let mut metablocks = Vec::new();
+  loop {
+    let start = br.pos;
+    let is_last = br.read(1)? != 0;
+    if is_last && br.read(1)? != 0 {
+      let mut report = NewMetaBlockReport(BrotliMetaBlockType::LastEmpty, true, start);
+      br.align()?;
+      report.header_bits = br.pos - start;
+      metablocks.push(report);
+      break;
+    }
+    let num_nibbles = match br.read(2)? {
+      3 => 0,
+      nibbles => nibbles as u32 + 4,
+    };
+    if num_nibbles == 0 {
+      let mut report = NewMetaBlockReport(BrotliMetaBlockType::Metadata, is_last, start);
+      if br.read(1)? != 0 {
+        return Err(BrotliAnalyzeError::InvalidMetaBlockHeader(start));
+      }
+      let num_bytes = br.read(2)? as u32;
+      if num_bytes != 0 {
+        report.length = br.read(num_bytes * 8)? as usize + 1;
+      }
+      br.align()?;
+      br.skip(report.length * 8)?;
+      report.header_bits = br.pos - start;
+      metablocks.push(report);
+      if is_last {
+        break;
+      }
+      continue;
+    }
+    let length = br.read(num_nibbles * 4)? as usize + 1;
+    let is_uncompressed = !is_last && br.read(1)? != 0;
+    if is_uncompressed {
+      let mut report = NewMetaBlockReport(BrotliMetaBlockType::Uncompressed, is_last, start);
+      report.length = length;
+      br.align()?;
+      report.header_bits = br.pos - start;
+      if br.pos / 8 + length > data.len() {
+        return Err(BrotliAnalyzeError::Truncated(br.pos));
+      }
+      for byte in data[br.pos / 8..br.pos / 8 + length].iter() {
+        window.push(*byte);
+      }
+      br.pos += length * 8;
+      report.num_literals = length;
+      report.literal_bits = length * 8;
+      metablocks.push(report);
+      continue;
+    }
+    let mut report = NewMetaBlockReport(BrotliMetaBlockType::Compressed, is_last, start);
+    report.length = length;
+    ReadCompressedMetaBlock(&mut br, &mut window, &dictionary, &mut dist_cache, large_window, &mut report)?;
+    metablocks.push(report);
+    if is_last {
+      br.align()?;
+      break;
+    }
+  }
+  Ok(BrotliStreamReport {
+    window_bits: window_bits,
+    large_window: large_window,
+    metablocks: metablocks,
+    output_size: window.pos,
+    total_bits: br.pos,
+    trailing_bytes: data.len() - br.pos / 8,
+  })
+}
@@ -102,9 +102,9 @@ impl<'a,
       ret
    }
    pub fn choose_stride(&self, stride_data: &mut[u8]) {
-       assert_eq!(stride_data.len(), self.cur_score_epoch);
-       assert!(self.score.slice().len() > stride_data.len());
-       assert!(self.score.slice().len() > (stride_data.len() << 3) + 7 + 8);
+       debug_assert_eq!(stride_data.len(), self.cur_score_epoch);
+       debug_assert!(self.score.slice().len() > stride_data.len());
+       debug_assert!(self.score.slice().len() > (stride_data.len() << 3) + 7 + 8);
        for (index, choice) in stride_data.iter_mut().enumerate() {
            let choices = self.score.slice().split_at((1 + index) << 3).1.split_at(8).0;
            let mut best_choice: u8 = 0;
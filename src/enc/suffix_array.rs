@@ -0,0 +1,401 @@
+use super::hash_to_binary_tree::{BackwardMatchMut, InitBackwardMatch};
+use super::super::alloc::{SliceWrapper, SliceWrapperMut, Allocator};
+use core;
+
+// shortest copy the format can express
+const SUFFIX_ARRAY_MIN_MATCH_LEN: usize = 2;
+// the zopfli search keeps only the longest match of a position once it is longer than this
+const SUFFIX_ARRAY_MAX_USED_LEN: usize = 325;
+// upper bound on the matches reported for one position: every length from
+// SUFFIX_ARRAY_MIN_MATCH_LEN to SUFFIX_ARRAY_MAX_USED_LEN and the longest one
+pub const SUFFIX_ARRAY_MAX_MATCHES: usize = SUFFIX_ARRAY_MAX_USED_LEN - SUFFIX_ARRAY_MIN_MATCH_LEN + 2;
+
+// Exhaustive match finder for the maximum-ratio mode.
+// A range of the stream is indexed with a suffix array and its LCP table. The earlier
+// occurrences of a position that share at least L bytes with it are exactly the suffixes in
+// one interval of ranks around the position's own, and those intervals nest as L shrinks.
+// Walking the intervals from the innermost outward and asking each for its closest earlier
+// occurrence yields every match that is closer than all longer ones, without a bound on the
+// number of occurrences or on the search depth.
+pub struct SuffixArrayMatchFinder<AllocU32: Allocator<u32>> {
+  // stream position of the first indexed byte
+  pub start_: usize,
+  // number of indexed bytes
+  pub len_: usize,
+  // rank_[i] is the rank of the suffix at offset i (from start_)
+  rank_: AllocU32::AllocatedMemory,
+  // lcp_[r] is the length of the common prefix of the suffixes of rank r - 1 and r;
+  // lcp_[0] and lcp_[len_] are 0
+  lcp_: AllocU32::AllocatedMemory,
+  // psv_[r] is the largest r' < r with lcp_[r'] < lcp_[r], nsv_[r] the smallest r' > r
+  psv_: AllocU32::AllocatedMemory,
+  nsv_: AllocU32::AllocatedMemory,
+  // wavelet matrix over the suffix array, so that the closest earlier occurrence in a range
+  // of ranks takes one pass over the bits of the offsets. Each level holds pairs of a 32 bit
+  // word and the number of ones in the words before it.
+  wavelet_: AllocU32::AllocatedMemory,
+  levels_: usize,
+  zeros_: [u32; 32],
+}
+
+#[inline(always)]
+fn ByteAt(ringbuffer: &[u8], ringbuffer_mask: usize, pos: usize) -> u8 {
+  ringbuffer[pos & ringbuffer_mask]
+}
+
+#[inline(always)]
+fn WaveletStride(len: usize) -> usize {
+  ((len >> 5) + 1) << 1
+}
+
+// Indexes the stream bytes in [start, end) that are still present in the ringbuffer.
+pub fn InitializeSuffixArrayMatchFinder<AllocU32: Allocator<u32>>(
+  m32: &mut AllocU32,
+  ringbuffer: &[u8],
+  ringbuffer_mask: usize,
+  start: usize,
+  end: usize,
+) -> SuffixArrayMatchFinder<AllocU32> {
+  let n = end - start;
+  if n == 0 {
+    return SuffixArrayMatchFinder::<AllocU32> {
+      start_: start,
+      len_: 0,
+      rank_: AllocU32::AllocatedMemory::default(),
+      lcp_: AllocU32::AllocatedMemory::default(),
+      psv_: AllocU32::AllocatedMemory::default(),
+      nsv_: AllocU32::AllocatedMemory::default(),
+      wavelet_: AllocU32::AllocatedMemory::default(),
+      levels_: 0,
+      zeros_: [0; 32],
+    };
+  }
+  let mut sa = m32.alloc_cell(n);
+  let mut rank = m32.alloc_cell(n);
+  let mut tmp = m32.alloc_cell(n);
+  let mut count = m32.alloc_cell(core::cmp::max(n, 256) + 1);
+  {
+    // bucket sort on the first byte; ranks start out as the byte values
+    let cnt = count.slice_mut();
+    for item in cnt.iter_mut() {
+      *item = 0;
+    }
+    for i in 0..n {
+      let b = ByteAt(ringbuffer, ringbuffer_mask, start + i) as usize;
+      rank.slice_mut()[i] = b as u32;
+      cnt[b + 1] += 1;
+    }
+    for b in 0..256 {
+      cnt[b + 1] += cnt[b];
+    }
+    for i in 0..n {
+      let b = rank.slice()[i] as usize;
+      sa.slice_mut()[cnt[b] as usize] = i as u32;
+      cnt[b] += 1;
+    }
+  }
+  // prefix doubling: after the pass for k, suffixes are ordered by their first 2k bytes
+  let mut num_classes = 256usize;
+  let mut k = 1usize;
+  loop {
+    {
+      let sa_s = sa.slice_mut();
+      let rank_s = rank.slice();
+      let tmp_s = tmp.slice_mut();
+      let cnt = count.slice_mut();
+      // order by the second half: suffixes without one come first
+      let mut p = 0usize;
+      for i in n.saturating_sub(k)..n {
+        tmp_s[p] = i as u32;
+        p += 1;
+      }
+      for item in sa_s.iter() {
+        let s = *item as usize;
+        if s >= k {
+          tmp_s[p] = (s - k) as u32;
+          p += 1;
+        }
+      }
+      // stable counting sort by the first half
+      for item in cnt[..num_classes + 1].iter_mut() {
+        *item = 0;
+      }
+      for i in 0..n {
+        cnt[rank_s[i] as usize + 1] += 1;
+      }
+      for c in 0..num_classes {
+        cnt[c + 1] += cnt[c];
+      }
+      for item in tmp_s.iter() {
+        let s = *item as usize;
+        let c = rank_s[s] as usize;
+        sa_s[cnt[c] as usize] = s as u32;
+        cnt[c] += 1;
+      }
+      // assign the new ranks into tmp
+      let second = |x: usize| -> u32 { if x + k < n { rank_s[x + k] + 1 } else { 0 } };
+      let mut classes = 1usize;
+      tmp_s[sa_s[0] as usize] = 0;
+      for r in 1..n {
+        let prev = sa_s[r - 1] as usize;
+        let cur = sa_s[r] as usize;
+        if rank_s[prev] != rank_s[cur] || second(prev) != second(cur) {
+          classes += 1;
+        }
+        tmp_s[cur] = (classes - 1) as u32;
+      }
+      num_classes = classes;
+    }
+    core::mem::swap(&mut rank, &mut tmp);
+    if num_classes == n {
+      break;
+    }
+    k <<= 1;
+  }
+  m32.free_cell(count);
+  let mut lcp = m32.alloc_cell(n + 1);
+  {
+    // Kasai et al: the LCP of consecutive suffixes in text order drops by at most one
+    let sa_s = sa.slice();
+    let rank_s = rank.slice();
+    let lcp_s = lcp.slice_mut();
+    let mut h = 0usize;
+    lcp_s[0] = 0;
+    lcp_s[n] = 0;
+    for i in 0..n {
+      let r = rank_s[i] as usize;
+      if r == 0 {
+        h = 0;
+        continue;
+      }
+      let j = sa_s[r - 1] as usize;
+      while i + h < n && j + h < n &&
+            ByteAt(ringbuffer, ringbuffer_mask, start + i + h) ==
+            ByteAt(ringbuffer, ringbuffer_mask, start + j + h) {
+        h += 1;
+      }
+      lcp_s[r] = h as u32;
+      h = h.saturating_sub(1);
+    }
+  }
+  let mut psv = m32.alloc_cell(n + 1);
+  let mut nsv = m32.alloc_cell(n + 1);
+  {
+    // following the links of the neighbours keeps both passes linear
+    let lcp_s = lcp.slice();
+    let psv_s = psv.slice_mut();
+    psv_s[0] = 0;
+    for r in 1..n + 1 {
+      let mut j = r - 1;
+      while j != 0 && lcp_s[j] >= lcp_s[r] {
+        j = psv_s[j] as usize;
+      }
+      psv_s[r] = j as u32;
+    }
+    let nsv_s = nsv.slice_mut();
+    nsv_s[n] = n as u32;
+    for r in (0..n).rev() {
+      let mut j = r + 1;
+      while j != n && lcp_s[j] >= lcp_s[r] {
+        j = nsv_s[j] as usize;
+      }
+      nsv_s[r] = j as u32;
+    }
+  }
+  let mut levels = 1usize;
+  while (n - 1) >> levels != 0 {
+    levels += 1;
+  }
+  let stride = WaveletStride(n);
+  let mut wavelet = m32.alloc_cell(stride * levels);
+  let mut zeros = [0u32; 32];
+  {
+    // each level stably moves the offsets with a 0 in its bit ahead of those with a 1
+    let wavelet_s = wavelet.slice_mut();
+    for level in 0..levels {
+      let bit = levels - 1 - level;
+      let words = &mut wavelet_s[level * stride..(level + 1) * stride];
+      for item in words.iter_mut() {
+        *item = 0;
+      }
+      let mut num_zeros = 0usize;
+      for (i, item) in sa.slice().iter().enumerate() {
+        if (*item >> bit) & 1 != 0 {
+          words[(i >> 5) << 1] |= 1u32 << (i & 31);
+        } else {
+          num_zeros += 1;
+        }
+      }
+      let mut ones = 0u32;
+      for w in 0..stride >> 1 {
+        words[(w << 1) + 1] = ones;
+        ones += words[w << 1].count_ones();
+      }
+      zeros[level] = num_zeros as u32;
+      let (mut z, mut o) = (0usize, num_zeros);
+      let tmp_s = tmp.slice_mut();
+      for item in sa.slice().iter() {
+        if (*item >> bit) & 1 != 0 {
+          tmp_s[o] = *item;
+          o += 1;
+        } else {
+          tmp_s[z] = *item;
+          z += 1;
+        }
+      }
+      core::mem::swap(&mut sa, &mut tmp);
+    }
+  }
+  m32.free_cell(sa);
+  m32.free_cell(tmp);
+  SuffixArrayMatchFinder::<AllocU32> {
+    start_: start,
+    len_: n,
+    rank_: rank,
+    lcp_: lcp,
+    psv_: psv,
+    nsv_: nsv,
+    wavelet_: wavelet,
+    levels_: levels,
+    zeros_: zeros,
+  }
+}
+
+impl<AllocU32: Allocator<u32>> SuffixArrayMatchFinder<AllocU32> {
+  pub fn free(&mut self, m32: &mut AllocU32) {
+    m32.free_cell(core::mem::replace(&mut self.rank_, AllocU32::AllocatedMemory::default()));
+    m32.free_cell(core::mem::replace(&mut self.lcp_, AllocU32::AllocatedMemory::default()));
+    m32.free_cell(core::mem::replace(&mut self.psv_, AllocU32::AllocatedMemory::default()));
+    m32.free_cell(core::mem::replace(&mut self.nsv_, AllocU32::AllocatedMemory::default()));
+    m32.free_cell(core::mem::replace(&mut self.wavelet_, AllocU32::AllocatedMemory::default()));
+    self.len_ = 0;
+  }
+
+  // whether the stream positions in [start, end) are all indexed
+  pub fn covers(&self, start: usize, end: usize) -> bool {
+    self.start_ <= start && end <= self.start_ + self.len_
+  }
+
+  // number of ones among the first i bits of a level
+  #[inline(always)]
+  fn Ones(&self, level: usize, i: usize) -> usize {
+    let words = &self.wavelet_.slice()[level * WaveletStride(self.len_)..];
+    let w = (i >> 5) << 1;
+    let mask = (1u32 << (i & 31)).wrapping_sub(1);
+    words[w + 1] as usize + (words[w] & mask).count_ones() as usize
+  }
+
+  // The largest offset below limit among the suffixes of rank lo to hi inclusive.
+  fn ClosestBefore(&self, lo: usize, hi: usize, limit: usize) -> Option<usize> {
+    // count the offsets below limit, then select the largest of them
+    let (mut l, mut r) = (lo, hi + 1);
+    let mut below = 0usize;
+    if limit >> self.levels_ != 0 {
+      below = r - l;
+    } else {
+      for level in 0..self.levels_ {
+        let (l1, r1) = (self.Ones(level, l), self.Ones(level, r));
+        if (limit >> (self.levels_ - 1 - level)) & 1 != 0 {
+          below += (r - l) - (r1 - l1);
+          l = self.zeros_[level] as usize + l1;
+          r = self.zeros_[level] as usize + r1;
+        } else {
+          l -= l1;
+          r -= r1;
+        }
+      }
+    }
+    if below == 0 {
+      return None;
+    }
+    let mut k = below - 1;
+    let (mut l, mut r) = (lo, hi + 1);
+    let mut value = 0usize;
+    for level in 0..self.levels_ {
+      let (l1, r1) = (self.Ones(level, l), self.Ones(level, r));
+      let num_zeros = (r - l) - (r1 - l1);
+      if k < num_zeros {
+        l -= l1;
+        r -= r1;
+      } else {
+        k -= num_zeros;
+        value |= 1 << (self.levels_ - 1 - level);
+        l = self.zeros_[level] as usize + l1;
+        r = self.zeros_[level] as usize + r1;
+      }
+    }
+    Some(value)
+  }
+}
+
+// Writes the matches for cur_ix in the same layout as FindAllMatchesH10: sorted by
+// increasing length, each one strictly longer and strictly farther than the last.
+// Every match that is closer than all longer ones is reported, except that once the
+// longest is past SUFFIX_ARRAY_MAX_USED_LEN the ones between that and it are left out.
+// best_len is raised to the length of the longest match found.
+pub fn FindAllMatchesSuffixArray<AllocU32: Allocator<u32>>(
+  handle: &SuffixArrayMatchFinder<AllocU32>,
+  cur_ix: usize,
+  max_length: usize,
+  max_backward: usize,
+  best_len: &mut usize,
+  matches: &mut [u64],
+) -> usize {
+  if cur_ix < handle.start_ || cur_ix >= handle.start_ + handle.len_ {
+    return 0;
+  }
+  let p = cur_ix - handle.start_;
+  let lcp = handle.lcp_.slice();
+  let psv = handle.psv_.slice();
+  let nsv = handle.nsv_.slice();
+  let rank = handle.rank_.slice()[p] as usize;
+  // found[..num_found] holds (length, distance) by decreasing length and distance
+  let mut found = [(0u32, 0u32); SUFFIX_ARRAY_MAX_MATCHES];
+  let mut num_found = 0usize;
+  let mut closest = 0usize; // offset + 1 of the closest occurrence reported so far
+  let (mut lo, mut hi) = (rank, rank);
+  loop {
+    // the next enclosing interval: the ranks whose suffixes share len bytes with p
+    let len = core::cmp::max(lcp[lo], lcp[hi + 1]) as usize;
+    if len < SUFFIX_ARRAY_MIN_MATCH_LEN {
+      break;
+    }
+    if lcp[lo] as usize == len {
+      lo = psv[lo] as usize;
+    }
+    if lcp[hi + 1] as usize == len {
+      hi = nsv[hi + 1] as usize - 1;
+    }
+    let capped_len = core::cmp::min(len, max_length) as u32;
+    if num_found != 0 && found[0].0 as usize > SUFFIX_ARRAY_MAX_USED_LEN && found[0].0 != capped_len {
+      // only the longest match is used, and no closer occurrence of it is left
+      break;
+    }
+    let q = match handle.ClosestBefore(lo, hi, p) {
+      Some(q) => q,
+      None => continue,
+    };
+    if q < closest || p - q > max_backward {
+      continue;
+    }
+    if num_found != 0 && found[num_found - 1].0 == capped_len {
+      // a closer occurrence of a match that max_length cut short
+      num_found -= 1;
+    }
+    found[num_found] = (capped_len, (p - q) as u32);
+    num_found += 1;
+    closest = q + 1;
+    if closest == p {
+      break;
+    }
+  }
+  for (index, item) in found[..num_found].iter().rev().enumerate() {
+    InitBackwardMatch(&mut BackwardMatchMut(&mut matches[index]),
+                      item.1 as usize,
+                      item.0 as usize);
+  }
+  if num_found != 0 && found[0].0 as usize > *best_len {
+    *best_len = found[0].0 as usize;
+  }
+  num_found
+}
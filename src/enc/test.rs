@@ -7,7 +7,8 @@ use super::cluster::HistogramPair;
 use super::ZopfliNode;
 use super::encode::{BrotliEncoderCreateInstance, BrotliEncoderSetParameter,
                     BrotliEncoderDestroyInstance, BrotliEncoderIsFinished,
-                    BrotliEncoderCompressStream, BrotliEncoderParameter, BrotliEncoderOperation};
+                    BrotliEncoderCompressStream, BrotliEncoderParameter, BrotliEncoderOperation,
+                    BrotliEncoderSetSharedDictionary, BrotliEncoderGetStaticDictionaryStats};
 use super::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance};
 use super::super::alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut,
                           StackAllocator, bzero};
@@ -39,6 +40,38 @@ fn oneshot_compress(input: &[u8],
                     in_batch_size: usize,
                     out_batch_size: usize)
                     -> (i32, usize) {
+  oneshot_compress_with_dictionary(input, output, quality, lgwin, in_batch_size, out_batch_size, &[])
+}
+
+fn oneshot_compress_with_dictionary(input: &[u8],
+                                    output: &mut [u8],
+                                    quality: u32,
+                                    lgwin: u32,
+                                    in_batch_size: usize,
+                                    out_batch_size: usize,
+                                    shared_dictionary: &[u8])
+                                    -> (i32, usize) {
+  let (success, output_size, _) = oneshot_compress_with_params(input,
+                                                               output,
+                                                               quality,
+                                                               lgwin,
+                                                               in_batch_size,
+                                                               out_batch_size,
+                                                               shared_dictionary,
+                                                               &[]);
+  (success, output_size)
+}
+
+// Also sets extra_params after the others and returns the static dictionary stats of the encoder.
+fn oneshot_compress_with_params(input: &[u8],
+                                output: &mut [u8],
+                                quality: u32,
+                                lgwin: u32,
+                                in_batch_size: usize,
+                                out_batch_size: usize,
+                                shared_dictionary: &[u8],
+                                extra_params: &[(BrotliEncoderParameter, u32)])
+                                -> (i32, usize, (usize, usize)) {
   let stack_u8_buffer =
     unsafe { define_allocator_memory_pool!(96, u8, [0; 24 * 1024 * 1024], calloc) };
   let stack_u16_buffer =
@@ -48,7 +81,7 @@ fn oneshot_compress(input: &[u8],
   let stack_u32_buffer =
     unsafe { define_allocator_memory_pool!(96, u32, [0; 32 * 1024 * 1024], calloc) };
   let stack_u64_buffer =
-    unsafe { define_allocator_memory_pool!(96, u64, [0; 32 * 1024], calloc) };
+    unsafe { define_allocator_memory_pool!(96, u64, [0; 1024 * 1024], calloc) };
   let stack_f64_buffer =
     unsafe { define_allocator_memory_pool!(48, super::util::floatX, [0; 128 * 1024], calloc) };
   let stack_fv_buffer =
@@ -66,7 +99,7 @@ fn oneshot_compress(input: &[u8],
   let stack_ht_buffer =
     unsafe { define_allocator_memory_pool!(48, HuffmanTree, [0; 128 * 1024], calloc) };
   let stack_zn_buffer =
-    unsafe { define_allocator_memory_pool!(48, ZopfliNode, [0; 1024], calloc) };
+    unsafe { define_allocator_memory_pool!(48, ZopfliNode, [0; 64 * 1024], calloc) };
   let stack_mc_buffer =
     unsafe { define_allocator_memory_pool!(48, Command, [0; 128 * 1024], calloc) };
   let stack_pdf_buffer =
@@ -106,6 +139,7 @@ fn oneshot_compress(input: &[u8],
                                                stack_mc_allocator);
   let mut next_in_offset: usize = 0;
   let mut next_out_offset: usize = 0;
+  let dictionary_stats: (usize, usize);
   {
     let s = &mut s_orig;
 
@@ -124,6 +158,12 @@ fn oneshot_compress(input: &[u8],
     BrotliEncoderSetParameter(s,
                               BrotliEncoderParameter::BROTLI_PARAM_SIZE_HINT,
                               input.len() as (u32));
+    if shared_dictionary.len() != 0 {
+      assert_eq!(BrotliEncoderSetSharedDictionary(s, shared_dictionary), 1);
+    }
+    for &(param, value) in extra_params.iter() {
+      assert_eq!(BrotliEncoderSetParameter(s, param, value), 1);
+    }
     loop {
       let mut available_in: usize = brotli_min_size_t(input.len() - next_in_offset, in_batch_size);
       let mut available_out: usize = brotli_min_size_t(output.len() - next_out_offset,
@@ -168,20 +208,27 @@ fn oneshot_compress(input: &[u8],
                                                &mut total_out,
                                                &mut nop_callback);
       if result <= 0 {
-        return (result, next_out_offset);
+        return (result, next_out_offset, (0, 0));
       }
       if BrotliEncoderIsFinished(s) != 0 {
         break;
       }
     }
-
+    dictionary_stats = BrotliEncoderGetStaticDictionaryStats(s);
     BrotliEncoderDestroyInstance(s);
   }
 
-  return (1, next_out_offset);
+  return (1, next_out_offset, dictionary_stats);
 }
 
-fn oneshot_decompress(compressed: &[u8], mut output: &mut [u8]) -> (BrotliResult, usize, usize) {
+fn oneshot_decompress(compressed: &[u8], output: &mut [u8]) -> (BrotliResult, usize, usize) {
+  oneshot_decompress_with_dictionary(compressed, output, &[])
+}
+
+fn oneshot_decompress_with_dictionary(compressed: &[u8],
+                                      mut output: &mut [u8],
+                                      custom_dictionary: &[u8])
+                                      -> (BrotliResult, usize, usize) {
   let mut available_in: usize = compressed.len();
   let mut available_out: usize = output.len();
   let mut stack_u8_buffer = define_allocator_memory_pool!(128, u8, [0; 100 * 1024], stack);
@@ -197,8 +244,14 @@ fn oneshot_decompress(compressed: &[u8], mut output: &mut [u8]) -> (BrotliResult
   let mut input_offset: usize = 0;
   let mut output_offset: usize = 0;
   let mut written: usize = 0;
-  let mut brotli_state =
-    BrotliState::new(stack_u8_allocator, stack_u32_allocator, stack_hc_allocator);
+  let mut brotli_state = if custom_dictionary.len() != 0 {
+    let mut stack_u8_allocator = stack_u8_allocator;
+    let mut dict = stack_u8_allocator.alloc_cell(custom_dictionary.len());
+    dict.slice_mut().clone_from_slice(custom_dictionary);
+    BrotliState::new_with_custom_dictionary(stack_u8_allocator, stack_u32_allocator, stack_hc_allocator, dict)
+  } else {
+    BrotliState::new(stack_u8_allocator, stack_u32_allocator, stack_hc_allocator)
+  };
   let result = BrotliDecompressStream(&mut available_in,
                                       &mut input_offset,
                                       &compressed[..],
@@ -425,3 +478,377 @@ impl io::Write for Buffer {
 
 
 */
+
+// Serializes a shared dictionary with the given LZ77 prefix. With with_words set it also
+// carries a copy of the built-in word list and transforms that agree with the built-in
+// ones on the cutoff transforms and are plain copies of transform 0 otherwise, which the
+// search never prefers to it, so the stream stays decodable without shared dictionary support.
+fn serialize_shared_dictionary(prefix: &[u8], with_words: bool, out: &mut [u8]) -> usize {
+  use super::super::dictionary::{kBrotliDictionary, kBrotliDictionarySizeBitsByLength};
+  use super::static_dict::kOmitLastNTransforms;
+  let mut pos = 0usize;
+  let mut push = |out: &mut [u8], bytes: &[u8]| {
+    out[pos..pos + bytes.len()].clone_from_slice(bytes);
+    pos += bytes.len();
+    pos
+  };
+  push(out, &[0x91, 0x00]);
+  let mut prefix_size = prefix.len();
+  while prefix_size >= 0x80 {
+    push(out, &[(prefix_size & 0x7f) as u8 | 0x80]);
+    prefix_size >>= 7;
+  }
+  push(out, &[prefix_size as u8]);
+  push(out, prefix);
+  if !with_words {
+    // no word or transform lists, one dictionary using the built-in ones, no context map
+    return push(out, &[0, 0, 1, 0, 0, 0]);
+  }
+  push(out, &[1]);
+  push(out, &kBrotliDictionarySizeBitsByLength[4..]);
+  push(out, &[0u8; 7]);
+  push(out, &kBrotliDictionary[..]);
+  // strings: " " and the empty one
+  push(out, &[1, 3, 0, 1, b' ', 0]);
+  push(out, &[121]);
+  for transform_id in 0..121 {
+    match kOmitLastNTransforms.iter().position(|id| *id as usize == transform_id) {
+      Some(cut) => push(out, &[1, cut as u8, 1]),
+      None => push(out, &[0, 0, 1]),
+    };
+  }
+  push(out, &[1, 0, 0, 0])
+}
+
+fn shared_dictionary_roundtrip(input: &[u8], prefix: &[u8], with_words: bool, quality: u32) -> usize {
+  let mut dict = [0u8; 140 * 1024];
+  let dict_size = serialize_shared_dictionary(prefix, with_words, &mut dict[..]);
+  let mut compressed = [0u8; 64 * 1024];
+  let mut output = [0u8; 64 * 1024];
+  let (success, compressed_size) = oneshot_compress_with_dictionary(input,
+                                                                    &mut compressed[..],
+                                                                    quality,
+                                                                    22,
+                                                                    4096,
+                                                                    4096,
+                                                                    &dict[..dict_size]);
+  assert_eq!(success, 1);
+  let (result, _, output_size) =
+    oneshot_decompress_with_dictionary(&compressed[..compressed_size], &mut output[..], prefix);
+  match result {
+    BrotliResult::ResultSuccess => {}
+    _ => assert!(false),
+  }
+  assert_eq!(output_size, input.len());
+  assert_eq!(&output[..output_size], input);
+  compressed_size
+}
+
+#[test]
+fn test_shared_dictionary_prefix() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let input = &alice[8192..24576];
+  let with_prefix = shared_dictionary_roundtrip(input, &alice[..16384], false, 9);
+  let without_prefix = shared_dictionary_roundtrip(input, &[], false, 9);
+  assert!(with_prefix * 2 < without_prefix, "{} {}", with_prefix, without_prefix);
+}
+
+#[test]
+fn test_shared_dictionary_words() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  for quality in [5u32, 9, 10, 11].iter() {
+    shared_dictionary_roundtrip(&alice[..32768], &[], true, *quality);
+    shared_dictionary_roundtrip(&alice[32768..49152], &alice[..16384], true, *quality);
+  }
+}
+
+// A stream written by hand from the Shared Brotli draft, lgwin 16, over a 20 byte prefix: a
+// copy of 4 at distance 10, which reaches 9 bytes past the empty window into the prefix, and
+// a copy of 4 at distance 25, which is past the window and the prefix and so is the first
+// word of length 4 in the static dictionary.
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_shared_dictionary_compound_distances() {
+  use std::vec::Vec;
+  use super::super::dictionary::{kBrotliDictionary, kBrotliDictionaryOffsetsByLength};
+  use super::stream_decoder::BrotliDecodeWithSharedDictionary;
+  use super::shared_dictionary::BrotliParseSharedDictionary;
+  let prefix = b"0123456789abcdefghij";
+  let mut stream = [0u8; 32];
+  let mut bit_pos = 0usize;
+  {
+    let mut write = |nbits: usize, value: usize| {
+      for i in 0..nbits {
+        stream[(bit_pos + i) >> 3] |= (((value >> i) & 1) << ((bit_pos + i) & 7)) as u8;
+      }
+      bit_pos += nbits;
+    };
+    write(1, 0); // WBITS 16
+    write(1, 1); // ISLAST
+    write(1, 0); // ISLASTEMPTY
+    write(2, 0); // MNIBBLES 4
+    write(16, 8 - 1); // MLEN - 1
+    write(3, 0); // one block type for literals, commands and distances
+    write(2, 0); // NPOSTFIX
+    write(4, 0); // NDIRECT
+    write(2, 0); // literal context mode
+    write(2, 0); // one literal and one distance tree
+    write(2, 1); // simple literal code,
+    write(2, 0); // of one symbol
+    write(8, 0);
+    write(2, 1); // simple command code,
+    write(2, 0); // of one symbol: insert 0, copy 4, explicit distance
+    write(10, 130);
+    write(2, 1); // simple distance code,
+    write(2, 1); // of two symbols: distances 9..12 and 21..28
+    write(6, 19);
+    write(6, 21);
+    write(1, 0); // distance code 19,
+    write(2, 1); // distance 10
+    write(1, 1); // distance code 21,
+    write(3, 4); // distance 25
+  }
+  let stream_size = (bit_pos + 7) >> 3;
+  let mut dict = [0u8; 64];
+  let dict_size = serialize_shared_dictionary(&prefix[..], false, &mut dict[..]);
+  let parsed = BrotliParseSharedDictionary(&dict[..dict_size]).unwrap();
+  let mut output = Vec::new();
+  BrotliDecodeWithSharedDictionary(&stream[..stream_size], &parsed, &mut output).unwrap();
+  let word_offset = kBrotliDictionaryOffsetsByLength[4] as usize;
+  assert_eq!(&output[..4], b"abcd");
+  assert_eq!(&output[4..], &kBrotliDictionary[word_offset..word_offset + 4]);
+}
+
+// Compresses text longer than a 1KiB window that repeats the end of the prefix followed by
+// its own start, so the longest matches run from the prefix into the stream, and decodes it
+// with the shared dictionary. Past the window the static dictionary distances keep skipping
+// the whole prefix.
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_shared_dictionary_prefix_small_window() {
+  use std::vec::Vec;
+  use super::stream_decoder::BrotliDecodeWithSharedDictionary;
+  use super::shared_dictionary::BrotliParseSharedDictionary;
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let prefix = &alice[..2048];
+  let mut input = Vec::new();
+  input.extend_from_slice(&alice[5000..5300]);
+  input.extend_from_slice(&prefix[1948..]);
+  input.extend_from_slice(&alice[5000..5300]);
+  input.extend_from_slice(&alice[6000..14000]);
+  input.extend_from_slice(&prefix[1948..]);
+  let mut dict = [0u8; 140 * 1024];
+  let mut compressed = [0u8; 16 * 1024];
+  for with_words in [false, true].iter() {
+    let dict_size = serialize_shared_dictionary(prefix, *with_words, &mut dict[..]);
+    let parsed = BrotliParseSharedDictionary(&dict[..dict_size]).unwrap();
+    for quality in [2u32, 5, 9, 10, 11].iter() {
+      let (success, compressed_size) = oneshot_compress_with_dictionary(&input[..],
+                                                                        &mut compressed[..],
+                                                                        *quality,
+                                                                        10,
+                                                                        4096,
+                                                                        4096,
+                                                                        &dict[..dict_size]);
+      assert_eq!(success, 1);
+      let mut output = Vec::new();
+      let report = BrotliDecodeWithSharedDictionary(&compressed[..compressed_size], &parsed, &mut output).unwrap();
+      assert!(output[..] == input[..], "quality {}", quality);
+      if *quality > 2 {
+        // static dictionary distances past the window and the prefix
+        assert!(report.metablocks.iter().map(|metablock| metablock.num_dictionary_words).sum::<usize>() > 0);
+      }
+    }
+  }
+}
+
+// The same input, compressed by libbrotlienc 1.2.0 at quality 5 and 11 with lgwin 10 and the
+// prefix attached as a raw dictionary (BrotliEncoderPrepareDictionary with type 0), so the
+// decoder is checked against streams that this encoder did not write.
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_shared_dictionary_prefix_reference_streams() {
+  use std::vec::Vec;
+  use super::stream_decoder::BrotliDecodeWithSharedDictionary;
+  use super::shared_dictionary::BrotliParseSharedDictionary;
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let prefix = &alice[..2048];
+  let mut input = Vec::new();
+  input.extend_from_slice(&alice[5000..5300]);
+  input.extend_from_slice(&prefix[1948..]);
+  input.extend_from_slice(&alice[5000..5300]);
+  input.extend_from_slice(&alice[6000..14000]);
+  input.extend_from_slice(&prefix[1948..]);
+  let mut dict = [0u8; 4096];
+  let dict_size = serialize_shared_dictionary(prefix, false, &mut dict[..]);
+  let parsed = BrotliParseSharedDictionary(&dict[..dict_size]).unwrap();
+  let streams: [&[u8]; 2] = [include_bytes!("../../testdata/alice29.prefix2048.q5.compressed"),
+                             include_bytes!("../../testdata/alice29.prefix2048.q11.compressed")];
+  for stream in streams.iter() {
+    let mut output = Vec::new();
+    let report = BrotliDecodeWithSharedDictionary(stream, &parsed, &mut output).unwrap();
+    assert_eq!(report.window_bits, 10);
+    assert!(output[..] == input[..]);
+    // without the prefix the copies that reach into it are past the window
+    let mut builtin_output = Vec::new();
+    let builtin = super::shared_dictionary::BrotliSharedDictionary {
+      prefix: &[],
+      words: None,
+      transforms: None,
+      context_based: false,
+    };
+    match BrotliDecodeWithSharedDictionary(stream, &builtin, &mut builtin_output) {
+      Ok(_) => assert!(builtin_output[..] != input[..]),
+      Err(_) => {}
+    }
+  }
+}
+
+// Serializes a shared dictionary of 4096 random eight letter words and 8 five letter ones,
+// with transforms that the built-in dictionary does not have: the bare word, the word
+// without its last two letters, " the Word.", "WORD.", every letter shifted by one and the
+// word without its first two letters. Returns the size and where the eight letter words start.
+#[cfg(not(feature="no-stdlib"))]
+fn serialize_custom_shared_dictionary(out: &mut [u8]) -> (usize, usize) {
+  let mut pos = 0usize;
+  let mut push = |out: &mut [u8], bytes: &[u8]| {
+    out[pos..pos + bytes.len()].clone_from_slice(bytes);
+    pos += bytes.len();
+    pos
+  };
+  push(out, &[0x91, 0x00, 0, 1]);
+  let mut size_bits = [0u8; 28];
+  size_bits[5 - 4] = 3;
+  size_bits[8 - 4] = 12;
+  push(out, &size_bits[..]);
+  let mut seed = 7u32;
+  let words_start = push(out, &[]);
+  for index in 0..(8 * 5 + 4096 * 8) {
+    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+    push(out, &[b'a' + ((seed >> 16) % 26) as u8]);
+    if index + 1 == 8 * 5 {
+      push(out, &[]);
+    }
+  }
+  // strings " the ", "." and the empty one
+  push(out, &[1, 9, 0, 5, b' ', b't', b'h', b'e', b' ', 1, b'.', 0]);
+  push(out, &[6, 2, 0, 2, 2, 2, 2, 0, 10, 1, 2, 11, 1, 2, 22, 2, 2, 13, 2]);
+  push(out, &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+  (push(out, &[1, 0, 0, 0]), words_start + 8 * 5)
+}
+
+// Compresses text made of the words and transforms of serialize_custom_shared_dictionary,
+// decodes it with that dictionary and returns the compressed sizes with the dictionary
+// (all transforms, then only the bare and shortened words) and without one.
+#[cfg(not(feature="no-stdlib"))]
+fn custom_shared_dictionary_roundtrip(quality: u32) -> (usize, usize, usize) {
+  use std::vec::Vec;
+  use super::stream_decoder::BrotliDecodeWithSharedDictionary;
+  use super::shared_dictionary::BrotliParseSharedDictionary;
+  use super::static_dict::{BROTLI_DICTIONARY_TRANSFORM_IDENTITY, BROTLI_DICTIONARY_TRANSFORM_OMIT_LAST};
+  let mut dict = [0u8; 40 * 1024];
+  let (dict_size, words_start) = serialize_custom_shared_dictionary(&mut dict[..]);
+  let mut input = [0u8; 32 * 1024];
+  let mut input_size = 0usize;
+  let mut seed = 11u32;
+  while input_size + 16 < input.len() {
+    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+    let index = ((seed >> 8) % 4096) as usize;
+    let word = &dict[words_start + index * 8..words_start + index * 8 + 8];
+    let piece = &mut input[input_size..];
+    input_size += match (seed >> 24) % 5 {
+      0 => {
+        piece[..8].clone_from_slice(word);
+        8
+      }
+      1 => {
+        piece[..6].clone_from_slice(&word[..6]);
+        6
+      }
+      2 => {
+        piece[..5].clone_from_slice(b" the ");
+        piece[5..13].clone_from_slice(word);
+        piece[5] -= 32;
+        piece[13] = b'.';
+        14
+      }
+      3 => {
+        for (upper, letter) in piece[..8].iter_mut().zip(word.iter()) {
+          *upper = *letter - 32;
+        }
+        piece[8] = b'.';
+        9
+      }
+      _ => {
+        for (shifted, letter) in piece[..8].iter_mut().zip(word.iter()) {
+          *shifted = *letter + 1;
+        }
+        8
+      }
+    };
+  }
+  let input = &input[..input_size];
+  let parsed = BrotliParseSharedDictionary(&dict[..dict_size]).unwrap();
+  let mut compressed = [0u8; 48 * 1024];
+  let mut sizes = [0usize; 3];
+  // the zopfli search of quality 10 and 11 rather than the hasher of the q9.5 mode
+  let all_transforms = [(BrotliEncoderParameter::BROTLI_PARAM_Q9_5, 0)];
+  let restricted = [(BrotliEncoderParameter::BROTLI_PARAM_Q9_5, 0),
+                    (BrotliEncoderParameter::BROTLI_PARAM_STATIC_DICTIONARY_TRANSFORMS,
+                     BROTLI_DICTIONARY_TRANSFORM_IDENTITY | BROTLI_DICTIONARY_TRANSFORM_OMIT_LAST)];
+  for (size, extra_params) in sizes[..2].iter_mut().zip([&all_transforms[..], &restricted[..]].iter()) {
+    let (success, compressed_size, _) = oneshot_compress_with_params(input,
+                                                                     &mut compressed[..],
+                                                                     quality,
+                                                                     22,
+                                                                     4096,
+                                                                     4096,
+                                                                     &dict[..dict_size],
+                                                                     extra_params);
+    assert_eq!(success, 1);
+    let mut output = Vec::new();
+    let report = BrotliDecodeWithSharedDictionary(&compressed[..compressed_size], &parsed, &mut output).unwrap();
+    assert_eq!(output.len(), input.len());
+    assert!(output[..] == input[..]);
+    assert!(report.metablocks.iter().map(|metablock| metablock.num_dictionary_words).sum::<usize>() > 0);
+    *size = compressed_size;
+  }
+  sizes[2] = oneshot_compress(input, &mut compressed[..], quality, 22, 4096, 4096).1;
+  (sizes[0], sizes[1], sizes[2])
+}
+
+#[cfg(not(feature="no-stdlib"))]
+#[test]
+fn test_custom_shared_dictionary_words() {
+  for quality in [5u32, 9].iter() {
+    let (size, restricted_size, nodict_size) = custom_shared_dictionary_roundtrip(*quality);
+    assert!(size <= restricted_size, "{} {}", size, restricted_size);
+    assert!(size < nodict_size, "{} {}", size, nodict_size);
+  }
+  // the zopfli search tries every transform that keeps the start of the word
+  let (size, restricted_size, nodict_size) = custom_shared_dictionary_roundtrip(11);
+  assert!(size * 5 < restricted_size * 4, "{} {}", size, restricted_size);
+  assert!(size * 2 < nodict_size, "{} {}", size, nodict_size);
+}
+
+#[test]
+fn test_static_dictionary_stats() {
+  let alice = include_bytes!("../../testdata/alice29.txt");
+  let input = &alice[..32768];
+  let mut compressed = [0u8; 64 * 1024];
+  let (success, _, (lookups, matches)) =
+    oneshot_compress_with_params(input, &mut compressed[..], 9, 22, 4096, 4096, &[], &[]);
+  assert_eq!(success, 1);
+  assert!(lookups > 0);
+  assert!(matches > 0 && matches <= lookups, "{} of {}", matches, lookups);
+  let (success, _, restricted_stats) =
+    oneshot_compress_with_params(input, &mut compressed[..], 9, 22, 4096, 4096, &[],
+                                 &[(BrotliEncoderParameter::BROTLI_PARAM_STATIC_DICTIONARY_MIN_WORD_LEN, 8)]);
+  assert_eq!(success, 1);
+  assert!(restricted_stats.1 < matches, "{:?} vs {} of {}", restricted_stats, matches, lookups);
+  let (success, _, disabled_stats) =
+    oneshot_compress_with_params(input, &mut compressed[..], 9, 22, 4096, 4096, &[],
+                                 &[(BrotliEncoderParameter::BROTLI_PARAM_DISABLE_STATIC_DICTIONARY, 1)]);
+  assert_eq!(success, 1);
+  assert_eq!(disabled_stats, (0, 0));
+}
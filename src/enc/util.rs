@@ -286,12 +286,115 @@ static kLog2Table: [f32; 256] = [0.0000000000000000f32,
                                  7.9886846867721664f32,
                                  7.9943534368588578f32];
 
+// The fixed-point cost model measures bits in units of 1 / (1 << BROTLI_FIXED_POINT_BITS)
+// and only uses integer arithmetic, so every target computes the same costs.
+pub const BROTLI_FIXED_POINT_BITS: u32 = 16;
+
+// round(log2(1 + i / 256) << BROTLI_FIXED_POINT_BITS)
+static kFixedLog2Table: [u32; 257] = [0, 369, 736, 1102, 1466, 1829, 2190, 2551, 2909, 3267, 3623, 3978,
+                                      4331, 4683, 5034, 5384, 5732, 6079, 6425, 6769, 7112, 7454, 7795, 8134,
+                                      8473, 8810, 9146, 9480, 9814, 10146, 10477, 10807, 11136, 11464, 11791, 12116,
+                                      12440, 12764, 13086, 13407, 13727, 14046, 14363, 14680, 14996, 15310, 15624, 15937,
+                                      16248, 16559, 16868, 17177, 17484, 17791, 18096, 18401, 18704, 19007, 19308, 19609,
+                                      19909, 20207, 20505, 20802, 21098, 21393, 21687, 21980, 22272, 22564, 22854, 23144,
+                                      23433, 23720, 24007, 24293, 24579, 24863, 25146, 25429, 25711, 25992, 26272, 26551,
+                                      26830, 27108, 27384, 27660, 27936, 28210, 28484, 28757, 29029, 29300, 29571, 29840,
+                                      30109, 30378, 30645, 30912, 31178, 31443, 31707, 31971, 32234, 32496, 32758, 33019,
+                                      33279, 33538, 33797, 34055, 34312, 34569, 34825, 35080, 35334, 35588, 35841, 36094,
+                                      36346, 36597, 36847, 37097, 37346, 37595, 37842, 38090, 38336, 38582, 38827, 39072,
+                                      39316, 39559, 39802, 40044, 40286, 40527, 40767, 41006, 41246, 41484, 41722, 41959,
+                                      42196, 42432, 42667, 42902, 43137, 43370, 43603, 43836, 44068, 44300, 44530, 44761,
+                                      44990, 45220, 45448, 45676, 45904, 46131, 46357, 46583, 46809, 47034, 47258, 47482,
+                                      47705, 47928, 48150, 48372, 48593, 48813, 49034, 49253, 49472, 49691, 49909, 50127,
+                                      50344, 50560, 50776, 50992, 51207, 51422, 51636, 51850, 52063, 52276, 52488, 52700,
+                                      52911, 53122, 53332, 53542, 53751, 53960, 54169, 54377, 54584, 54791, 54998, 55204,
+                                      55410, 55615, 55820, 56025, 56229, 56432, 56635, 56838, 57040, 57242, 57443, 57644,
+                                      57845, 58045, 58245, 58444, 58643, 58841, 59039, 59237, 59434, 59631, 59827, 60023,
+                                      60219, 60414, 60609, 60803, 60997, 61190, 61384, 61576, 61769, 61961, 62152, 62343,
+                                      62534, 62725, 62915, 63104, 63294, 63483, 63671, 63859, 64047, 64234, 64421, 64608,
+                                      64794, 64980, 65166, 65351, 65536];
+// round(2 ** (i / 256) << BROTLI_FIXED_POINT_BITS)
+static kFixedPow2Table: [u32; 257] = [65536, 65714, 65892, 66071, 66250, 66429, 66609, 66790, 66971, 67153, 67335, 67517,
+                                      67700, 67884, 68068, 68252, 68438, 68623, 68809, 68996, 69183, 69370, 69558, 69747,
+                                      69936, 70126, 70316, 70507, 70698, 70889, 71082, 71274, 71468, 71661, 71856, 72050,
+                                      72246, 72442, 72638, 72835, 73032, 73230, 73429, 73628, 73828, 74028, 74229, 74430,
+                                      74632, 74834, 75037, 75240, 75444, 75649, 75854, 76060, 76266, 76473, 76680, 76888,
+                                      77096, 77305, 77515, 77725, 77936, 78147, 78359, 78572, 78785, 78998, 79212, 79427,
+                                      79642, 79858, 80075, 80292, 80510, 80728, 80947, 81166, 81386, 81607, 81828, 82050,
+                                      82273, 82496, 82719, 82944, 83169, 83394, 83620, 83847, 84074, 84302, 84531, 84760,
+                                      84990, 85220, 85451, 85683, 85915, 86148, 86382, 86616, 86851, 87086, 87322, 87559,
+                                      87796, 88034, 88273, 88513, 88752, 88993, 89234, 89476, 89719, 89962, 90206, 90451,
+                                      90696, 90942, 91188, 91436, 91684, 91932, 92181, 92431, 92682, 92933, 93185, 93438,
+                                      93691, 93945, 94200, 94455, 94711, 94968, 95226, 95484, 95743, 96002, 96263, 96524,
+                                      96785, 97048, 97311, 97575, 97839, 98104, 98370, 98637, 98905, 99173, 99442, 99711,
+                                      99982, 100253, 100524, 100797, 101070, 101344, 101619, 101895, 102171, 102448, 102726, 103004,
+                                      103283, 103564, 103844, 104126, 104408, 104691, 104975, 105260, 105545, 105831, 106118, 106406,
+                                      106694, 106984, 107274, 107565, 107856, 108149, 108442, 108736, 109031, 109326, 109623, 109920,
+                                      110218, 110517, 110816, 111117, 111418, 111720, 112023, 112327, 112631, 112937, 113243, 113550,
+                                      113858, 114167, 114476, 114787, 115098, 115410, 115723, 116036, 116351, 116667, 116983, 117300,
+                                      117618, 117937, 118257, 118577, 118899, 119221, 119544, 119869, 120194, 120519, 120846, 121174,
+                                      121502, 121832, 122162, 122493, 122825, 123158, 123492, 123827, 124163, 124500, 124837, 125176,
+                                      125515, 125855, 126197, 126539, 126882, 127226, 127571, 127917, 128263, 128611, 128960, 129310,
+                                      129660, 130012, 130364, 130718, 131072];
+
+// log2(v) in fixed point, interpolating linearly between the table entries around the 16 bits
+// below the leading one of v. Zero for v == 0, like the float logs.
+pub fn FixedLog2(v: u64) -> u64 {
+  if v == 0 {
+    return 0;
+  }
+  let log2floor = Log2FloorNonZero(v) as u64;
+  let frac = if log2floor >= 16 {
+    (v >> (log2floor - 16)) & 0xffff
+  } else {
+    (v << (16 - log2floor)) & 0xffff
+  };
+  let lower = u64::from(kFixedLog2Table[(frac >> 8) as usize]);
+  let upper = u64::from(kFixedLog2Table[(frac >> 8) as usize + 1]);
+  (log2floor << BROTLI_FIXED_POINT_BITS) + lower + (((upper - lower) * (frac & 0xff) + 128) >> 8)
+}
+
+// 2 ** v for a fixed-point v, as a fixed-point value saturating at u64::MAX.
+pub fn FixedPow2(v: i64) -> u64 {
+  let whole = v >> BROTLI_FIXED_POINT_BITS;
+  let frac = (v & ((1 << BROTLI_FIXED_POINT_BITS) - 1)) as u64;
+  let lower = u64::from(kFixedPow2Table[(frac >> 8) as usize]);
+  let upper = u64::from(kFixedPow2Table[(frac >> 8) as usize + 1]);
+  let mantissa = lower + (((upper - lower) * (frac & 0xff) + 128) >> 8);
+  if whole < 0 {
+    if whole <= -64 { 0 } else { mantissa >> -whole }
+  } else if whole >= 64 - 17 {
+    u64::max_value()
+  } else {
+    mantissa << whole
+  }
+}
+
+// Converting is exact while the value fits the mantissa of floatX and rounds the same way on
+// every target past that.
+#[inline(always)]
+pub fn FixedToFloat(v: u64) -> floatX {
+  v as floatX / (1u64 << BROTLI_FIXED_POINT_BITS) as floatX
+}
+
+#[inline(always)]
+pub fn FloatToFixed(v: floatX) -> i64 {
+  (v * (1u64 << BROTLI_FIXED_POINT_BITS) as floatX) as i64
+}
+
+#[cfg(not(feature="fixed-point"))]
 #[inline(always)]
 pub fn FastLog2u16(v: u16) -> floatX {
     log64k[v as usize]
 }
 
-#[cfg(not(feature="no-stdlib"))]
+#[cfg(feature="fixed-point")]
+#[inline(always)]
+pub fn FastLog2u16(v: u16) -> floatX {
+    FixedToFloat(FixedLog2(u64::from(v)))
+}
+
+#[cfg(all(not(feature="no-stdlib"), not(feature="fixed-point")))]
 #[inline(always)]
 pub fn FastLog2(v: u64) -> floatX {
     if v < 256 {
@@ -300,7 +403,7 @@ pub fn FastLog2(v: u64) -> floatX {
     (v as f32).log2() as floatX
 }
 
-#[cfg(feature="no-stdlib")]
+#[cfg(all(feature="no-stdlib", not(feature="fixed-point")))]
 #[inline(always)]
 pub fn FastLog2(v: u64) -> floatX {
   if v < 256 {
@@ -309,7 +412,13 @@ pub fn FastLog2(v: u64) -> floatX {
   FastLog2u64(v)
 }
 
-#[cfg(not(feature="no-stdlib"))]
+#[cfg(feature="fixed-point")]
+#[inline(always)]
+pub fn FastLog2(v: u64) -> floatX {
+  FixedToFloat(FixedLog2(v))
+}
+
+#[cfg(all(not(feature="no-stdlib"), not(feature="fixed-point")))]
 #[inline(always)]
 pub fn FastLog2f64(v: u64) -> f64 {
     if v < 256 {
@@ -318,12 +427,18 @@ pub fn FastLog2f64(v: u64) -> f64 {
     (v as f64).log2()
 }
 
-#[cfg(feature="no-stdlib")]
+#[cfg(all(feature="no-stdlib", not(feature="fixed-point")))]
 #[inline(always)]
 pub fn FastLog2f64(v: u64) -> f64 {
     FastLog2(v) as f64
 }
 
+#[cfg(feature="fixed-point")]
+#[inline(always)]
+pub fn FastLog2f64(v: u64) -> f64 {
+    FixedLog2(v) as f64 / (1u64 << BROTLI_FIXED_POINT_BITS) as f64
+}
+
 #[inline]
 pub fn FastLog2u64(v: u64) -> floatX {
   let bsr_8 = 56i8 - v.leading_zeros() as i8;
@@ -345,17 +460,17 @@ pub fn xFastLog2u16(v: u16) -> floatX {
   offset as floatX + kLog2Table[(v >> offset) as u8 as usize] as (floatX)
 }
 
-#[cfg(not(feature="no-stdlib"))]
+#[cfg(all(not(feature="no-stdlib"), not(feature="fixed-point")))]
 #[inline(always)]
 pub fn FastPow2(v: super::util::floatX) -> super::util::floatX {
   return (2 as super::util::floatX).powf(v);
 }
 
 
-#[cfg(feature="no-stdlib")]
+#[cfg(all(feature="no-stdlib", not(feature="fixed-point")))]
 #[inline(always)]
 pub fn FastPow2(v: super::util::floatX) -> super::util::floatX {
-   assert!(v >= 0 as super::util::floatX);
+   debug_assert!(v >= 0 as super::util::floatX);
    let round_down = v as i32;
    let remainder = v - round_down as super::util::floatX;
    let mut x = 1 as super::util::floatX;
@@ -373,6 +488,12 @@ pub fn FastPow2(v: super::util::floatX) -> super::util::floatX {
    return (1 << round_down) as super::util::floatX * x;
 }
 
+#[cfg(feature="fixed-point")]
+#[inline(always)]
+pub fn FastPow2(v: super::util::floatX) -> super::util::floatX {
+   FixedToFloat(FixedPow2(FloatToFixed(v)))
+}
+
 #[inline(always)]
 pub fn Log2FloorNonZero(v: u64) -> u32 {
   (63u32 ^ v.leading_zeros()) as u32
@@ -440,6 +561,23 @@ pub fn approx_eq(a : f64, b: f64, tol: f64) {
       }
     }
   }
+  #[test]
+  fn fixed_log2_works(){
+    let unit = (1u64 << super::BROTLI_FIXED_POINT_BITS) as f64;
+    assert_eq!(super::FixedLog2(0), 0);
+    assert_eq!(super::FixedLog2(1), 0);
+    assert_eq!(super::FixedLog2(1 << 40), 40 << super::BROTLI_FIXED_POINT_BITS);
+    let mut v: u64 = 1;
+    while v < (1u64 << 62) {
+      for example in [v, v + 1, v + v / 3, v + v / 2 + 7].iter() {
+        approx_eq(super::FixedLog2(*example) as f64 / unit, (*example as f64).log2(), 2.0 / unit);
+      }
+      v = v * 3 + 1;
+    }
+    for fixed in [-5i64 << 16, -3, 0, 1 << 15, 5 << 16, (7 << 16) + 12345, 40 << 16].iter() {
+      approx_eq(super::FixedPow2(*fixed) as f64 / unit / (*fixed as f64 / unit).exp2(), 1.0, 0.0001);
+    }
+  }
 }
 
 
@@ -0,0 +1,156 @@
+// Allocators for targets that have a global allocator but not the rest of the stdlib (the alloc
+// feature, which only matters together with no-stdlib; builds with the stdlib always have them).
+// Every cell is a boxed slice, as with HeapAlloc, and nothing here needs std::io: the compressor
+// reader and writer built on these allocators (CompressorReaderVecAlloc and
+// CompressorWriterVecAlloc) work over CustomRead and CustomWrite.
+use rust_alloc::boxed::Box;
+use rust_alloc::vec::Vec;
+use super::backward_references::BrotliEncoderParams;
+use super::cluster::HistogramPair;
+use super::command::Command;
+use super::entropy_encode::HuffmanTree;
+use super::hash_to_binary_tree::ZopfliNode;
+use super::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance};
+use super::interface;
+use super::pdf::PDF;
+use super::util::floatX;
+use super::vectorization::Mem256f;
+use super::{BrotliCompressWholeBufferCustomIo, StaticCommand};
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use brotli_decompressor::CustomWrite;
+
+pub struct BoxedSlice<T>(Box<[T]>);
+
+impl<T> Default for BoxedSlice<T> {
+  fn default() -> Self {
+    BoxedSlice(Vec::new().into_boxed_slice())
+  }
+}
+
+impl<T> SliceWrapper<T> for BoxedSlice<T> {
+  fn slice(&self) -> &[T] {
+    &self.0[..]
+  }
+}
+
+impl<T> SliceWrapperMut<T> for BoxedSlice<T> {
+  fn slice_mut(&mut self) -> &mut [T] {
+    &mut self.0[..]
+  }
+}
+
+pub struct VecAlloc<T: Clone> {
+  pub default_value: T,
+}
+
+impl<T: Clone> VecAlloc<T> {
+  pub fn new(default_value: T) -> Self {
+    VecAlloc::<T> { default_value }
+  }
+}
+
+impl<T: Clone> Allocator<T> for VecAlloc<T> {
+  type AllocatedMemory = BoxedSlice<T>;
+  fn alloc_cell(&mut self, len: usize) -> BoxedSlice<T> {
+    let mut v = Vec::with_capacity(len);
+    v.resize(len, self.default_value.clone());
+    BoxedSlice(v.into_boxed_slice())
+  }
+  fn free_cell(&mut self, _data: BoxedSlice<T>) {}
+}
+
+// One allocator for each type the encoder allocates, in the order BrotliCompressCustomIo and the
+// CustomIo reader and writer take them.
+pub struct VecAllocBundle {
+  pub alloc_u8: VecAlloc<u8>,
+  pub alloc_u16: VecAlloc<u16>,
+  pub alloc_i32: VecAlloc<i32>,
+  pub alloc_u32: VecAlloc<u32>,
+  pub alloc_u64: VecAlloc<u64>,
+  pub alloc_c: VecAlloc<Command>,
+  pub alloc_f64: VecAlloc<floatX>,
+  pub alloc_fv: VecAlloc<Mem256f>,
+  pub alloc_pdf: VecAlloc<PDF>,
+  pub alloc_sc: VecAlloc<StaticCommand>,
+  pub alloc_hl: VecAlloc<HistogramLiteral>,
+  pub alloc_hc: VecAlloc<HistogramCommand>,
+  pub alloc_hd: VecAlloc<HistogramDistance>,
+  pub alloc_hp: VecAlloc<HistogramPair>,
+  pub alloc_ct: VecAlloc<ContextType>,
+  pub alloc_ht: VecAlloc<HuffmanTree>,
+  pub alloc_zn: VecAlloc<ZopfliNode>,
+}
+
+impl Default for VecAllocBundle {
+  fn default() -> Self {
+    VecAllocBundle {
+      alloc_u8: VecAlloc::new(0),
+      alloc_u16: VecAlloc::new(0),
+      alloc_i32: VecAlloc::new(0),
+      alloc_u32: VecAlloc::new(0),
+      alloc_u64: VecAlloc::new(0),
+      alloc_c: VecAlloc::new(Command::default()),
+      alloc_f64: VecAlloc::new(0.0 as floatX),
+      alloc_fv: VecAlloc::new(Mem256f::default()),
+      alloc_pdf: VecAlloc::new(PDF::default()),
+      alloc_sc: VecAlloc::new(StaticCommand::default()),
+      alloc_hl: VecAlloc::new(HistogramLiteral::default()),
+      alloc_hc: VecAlloc::new(HistogramCommand::default()),
+      alloc_hd: VecAlloc::new(HistogramDistance::default()),
+      alloc_hp: VecAlloc::new(HistogramPair::default()),
+      alloc_ct: VecAlloc::new(ContextType::default()),
+      alloc_ht: VecAlloc::new(HuffmanTree::default()),
+      alloc_zn: VecAlloc::new(ZopfliNode::default()),
+    }
+  }
+}
+
+struct VecWriter<'a>(&'a mut Vec<u8>);
+
+impl<'a> CustomWrite<()> for VecWriter<'a> {
+  fn write(&mut self, data: &[u8]) -> Result<usize, ()> {
+    self.0.extend_from_slice(data);
+    Ok(data.len())
+  }
+  fn flush(&mut self) -> Result<(), ()> {
+    Ok(())
+  }
+}
+
+// Compresses input as a whole (see BrotliCompressWholeBufferCustomIo). Returns None if the encoder
+// rejects the input, which is only the case for inputs over 2GB or parameters it cannot encode.
+pub fn compress_to_vec(input: &[u8], params: &BrotliEncoderParams) -> Option<Vec<u8>> {
+  let m = VecAllocBundle::default();
+  let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
+                          _cmds: &mut [interface::StaticCommand],
+                          _mb: interface::InputPair,
+                          _mfv: &mut VecAlloc<Mem256f>,
+                          _mpdf: &mut VecAlloc<PDF>,
+                          _mc: &mut VecAlloc<StaticCommand>| ();
+  let mut output = Vec::new();
+  match BrotliCompressWholeBufferCustomIo(input,
+                                          &mut VecWriter(&mut output),
+                                          params,
+                                          m.alloc_u8,
+                                          m.alloc_u16,
+                                          m.alloc_i32,
+                                          m.alloc_u32,
+                                          m.alloc_u64,
+                                          m.alloc_c,
+                                          m.alloc_f64,
+                                          m.alloc_fv,
+                                          m.alloc_pdf,
+                                          m.alloc_sc,
+                                          m.alloc_hl,
+                                          m.alloc_hc,
+                                          m.alloc_hd,
+                                          m.alloc_hp,
+                                          m.alloc_ct,
+                                          m.alloc_ht,
+                                          m.alloc_zn,
+                                          &mut nop_callback,
+                                          ()) {
+    Ok(_) => Some(output),
+    Err(()) => None,
+  }
+}
@@ -0,0 +1,264 @@
+// Compression that proves its own output: every piece of compressed output runs through a decoder
+// before it reaches the writer, and what the decoder produces is compared with the input.
+//
+// The input is only held until the decoder has reproduced it, which happens as soon as the encoder
+// emits the metablock containing it. Memory therefore stays within what the encoder buffers anyway
+// (about one window plus one input block, see lgwin and lgblock) and the decoder's own window,
+// however long the stream is.
+use super::backward_references::BrotliEncoderParams;
+use super::pull::Encoder;
+use brotli_decompressor::{BrotliDecompressStream, BrotliResult, BrotliState, HuffmanCode};
+use alloc::HeapAlloc;
+use core::fmt;
+use std::collections::VecDeque;
+use std::error;
+use std::io::{self, Error, ErrorKind, Write};
+use std::vec::Vec;
+
+// Where the decoded stream first departs from the input, counted in decoded bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VerificationFailed {
+  pub offset: u64,
+}
+
+impl fmt::Display for VerificationFailed {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "compressed output does not decode to the input at byte {}", self.offset)
+  }
+}
+
+impl error::Error for VerificationFailed {}
+
+// Decodes a compressed stream piece by piece and checks that it reproduces the input given to
+// expect, in order. Every failure is an io::Error of kind InvalidData wrapping VerificationFailed,
+// and once one is reported every later call reports it again.
+pub struct StreamVerifier {
+  state: BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>,
+  expected: VecDeque<u8>,
+  buffer: Vec<u8>,
+  verified: u64,
+  decoder_finished: bool,
+  failed: bool,
+}
+
+impl StreamVerifier {
+  pub fn new(buffer_size: usize, large_window: bool) -> Self {
+    let mut state = BrotliState::new(HeapAlloc::<u8> { default_value: 0 },
+                                     HeapAlloc::<u32> { default_value: 0 },
+                                     HeapAlloc::<HuffmanCode> { default_value: HuffmanCode::default() });
+    state.large_window = large_window;
+    StreamVerifier {
+      state,
+      expected: VecDeque::new(),
+      buffer: vec![0u8; if buffer_size == 0 { 4096 } else { buffer_size }],
+      verified: 0,
+      decoder_finished: false,
+      failed: false,
+    }
+  }
+
+  // Input the stream should decode to after whatever was expected before.
+  pub fn expect(&mut self, input: &[u8]) {
+    self.expected.extend(input.iter());
+  }
+
+  // The number of expected bytes the stream has not reproduced yet.
+  pub fn pending(&self) -> usize {
+    self.expected.len()
+  }
+
+  // The number of bytes the stream has reproduced so far.
+  pub fn verified(&self) -> u64 {
+    self.verified
+  }
+
+  fn fail(&mut self) -> Error {
+    self.failed = true;
+    Error::new(ErrorKind::InvalidData, VerificationFailed { offset: self.verified })
+  }
+
+  // Decodes the next piece of the stream. Decoding more than was expected, decoding something
+  // else, corrupt data and data after the end of the stream are failures.
+  pub fn verify(&mut self, compressed: &[u8]) -> Result<(), Error> {
+    if self.failed {
+      return Err(self.fail());
+    }
+    if compressed.is_empty() {
+      return Ok(());
+    }
+    if self.decoder_finished {
+      return Err(self.fail());
+    }
+    let mut avail_in = compressed.len();
+    let mut input_offset: usize = 0;
+    let mut total_out: usize = 0;
+    loop {
+      let mut avail_out = self.buffer.len();
+      let mut output_offset: usize = 0;
+      let result = BrotliDecompressStream(&mut avail_in,
+                                          &mut input_offset,
+                                          compressed,
+                                          &mut avail_out,
+                                          &mut output_offset,
+                                          &mut self.buffer[..],
+                                          &mut total_out,
+                                          &mut self.state);
+      let mut matched: usize = 0;
+      {
+        let (front, back) = self.expected.as_slices();
+        for (decoded, expected) in self.buffer[..output_offset].iter().zip(front.iter().chain(back.iter())) {
+          if decoded != expected {
+            break;
+          }
+          matched += 1;
+        }
+      }
+      self.expected.drain(..matched);
+      self.verified += matched as u64;
+      if matched != output_offset {
+        return Err(self.fail());
+      }
+      match result {
+        BrotliResult::NeedsMoreOutput => continue,
+        // the decoder may stop for input with decoded bytes it has not been able to write yet
+        BrotliResult::NeedsMoreInput if avail_out == 0 => continue,
+        BrotliResult::NeedsMoreInput => return Ok(()),
+        BrotliResult::ResultSuccess => {
+          self.decoder_finished = true;
+          if avail_in != 0 {
+            return Err(self.fail());
+          }
+          return Ok(());
+        }
+        BrotliResult::ResultFailure => return Err(self.fail()),
+      }
+    }
+  }
+
+  // Checks that the stream so far has reproduced everything expected, as it must after a flush.
+  pub fn check_caught_up(&mut self) -> Result<(), Error> {
+    if self.failed || !self.expected.is_empty() {
+      return Err(self.fail());
+    }
+    Ok(())
+  }
+
+  // Checks that the stream has ended and reproduced everything expected.
+  pub fn finish(&mut self) -> Result<(), Error> {
+    if !self.decoder_finished {
+      return Err(self.fail());
+    }
+    self.check_caught_up()
+  }
+}
+
+impl Drop for StreamVerifier {
+  fn drop(&mut self) {
+    self.state.BrotliStateCleanup();
+  }
+}
+
+// A compressor writer that checks all of its output with a StreamVerifier before writing it, so a
+// write that returns Ok has only passed on output that decodes to the input so far. Call finish to
+// end the stream and learn whether the whole of it verified; dropping the writer ends the stream
+// too, but has no way to report a failure.
+pub struct VerifyingCompressorWriter<W: Write> {
+  encoder: Encoder,
+  verifier: StreamVerifier,
+  output: Option<W>,
+  finished: bool,
+}
+
+fn verify_and_write<W: Write>(verifier: &mut StreamVerifier, output: &mut Option<W>, data: &[u8]) -> Result<(), Error> {
+  verifier.verify(data)?;
+  match *output {
+    Some(ref mut w) => w.write_all(data),
+    None => Ok(()),
+  }
+}
+
+impl<W: Write> VerifyingCompressorWriter<W> {
+  pub fn new(w: W, buffer_size: usize, q: u32, lgwin: u32) -> Self {
+    let mut params = super::encode::BrotliEncoderInitParams();
+    params.quality = q as i32;
+    params.lgwin = lgwin as i32;
+    Self::with_params(w, buffer_size, &params)
+  }
+
+  pub fn with_params(w: W, buffer_size: usize, params: &BrotliEncoderParams) -> Self {
+    VerifyingCompressorWriter {
+      encoder: Encoder::new(params),
+      verifier: StreamVerifier::new(buffer_size, params.large_window),
+      output: Some(w),
+      finished: false,
+    }
+  }
+
+  // The number of input bytes written that the output so far does not cover yet.
+  pub fn pending(&self) -> usize {
+    self.verifier.pending()
+  }
+
+  // Runs once, from finish or drop; a failure is not retried.
+  fn close(&mut self) -> Result<(), Error> {
+    self.finished = true;
+    while !self.encoder.is_finished() {
+      let output = self.encoder.finish()?;
+      verify_and_write(&mut self.verifier, &mut self.output, output)?;
+    }
+    self.verifier.finish()?;
+    match self.output {
+      Some(ref mut w) => w.flush(),
+      None => Ok(()),
+    }
+  }
+
+  // Ends the stream, checks that it decodes to everything written and returns the writer.
+  pub fn finish(mut self) -> Result<W, Error> {
+    self.close()?;
+    match self.output.take() {
+      Some(w) => Ok(w),
+      None => Err(Error::new(ErrorKind::Other, "writer already taken")),
+    }
+  }
+}
+
+impl<W: Write> Write for VerifyingCompressorWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let mut offset: usize = 0;
+    while offset < buf.len() {
+      let (consumed, output) = self.encoder.push(&buf[offset..])?;
+      // the output may already cover the bytes just consumed
+      self.verifier.expect(&buf[offset..offset + consumed]);
+      verify_and_write(&mut self.verifier, &mut self.output, output)?;
+      if consumed == 0 {
+        break;
+      }
+      offset += consumed;
+    }
+    Ok(offset)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    loop {
+      let output = self.encoder.flush()?;
+      if output.is_empty() {
+        break;
+      }
+      verify_and_write(&mut self.verifier, &mut self.output, output)?;
+    }
+    self.verifier.check_caught_up()?;
+    match self.output {
+      Some(ref mut w) => w.flush(),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<W: Write> Drop for VerifyingCompressorWriter<W> {
+  fn drop(&mut self) {
+    if !self.finished {
+      let _ = self.close();
+    }
+  }
+}
@@ -7,7 +7,9 @@ use enc::StaticCommand;
 use super::hash_to_binary_tree::ZopfliNode;
 use super::encode::{BrotliEncoderCreateInstance, BrotliEncoderDestroyInstance,
                     BrotliEncoderParameter, BrotliEncoderSetParameter, BrotliEncoderOperation,
-                    BrotliEncoderStateStruct, BrotliEncoderCompressStream, BrotliEncoderIsFinished};
+                    BrotliEncoderStateStruct, BrotliEncoderCompressStreamWithStats, BrotliEncoderIsFinished,
+                    BrotliEncoderHasMoreOutput, BrotliEncoderStreamState};
+use super::brotli_bit_stream::BrotliMetaBlockStats;
 use super::backward_references::BrotliEncoderParams;
 use super::entropy_encode::HuffmanTree;
 use super::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance};
@@ -21,9 +23,25 @@ pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut,
 pub use alloc::HeapAlloc;
 #[cfg(not(feature="no-stdlib"))]
 use std::io;
+#[cfg(not(feature="no-stdlib"))]
+use std::boxed::Box;
 
 #[cfg(not(feature="no-stdlib"))]
 use std::io::{Write, Error, ErrorKind};
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+use super::vec_alloc::{BoxedSlice, VecAlloc, VecAllocBundle};
+
+// Called by the writer with the statistics of each metablock once it has been stored.
+#[cfg(not(feature="no-stdlib"))]
+pub type BrotliMetaBlockStatsCallback = Option<Box<dyn FnMut(&BrotliMetaBlockStats) + Send>>;
+#[cfg(feature="no-stdlib")]
+pub type BrotliMetaBlockStatsCallback = Option<fn(&BrotliMetaBlockStats)>;
+
+fn report_metablock_stats(callback: &mut BrotliMetaBlockStatsCallback, stats: &BrotliMetaBlockStats) {
+  if let Some(ref mut callback) = *callback {
+    callback(stats);
+  }
+}
 
 
 
@@ -118,6 +136,10 @@ impl<W: Write,
     pub fn get_ref(&self) -> &W {
       &self.0.get_ref().0
     }
+
+    pub fn set_metablock_stats_callback(&mut self, callback: BrotliMetaBlockStatsCallback) {
+      self.0.set_metablock_stats_callback(callback)
+    }
 }
 
 #[cfg(not(feature="no-stdlib"))]
@@ -228,8 +250,91 @@ impl<W: Write> CompressorWriter<W> {
   pub fn get_ref(&self) -> &W {
     self.0.get_ref()
   }
+
+  // Reports the statistics of every metablock the writer stores from now on.
+  pub fn set_metablock_stats_callback<F: FnMut(&BrotliMetaBlockStats) + Send + 'static>(&mut self, callback: F) {
+    self.0.set_metablock_stats_callback(Some(Box::new(callback)))
+  }
+}
+
+
+
+// CompressorWriter for the alloc feature: it writes to a CustomWrite and needs no std::io. As with
+// CompressorWriter, dropping it finishes the stream.
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+type VecAllocWriterCustomIo<ErrType, W> = CompressorWriterCustomIo<ErrType,
+                                                                   W,
+                                                                   BoxedSlice<u8>,
+                                                                   VecAlloc<u8>,
+                                                                   VecAlloc<u16>,
+                                                                   VecAlloc<i32>,
+                                                                   VecAlloc<u32>,
+                                                                   VecAlloc<u64>,
+                                                                   VecAlloc<Command>,
+                                                                   VecAlloc<super::util::floatX>,
+                                                                   VecAlloc<Mem256f>,
+                                                                   VecAlloc<PDF>,
+                                                                   VecAlloc<StaticCommand>,
+                                                                   VecAlloc<HistogramLiteral>,
+                                                                   VecAlloc<HistogramCommand>,
+                                                                   VecAlloc<HistogramDistance>,
+                                                                   VecAlloc<HistogramPair>,
+                                                                   VecAlloc<ContextType>,
+                                                                   VecAlloc<HuffmanTree>,
+                                                                   VecAlloc<ZopfliNode>>;
+
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+pub struct CompressorWriterVecAlloc<ErrType, W: CustomWrite<ErrType>>(VecAllocWriterCustomIo<ErrType, W>);
+
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+impl<ErrType, W: CustomWrite<ErrType>> CompressorWriterVecAlloc<ErrType, W> {
+  pub fn new(w: W, buffer_size: usize, q: u32, lgwin: u32, invalid_data_error: ErrType) -> Self {
+    let mut m = VecAllocBundle::default();
+    let buffer = m.alloc_u8.alloc_cell(if buffer_size == 0 { 4096 } else { buffer_size });
+    CompressorWriterVecAlloc::<ErrType, W>(CompressorWriterCustomIo::new(w,
+                                                                         buffer,
+                                                                         m.alloc_u8,
+                                                                         m.alloc_u16,
+                                                                         m.alloc_i32,
+                                                                         m.alloc_u32,
+                                                                         m.alloc_u64,
+                                                                         m.alloc_c,
+                                                                         m.alloc_f64,
+                                                                         m.alloc_fv,
+                                                                         m.alloc_pdf,
+                                                                         m.alloc_sc,
+                                                                         m.alloc_hl,
+                                                                         m.alloc_hc,
+                                                                         m.alloc_hd,
+                                                                         m.alloc_hp,
+                                                                         m.alloc_ct,
+                                                                         m.alloc_ht,
+                                                                         m.alloc_zn,
+                                                                         invalid_data_error,
+                                                                         q,
+                                                                         lgwin))
+  }
+
+  pub fn with_params(w: W, buffer_size: usize, params: &BrotliEncoderParams, invalid_data_error: ErrType) -> Self {
+    let mut writer = Self::new(w, buffer_size, params.quality as u32, params.lgwin as u32, invalid_data_error);
+    writer.0.state.params = params.clone();
+    writer
+  }
+
+  pub fn get_ref(&self) -> &W {
+    self.0.get_ref()
+  }
 }
 
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+impl<ErrType, W: CustomWrite<ErrType>> CustomWrite<ErrType> for CompressorWriterVecAlloc<ErrType, W> {
+  fn write(&mut self, buf: &[u8]) -> Result<usize, ErrType> {
+    self.0.write(buf)
+  }
+  fn flush(&mut self) -> Result<(), ErrType> {
+    self.0.flush()
+  }
+}
 
 
 #[cfg(not(feature="no-stdlib"))]
@@ -280,11 +385,12 @@ pub struct CompressorWriterCustomIo<ErrType,
   alloc_ht: AllocHT,
   alloc_zn: AllocZN,
   state: BrotliEncoderStateStruct<AllocU8, AllocU16, AllocU32, AllocI32, AllocCommand>,
+  metablock_stats_callback: BrotliMetaBlockStatsCallback,
 }
 pub fn write_all<ErrType, W: CustomWrite<ErrType>>(writer: &mut W, mut buf : &[u8]) -> Result<(), ErrType> {
     while buf.len() != 0 {
           match writer.write(buf) {
-                Ok(bytes_written) => buf = &buf[bytes_written..],
+                Ok(bytes_written) => buf = &buf[core::cmp::min(bytes_written, buf.len())..],
                 Err(e) => return Err(e),
           }
     }
@@ -357,6 +463,7 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
             alloc_ht:alloc_ht,
             alloc_zn:alloc_zn,
             error_if_invalid_data : Some(invalid_data_error_type),
+            metablock_stats_callback: None,
         };
         BrotliEncoderSetParameter(&mut ret.state,
                                   BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
@@ -371,13 +478,15 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
        let mut nop_callback = |_data:&mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
                                _cmds: &mut [interface::StaticCommand],
                                _mb: interface::InputPair, _mfv: &mut AllocFV, _mpdf: &mut AllocPDF, _mc: &mut AllocStaticCommand|();
+        let metablock_stats_callback = &mut self.metablock_stats_callback;
+        let mut stats_callback = |stats: &BrotliMetaBlockStats| report_metablock_stats(metablock_stats_callback, stats);
 
         loop {
             let mut avail_in : usize = 0;
             let mut input_offset : usize = 0;
             let mut avail_out : usize = self.output_buffer.slice_mut().len();
             let mut output_offset : usize = 0;
-            let ret = BrotliEncoderCompressStream(
+            let ret = BrotliEncoderCompressStreamWithStats(
                 &mut self.state,
                 &mut self.alloc_u64,
                 &mut self.alloc_f64,
@@ -399,7 +508,8 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
                 self.output_buffer.slice_mut(),
                 &mut output_offset,
                 &mut self.total_out,
-                &mut nop_callback);
+                &mut nop_callback,
+                &mut stats_callback);
            if output_offset > 0 {
              match write_all(&mut self.output, &self.output_buffer.slice_mut()[..output_offset]) {
                Ok(_) => {},
@@ -407,10 +517,19 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
              }
            }
            if ret <= 0 {
-              return Err(self.error_if_invalid_data.take().unwrap());
+              // the error is only handed out once; a later flush or the close on drop is a no-op
+              return match self.error_if_invalid_data.take() {
+                Some(e) => Err(e),
+                None => Ok(()),
+              };
            }
            if let BrotliEncoderOperation::BROTLI_OPERATION_FLUSH = op {
-              return Ok(());
+              // a flush ends with the padding block, which may not have fit in the buffer
+              if BrotliEncoderHasMoreOutput(&self.state) == 0 &&
+                 self.state.stream_state_ == BrotliEncoderStreamState::BROTLI_STREAM_PROCESSING {
+                return Ok(());
+              }
+              continue;
            }
            if BrotliEncoderIsFinished(&mut self.state) != 0 {
               return Ok(());
@@ -421,6 +540,10 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
     pub fn get_ref(&self) -> &W {
       &self.output
     }
+
+    pub fn set_metablock_stats_callback(&mut self, callback: BrotliMetaBlockStatsCallback) {
+      self.metablock_stats_callback = callback;
+    }
 }
 
 impl<ErrType,
@@ -479,12 +602,14 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
         let mut nop_callback = |_data:&mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
                                 _cmds: &mut [interface::StaticCommand],
                                 _mb: interface::InputPair, _mfv: &mut AllocFV, _mpdf: &mut AllocPDF, _mc: &mut AllocStaticCommand|();
+        let metablock_stats_callback = &mut self.metablock_stats_callback;
+        let mut stats_callback = |stats: &BrotliMetaBlockStats| report_metablock_stats(metablock_stats_callback, stats);
         let mut avail_in = buf.len();
         let mut input_offset : usize = 0;
         while avail_in != 0 {
             let mut output_offset = 0;
             let mut avail_out = self.output_buffer.slice_mut().len();
-            let ret = BrotliEncoderCompressStream(
+            let ret = BrotliEncoderCompressStreamWithStats(
                 &mut self.state,
                 &mut self.alloc_u64,
                 &mut self.alloc_f64,
@@ -506,7 +631,8 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
                 self.output_buffer.slice_mut(),
                 &mut output_offset,
                 &mut self.total_out,
-                &mut nop_callback);
+                &mut nop_callback,
+                &mut stats_callback);
            if output_offset > 0 {
              match write_all(&mut self.output, &self.output_buffer.slice_mut()[..output_offset]) {
               Ok(_) => {},
@@ -514,7 +640,10 @@ CompressorWriterCustomIo<ErrType, W, BufferType, AllocU8, AllocU16, AllocI32, Al
              }
            }
            if ret <= 0 {
-              return Err(self.error_if_invalid_data.take().unwrap());
+              return match self.error_if_invalid_data.take() {
+                Some(e) => Err(e),
+                None => Ok(0),
+              };
            }
         }
         Ok(buf.len())
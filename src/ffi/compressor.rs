@@ -0,0 +1,307 @@
+use super::{brotli_alloc_func, brotli_free_func, catch_panic, slice_from_raw, slice_from_raw_mut, BROTLI_BOOL,
+            BROTLI_FALSE};
+use core::ptr;
+use enc::backward_references::BrotliEncoderMode;
+use enc::cluster::HistogramPair;
+use enc::command::Command;
+use enc::encode;
+use enc::encode::{BrotliEncoderOperation, BrotliEncoderParameter, BrotliEncoderStateStruct};
+use enc::entropy_encode::HuffmanTree;
+use enc::hash_to_binary_tree::ZopfliNode;
+use enc::histogram::{ContextType, HistogramLiteral, HistogramCommand, HistogramDistance};
+use enc::interface;
+use enc::util::floatX;
+use enc::vectorization::Mem256f;
+use enc::{PDF, StaticCommand};
+use alloc::HeapAlloc;
+use std::boxed::Box;
+use std::os::raw::{c_int, c_void};
+
+type EncoderState = BrotliEncoderStateStruct<HeapAlloc<u8>, HeapAlloc<u16>, HeapAlloc<u32>, HeapAlloc<i32>,
+                                             HeapAlloc<Command>>;
+
+// What BrotliEncoderCreateInstance returns: the encoder state with the allocators it only
+// borrows for the duration of each call. failed is set while a call runs the encoder, so it stays
+// set if the encoder panics and leaves the state half updated.
+pub struct BrotliEncoderState {
+  state: EncoderState,
+  failed: bool,
+  alloc_u64: HeapAlloc<u64>,
+  alloc_f64: HeapAlloc<floatX>,
+  alloc_fv: HeapAlloc<Mem256f>,
+  alloc_pdf: HeapAlloc<PDF>,
+  alloc_sc: HeapAlloc<StaticCommand>,
+  alloc_hl: HeapAlloc<HistogramLiteral>,
+  alloc_hc: HeapAlloc<HistogramCommand>,
+  alloc_hd: HeapAlloc<HistogramDistance>,
+  alloc_hp: HeapAlloc<HistogramPair>,
+  alloc_ct: HeapAlloc<ContextType>,
+  alloc_ht: HeapAlloc<HuffmanTree>,
+  alloc_zn: HeapAlloc<ZopfliNode>,
+}
+
+fn new_encoder_state() -> EncoderState {
+  encode::BrotliEncoderCreateInstance(HeapAlloc::<u8> { default_value: 0 },
+                                      HeapAlloc::<u16> { default_value: 0 },
+                                      HeapAlloc::<i32> { default_value: 0 },
+                                      HeapAlloc::<u32> { default_value: 0 },
+                                      HeapAlloc::<Command> { default_value: Command::default() })
+}
+
+fn encoder_mode(mode: c_int) -> Option<BrotliEncoderMode> {
+  match mode {
+    0 => Some(BrotliEncoderMode::BROTLI_MODE_GENERIC),
+    1 => Some(BrotliEncoderMode::BROTLI_MODE_TEXT),
+    2 => Some(BrotliEncoderMode::BROTLI_MODE_FONT),
+    _ => None,
+  }
+}
+
+#[no_mangle]
+pub extern "C" fn BrotliEncoderCreateInstance(alloc_func: brotli_alloc_func,
+                                              free_func: brotli_free_func,
+                                              _opaque: *mut c_void)
+                                              -> *mut BrotliEncoderState {
+  catch_panic(ptr::null_mut(), || {
+    if alloc_func.is_some() || free_func.is_some() {
+      return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(BrotliEncoderState {
+      state: new_encoder_state(),
+      failed: false,
+      alloc_u64: HeapAlloc::<u64> { default_value: 0 },
+      alloc_f64: HeapAlloc::<floatX> { default_value: 0.0 as floatX },
+      alloc_fv: HeapAlloc::<Mem256f> { default_value: Mem256f::default() },
+      alloc_pdf: HeapAlloc::<PDF> { default_value: PDF::default() },
+      alloc_sc: HeapAlloc::<StaticCommand> { default_value: StaticCommand::default() },
+      alloc_hl: HeapAlloc::<HistogramLiteral> { default_value: HistogramLiteral::default() },
+      alloc_hc: HeapAlloc::<HistogramCommand> { default_value: HistogramCommand::default() },
+      alloc_hd: HeapAlloc::<HistogramDistance> { default_value: HistogramDistance::default() },
+      alloc_hp: HeapAlloc::<HistogramPair> { default_value: HistogramPair::default() },
+      alloc_ct: HeapAlloc::<ContextType> { default_value: ContextType::default() },
+      alloc_ht: HeapAlloc::<HuffmanTree> { default_value: HuffmanTree::default() },
+      alloc_zn: HeapAlloc::<ZopfliNode> { default_value: ZopfliNode::default() },
+    }))
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliEncoderDestroyInstance(state: *mut BrotliEncoderState) {
+  catch_panic((), || {
+    if !state.is_null() {
+      let mut s = Box::from_raw(state);
+      encode::BrotliEncoderDestroyInstance(&mut s.state);
+    }
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliEncoderSetParameter(state: *mut BrotliEncoderState,
+                                                   param: c_int,
+                                                   value: u32)
+                                                   -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if state.is_null() || (*state).failed {
+      return BROTLI_FALSE;
+    }
+    let p = match param {
+      0 => BrotliEncoderParameter::BROTLI_PARAM_MODE,
+      1 => BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
+      2 => BrotliEncoderParameter::BROTLI_PARAM_LGWIN,
+      3 => BrotliEncoderParameter::BROTLI_PARAM_LGBLOCK,
+      4 => BrotliEncoderParameter::BROTLI_PARAM_DISABLE_LITERAL_CONTEXT_MODELING,
+      5 => BrotliEncoderParameter::BROTLI_PARAM_SIZE_HINT,
+      6 => BrotliEncoderParameter::BROTLI_PARAM_LARGE_WINDOW,
+      7 => BrotliEncoderParameter::BROTLI_PARAM_NPOSTFIX,
+      8 => BrotliEncoderParameter::BROTLI_PARAM_NDIRECT,
+      _ => return BROTLI_FALSE,
+    };
+    encode::BrotliEncoderSetParameter(&mut (*state).state, p, value)
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliEncoderCompressStream(state: *mut BrotliEncoderState,
+                                                     op: c_int,
+                                                     available_in: *mut usize,
+                                                     next_in: *mut *const u8,
+                                                     available_out: *mut usize,
+                                                     next_out: *mut *mut u8,
+                                                     total_out: *mut usize)
+                                                     -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if state.is_null() || (*state).failed || available_in.is_null() || next_in.is_null() ||
+       available_out.is_null() || next_out.is_null() {
+      return BROTLI_FALSE;
+    }
+    let op = match op {
+      0 => BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
+      1 => BrotliEncoderOperation::BROTLI_OPERATION_FLUSH,
+      2 => BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
+      3 => BrotliEncoderOperation::BROTLI_OPERATION_EMIT_METADATA,
+      _ => return BROTLI_FALSE,
+    };
+    let input = match slice_from_raw(*next_in, *available_in) {
+      Some(input) => input,
+      None => return BROTLI_FALSE,
+    };
+    let output = match slice_from_raw_mut(*next_out, *available_out) {
+      Some(output) => output,
+      None => return BROTLI_FALSE,
+    };
+    let s = &mut *state;
+    let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
+                            _cmds: &mut [interface::StaticCommand],
+                            _mb: interface::InputPair, _mfv: &mut HeapAlloc<Mem256f>,
+                            _mpdf: &mut HeapAlloc<PDF>, _mc: &mut HeapAlloc<StaticCommand>| ();
+    let mut input_offset: usize = 0;
+    let mut output_offset: usize = 0;
+    let mut unused_total_out: Option<usize> = None;
+    s.failed = true;
+    let ret = encode::BrotliEncoderCompressStream(&mut s.state,
+                                                  &mut s.alloc_u64,
+                                                  &mut s.alloc_f64,
+                                                  &mut s.alloc_fv,
+                                                  &mut s.alloc_pdf,
+                                                  &mut s.alloc_sc,
+                                                  &mut s.alloc_hl,
+                                                  &mut s.alloc_hc,
+                                                  &mut s.alloc_hd,
+                                                  &mut s.alloc_hp,
+                                                  &mut s.alloc_ct,
+                                                  &mut s.alloc_ht,
+                                                  &mut s.alloc_zn,
+                                                  op,
+                                                  &mut *available_in,
+                                                  input,
+                                                  &mut input_offset,
+                                                  &mut *available_out,
+                                                  output,
+                                                  &mut output_offset,
+                                                  &mut unused_total_out,
+                                                  &mut nop_callback);
+    s.failed = false;
+    if input_offset != 0 {
+      *next_in = (*next_in).add(input_offset);
+    }
+    if output_offset != 0 {
+      *next_out = (*next_out).add(output_offset);
+    }
+    if !total_out.is_null() {
+      *total_out = s.state.total_out_;
+    }
+    ret
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliEncoderIsFinished(state: *mut BrotliEncoderState) -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if state.is_null() || (*state).failed {
+      return BROTLI_FALSE;
+    }
+    encode::BrotliEncoderIsFinished(&mut (*state).state)
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliEncoderHasMoreOutput(state: *mut BrotliEncoderState) -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if state.is_null() || (*state).failed {
+      return BROTLI_FALSE;
+    }
+    encode::BrotliEncoderHasMoreOutput(&(*state).state)
+  })
+}
+
+// The returned bytes stay valid until the next call with this state.
+#[no_mangle]
+pub unsafe extern "C" fn BrotliEncoderTakeOutput(state: *mut BrotliEncoderState, size: *mut usize) -> *const u8 {
+  catch_panic(ptr::null(), || {
+    if state.is_null() || size.is_null() {
+      return ptr::null();
+    }
+    if (*state).failed {
+      *size = 0;
+      return ptr::null();
+    }
+    let output = encode::BrotliEncoderTakeOutput(&mut (*state).state, &mut *size);
+    if output.is_empty() {
+      ptr::null()
+    } else {
+      output.as_ptr()
+    }
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn BrotliEncoderMaxCompressedSize(input_size: usize) -> usize {
+  catch_panic(0, || encode::BrotliEncoderMaxCompressedSize(input_size))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliEncoderCompress(quality: c_int,
+                                               lgwin: c_int,
+                                               mode: c_int,
+                                               input_size: usize,
+                                               input_buffer: *const u8,
+                                               encoded_size: *mut usize,
+                                               encoded_buffer: *mut u8)
+                                               -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if encoded_size.is_null() {
+      return BROTLI_FALSE;
+    }
+    let mode = match encoder_mode(mode) {
+      Some(mode) => mode,
+      None => return BROTLI_FALSE,
+    };
+    let input = match slice_from_raw(input_buffer, input_size) {
+      Some(input) => input,
+      None => return BROTLI_FALSE,
+    };
+    let output = match slice_from_raw_mut(encoded_buffer, *encoded_size) {
+      Some(output) => output,
+      None => return BROTLI_FALSE,
+    };
+    let mut nop_callback = |_data: &mut interface::PredictionModeContextMap<interface::InputReferenceMut>,
+                            _cmds: &mut [interface::StaticCommand],
+                            _mb: interface::InputPair, _mfv: &mut HeapAlloc<Mem256f>,
+                            _mpdf: &mut HeapAlloc<PDF>, _mc: &mut HeapAlloc<StaticCommand>| ();
+    encode::BrotliEncoderCompress(HeapAlloc::<u8> { default_value: 0 },
+                                  HeapAlloc::<u16> { default_value: 0 },
+                                  HeapAlloc::<u32> { default_value: 0 },
+                                  HeapAlloc::<i32> { default_value: 0 },
+                                  HeapAlloc::<Command> { default_value: Command::default() },
+                                  &mut HeapAlloc::<u8> { default_value: 0 },
+                                  &mut HeapAlloc::<u16> { default_value: 0 },
+                                  &mut HeapAlloc::<u32> { default_value: 0 },
+                                  &mut HeapAlloc::<i32> { default_value: 0 },
+                                  &mut HeapAlloc::<Command> { default_value: Command::default() },
+                                  &mut HeapAlloc::<u64> { default_value: 0 },
+                                  &mut HeapAlloc::<floatX> { default_value: 0.0 as floatX },
+                                  &mut HeapAlloc::<Mem256f> { default_value: Mem256f::default() },
+                                  &mut HeapAlloc::<PDF> { default_value: PDF::default() },
+                                  &mut HeapAlloc::<StaticCommand> { default_value: StaticCommand::default() },
+                                  &mut HeapAlloc::<HistogramLiteral> { default_value: HistogramLiteral::default() },
+                                  &mut HeapAlloc::<HistogramCommand> { default_value: HistogramCommand::default() },
+                                  &mut HeapAlloc::<HistogramDistance> { default_value: HistogramDistance::default() },
+                                  &mut HeapAlloc::<HistogramPair> { default_value: HistogramPair::default() },
+                                  &mut HeapAlloc::<ContextType> { default_value: ContextType::default() },
+                                  &mut HeapAlloc::<HuffmanTree> { default_value: HuffmanTree::default() },
+                                  &mut HeapAlloc::<ZopfliNode> { default_value: ZopfliNode::default() },
+                                  quality,
+                                  lgwin,
+                                  mode,
+                                  input_size,
+                                  input,
+                                  &mut *encoded_size,
+                                  output,
+                                  &mut nop_callback)
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn BrotliEncoderVersion() -> u32 {
+  catch_panic(0, encode::BrotliEncoderVersion)
+}
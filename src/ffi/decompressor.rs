@@ -0,0 +1,365 @@
+use super::{brotli_alloc_func, brotli_free_func, catch_panic, slice_from_raw, slice_from_raw_mut, BROTLI_BOOL,
+            BROTLI_FALSE, BROTLI_TRUE};
+use core::ptr;
+use alloc::HeapAlloc;
+use brotli_decompressor::{BrotliDecompressStream, BrotliResult, BrotliState, HuffmanCode};
+use enc::encode::BrotliEncoderVersion;
+use std::boxed::Box;
+use std::os::raw::{c_char, c_int, c_void};
+use std::vec::Vec;
+
+// BrotliDecoderResult in c/brotli/decode.h
+pub const BROTLI_DECODER_RESULT_ERROR: c_int = 0;
+pub const BROTLI_DECODER_RESULT_SUCCESS: c_int = 1;
+pub const BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT: c_int = 2;
+pub const BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT: c_int = 3;
+
+// BrotliDecoderErrorCode in c/brotli/decode.h, with the values of libbrotlidec. The decoder does not
+// say which check a malformed stream failed, so all of them are BROTLI_DECODER_ERROR_FORMAT, a code
+// of this crate next to the ones of libbrotlidec. BROTLI_DECODER_ERROR_UNREACHABLE is a panic.
+pub const BROTLI_DECODER_NO_ERROR: c_int = 0;
+pub const BROTLI_DECODER_SUCCESS: c_int = 1;
+pub const BROTLI_DECODER_NEEDS_MORE_INPUT: c_int = 2;
+pub const BROTLI_DECODER_NEEDS_MORE_OUTPUT: c_int = 3;
+pub const BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_NIBBLE: c_int = -1;
+pub const BROTLI_DECODER_ERROR_FORMAT_RESERVED: c_int = -2;
+pub const BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_META_NIBBLE: c_int = -3;
+pub const BROTLI_DECODER_ERROR_FORMAT_SIMPLE_HUFFMAN_ALPHABET: c_int = -4;
+pub const BROTLI_DECODER_ERROR_FORMAT_SIMPLE_HUFFMAN_SAME: c_int = -5;
+pub const BROTLI_DECODER_ERROR_FORMAT_CL_SPACE: c_int = -6;
+pub const BROTLI_DECODER_ERROR_FORMAT_HUFFMAN_SPACE: c_int = -7;
+pub const BROTLI_DECODER_ERROR_FORMAT_CONTEXT_MAP_REPEAT: c_int = -8;
+pub const BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_1: c_int = -9;
+pub const BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_2: c_int = -10;
+pub const BROTLI_DECODER_ERROR_FORMAT_TRANSFORM: c_int = -11;
+pub const BROTLI_DECODER_ERROR_FORMAT_DICTIONARY: c_int = -12;
+pub const BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS: c_int = -13;
+pub const BROTLI_DECODER_ERROR_FORMAT_PADDING_1: c_int = -14;
+pub const BROTLI_DECODER_ERROR_FORMAT_PADDING_2: c_int = -15;
+pub const BROTLI_DECODER_ERROR_FORMAT_DISTANCE: c_int = -16;
+pub const BROTLI_DECODER_ERROR_DICTIONARY_NOT_SET: c_int = -19;
+pub const BROTLI_DECODER_ERROR_INVALID_ARGUMENTS: c_int = -20;
+pub const BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES: c_int = -21;
+pub const BROTLI_DECODER_ERROR_ALLOC_TREE_GROUPS: c_int = -22;
+pub const BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MAP: c_int = -25;
+pub const BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1: c_int = -26;
+pub const BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2: c_int = -27;
+pub const BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES: c_int = -30;
+pub const BROTLI_DECODER_ERROR_UNREACHABLE: c_int = -31;
+pub const BROTLI_DECODER_ERROR_FORMAT: c_int = -32;
+
+type DecoderState = BrotliState<HeapAlloc<u8>, HeapAlloc<u32>, HeapAlloc<HuffmanCode>>;
+
+// What BrotliDecoderCreateInstance returns. The decoder here writes straight into the caller's
+// output, so BrotliDecoderTakeOutput decodes into a buffer of its own and hands that out.
+// error_code is what BrotliDecoderGetErrorCode reports; once it is negative every call fails.
+pub struct BrotliDecoderState {
+  state: DecoderState,
+  result: c_int,
+  error_code: c_int,
+  total_out: usize,
+  take_output_buffer: Vec<u8>,
+}
+
+fn new_decoder_state() -> DecoderState {
+  BrotliState::new_strict(HeapAlloc::<u8> { default_value: 0 },
+                          HeapAlloc::<u32> { default_value: 0 },
+                          HeapAlloc::<HuffmanCode> { default_value: HuffmanCode::default() })
+}
+
+fn decoder_result(result: BrotliResult) -> c_int {
+  match result {
+    BrotliResult::ResultSuccess => BROTLI_DECODER_RESULT_SUCCESS,
+    BrotliResult::NeedsMoreInput => BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT,
+    BrotliResult::NeedsMoreOutput => BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT,
+    BrotliResult::ResultFailure => BROTLI_DECODER_RESULT_ERROR,
+  }
+}
+
+// Runs one step of the decoder on s and records its result. The error code says UNREACHABLE while
+// the decoder runs, so that is what is left if it panics.
+fn decompress_step(s: &mut BrotliDecoderState,
+                   available_in: &mut usize,
+                   input_offset: &mut usize,
+                   input: &[u8],
+                   available_out: &mut usize,
+                   output_offset: &mut usize,
+                   output: &mut [u8]) {
+  s.result = BROTLI_DECODER_RESULT_ERROR;
+  s.error_code = BROTLI_DECODER_ERROR_UNREACHABLE;
+  let result = BrotliDecompressStream(available_in,
+                                      input_offset,
+                                      input,
+                                      available_out,
+                                      output_offset,
+                                      output,
+                                      &mut s.total_out,
+                                      &mut s.state);
+  s.error_code = match result {
+    BrotliResult::ResultSuccess => BROTLI_DECODER_SUCCESS,
+    BrotliResult::NeedsMoreInput => BROTLI_DECODER_NEEDS_MORE_INPUT,
+    BrotliResult::NeedsMoreOutput => BROTLI_DECODER_NEEDS_MORE_OUTPUT,
+    BrotliResult::ResultFailure => BROTLI_DECODER_ERROR_FORMAT,
+  };
+  s.result = decoder_result(result);
+}
+
+#[no_mangle]
+pub extern "C" fn BrotliDecoderCreateInstance(alloc_func: brotli_alloc_func,
+                                              free_func: brotli_free_func,
+                                              _opaque: *mut c_void)
+                                              -> *mut BrotliDecoderState {
+  catch_panic(ptr::null_mut(), || {
+    if alloc_func.is_some() || free_func.is_some() {
+      return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(BrotliDecoderState {
+      state: new_decoder_state(),
+      result: BROTLI_DECODER_RESULT_NEEDS_MORE_INPUT,
+      error_code: BROTLI_DECODER_NO_ERROR,
+      total_out: 0,
+      take_output_buffer: Vec::new(),
+    }))
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderDestroyInstance(state: *mut BrotliDecoderState) {
+  catch_panic((), || {
+    if !state.is_null() {
+      let mut s = Box::from_raw(state);
+      s.state.BrotliStateCleanup();
+    }
+  })
+}
+
+// BROTLI_DECODER_PARAM_DISABLE_RING_BUFFER_REALLOCATION is accepted and has no effect.
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderSetParameter(state: *mut BrotliDecoderState,
+                                                   param: c_int,
+                                                   value: u32)
+                                                   -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if state.is_null() {
+      return BROTLI_FALSE;
+    }
+    match param {
+      0 => BROTLI_TRUE,
+      1 => {
+        (*state).state.large_window = value != 0;
+        BROTLI_TRUE
+      }
+      _ => BROTLI_FALSE,
+    }
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderDecompressStream(state: *mut BrotliDecoderState,
+                                                       available_in: *mut usize,
+                                                       next_in: *mut *const u8,
+                                                       available_out: *mut usize,
+                                                       next_out: *mut *mut u8,
+                                                       total_out: *mut usize)
+                                                       -> c_int {
+  catch_panic(BROTLI_DECODER_RESULT_ERROR, || {
+    if state.is_null() {
+      return BROTLI_DECODER_RESULT_ERROR;
+    }
+    let s = &mut *state;
+    if s.error_code < 0 {
+      return BROTLI_DECODER_RESULT_ERROR;
+    }
+    // As in libbrotlidec, next_out may be NULL when there is no room for output.
+    if available_in.is_null() || next_in.is_null() || available_out.is_null() ||
+       (next_out.is_null() && *available_out != 0) {
+      return invalid_arguments(s);
+    }
+    let input = match slice_from_raw(*next_in, *available_in) {
+      Some(input) => input,
+      None => return invalid_arguments(s),
+    };
+    let output = if next_out.is_null() {
+      &mut []
+    } else {
+      match slice_from_raw_mut(*next_out, *available_out) {
+        Some(output) => output,
+        None => return invalid_arguments(s),
+      }
+    };
+    let mut input_offset: usize = 0;
+    let mut output_offset: usize = 0;
+    decompress_step(s, &mut *available_in, &mut input_offset, input, &mut *available_out, &mut output_offset,
+                    output);
+    if input_offset != 0 {
+      *next_in = (*next_in).add(input_offset);
+    }
+    if output_offset != 0 {
+      *next_out = (*next_out).add(output_offset);
+    }
+    if !total_out.is_null() {
+      *total_out = s.total_out;
+    }
+    s.result
+  })
+}
+
+fn invalid_arguments(s: &mut BrotliDecoderState) -> c_int {
+  s.result = BROTLI_DECODER_RESULT_ERROR;
+  s.error_code = BROTLI_DECODER_ERROR_INVALID_ARGUMENTS;
+  s.result
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderHasMoreOutput(state: *const BrotliDecoderState) -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if state.is_null() || (*state).result != BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT {
+      return BROTLI_FALSE;
+    }
+    BROTLI_TRUE
+  })
+}
+
+// Decodes up to *size bytes (64KB if *size is 0) without taking more input. The returned bytes stay
+// valid until the next call with this state.
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderTakeOutput(state: *mut BrotliDecoderState, size: *mut usize) -> *const u8 {
+  catch_panic(ptr::null(), || {
+    if state.is_null() || size.is_null() {
+      return ptr::null();
+    }
+    let s = &mut *state;
+    if s.result != BROTLI_DECODER_RESULT_NEEDS_MORE_OUTPUT {
+      *size = 0;
+      return ptr::null();
+    }
+    let wanted = if *size == 0 { 1 << 16 } else { *size };
+    *size = 0;
+    let mut buffer = core::mem::take(&mut s.take_output_buffer);
+    buffer.resize(wanted, 0);
+    let mut available_in: usize = 0;
+    let mut input_offset: usize = 0;
+    let mut available_out: usize = wanted;
+    let mut output_offset: usize = 0;
+    decompress_step(s, &mut available_in, &mut input_offset, &[], &mut available_out, &mut output_offset,
+                    &mut buffer[..]);
+    s.take_output_buffer = buffer;
+    *size = output_offset;
+    if output_offset == 0 {
+      ptr::null()
+    } else {
+      s.take_output_buffer.as_ptr()
+    }
+  })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderIsFinished(state: *const BrotliDecoderState) -> BROTLI_BOOL {
+  catch_panic(BROTLI_FALSE, || {
+    if state.is_null() || (*state).result != BROTLI_DECODER_RESULT_SUCCESS {
+      return BROTLI_FALSE;
+    }
+    BROTLI_TRUE
+  })
+}
+
+// The outcome of the last call with this state: BROTLI_DECODER_NO_ERROR before the first one,
+// the BrotliDecoderResult for a call that succeeded, and a negative code for one that failed.
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderGetErrorCode(state: *const BrotliDecoderState) -> c_int {
+  catch_panic(BROTLI_DECODER_ERROR_UNREACHABLE, || {
+    if state.is_null() {
+      return BROTLI_DECODER_ERROR_INVALID_ARGUMENTS;
+    }
+    (*state).error_code
+  })
+}
+
+// The name of an error code without the BROTLI_DECODER prefix, as in libbrotlidec, and "INVALID"
+// for a value that is not one.
+#[no_mangle]
+pub extern "C" fn BrotliDecoderErrorString(code: c_int) -> *const c_char {
+  let name: &'static [u8] = match code {
+    BROTLI_DECODER_NO_ERROR => b"_NO_ERROR\0",
+    BROTLI_DECODER_SUCCESS => b"_SUCCESS\0",
+    BROTLI_DECODER_NEEDS_MORE_INPUT => b"_NEEDS_MORE_INPUT\0",
+    BROTLI_DECODER_NEEDS_MORE_OUTPUT => b"_NEEDS_MORE_OUTPUT\0",
+    BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_NIBBLE => b"_ERROR_FORMAT_EXUBERANT_NIBBLE\0",
+    BROTLI_DECODER_ERROR_FORMAT_RESERVED => b"_ERROR_FORMAT_RESERVED\0",
+    BROTLI_DECODER_ERROR_FORMAT_EXUBERANT_META_NIBBLE => b"_ERROR_FORMAT_EXUBERANT_META_NIBBLE\0",
+    BROTLI_DECODER_ERROR_FORMAT_SIMPLE_HUFFMAN_ALPHABET => b"_ERROR_FORMAT_SIMPLE_HUFFMAN_ALPHABET\0",
+    BROTLI_DECODER_ERROR_FORMAT_SIMPLE_HUFFMAN_SAME => b"_ERROR_FORMAT_SIMPLE_HUFFMAN_SAME\0",
+    BROTLI_DECODER_ERROR_FORMAT_CL_SPACE => b"_ERROR_FORMAT_CL_SPACE\0",
+    BROTLI_DECODER_ERROR_FORMAT_HUFFMAN_SPACE => b"_ERROR_FORMAT_HUFFMAN_SPACE\0",
+    BROTLI_DECODER_ERROR_FORMAT_CONTEXT_MAP_REPEAT => b"_ERROR_FORMAT_CONTEXT_MAP_REPEAT\0",
+    BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_1 => b"_ERROR_FORMAT_BLOCK_LENGTH_1\0",
+    BROTLI_DECODER_ERROR_FORMAT_BLOCK_LENGTH_2 => b"_ERROR_FORMAT_BLOCK_LENGTH_2\0",
+    BROTLI_DECODER_ERROR_FORMAT_TRANSFORM => b"_ERROR_FORMAT_TRANSFORM\0",
+    BROTLI_DECODER_ERROR_FORMAT_DICTIONARY => b"_ERROR_FORMAT_DICTIONARY\0",
+    BROTLI_DECODER_ERROR_FORMAT_WINDOW_BITS => b"_ERROR_FORMAT_WINDOW_BITS\0",
+    BROTLI_DECODER_ERROR_FORMAT_PADDING_1 => b"_ERROR_FORMAT_PADDING_1\0",
+    BROTLI_DECODER_ERROR_FORMAT_PADDING_2 => b"_ERROR_FORMAT_PADDING_2\0",
+    BROTLI_DECODER_ERROR_FORMAT_DISTANCE => b"_ERROR_FORMAT_DISTANCE\0",
+    BROTLI_DECODER_ERROR_DICTIONARY_NOT_SET => b"_ERROR_DICTIONARY_NOT_SET\0",
+    BROTLI_DECODER_ERROR_INVALID_ARGUMENTS => b"_ERROR_INVALID_ARGUMENTS\0",
+    BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MODES => b"_ERROR_ALLOC_CONTEXT_MODES\0",
+    BROTLI_DECODER_ERROR_ALLOC_TREE_GROUPS => b"_ERROR_ALLOC_TREE_GROUPS\0",
+    BROTLI_DECODER_ERROR_ALLOC_CONTEXT_MAP => b"_ERROR_ALLOC_CONTEXT_MAP\0",
+    BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_1 => b"_ERROR_ALLOC_RING_BUFFER_1\0",
+    BROTLI_DECODER_ERROR_ALLOC_RING_BUFFER_2 => b"_ERROR_ALLOC_RING_BUFFER_2\0",
+    BROTLI_DECODER_ERROR_ALLOC_BLOCK_TYPE_TREES => b"_ERROR_ALLOC_BLOCK_TYPE_TREES\0",
+    BROTLI_DECODER_ERROR_UNREACHABLE => b"_ERROR_UNREACHABLE\0",
+    BROTLI_DECODER_ERROR_FORMAT => b"_ERROR_FORMAT\0",
+    _ => b"INVALID\0",
+  };
+  name.as_ptr() as *const c_char
+}
+
+// Anything short of decoding the whole stream into the buffer is an error, as in libbrotlidec.
+#[no_mangle]
+pub unsafe extern "C" fn BrotliDecoderDecompress(encoded_size: usize,
+                                                 encoded_buffer: *const u8,
+                                                 decoded_size: *mut usize,
+                                                 decoded_buffer: *mut u8)
+                                                 -> c_int {
+  catch_panic(BROTLI_DECODER_RESULT_ERROR, || {
+    if decoded_size.is_null() {
+      return BROTLI_DECODER_RESULT_ERROR;
+    }
+    let input = match slice_from_raw(encoded_buffer, encoded_size) {
+      Some(input) => input,
+      None => return BROTLI_DECODER_RESULT_ERROR,
+    };
+    let output = match slice_from_raw_mut(decoded_buffer, *decoded_size) {
+      Some(output) => output,
+      None => return BROTLI_DECODER_RESULT_ERROR,
+    };
+    let mut state = new_decoder_state();
+    let mut available_in = input.len();
+    let mut input_offset: usize = 0;
+    let mut available_out = output.len();
+    let mut output_offset: usize = 0;
+    let mut total_out: usize = 0;
+    let result = BrotliDecompressStream(&mut available_in,
+                                        &mut input_offset,
+                                        input,
+                                        &mut available_out,
+                                        &mut output_offset,
+                                        output,
+                                        &mut total_out,
+                                        &mut state);
+    state.BrotliStateCleanup();
+    match result {
+      BrotliResult::ResultSuccess => {
+        *decoded_size = output_offset;
+        BROTLI_DECODER_RESULT_SUCCESS
+      }
+      _ => BROTLI_DECODER_RESULT_ERROR,
+    }
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn BrotliDecoderVersion() -> u32 {
+  catch_panic(0, BrotliEncoderVersion)
+}
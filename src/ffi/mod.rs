@@ -0,0 +1,51 @@
+// A C ABI with the symbols and semantics of the libbrotlienc and libbrotlidec functions of the same
+// names, declared in c/brotli/encode.h and c/brotli/decode.h, so C and C++ programs can link
+// against this crate in place of the C library. The brotli-ffi crate in c/ builds it as a static and
+// a shared library: cargo build --release -p brotli-ffi
+// The custom allocator arguments of the CreateInstance functions are not supported: passing
+// anything but NULL for them makes CreateInstance return NULL.
+// What the pointer arguments must satisfy is what the C headers and libbrotli document. None of the
+// functions lets a panic escape: it fails the call, and the state it happened in fails every later
+// call as well.
+#![allow(clippy::missing_safety_doc)]
+pub mod compressor;
+pub mod decompressor;
+
+use core::slice;
+use std::os::raw::{c_int, c_void};
+use std::panic;
+
+pub type BROTLI_BOOL = c_int;
+pub const BROTLI_TRUE: BROTLI_BOOL = 1;
+pub const BROTLI_FALSE: BROTLI_BOOL = 0;
+
+pub type brotli_alloc_func = Option<extern "C" fn(opaque: *mut c_void, size: usize) -> *mut c_void>;
+pub type brotli_free_func = Option<extern "C" fn(opaque: *mut c_void, address: *mut c_void)>;
+
+// Runs the body of an exported function. A panic must not unwind into the C caller, so it ends the
+// call with on_panic, the failure value of that function, instead.
+fn catch_panic<T, F: FnOnce() -> T>(on_panic: T, body: F) -> T {
+  panic::catch_unwind(panic::AssertUnwindSafe(body)).unwrap_or(on_panic)
+}
+
+// The buffers C hands over as a pointer and a length, where the pointer may be NULL if the
+// length is 0. None if it is NULL for a nonzero length.
+unsafe fn slice_from_raw<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+  if len == 0 {
+    Some(&[])
+  } else if data.is_null() {
+    None
+  } else {
+    Some(slice::from_raw_parts(data, len))
+  }
+}
+
+unsafe fn slice_from_raw_mut<'a>(data: *mut u8, len: usize) -> Option<&'a mut [u8]> {
+  if len == 0 {
+    Some(&mut [])
+  } else if data.is_null() {
+    None
+  } else {
+    Some(slice::from_raw_parts_mut(data, len))
+  }
+}
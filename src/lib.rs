@@ -4,12 +4,17 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
+// ranges are compared with >= and <=, as in the C sources
+#![allow(clippy::manual_range_contains)]
 
 #[macro_use]
 // <-- for debugging, remove xprintln from bit_reader and replace with println
 #[cfg(not(feature="no-stdlib"))]
 extern crate std;
 
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+extern crate alloc as rust_alloc;
+
 #[allow(unused_imports)]
 #[macro_use]
 extern crate alloc_no_stdlib as alloc;
@@ -19,6 +24,8 @@ pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut,
 #[cfg(not(feature="no-stdlib"))]
 pub use alloc::HeapAlloc;
 pub mod enc;
+#[cfg(all(feature="ffi", not(feature="no-stdlib")))]
+pub mod ffi;
 pub use brotli_decompressor::transform;
 pub use brotli_decompressor::dictionary;
 pub use brotli_decompressor::reader;
@@ -59,8 +66,9 @@ pub use enc::interface::thaw_pair;
 pub use brotli_decompressor::{BrotliDecompressStream, BrotliResult};
 
 #[cfg(not(feature="no-stdlib"))]
-pub use enc::{BrotliCompress, BrotliCompressCustomAlloc};
-pub use enc::{BrotliCompressCustomIo};
+pub use enc::{BrotliCompress, BrotliCompressCustomAlloc, BrotliCompressCustomDict, BrotliCompressWholeBuffer};
+pub use enc::{BrotliCompressCustomIo, BrotliCompressCustomIoCustomDict, BrotliCompressWholeBufferCustomIo};
+pub use enc::stack_profile::BrotliCompressStackProfile;
 
 #[cfg(not(feature="no-stdlib"))]
 pub use enc::reader::{CompressorReader};
@@ -69,16 +77,31 @@ pub use enc::reader::{CompressorReaderCustomIo};
 
 #[cfg(not(feature="no-stdlib"))]
 pub use enc::writer::{CompressorWriter};
+#[cfg(not(feature="no-stdlib"))]
+pub use enc::message::{MessageCompressor, MessageDecompressor};
+#[cfg(not(feature="no-stdlib"))]
+pub use enc::pull::Encoder;
+#[cfg(not(feature="no-stdlib"))]
+pub use enc::verify::{StreamVerifier, VerificationFailed, VerifyingCompressorWriter};
 pub use enc::writer::{CompressorWriterCustomIo};
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+pub use enc::reader::CompressorReaderVecAlloc;
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+pub use enc::writer::CompressorWriterVecAlloc;
+#[cfg(any(feature="alloc", not(feature="no-stdlib")))]
+pub use enc::vec_alloc::{compress_to_vec, VecAlloc, VecAllocBundle};
 
 
 #[cfg(not(feature="no-stdlib"))]
 pub use brotli_decompressor::BrotliDecompress;
+#[cfg(not(feature="no-stdlib"))]
+pub use enc::limited_decompressor::{decompress_to_vec_with_limit, is_output_limit_exceeded, LimitedDecompressor,
+                                    OutputLimitExceeded};
 
 
 
 #[cfg(not(feature="no-stdlib"))]
-pub use brotli_decompressor::BrotliDecompressCustomAlloc;
+pub use brotli_decompressor::{BrotliDecompressCustomAlloc, BrotliDecompressCustomDict};
 
 pub use brotli_decompressor::BrotliDecompressCustomIo;
 